@@ -1,11 +1,30 @@
 use std::env;
 
 fn main() {
+    println!("cargo:rustc-check-cfg=cfg(have_min_max_version)");
+    println!("cargo:rustc-check-cfg=cfg(have_client_hello_callback)");
+    println!("cargo:rustc-check-cfg=cfg(have_tls13)");
+    println!("cargo:rustc-check-cfg=cfg(have_rpk)");
+    println!("cargo:rustc-check-cfg=cfg(have_verified_chain)");
+
     if let Ok(version) = env::var("DEP_OPENSSL_VERSION_NUMBER") {
         let version = u64::from_str_radix(&version, 16).unwrap();
 
         if version >= 0x1_01_00_00_0 {
             println!("cargo:rustc-cfg=have_min_max_version");
+
+            if env::var("DEP_OPENSSL_LIBRESSL_VERSION_NUMBER").is_err() {
+                println!("cargo:rustc-cfg=have_verified_chain");
+            }
+        }
+
+        if version >= 0x1_01_01_00_0 {
+            println!("cargo:rustc-cfg=have_client_hello_callback");
+            println!("cargo:rustc-cfg=have_tls13");
+        }
+
+        if version >= 0x3_02_00_00_0 {
+            println!("cargo:rustc-cfg=have_rpk");
         }
     }
 
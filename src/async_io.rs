@@ -0,0 +1,81 @@
+//! Runtime-independent support for driving streams from async tasks.
+//!
+//! The backends only speak blocking `Read` and `Write`, so the async adapters wrap the underlying
+//! async stream in a synchronous one which polls it with the context of the task currently polling
+//! the TLS stream, turning `Poll::Pending` into `WouldBlock` errors and back again.
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::task::{Context, Poll};
+
+use {HandshakeError, MidHandshakeTlsStream, Result, TlsAcceptor, TlsConnector, TlsStream};
+
+/// A synchronous wrapper over an async stream.
+pub trait AllowStd: io::Read + io::Write {
+    /// Sets the task context used by reads and writes, or clears it if `cx` is null.
+    ///
+    /// `cx` points to a `Context` which must stay alive until the context is cleared.
+    fn set_context(&mut self, cx: *mut ());
+}
+
+/// Runs `f` against a TLS stream with the task context installed, mapping `WouldBlock` errors to
+/// `Poll::Pending`.
+pub fn poll_with<A, F, R>(stream: &mut TlsStream<A>, cx: &mut Context, f: F) -> Poll<io::Result<R>>
+where
+    A: AllowStd,
+    F: FnOnce(&mut TlsStream<A>) -> io::Result<R>,
+{
+    stream.get_mut().set_context(cx as *mut Context as *mut ());
+    let r = f(stream);
+    stream.get_mut().set_context(ptr::null_mut());
+    match r {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+        r => Poll::Ready(r),
+    }
+}
+
+/// The state of a handshake driven by an async task.
+pub enum Handshake<A> {
+    Connect(TlsConnector, String, A),
+    Accept(TlsAcceptor, A),
+    Mid(MidHandshakeTlsStream<A>),
+    Done,
+}
+
+impl<A> Handshake<A>
+where
+    A: AllowStd,
+{
+    pub fn poll(&mut self, cx: &mut Context) -> Poll<Result<TlsStream<A>>> {
+        let cx = cx as *mut Context as *mut ();
+        let result = match mem::replace(self, Handshake::Done) {
+            Handshake::Connect(connector, domain, mut stream) => {
+                stream.set_context(cx);
+                connector.connect(&domain, stream)
+            }
+            Handshake::Accept(acceptor, mut stream) => {
+                stream.set_context(cx);
+                acceptor.accept(stream)
+            }
+            Handshake::Mid(mut stream) => {
+                stream.get_mut().set_context(cx);
+                stream.handshake()
+            }
+            Handshake::Done => panic!("handshake polled after completion"),
+        };
+
+        match result {
+            Ok(mut stream) => {
+                stream.get_mut().set_context(ptr::null_mut());
+                Poll::Ready(Ok(stream))
+            }
+            Err(HandshakeError::WouldBlock(mut stream)) => {
+                stream.get_mut().set_context(ptr::null_mut());
+                *self = Handshake::Mid(stream);
+                Poll::Pending
+            }
+            Err(HandshakeError::Failure(e)) => Poll::Ready(Err(e)),
+        }
+    }
+}
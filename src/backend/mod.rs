@@ -0,0 +1,13 @@
+//! Platform-specific extensions.
+//!
+//! These modules provide access to the underlying TLS implementation for functionality that is
+//! not exposed by the portable API. Only the module for the implementation in use on the current
+//! platform is available. Identities created through these modules can't be used with the rustls
+//! backend.
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub mod security_framework;
+#[cfg(target_os = "windows")]
+pub mod schannel;
+#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+pub mod openssl;
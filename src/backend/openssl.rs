@@ -0,0 +1,340 @@
+//! OpenSSL-specific extensions.
+
+extern crate openssl;
+
+use self::openssl::error::ErrorStack;
+use self::openssl::pkey::{PKey, Private};
+use self::openssl::ssl::{self, SslContextBuilder};
+use self::openssl::x509::{X509VerifyResult, X509};
+use std::fmt;
+use std::time::Duration;
+
+use imp::{self, native};
+use {Certificate, Error, Identity, TlsAcceptorBuilder, TlsConnectorBuilder};
+
+/// OpenSSL-specific extensions to `Identity`.
+pub trait IdentityExt {
+    /// Creates an `Identity` from an OpenSSL private key, leaf certificate, and chain of
+    /// intermediate certificates.
+    ///
+    /// The chain certificates should be in order from the leaf certificate towards the root. An
+    /// error is returned if the private key doesn't match the leaf certificate's public key. The
+    /// identity can't be used with the rustls backend.
+    fn from_openssl(pkey: PKey<Private>, cert: X509, chain: Vec<X509>) -> ::Result<Identity>;
+}
+
+impl IdentityExt for Identity {
+    fn from_openssl(pkey: PKey<Private>, cert: X509, chain: Vec<X509>) -> ::Result<Identity> {
+        let identity = native::Identity::from_parts(pkey, cert, chain).map_err(imp::Error::from)?;
+        Ok(Identity(imp::Identity::from(identity)))
+    }
+}
+
+/// OpenSSL-specific extensions to `Certificate`.
+pub trait CertificateExt {
+    /// Creates a `Certificate` from an OpenSSL certificate.
+    fn from_openssl(cert: X509) -> Certificate;
+
+    /// Returns a shared reference to the underlying OpenSSL certificate.
+    fn openssl(&self) -> &X509;
+}
+
+impl CertificateExt for Certificate {
+    fn from_openssl(cert: X509) -> Certificate {
+        Certificate(imp::Certificate::from(native::Certificate::from(cert)))
+    }
+
+    fn openssl(&self) -> &X509 {
+        self.0.native().as_inner()
+    }
+}
+
+/// OpenSSL-specific extensions to `Error`.
+pub trait ErrorExt {
+    /// Returns the underlying OpenSSL error of a failed handshake or stream operation.
+    fn openssl_ssl_error(&self) -> Option<&ssl::Error>;
+
+    /// Returns OpenSSL's error stack, if OpenSSL reported any errors.
+    fn openssl_error_stack(&self) -> Option<&ErrorStack>;
+
+    /// Returns the result of the peer's certificate verification, if the error was raised by a
+    /// handshake.
+    fn openssl_verify_result(&self) -> Option<X509VerifyResult>;
+}
+
+impl ErrorExt for Error {
+    fn openssl_ssl_error(&self) -> Option<&ssl::Error> {
+        match self.0.native() {
+            Some(native::Error::Ssl(e, _)) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn openssl_error_stack(&self) -> Option<&ErrorStack> {
+        match self.0.native() {
+            Some(native::Error::Normal(e)) => Some(e),
+            Some(native::Error::Ssl(e, _)) => e.ssl_error(),
+            _ => None,
+        }
+    }
+
+    fn openssl_verify_result(&self) -> Option<X509VerifyResult> {
+        match self.0.native() {
+            Some(&native::Error::Ssl(_, v)) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Keys used by an acceptor to encrypt and decrypt TLS session tickets.
+///
+/// By default each acceptor generates its own ticket keys, so a client can only resume its session
+/// with the server which issued the ticket. Acceptors configured with the same keys, including
+/// those of other processes and machines, accept each other's tickets.
+///
+/// Each key is 80 bytes: a 16 byte name identifying the key, followed by a 32 byte HMAC-SHA256 key
+/// and a 32 byte AES-256 key. This matches the layout of nginx's `ssl_session_ticket_key` files.
+/// Keys must be randomly generated and kept secret, as anyone who knows them can decrypt the
+/// traffic of sessions resumed with their tickets.
+///
+/// Clones share the same keys, so a rotation applies to every acceptor configured with them.
+#[derive(Clone)]
+pub struct SessionTicketKeys(imp::SessionTicketKeys);
+
+impl fmt::Debug for SessionTicketKeys {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SessionTicketKeys").finish()
+    }
+}
+
+impl SessionTicketKeys {
+    /// Creates a set of keys which encrypts tickets with the specified key.
+    pub fn new(key: &[u8; 80]) -> SessionTicketKeys {
+        SessionTicketKeys(imp::SessionTicketKeys::new(key))
+    }
+
+    /// Replaces the key used to encrypt new tickets.
+    ///
+    /// Tickets encrypted with the previous key are still accepted, and clients presenting them
+    /// are issued a new ticket encrypted with the new key. Tickets encrypted with any older key
+    /// are rejected, falling back to a full handshake.
+    pub fn rotate(&self, key: &[u8; 80]) {
+        self.0.rotate(key);
+    }
+}
+
+/// OpenSSL-specific extensions to `TlsConnectorBuilder`.
+pub trait TlsConnectorBuilderExt {
+    /// Controls TLS 1.3 middlebox compatibility mode (RFC 8446 appendix D.4).
+    ///
+    /// In compatibility mode a TLS 1.3 handshake includes a legacy session ID and dummy
+    /// `ChangeCipherSpec` messages so it looks like a TLS 1.2 session resumption to middleboxes
+    /// which don't understand TLS 1.3. Some broken middleboxes instead fail on those messages. This
+    /// has no effect if the linked OpenSSL doesn't support TLS 1.3.
+    ///
+    /// Defaults to OpenSSL's default, which is enabled.
+    fn middlebox_compat(&mut self, enable: bool) -> &mut TlsConnectorBuilder;
+
+    /// Adds a raw public key (RFC 7250) which the server may authenticate with instead of an
+    /// X509 certificate.
+    ///
+    /// The key is a DER-encoded `SubjectPublicKeyInfo`. If any keys are added, the connector will
+    /// offer to accept a raw public key from the server, which must match one of them. Servers
+    /// may still present an X509 certificate instead; if its key matches one of the expected keys
+    /// it is accepted without validating its chain or hostname, as with a DANE-EE TLSA record.
+    /// This has no effect if the linked OpenSSL is older than 3.2.
+    fn add_expected_raw_public_key(&mut self, spki: &[u8]) -> &mut TlsConnectorBuilder;
+
+    /// Sets the number of plaintext bytes transferred over a stream after which its keys are
+    /// updated.
+    ///
+    /// Once the limit is reached, a TLS 1.3 `KeyUpdate` message requesting that the peer update
+    /// its keys as well is sent ahead of the next read or write. TLS 1.2 connections are
+    /// renegotiated instead, but only if both sides support secure renegotiation (RFC 5746).
+    /// Otherwise, and for older protocol versions, the keys are never updated, so set the minimum
+    /// protocol version to `Protocol::Tlsv13` if rekeying is required.
+    ///
+    /// Defaults to `None`.
+    fn rekey_after_bytes(&mut self, bytes: Option<u64>) -> &mut TlsConnectorBuilder;
+
+    /// Sets the time after which a stream's keys are updated.
+    ///
+    /// This behaves like `rekey_after_bytes`, with the interval measured from the end of the
+    /// handshake or the previous key update.
+    ///
+    /// Defaults to `None`.
+    fn rekey_interval(&mut self, interval: Option<Duration>) -> &mut TlsConnectorBuilder;
+
+    /// Controls offering the ShangMi cipher suites of RFC 8998, `TLS_SM4_GCM_SM3` and
+    /// `TLS_SM4_CCM_SM3`, after OpenSSL's default TLS 1.3 cipher suites.
+    ///
+    /// These are provided by some OpenSSL builds and forks such as Tongsuo, and building the
+    /// connector will fail if the linked library lacks them. SM2 identities and certificates can be
+    /// loaded through the usual constructors if the library supports SM2 keys. This has no effect
+    /// if the linked OpenSSL doesn't support TLS 1.3.
+    ///
+    /// Defaults to `false`.
+    fn sm_cipher_suites(&mut self, enable: bool) -> &mut TlsConnectorBuilder;
+
+    /// Sets a callback which can configure the OpenSSL context directly when the connector is
+    /// built.
+    ///
+    /// The callback runs after the context has been configured from the builder, so settings it
+    /// changes take precedence. An error returned by the callback fails the build. Settings which
+    /// conflict with the ones this crate relies on, such as the verify callback, may break
+    /// certificate validation.
+    ///
+    /// Defaults to no callback.
+    fn configure_context<F>(&mut self, callback: F) -> &mut TlsConnectorBuilder
+    where
+        F: Fn(&mut SslContextBuilder) -> Result<(), ErrorStack> + Sync + Send + 'static;
+}
+
+impl TlsConnectorBuilderExt for TlsConnectorBuilder {
+    fn middlebox_compat(&mut self, enable: bool) -> &mut TlsConnectorBuilder {
+        self.middlebox_compat = Some(enable);
+        self
+    }
+
+    fn add_expected_raw_public_key(&mut self, spki: &[u8]) -> &mut TlsConnectorBuilder {
+        self.expected_raw_public_keys.push(spki.to_vec());
+        self
+    }
+
+    fn rekey_after_bytes(&mut self, bytes: Option<u64>) -> &mut TlsConnectorBuilder {
+        self.rekey_after_bytes = bytes;
+        self
+    }
+
+    fn rekey_interval(&mut self, interval: Option<Duration>) -> &mut TlsConnectorBuilder {
+        self.rekey_interval = interval;
+        self
+    }
+
+    fn sm_cipher_suites(&mut self, enable: bool) -> &mut TlsConnectorBuilder {
+        self.sm_cipher_suites = enable;
+        self
+    }
+
+    fn configure_context<F>(&mut self, callback: F) -> &mut TlsConnectorBuilder
+    where
+        F: Fn(&mut SslContextBuilder) -> Result<(), ErrorStack> + Sync + Send + 'static,
+    {
+        self.context_callback = Some(imp::ContextCallback::new(callback));
+        self
+    }
+}
+
+/// OpenSSL-specific extensions to `TlsAcceptorBuilder`.
+pub trait TlsAcceptorBuilderExt {
+    /// Controls TLS 1.3 middlebox compatibility mode (RFC 8446 appendix D.4).
+    ///
+    /// In compatibility mode the server echoes the client's legacy session ID and sends a dummy
+    /// `ChangeCipherSpec` message. This has no effect if the linked OpenSSL doesn't support
+    /// TLS 1.3.
+    ///
+    /// Defaults to OpenSSL's default, which is enabled.
+    fn middlebox_compat(&mut self, enable: bool) -> &mut TlsAcceptorBuilder;
+
+    /// Controls authenticating with the raw public key (RFC 7250) of the identity's certificate
+    /// to clients which support it.
+    ///
+    /// Clients which don't are sent the certificate as usual. This has no effect if the linked
+    /// OpenSSL is older than 3.2.
+    ///
+    /// Defaults to `false`.
+    fn raw_public_key(&mut self, enable: bool) -> &mut TlsAcceptorBuilder;
+
+    /// Sets the number of plaintext bytes transferred over a stream after which its keys are
+    /// updated.
+    ///
+    /// See `TlsConnectorBuilderExt::rekey_after_bytes`.
+    ///
+    /// Defaults to `None`.
+    fn rekey_after_bytes(&mut self, bytes: Option<u64>) -> &mut TlsAcceptorBuilder;
+
+    /// Sets the time after which a stream's keys are updated.
+    ///
+    /// See `TlsConnectorBuilderExt::rekey_interval`.
+    ///
+    /// Defaults to `None`.
+    fn rekey_interval(&mut self, interval: Option<Duration>) -> &mut TlsAcceptorBuilder;
+
+    /// Controls accepting the ShangMi cipher suites of RFC 8998.
+    ///
+    /// These cipher suites only exist in TLS 1.3, so enabling them also enables TLS 1.3 on the
+    /// acceptor. See `TlsConnectorBuilderExt::sm_cipher_suites`.
+    ///
+    /// Defaults to `false`.
+    fn sm_cipher_suites(&mut self, enable: bool) -> &mut TlsAcceptorBuilder;
+
+    /// Controls issuing session tickets (RFC 5077) to clients so that they can resume their
+    /// sessions.
+    ///
+    /// If disabled, sessions can still be resumed from the acceptor's own session cache.
+    ///
+    /// Defaults to `true`.
+    fn session_tickets(&mut self, enable: bool) -> &mut TlsAcceptorBuilder;
+
+    /// Sets the keys used to encrypt and decrypt session tickets.
+    ///
+    /// Defaults to keys generated by the acceptor.
+    fn session_ticket_keys(&mut self, keys: SessionTicketKeys) -> &mut TlsAcceptorBuilder;
+
+    /// Sets a callback which can configure the OpenSSL context directly when the acceptor is
+    /// built.
+    ///
+    /// The callback runs for every context of the acceptor, including those of
+    /// `TlsAcceptorBuilder::add_sni_identity`, after it has been configured from the builder. See
+    /// `TlsConnectorBuilderExt::configure_context`.
+    ///
+    /// Defaults to no callback.
+    fn configure_context<F>(&mut self, callback: F) -> &mut TlsAcceptorBuilder
+    where
+        F: Fn(&mut SslContextBuilder) -> Result<(), ErrorStack> + Sync + Send + 'static;
+}
+
+impl TlsAcceptorBuilderExt for TlsAcceptorBuilder {
+    fn middlebox_compat(&mut self, enable: bool) -> &mut TlsAcceptorBuilder {
+        self.middlebox_compat = Some(enable);
+        self
+    }
+
+    fn raw_public_key(&mut self, enable: bool) -> &mut TlsAcceptorBuilder {
+        self.raw_public_key = enable;
+        self
+    }
+
+    fn rekey_after_bytes(&mut self, bytes: Option<u64>) -> &mut TlsAcceptorBuilder {
+        self.rekey_after_bytes = bytes;
+        self
+    }
+
+    fn rekey_interval(&mut self, interval: Option<Duration>) -> &mut TlsAcceptorBuilder {
+        self.rekey_interval = interval;
+        self
+    }
+
+    fn sm_cipher_suites(&mut self, enable: bool) -> &mut TlsAcceptorBuilder {
+        self.sm_cipher_suites = enable;
+        self
+    }
+
+    fn session_tickets(&mut self, enable: bool) -> &mut TlsAcceptorBuilder {
+        self.session_tickets = enable;
+        self
+    }
+
+    fn session_ticket_keys(&mut self, keys: SessionTicketKeys) -> &mut TlsAcceptorBuilder {
+        self.session_ticket_keys = Some(keys.0);
+        self
+    }
+
+    fn configure_context<F>(&mut self, callback: F) -> &mut TlsAcceptorBuilder
+    where
+        F: Fn(&mut SslContextBuilder) -> Result<(), ErrorStack> + Sync + Send + 'static,
+    {
+        self.context_callback = Some(imp::ContextCallback::new(callback));
+        self
+    }
+}
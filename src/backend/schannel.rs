@@ -0,0 +1,193 @@
+//! SChannel-specific extensions.
+
+extern crate schannel;
+
+use self::schannel::cert_context::CertContext;
+use std::io;
+use std::mem;
+use std::os::raw::c_void;
+use std::ptr;
+
+use imp::{self, native};
+use {
+    Certificate, Error, Identity, Protocol, SecretKind, SecretProvider, TlsAcceptorBuilder,
+    TlsConnectorBuilder,
+};
+
+/// SChannel-specific extensions to `Identity`.
+pub trait IdentityExt {
+    /// Creates an `Identity` from a certificate context with an associated private key.
+    ///
+    /// The identity can't be used with the rustls backend.
+    fn from_cert_context(cert: CertContext) -> Identity;
+
+    /// Creates an `Identity` from a certificate context whose private key is held on a smart card.
+    ///
+    /// The card's PIN is requested from `provider` as `SecretKind::Pin` and handed to the
+    /// cryptographic provider up front, so the handshake completes without a PIN dialog. Both
+    /// legacy CSP and CNG KSP smart card providers are supported. The certificate context is
+    /// typically found in the user's `MY` store, where smart card certificates are propagated.
+    /// The identity can't be used with the rustls backend.
+    fn from_smart_card(cert: CertContext, provider: &dyn SecretProvider) -> ::Result<Identity>;
+}
+
+impl IdentityExt for Identity {
+    fn from_cert_context(cert: CertContext) -> Identity {
+        Identity(imp::Identity::from(native::Identity::from_parts(cert)))
+    }
+
+    fn from_smart_card(cert: CertContext, provider: &dyn SecretProvider) -> ::Result<Identity> {
+        let pin = ::secret(provider, SecretKind::Pin)?;
+        let identity = native::Identity::from_smart_card(cert, &pin).map_err(imp::Error::from)?;
+        Ok(Identity(imp::Identity::from(identity)))
+    }
+}
+
+/// SChannel-specific extensions to `Certificate`.
+pub trait CertificateExt {
+    /// Creates a `Certificate` from a certificate context.
+    fn from_cert_context(cert: CertContext) -> Certificate;
+
+    /// Returns a shared reference to the underlying certificate context.
+    fn cert_context(&self) -> &CertContext;
+}
+
+impl CertificateExt for Certificate {
+    fn from_cert_context(cert: CertContext) -> Certificate {
+        Certificate(imp::Certificate::from(native::Certificate::from(cert)))
+    }
+
+    fn cert_context(&self) -> &CertContext {
+        self.0.native().as_inner()
+    }
+}
+
+/// SChannel-specific extensions to `Error`.
+pub trait ErrorExt {
+    /// Returns a shared reference to the underlying error.
+    ///
+    /// Errors reported by SChannel and certificate chain validation carry their `SECURITY_STATUS`
+    /// or `HRESULT` as the `raw_os_error`. Errors raised by the rustls backend are returned as the
+    /// I/O error they wrap.
+    fn schannel_error(&self) -> &io::Error;
+}
+
+impl ErrorExt for Error {
+    fn schannel_error(&self) -> &io::Error {
+        match self.0 {
+            imp::Error::Native(ref e) => e.as_inner(),
+            #[cfg(feature = "rustls")]
+            imp::Error::Rustls(ref e) => e.as_inner(),
+        }
+    }
+}
+
+/// SChannel-specific extensions to `TlsConnectorBuilder`.
+pub trait TlsConnectorBuilderExt {
+    /// Defers protocol version selection to the system's SChannel configuration.
+    ///
+    /// When enabled, `min_protocol_version` and `max_protocol_version` are ignored and the
+    /// protocols enabled by group policy or the registry are used instead. Cipher suites are
+    /// always selected by the system.
+    ///
+    /// Defaults to `false`.
+    fn use_system_protocols(&mut self, use_system_protocols: bool) -> &mut TlsConnectorBuilder;
+}
+
+impl TlsConnectorBuilderExt for TlsConnectorBuilder {
+    fn use_system_protocols(&mut self, use_system_protocols: bool) -> &mut TlsConnectorBuilder {
+        self.use_system_protocols = use_system_protocols;
+        self
+    }
+}
+
+/// SChannel-specific extensions to `TlsAcceptorBuilder`.
+pub trait TlsAcceptorBuilderExt {
+    /// Defers protocol version selection to the system's SChannel configuration.
+    ///
+    /// When enabled, `min_protocol_version` and `max_protocol_version` are ignored and the
+    /// protocols enabled by group policy or the registry are used instead. Cipher suites are
+    /// always selected by the system.
+    ///
+    /// Defaults to `false`.
+    fn use_system_protocols(&mut self, use_system_protocols: bool) -> &mut TlsAcceptorBuilder;
+}
+
+impl TlsAcceptorBuilderExt for TlsAcceptorBuilder {
+    fn use_system_protocols(&mut self, use_system_protocols: bool) -> &mut TlsAcceptorBuilder {
+        self.use_system_protocols = use_system_protocols;
+        self
+    }
+}
+
+/// Returns whether the system's SChannel configuration enables a protocol by default.
+///
+/// This reads the `SCHANNEL\Protocols` registry keys managed by group policy, for client
+/// connections if `server` is `false` and server connections otherwise. `None` is returned if the
+/// protocol is not configured, in which case the operating system's built-in default applies.
+pub fn system_protocol_enabled(protocol: Protocol, server: bool) -> io::Result<Option<bool>> {
+    let name = match protocol {
+        Protocol::Sslv3 => "SSL 3.0",
+        Protocol::Tlsv10 => "TLS 1.0",
+        Protocol::Tlsv11 => "TLS 1.1",
+        Protocol::Tlsv12 => "TLS 1.2",
+        Protocol::Tlsv13 => "TLS 1.3",
+        Protocol::__NonExhaustive => unreachable!(),
+    };
+    let key = format!(
+        "SYSTEM\\CurrentControlSet\\Control\\SecurityProviders\\SCHANNEL\\Protocols\\{}\\{}",
+        name,
+        if server { "Server" } else { "Client" }
+    );
+
+    let enabled = read_dword(&key, "Enabled")?;
+    let disabled_by_default = read_dword(&key, "DisabledByDefault")?;
+    let enabled = match (enabled, disabled_by_default) {
+        (None, None) => None,
+        (Some(0), _) | (_, Some(1)) => Some(false),
+        _ => Some(true),
+    };
+    Ok(enabled)
+}
+
+fn read_dword(key: &str, value: &str) -> io::Result<Option<u32>> {
+    let key = key.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+    let value = value.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+
+    let mut data = 0u32;
+    let mut len = mem::size_of::<u32>() as u32;
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            value.as_ptr(),
+            RRF_RT_REG_DWORD,
+            ptr::null_mut(),
+            &mut data as *mut u32 as *mut c_void,
+            &mut len,
+        )
+    };
+
+    match status {
+        0 => Ok(Some(data)),
+        ERROR_FILE_NOT_FOUND => Ok(None),
+        status => Err(io::Error::from_raw_os_error(status)),
+    }
+}
+
+const HKEY_LOCAL_MACHINE: isize = 0x8000_0002u32 as i32 as isize;
+const RRF_RT_REG_DWORD: u32 = 0x0000_0010;
+const ERROR_FILE_NOT_FOUND: i32 = 2;
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn RegGetValueW(
+        hkey: isize,
+        sub_key: *const u16,
+        value: *const u16,
+        flags: u32,
+        type_: *mut u32,
+        data: *mut c_void,
+        len: *mut u32,
+    ) -> i32;
+}
@@ -0,0 +1,67 @@
+//! Secure Transport-specific extensions.
+
+extern crate security_framework;
+
+use self::security_framework::base;
+use self::security_framework::certificate::SecCertificate;
+use self::security_framework::identity::SecIdentity;
+
+use imp::{self, native};
+use {Certificate, Error, Identity};
+
+/// Secure Transport-specific extensions to `Identity`.
+pub trait IdentityExt {
+    /// Creates an `Identity` from a Security framework identity and chain of intermediate
+    /// certificates.
+    ///
+    /// The chain certificates should be in order from the leaf certificate towards the root. The
+    /// identity can't be used with the rustls backend.
+    fn from_sec_identity(identity: SecIdentity, chain: Vec<SecCertificate>) -> Identity;
+}
+
+impl IdentityExt for Identity {
+    fn from_sec_identity(identity: SecIdentity, chain: Vec<SecCertificate>) -> Identity {
+        Identity(imp::Identity::from(native::Identity::from_parts(
+            identity, chain,
+        )))
+    }
+}
+
+/// Secure Transport-specific extensions to `Certificate`.
+pub trait CertificateExt {
+    /// Creates a `Certificate` from a Security framework certificate.
+    fn from_sec_certificate(cert: SecCertificate) -> Certificate;
+
+    /// Returns a shared reference to the underlying Security framework certificate.
+    fn sec_certificate(&self) -> &SecCertificate;
+}
+
+impl CertificateExt for Certificate {
+    fn from_sec_certificate(cert: SecCertificate) -> Certificate {
+        Certificate(imp::Certificate::from(native::Certificate::from(cert)))
+    }
+
+    fn sec_certificate(&self) -> &SecCertificate {
+        self.0.native().as_inner()
+    }
+}
+
+/// Secure Transport-specific extensions to `Error`.
+pub trait ErrorExt {
+    /// Returns a shared reference to the underlying Security framework error, whose `code` is the
+    /// `OSStatus` reported by Secure Transport or the Security framework.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the error was raised by the rustls backend.
+    fn sec_error(&self) -> &base::Error;
+}
+
+impl ErrorExt for Error {
+    fn sec_error(&self) -> &base::Error {
+        match self.0.native() {
+            Some(e) => e.as_inner(),
+            None => panic!("the error wasn't raised by Secure Transport"),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Repairs the certificate chains of identities, which are frequently assembled by hand.
+
+use der;
+
+/// Sorts the certificates sent after `leaf`, given the DER encoding of each of them.
+///
+/// Certificates are ordered from the issuer of the leaf towards the root. Any which aren't part
+/// of that path follow in their original order, and copies of the leaf are dropped. Certificates
+/// which can't be parsed are treated as not being part of the path.
+pub fn sort<T>(leaf: &[u8], chain: Vec<T>, ders: &[Vec<u8>]) -> Vec<T> {
+    let mut chain = chain.into_iter().map(Some).collect::<Vec<_>>();
+    order(leaf, ders)
+        .into_iter()
+        .filter_map(|i| chain[i].take())
+        .collect()
+}
+
+fn order(leaf: &[u8], chain: &[Vec<u8>]) -> Vec<usize> {
+    let mut remaining = (0..chain.len())
+        .filter(|&i| chain[i] != leaf)
+        .collect::<Vec<_>>();
+    let mut order = vec![];
+
+    let mut cert = leaf;
+    while let Ok(false) = der::is_self_issued(cert) {
+        let issuer = match der::issuer(cert) {
+            Ok(issuer) => issuer,
+            Err(_) => break,
+        };
+        let next = remaining
+            .iter()
+            .position(|&i| der::subject(&chain[i]).ok() == Some(issuer));
+        match next {
+            Some(pos) => {
+                let i = remaining.remove(pos);
+                order.push(i);
+                cert = &chain[i];
+            }
+            None => break,
+        }
+    }
+
+    order.extend(remaining);
+    order
+}
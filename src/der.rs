@@ -0,0 +1,476 @@
+//! Just enough DER parsing to pick apart keystores and certificates.
+
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const BOOLEAN: u8 = 0x01;
+pub const INTEGER: u8 = 0x02;
+#[cfg(any(target_os = "macos", target_os = "ios", feature = "rustls"))]
+pub const BIT_STRING: u8 = 0x03;
+pub const OCTET_STRING: u8 = 0x04;
+pub const OID: u8 = 0x06;
+pub const UTC_TIME: u8 = 0x17;
+pub const GENERALIZED_TIME: u8 = 0x18;
+pub const SEQUENCE: u8 = 0x30;
+pub const SET: u8 = 0x31;
+pub const CONTEXT_0: u8 = 0xa0;
+pub const CONTEXT_3: u8 = 0xa3;
+
+// GeneralName dNSName [2] IMPLICIT IA5String
+const DNS_NAME: u8 = 0x82;
+// GeneralName uniformResourceIdentifier [6] IMPLICIT IA5String
+const URI: u8 = 0x86;
+// GeneralName iPAddress [7] IMPLICIT OCTET STRING
+const IP_ADDRESS: u8 = 0x87;
+
+// 2.5.4.3
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+// Short names of the attributes commonly found in distinguished names, under 2.5.4.
+const NAME_ATTRIBUTES: &[(u8, &str)] = &[
+    (3, "CN"),
+    (6, "C"),
+    (7, "L"),
+    (8, "ST"),
+    (10, "O"),
+    (11, "OU"),
+];
+// 2.5.29.17
+const SUBJECT_ALT_NAME_OID: &[u8] = &[0x55, 0x1d, 0x11];
+// 2.5.29.31
+const CRL_DISTRIBUTION_POINTS_OID: &[u8] = &[0x55, 0x1d, 0x1f];
+// 1.3.6.1.5.5.7.1.1
+const AUTHORITY_INFO_ACCESS_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01];
+// 1.3.6.1.5.5.7.48.1
+pub const OCSP_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+// 1.3.6.1.5.5.7.48.2
+pub const CA_ISSUERS_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x02];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Error;
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("malformed DER data")
+    }
+}
+
+pub struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader(buf)
+    }
+
+    pub fn peek_tag(&self) -> Option<u8> {
+        self.0.first().cloned()
+    }
+
+    pub fn read_any(&mut self) -> Result<(u8, &'a [u8]), Error> {
+        let (&tag, rest) = self.0.split_first().ok_or(Error)?;
+        let (&len, mut rest) = rest.split_first().ok_or(Error)?;
+
+        let len = if len < 0x80 {
+            len as usize
+        } else {
+            let n = (len & 0x7f) as usize;
+            if n == 0 || n > 3 || rest.len() < n {
+                return Err(Error);
+            }
+            let len = rest[..n].iter().fold(0, |len, &b| len << 8 | b as usize);
+            rest = &rest[n..];
+            len
+        };
+
+        if rest.len() < len {
+            return Err(Error);
+        }
+        let (value, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok((tag, value))
+    }
+
+    pub fn read(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        match self.read_any()? {
+            (t, value) if t == tag => Ok(value),
+            _ => Err(Error),
+        }
+    }
+}
+
+struct TbsCertificate<'a> {
+    issuer: &'a [u8],
+    validity: &'a [u8],
+    subject: &'a [u8],
+    /// The encoded `subjectPublicKeyInfo`, including its tag and length.
+    subject_public_key_info: &'a [u8],
+    extensions: Option<&'a [u8]>,
+}
+
+fn tbs_certificate<'a>(cert: &'a [u8]) -> Result<TbsCertificate<'a>, Error> {
+    let cert = Reader::new(cert).read(SEQUENCE)?;
+    let mut tbs = Reader::new(Reader::new(cert).read(SEQUENCE)?);
+    if tbs.peek_tag() == Some(CONTEXT_0) {
+        tbs.read(CONTEXT_0)?; // version
+    }
+    tbs.read(INTEGER)?; // serialNumber
+    tbs.read(SEQUENCE)?; // signature
+    let issuer = tbs.read(SEQUENCE)?;
+    let validity = tbs.read(SEQUENCE)?;
+    let subject = tbs.read(SEQUENCE)?;
+    let rest = tbs.0;
+    tbs.read(SEQUENCE)?;
+    let subject_public_key_info = &rest[..rest.len() - tbs.0.len()];
+
+    let mut extensions = None;
+    while tbs.peek_tag().is_some() {
+        let (tag, value) = tbs.read_any()?;
+        if tag == CONTEXT_3 {
+            extensions = Some(Reader::new(value).read(SEQUENCE)?);
+        }
+    }
+
+    Ok(TbsCertificate {
+        issuer,
+        validity,
+        subject,
+        subject_public_key_info,
+        extensions,
+    })
+}
+
+/// Returns the `notBefore` and `notAfter` times of a DER-encoded X509 certificate.
+pub fn validity(cert: &[u8]) -> Result<(SystemTime, SystemTime), Error> {
+    let mut validity = Reader::new(tbs_certificate(cert)?.validity);
+    let (tag, not_before) = validity.read_any()?;
+    let not_before = time(tag, not_before)?;
+    let (tag, not_after) = validity.read_any()?;
+    let not_after = time(tag, not_after)?;
+
+    Ok((not_before, not_after))
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", feature = "rustls"))]
+pub struct SignedData<'a> {
+    /// The encoded `tbsCertificate`, including its tag and length.
+    pub tbs: &'a [u8],
+    /// The OID of the signature algorithm.
+    pub algorithm: &'a [u8],
+    pub signature: &'a [u8],
+}
+
+/// Splits a DER-encoded X509 certificate into the signed data and its signature.
+#[cfg(any(target_os = "macos", target_os = "ios", feature = "rustls"))]
+pub fn signed_data<'a>(cert: &'a [u8]) -> Result<SignedData<'a>, Error> {
+    let cert = Reader::new(cert).read(SEQUENCE)?;
+    let mut reader = Reader::new(cert);
+    reader.read(SEQUENCE)?; // tbsCertificate
+    let tbs = &cert[..cert.len() - reader.0.len()];
+    let algorithm = Reader::new(reader.read(SEQUENCE)?).read(OID)?;
+    match reader.read(BIT_STRING)?.split_first() {
+        Some((&0, signature)) => Ok(SignedData {
+            tbs,
+            algorithm,
+            signature,
+        }),
+        _ => Err(Error),
+    }
+}
+
+/// Returns the OID of the algorithm a DER-encoded X509 certificate is signed with.
+pub fn signature_algorithm(cert: &[u8]) -> Result<&[u8], Error> {
+    let mut cert = Reader::new(Reader::new(cert).read(SEQUENCE)?);
+    cert.read(SEQUENCE)?; // tbsCertificate
+    Reader::new(cert.read(SEQUENCE)?).read(OID)
+}
+
+/// Returns the encoded issuer name of a DER-encoded certificate revocation list.
+pub fn crl_issuer(crl: &[u8]) -> Result<&[u8], Error> {
+    let crl = Reader::new(crl).read(SEQUENCE)?;
+    let mut tbs = Reader::new(Reader::new(crl).read(SEQUENCE)?);
+    if tbs.peek_tag() == Some(INTEGER) {
+        tbs.read(INTEGER)?; // version
+    }
+    tbs.read(SEQUENCE)?; // signature
+    tbs.read(SEQUENCE)
+}
+
+/// Determines if a DER-encoded X509 certificate's issuer and subject are the same.
+pub fn is_self_issued(cert: &[u8]) -> Result<bool, Error> {
+    let tbs = tbs_certificate(cert)?;
+    Ok(tbs.issuer == tbs.subject)
+}
+
+/// Returns the encoded issuer name of a DER-encoded X509 certificate.
+pub fn issuer(cert: &[u8]) -> Result<&[u8], Error> {
+    Ok(tbs_certificate(cert)?.issuer)
+}
+
+/// Returns the encoded subject name of a DER-encoded X509 certificate.
+pub fn subject(cert: &[u8]) -> Result<&[u8], Error> {
+    Ok(tbs_certificate(cert)?.subject)
+}
+
+/// Returns the encoded subject public key info of a DER-encoded X509 certificate, including its
+/// tag and length.
+pub fn subject_public_key_info(cert: &[u8]) -> Result<&[u8], Error> {
+    Ok(tbs_certificate(cert)?.subject_public_key_info)
+}
+
+/// Splits an encoded subject public key info into the contents of its algorithm identifier and
+/// the key itself.
+#[cfg(feature = "rustls")]
+pub fn public_key(spki: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let mut spki = Reader::new(Reader::new(spki).read(SEQUENCE)?);
+    let algorithm = spki.read(SEQUENCE)?;
+    match spki.read(BIT_STRING)?.split_first() {
+        Some((&0, key)) => Ok((algorithm, key)),
+        _ => Err(Error),
+    }
+}
+
+/// Splits a DER-encoded PKCS #8 `EncryptedPrivateKeyInfo` into its encryption algorithm, including
+/// its tag and length, and the encrypted key.
+#[cfg(feature = "rustls")]
+pub fn encrypted_private_key_info(buf: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let mut info = Reader::new(Reader::new(buf).read(SEQUENCE)?);
+    let rest = info.0;
+    info.read(SEQUENCE)?;
+    let algorithm = &rest[..rest.len() - info.0.len()];
+    Ok((algorithm, info.read(OCTET_STRING)?))
+}
+
+/// Returns the values of a DER-encoded X509 certificate's extensions with the given OID.
+fn extensions<'a>(cert: &'a [u8], oid: &[u8]) -> Result<Vec<&'a [u8]>, Error> {
+    let mut values = vec![];
+    let mut extensions = match tbs_certificate(cert)?.extensions {
+        Some(extensions) => Reader::new(extensions),
+        None => return Ok(values),
+    };
+    while extensions.peek_tag().is_some() {
+        let mut extension = Reader::new(extensions.read(SEQUENCE)?);
+        if extension.read(OID)? != oid {
+            continue;
+        }
+        if extension.peek_tag() == Some(BOOLEAN) {
+            extension.read(BOOLEAN)?; // critical
+        }
+        values.push(extension.read(OCTET_STRING)?);
+    }
+    Ok(values)
+}
+
+/// Returns the names of a `GeneralNames` sequence's contents with the given tag.
+fn general_names<'a>(mut general_names: Reader<'a>, tag: u8) -> Result<Vec<&'a [u8]>, Error> {
+    let mut names = vec![];
+    while general_names.peek_tag().is_some() {
+        let (t, name) = general_names.read_any()?;
+        if t == tag {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Returns the DNS names of a DER-encoded X509 certificate's subject alternative name extension.
+pub fn dns_names(cert: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut names = vec![];
+    for value in extensions(cert, SUBJECT_ALT_NAME_OID)? {
+        let value = Reader::new(Reader::new(value).read(SEQUENCE)?);
+        names.extend(general_names(value, DNS_NAME)?);
+    }
+    Ok(names)
+}
+
+/// Returns the IP addresses of a DER-encoded X509 certificate's subject alternative name extension,
+/// in network byte order.
+pub fn ip_addresses(cert: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut addrs = vec![];
+    for value in extensions(cert, SUBJECT_ALT_NAME_OID)? {
+        let value = Reader::new(Reader::new(value).read(SEQUENCE)?);
+        addrs.extend(general_names(value, IP_ADDRESS)?);
+    }
+    Ok(addrs)
+}
+
+/// Returns the URIs of a DER-encoded X509 certificate's authority information access extension
+/// with the given access method.
+pub fn access_locations<'a>(cert: &'a [u8], method: &[u8]) -> Result<Vec<&'a [u8]>, Error> {
+    let mut locations = vec![];
+    for value in extensions(cert, AUTHORITY_INFO_ACCESS_OID)? {
+        let mut descriptions = Reader::new(Reader::new(value).read(SEQUENCE)?);
+        while descriptions.peek_tag().is_some() {
+            let mut description = Reader::new(descriptions.read(SEQUENCE)?);
+            if description.read(OID)? != method {
+                continue;
+            }
+            if let (URI, location) = description.read_any()? {
+                locations.push(location);
+            }
+        }
+    }
+    Ok(locations)
+}
+
+/// Returns the URIs of the full names in a DER-encoded X509 certificate's CRL distribution
+/// points extension.
+pub fn crl_distribution_points(cert: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut uris = vec![];
+    for value in extensions(cert, CRL_DISTRIBUTION_POINTS_OID)? {
+        let mut points = Reader::new(Reader::new(value).read(SEQUENCE)?);
+        while points.peek_tag().is_some() {
+            let mut point = Reader::new(points.read(SEQUENCE)?);
+            // distributionPoint [0] DistributionPointName OPTIONAL
+            if point.peek_tag() != Some(CONTEXT_0) {
+                continue;
+            }
+            // fullName [0] IMPLICIT GeneralNames, as opposed to nameRelativeToCRLIssuer
+            let mut name = Reader::new(point.read(CONTEXT_0)?);
+            if name.peek_tag() != Some(CONTEXT_0) {
+                continue;
+            }
+            uris.extend(general_names(Reader::new(name.read(CONTEXT_0)?), URI)?);
+        }
+    }
+    Ok(uris)
+}
+
+/// Returns the common names of a DER-encoded X509 certificate's subject.
+pub fn common_names(cert: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut names = vec![];
+    let mut subject = Reader::new(tbs_certificate(cert)?.subject);
+    while subject.peek_tag().is_some() {
+        let mut rdn = Reader::new(subject.read(SET)?);
+        while rdn.peek_tag().is_some() {
+            let mut attribute = Reader::new(rdn.read(SEQUENCE)?);
+            if attribute.read(OID)? == COMMON_NAME_OID {
+                names.push(attribute.read_any()?.1);
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Formats an encoded distinguished name, such as one returned by `subject`, for display.
+///
+/// Attributes are listed in the order they're encoded, as in `C=US, O=Example, CN=example.com`.
+pub fn display_name(name: &[u8]) -> Result<String, Error> {
+    let mut out = String::new();
+    let mut name = Reader::new(name);
+    while name.peek_tag().is_some() {
+        let mut rdn = Reader::new(name.read(SET)?);
+        while rdn.peek_tag().is_some() {
+            let mut attribute = Reader::new(rdn.read(SEQUENCE)?);
+            let oid = attribute.read(OID)?;
+            let (tag, value) = attribute.read_any()?;
+
+            if !out.is_empty() {
+                out.push_str(", ");
+            }
+            match NAME_ATTRIBUTES.iter().find(|a| oid == [0x55, 0x04, a.0]) {
+                Some(&(_, short_name)) => out.push_str(short_name),
+                None => out.push_str(&display_oid(oid)?),
+            }
+            out.push('=');
+            match tag {
+                // UTF8String, PrintableString, TeletexString and IA5String
+                0x0c | 0x13 | 0x14 | 0x16 => out.push_str(&String::from_utf8_lossy(value)),
+                _ => {
+                    out.push('#');
+                    for b in value {
+                        out.push_str(&format!("{:02x}", b));
+                    }
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn display_oid(oid: &[u8]) -> Result<String, Error> {
+    let mut arcs = vec![];
+    let mut arc = 0u64;
+    for &b in oid {
+        if arc >> 57 != 0 {
+            return Err(Error);
+        }
+        arc = arc << 7 | u64::from(b & 0x7f);
+        if b & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+    if arcs.is_empty() || arc != 0 {
+        return Err(Error);
+    }
+
+    let first = cmp::min(arcs[0] / 40, 2);
+    let mut out = format!("{}.{}", first, arcs[0] - first * 40);
+    for arc in &arcs[1..] {
+        out.push_str(&format!(".{}", arc));
+    }
+    Ok(out)
+}
+
+fn time(tag: u8, value: &[u8]) -> Result<SystemTime, Error> {
+    let s = str::from_utf8(value).map_err(|_| Error)?;
+    if !s.is_ascii() {
+        return Err(Error);
+    }
+    let (year, rest) = match tag {
+        UTC_TIME if s.len() == 13 => {
+            let year = digits(&s[..2])?;
+            // RFC 5280 maps two digit years onto 1950 through 2049
+            let year = if year >= 50 { 1900 + year } else { 2000 + year };
+            (year, &s[2..])
+        }
+        GENERALIZED_TIME if s.len() == 15 => (digits(&s[..4])?, &s[4..]),
+        _ => return Err(Error),
+    };
+    if !rest.ends_with('Z') {
+        return Err(Error);
+    }
+
+    let month = digits(&rest[0..2])?;
+    let day = digits(&rest[2..4])?;
+    let hour = digits(&rest[4..6])?;
+    let minute = digits(&rest[6..8])?;
+    let second = digits(&rest[8..10])?;
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(Error);
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    if secs >= 0 {
+        Ok(UNIX_EPOCH + Duration::from_secs(secs as u64))
+    } else {
+        Ok(UNIX_EPOCH - Duration::from_secs(-secs as u64))
+    }
+}
+
+fn digits(s: &str) -> Result<i64, Error> {
+    if !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error);
+    }
+    s.parse().map_err(|_| Error)
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let month = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * month + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
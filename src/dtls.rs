@@ -0,0 +1,140 @@
+//! Support for DTLS, the datagram variant of TLS.
+//!
+//! DTLS runs over an unreliable datagram transport rather than a byte stream. Each write to a
+//! `DtlsStream` is sent as a single record, and each read returns the contents of a single
+//! record. Lost handshake messages are retransmitted when `MidHandshakeTlsStream::handshake` is
+//! called again after the transport reported a timeout, so the transport should be given a read
+//! timeout.
+//!
+//! DTLS is currently only supported by the OpenSSL backend. Building a `DtlsConnector` or
+//! `DtlsAcceptor` fails with the other backends.
+
+use std::io;
+use std::net::UdpSocket;
+use std::result;
+
+use {HandshakeError, Identity, Result, TlsAcceptor, TlsConnector, TlsStream};
+
+/// A datagram transport for DTLS.
+///
+/// Both methods operate on whole datagrams.
+pub trait Datagram {
+    /// Sends a single datagram to the peer.
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Receives a single datagram from the peer.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// A connected `UdpSocket`.
+impl Datagram for UdpSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        UdpSocket::send(self, buf)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        UdpSocket::recv(self, buf)
+    }
+}
+
+/// A DTLS stream.
+pub type DtlsStream<D> = TlsStream<DatagramStream<D>>;
+
+/// Adapts a `Datagram` transport to the `Read` and `Write` traits used by `TlsStream`.
+///
+/// Timeouts are reported as `WouldBlock` errors, interrupting handshakes with a
+/// `HandshakeError::WouldBlock`.
+#[derive(Debug)]
+pub struct DatagramStream<D>(D);
+
+impl<D> DatagramStream<D> {
+    /// Returns a shared reference to the inner transport.
+    pub fn get_ref(&self) -> &D {
+        &self.0
+    }
+
+    /// Returns a mutable reference to the inner transport.
+    pub fn get_mut(&mut self) -> &mut D {
+        &mut self.0
+    }
+}
+
+fn timeout_to_would_block<T>(r: io::Result<T>) -> io::Result<T> {
+    match r {
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "datagram receive timed out"))
+        }
+        r => r,
+    }
+}
+
+impl<D: Datagram> io::Read for DatagramStream<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        timeout_to_would_block(self.0.recv(buf))
+    }
+}
+
+impl<D: Datagram> io::Write for DatagramStream<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        timeout_to_would_block(self.0.send(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A builder for client-side DTLS connections.
+///
+/// Created by `TlsConnectorBuilder::build_dtls`, which accepts the same configuration as for
+/// TLS connections.
+#[derive(Clone, Debug)]
+pub struct DtlsConnector(pub(crate) TlsConnector);
+
+impl DtlsConnector {
+    /// Returns a new connector with default settings.
+    pub fn new() -> Result<DtlsConnector> {
+        TlsConnector::builder().build_dtls()
+    }
+
+    /// Initiates a DTLS handshake over a datagram transport.
+    ///
+    /// The domain is used as in `TlsConnector::connect`.
+    pub fn connect<D>(
+        &self,
+        domain: &str,
+        transport: D,
+    ) -> result::Result<DtlsStream<D>, HandshakeError<DatagramStream<D>>>
+    where
+        D: Datagram,
+    {
+        self.0.connect(domain, DatagramStream(transport))
+    }
+}
+
+/// A builder for server-side DTLS connections.
+///
+/// Created by `TlsAcceptorBuilder::build_dtls`, which accepts the same configuration as for
+/// TLS connections.
+#[derive(Clone, Debug)]
+pub struct DtlsAcceptor(pub(crate) TlsAcceptor);
+
+impl DtlsAcceptor {
+    /// Creates an acceptor with default settings.
+    ///
+    /// The identity acts as the server's private key/certificate chain.
+    pub fn new(identity: Identity) -> Result<DtlsAcceptor> {
+        TlsAcceptor::builder(identity).build_dtls()
+    }
+
+    /// Accepts a DTLS handshake over a datagram transport.
+    pub fn accept<D>(
+        &self,
+        transport: D,
+    ) -> result::Result<DtlsStream<D>, HandshakeError<DatagramStream<D>>>
+    where
+        D: Datagram,
+    {
+        self.0.accept(DatagramStream(transport))
+    }
+}
@@ -0,0 +1,95 @@
+//! Hostname matching used to enforce wildcard policies stricter than the backends' own checks.
+
+use std::net::IpAddr;
+use std::str;
+
+use der;
+use WildcardPolicy;
+
+// Registry labels commonly found directly below country code TLDs, e.g. `co.uk` or `com.au`.
+const SECOND_LEVEL_REGISTRIES: &[&str] = &[
+    "ac", "co", "com", "edu", "gob", "go", "gov", "ltd", "mil", "ne", "net", "nhs", "or", "org",
+    "plc", "sch",
+];
+
+/// Determines if a DER-encoded X509 certificate is valid for `domain` under `policy`.
+///
+/// Subject alternative names are used if present, falling back to the subject's common names
+/// otherwise. IP addresses are always accepted, since wildcards don't apply to them.
+pub fn verify(cert: &[u8], domain: &str, policy: WildcardPolicy) -> Result<bool, der::Error> {
+    if domain.parse::<IpAddr>().is_ok() {
+        return Ok(true);
+    }
+    let domain = normalize(domain);
+
+    let mut names = der::dns_names(cert)?;
+    if names.is_empty() {
+        names = der::common_names(cert)?;
+    }
+
+    let matched = names
+        .iter()
+        .filter_map(|name| str::from_utf8(name).ok())
+        .any(|name| matches(&normalize(name), &domain, policy));
+    Ok(matched)
+}
+
+/// Determines if a DER-encoded X509 certificate lists `addr` among its subject alternative names.
+pub fn verify_ip(cert: &[u8], addr: IpAddr) -> Result<bool, der::Error> {
+    let octets = match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    };
+    let addrs = der::ip_addresses(cert)?;
+    Ok(addrs.iter().any(|a| *a == &octets[..]))
+}
+
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+pub fn matches(pattern: &str, host: &str, policy: WildcardPolicy) -> bool {
+    if !pattern.contains('*') {
+        return pattern == host;
+    }
+
+    let (pattern_label, pattern_rest) = split_label(pattern);
+    let (host_label, host_rest) = split_label(host);
+    // Wildcards are only valid in the left-most label, and never directly below a TLD.
+    if pattern_rest.contains('*') || !pattern_rest.contains('.') || pattern_rest != host_rest {
+        return false;
+    }
+
+    match policy {
+        WildcardPolicy::Disallow => return false,
+        WildcardPolicy::SingleLabel if pattern_label != "*" => return false,
+        WildcardPolicy::NoRegistryDomain if is_registry_domain(pattern_rest) => return false,
+        _ => {}
+    }
+
+    let mut parts = pattern_label.splitn(2, '*');
+    let prefix = parts.next().unwrap_or("");
+    let suffix = parts.next().unwrap_or("");
+    !suffix.contains('*')
+        && !host_label.is_empty()
+        && host_label.len() >= prefix.len() + suffix.len()
+        && host_label.starts_with(prefix)
+        && host_label.ends_with(suffix)
+}
+
+fn split_label(name: &str) -> (&str, &str) {
+    match name.find('.') {
+        Some(idx) => (&name[..idx], &name[idx + 1..]),
+        None => (name, ""),
+    }
+}
+
+// Only a TLD or a common registry label below a country code TLD counts as a registry domain.
+fn is_registry_domain(name: &str) -> bool {
+    let labels = name.split('.').collect::<Vec<_>>();
+    match labels[..] {
+        [_] => true,
+        [second, tld] => tld.len() == 2 && SECOND_LEVEL_REGISTRIES.contains(&second),
+        _ => false,
+    }
+}
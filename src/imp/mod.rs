@@ -0,0 +1,696 @@
+//! Dispatch between the backends compiled into the crate.
+//!
+//! The platform's TLS library is the native backend, used by default. The rustls backend is
+//! enabled by the `rustls` feature, and only used by builders which select it. Certificates,
+//! identities and CRLs are held in the native representation, and converted when handed to
+//! rustls.
+
+use std::error;
+use std::fmt;
+use std::io;
+#[cfg(feature = "rustls")]
+use std::sync::{Arc, Mutex};
+
+use {der, jks, pem, policy, Protocol, TlsAcceptorBuilder, TlsConnectorBuilder};
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[path = "security_framework.rs"]
+pub mod native;
+#[cfg(target_os = "windows")]
+#[path = "schannel.rs"]
+pub mod native;
+#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+#[path = "openssl.rs"]
+pub mod native;
+#[cfg(feature = "rustls")]
+pub mod rustls;
+
+#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+pub use self::native::{ContextCallback, SessionTicketKeys};
+
+// Runs `$body` with `$s` bound to the backend's value inside `$value`.
+macro_rules! dispatch {
+    ($ty:ident, $value:expr, $s:ident => $body:expr) => {
+        match $value {
+            $ty::Native($s) => $body,
+            #[cfg(feature = "rustls")]
+            $ty::Rustls($s) => $body,
+        }
+    };
+}
+
+// The backend used by a builder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Native,
+    #[cfg(feature = "rustls")]
+    Rustls,
+}
+
+impl Backend {
+    // Returns whether the backend matches IP addresses against the certificate itself.
+    pub fn checks_ip_addresses(self) -> bool {
+        match self {
+            Backend::Native => cfg!(not(any(
+                target_os = "macos",
+                target_os = "windows",
+                target_os = "ios"
+            ))),
+            #[cfg(feature = "rustls")]
+            Backend::Rustls => true,
+        }
+    }
+}
+
+pub fn backend_version() -> String {
+    native::backend_version()
+}
+
+pub enum Error {
+    Native(native::Error),
+    #[cfg(feature = "rustls")]
+    Rustls(rustls::Error),
+}
+
+impl Error {
+    pub fn kind(&self) -> ::ErrorKind {
+        dispatch!(Error, self, e => e.kind())
+    }
+
+    pub fn io_error(&self) -> Option<&io::Error> {
+        dispatch!(Error, self, e => e.io_error())
+    }
+
+    pub fn into_io_error(self) -> Result<io::Error, Error> {
+        dispatch!(Error, self, e => e.into_io_error().map_err(Error::from))
+    }
+
+    pub fn is_negotiation_failure(&self) -> bool {
+        dispatch!(Error, self, e => e.is_negotiation_failure())
+    }
+
+    pub fn native(&self) -> Option<&native::Error> {
+        match self {
+            Error::Native(e) => Some(e),
+            #[cfg(feature = "rustls")]
+            Error::Rustls(_) => None,
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        dispatch!(Error, self, e => error::Error::source(e))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        dispatch!(Error, self, e => fmt::Display::fmt(e, fmt))
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        dispatch!(Error, self, e => fmt::Debug::fmt(e, fmt))
+    }
+}
+
+impl From<native::Error> for Error {
+    fn from(err: native::Error) -> Error {
+        Error::Native(err)
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<rustls::Error> for Error {
+    fn from(err: rustls::Error) -> Error {
+        Error::Rustls(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::from(native::Error::from(err))
+    }
+}
+
+impl From<jks::Error> for Error {
+    fn from(err: jks::Error) -> Error {
+        Error::from(native::Error::from(err))
+    }
+}
+
+impl From<der::Error> for Error {
+    fn from(err: der::Error) -> Error {
+        Error::from(native::Error::from(err))
+    }
+}
+
+impl From<pem::Error> for Error {
+    fn from(err: pem::Error) -> Error {
+        Error::from(native::Error::from(err))
+    }
+}
+
+impl From<policy::Error> for Error {
+    fn from(err: policy::Error) -> Error {
+        Error::from(native::Error::from(err))
+    }
+}
+
+// Errors of the native backend are reported as I/O errors when converting values for rustls.
+#[cfg(feature = "rustls")]
+fn rustls_error(err: native::Error) -> rustls::Error {
+    rustls::Error::from(io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+}
+
+// The input an identity was loaded from, kept so the rustls backend can load it when a rustls
+// builder first uses the identity.
+#[cfg(feature = "rustls")]
+enum Source {
+    Pkcs12(Vec<u8>, String),
+    Pkcs8(Vec<Vec<u8>>, Vec<u8>),
+    EncryptedPkcs8(Vec<Vec<u8>>, Vec<u8>, String),
+}
+
+#[cfg(feature = "rustls")]
+impl Source {
+    fn load(&self) -> Result<rustls::Identity, rustls::Error> {
+        match *self {
+            Source::Pkcs12(ref buf, ref pass) => rustls::Identity::from_pkcs12(buf, pass),
+            Source::Pkcs8(ref chain, ref key) => {
+                let chain = chain.iter().map(|c| &**c).collect::<Vec<_>>();
+                rustls::Identity::from_pkcs8_der(&chain, key)
+            }
+            Source::EncryptedPkcs8(ref chain, ref key, ref pass) => {
+                let chain = chain.iter().map(|c| &**c).collect::<Vec<_>>();
+                rustls::Identity::from_encrypted_pkcs8_der(&chain, key, pass)
+            }
+        }
+    }
+}
+
+// An identity as loaded by rustls, on first use. Clones share the loaded identity.
+#[cfg(feature = "rustls")]
+#[derive(Clone)]
+struct LazyIdentity {
+    // Absent for identities created from native values, which rustls can't use.
+    source: Option<Arc<Source>>,
+    identity: Arc<Mutex<Option<rustls::Identity>>>,
+}
+
+#[cfg(feature = "rustls")]
+impl LazyIdentity {
+    fn new(source: Option<Source>) -> LazyIdentity {
+        LazyIdentity {
+            source: source.map(Arc::new),
+            identity: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Errors aren't cached, so every build using the identity reports the error rustls gave.
+    fn get(&self) -> Result<rustls::Identity, rustls::Error> {
+        let mut identity = match self.identity.lock() {
+            Ok(identity) => identity,
+            Err(e) => e.into_inner(),
+        };
+        if let Some(ref identity) = *identity {
+            return Ok(identity.clone());
+        }
+        let loaded = match self.source {
+            Some(ref source) => source.load()?,
+            None => {
+                return Err(rustls::Error::from(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "identities created from backend values can't be used with the rustls backend",
+                )))
+            }
+        };
+        *identity = Some(loaded.clone());
+        Ok(loaded)
+    }
+}
+
+#[derive(Clone)]
+pub struct Identity {
+    native: native::Identity,
+    #[cfg(feature = "rustls")]
+    rustls: LazyIdentity,
+}
+
+impl Identity {
+    pub fn from_pkcs12(buf: &[u8], pass: &str) -> Result<Identity, Error> {
+        Ok(Identity {
+            native: native::Identity::from_pkcs12(buf, pass)?,
+            #[cfg(feature = "rustls")]
+            rustls: LazyIdentity::new(Some(Source::Pkcs12(buf.to_vec(), pass.to_owned()))),
+        })
+    }
+
+    pub fn from_pkcs8_der(chain: &[&[u8]], key: &[u8]) -> Result<Identity, Error> {
+        Ok(Identity {
+            native: native::Identity::from_pkcs8_der(chain, key)?,
+            #[cfg(feature = "rustls")]
+            rustls: LazyIdentity::new(Some(Source::Pkcs8(
+                chain.iter().map(|c| c.to_vec()).collect(),
+                key.to_vec(),
+            ))),
+        })
+    }
+
+    pub fn from_encrypted_pkcs8_der(
+        chain: &[&[u8]],
+        key: &[u8],
+        pass: &str,
+    ) -> Result<Identity, Error> {
+        Ok(Identity {
+            native: native::Identity::from_encrypted_pkcs8_der(chain, key, pass)?,
+            #[cfg(feature = "rustls")]
+            rustls: LazyIdentity::new(Some(Source::EncryptedPkcs8(
+                chain.iter().map(|c| c.to_vec()).collect(),
+                key.to_vec(),
+                pass.to_owned(),
+            ))),
+        })
+    }
+
+    pub fn certificate(&self) -> Result<Certificate, Error> {
+        Ok(Certificate(self.native.certificate()?))
+    }
+
+    pub fn chain(&self) -> Result<Vec<Certificate>, Error> {
+        Ok(self.native.chain()?.into_iter().map(Certificate).collect())
+    }
+
+    pub fn native(&self) -> &native::Identity {
+        &self.native
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls(&self) -> Result<rustls::Identity, rustls::Error> {
+        self.rustls.get()
+    }
+}
+
+// Identities created from native values are only usable by the native backend.
+impl From<native::Identity> for Identity {
+    fn from(identity: native::Identity) -> Identity {
+        Identity {
+            native: identity,
+            #[cfg(feature = "rustls")]
+            rustls: LazyIdentity::new(None),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Certificate(native::Certificate);
+
+impl Certificate {
+    pub fn from_der(buf: &[u8]) -> Result<Certificate, Error> {
+        Ok(Certificate(native::Certificate::from_der(buf)?))
+    }
+
+    pub fn from_pem(buf: &[u8]) -> Result<Certificate, Error> {
+        Ok(Certificate(native::Certificate::from_pem(buf)?))
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.to_der()?)
+    }
+
+    pub fn fingerprint(&self, digest: ::Digest) -> Result<Vec<u8>, Error> {
+        Ok(self.0.fingerprint(digest)?)
+    }
+
+    pub fn public_key_sha256(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.public_key_sha256()?)
+    }
+
+    pub fn verify_signed_by(&self, issuer: &Certificate) -> Result<bool, Error> {
+        Ok(self.0.verify_signed_by(&issuer.0)?)
+    }
+
+    pub fn native(&self) -> &native::Certificate {
+        &self.0
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls(&self) -> Result<rustls::Certificate, rustls::Error> {
+        rustls::Certificate::from_der(&self.0.to_der().map_err(rustls_error)?)
+    }
+}
+
+impl From<native::Certificate> for Certificate {
+    fn from(cert: native::Certificate) -> Certificate {
+        Certificate(cert)
+    }
+}
+
+// Converts certificates returned by either backend to the native representation.
+trait IntoCertificate {
+    fn into_certificate(self) -> Result<Certificate, Error>;
+}
+
+impl IntoCertificate for native::Certificate {
+    fn into_certificate(self) -> Result<Certificate, Error> {
+        Ok(Certificate(self))
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl IntoCertificate for rustls::Certificate {
+    fn into_certificate(self) -> Result<Certificate, Error> {
+        Ok(Certificate(native::Certificate::from_der(&self.to_der()?)?))
+    }
+}
+
+fn certificates<C: IntoCertificate>(certs: Vec<C>) -> Result<Vec<Certificate>, Error> {
+    certs.into_iter().map(C::into_certificate).collect()
+}
+
+#[derive(Clone)]
+pub struct Crl(native::Crl);
+
+impl Crl {
+    pub fn from_der(buf: &[u8]) -> Result<Crl, Error> {
+        Ok(Crl(native::Crl::from_der(buf)?))
+    }
+
+    pub fn from_pem(buf: &[u8]) -> Result<Crl, Error> {
+        Ok(Crl(native::Crl::from_pem(buf)?))
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.to_der()?)
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    pub fn native(&self) -> &native::Crl {
+        &self.0
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls(&self) -> Result<rustls::Crl, rustls::Error> {
+        rustls::Crl::from_der(&self.0.to_der().map_err(rustls_error)?)
+    }
+}
+
+pub enum MidHandshakeTlsStream<S> {
+    Native(native::MidHandshakeTlsStream<S>),
+    #[cfg(feature = "rustls")]
+    Rustls(rustls::MidHandshakeTlsStream<S>),
+}
+
+impl<S> fmt::Debug for MidHandshakeTlsStream<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        dispatch!(MidHandshakeTlsStream, self, s => fmt::Debug::fmt(s, fmt))
+    }
+}
+
+impl<S> MidHandshakeTlsStream<S> {
+    pub fn get_ref(&self) -> &S {
+        dispatch!(MidHandshakeTlsStream, self, s => s.get_ref())
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        dispatch!(MidHandshakeTlsStream, self, s => s.get_mut())
+    }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        dispatch!(MidHandshakeTlsStream, self, s => Ok(s.sni_hostname()?))
+    }
+}
+
+impl<S> MidHandshakeTlsStream<S>
+where
+    S: io::Read + io::Write,
+{
+    pub fn handshake(self) -> Result<TlsStream<S>, HandshakeError<S>> {
+        match self {
+            MidHandshakeTlsStream::Native(s) => Ok(TlsStream::Native(s.handshake()?)),
+            #[cfg(feature = "rustls")]
+            MidHandshakeTlsStream::Rustls(s) => Ok(TlsStream::Rustls(Box::new(s.handshake()?))),
+        }
+    }
+}
+
+pub enum HandshakeError<S> {
+    Failure(Error),
+    WouldBlock(MidHandshakeTlsStream<S>),
+}
+
+impl<S> From<native::HandshakeError<S>> for HandshakeError<S> {
+    fn from(e: native::HandshakeError<S>) -> HandshakeError<S> {
+        match e {
+            native::HandshakeError::Failure(e) => HandshakeError::Failure(Error::Native(e)),
+            native::HandshakeError::WouldBlock(s) => {
+                HandshakeError::WouldBlock(MidHandshakeTlsStream::Native(s))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl<S> From<rustls::HandshakeError<S>> for HandshakeError<S> {
+    fn from(e: rustls::HandshakeError<S>) -> HandshakeError<S> {
+        match e {
+            rustls::HandshakeError::Failure(e) => HandshakeError::Failure(Error::Rustls(e)),
+            rustls::HandshakeError::WouldBlock(s) => {
+                HandshakeError::WouldBlock(MidHandshakeTlsStream::Rustls(s))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TlsConnector {
+    Native(native::TlsConnector),
+    #[cfg(feature = "rustls")]
+    Rustls(rustls::TlsConnector),
+}
+
+impl TlsConnector {
+    pub fn new(builder: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
+        match builder.backend {
+            Backend::Native => Ok(TlsConnector::Native(native::TlsConnector::new(builder)?)),
+            #[cfg(feature = "rustls")]
+            Backend::Rustls => Ok(TlsConnector::Rustls(rustls::TlsConnector::new(builder)?)),
+        }
+    }
+
+    pub fn new_dtls(builder: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
+        match builder.backend {
+            Backend::Native => Ok(TlsConnector::Native(native::TlsConnector::new_dtls(
+                builder,
+            )?)),
+            #[cfg(feature = "rustls")]
+            Backend::Rustls => Ok(TlsConnector::Rustls(rustls::TlsConnector::new_dtls(
+                builder,
+            )?)),
+        }
+    }
+
+    pub fn connect<S>(&self, domain: &str, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        match self {
+            TlsConnector::Native(c) => Ok(TlsStream::Native(c.connect(domain, stream)?)),
+            #[cfg(feature = "rustls")]
+            TlsConnector::Rustls(c) => Ok(TlsStream::Rustls(Box::new(c.connect(domain, stream)?))),
+        }
+    }
+
+    pub fn connect_without_sni<S>(
+        &self,
+        domain: Option<&str>,
+        stream: S,
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        match self {
+            TlsConnector::Native(c) => {
+                Ok(TlsStream::Native(c.connect_without_sni(domain, stream)?))
+            }
+            #[cfg(feature = "rustls")]
+            TlsConnector::Rustls(c) => Ok(TlsStream::Rustls(Box::new(
+                c.connect_without_sni(domain, stream)?,
+            ))),
+        }
+    }
+
+    pub fn connect_with_early_data<S>(
+        &self,
+        domain: &str,
+        stream: S,
+        data: &[u8],
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        match self {
+            TlsConnector::Native(c) => Ok(TlsStream::Native(
+                c.connect_with_early_data(domain, stream, data)?,
+            )),
+            #[cfg(feature = "rustls")]
+            TlsConnector::Rustls(c) => Ok(TlsStream::Rustls(Box::new(
+                c.connect_with_early_data(domain, stream, data)?,
+            ))),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum TlsAcceptor {
+    Native(native::TlsAcceptor),
+    #[cfg(feature = "rustls")]
+    Rustls(rustls::TlsAcceptor),
+}
+
+impl TlsAcceptor {
+    pub fn new(builder: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
+        match builder.backend {
+            Backend::Native => Ok(TlsAcceptor::Native(native::TlsAcceptor::new(builder)?)),
+            #[cfg(feature = "rustls")]
+            Backend::Rustls => Ok(TlsAcceptor::Rustls(rustls::TlsAcceptor::new(builder)?)),
+        }
+    }
+
+    pub fn new_dtls(builder: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
+        match builder.backend {
+            Backend::Native => Ok(TlsAcceptor::Native(native::TlsAcceptor::new_dtls(builder)?)),
+            #[cfg(feature = "rustls")]
+            Backend::Rustls => Ok(TlsAcceptor::Rustls(rustls::TlsAcceptor::new_dtls(builder)?)),
+        }
+    }
+
+    pub fn accept<S>(&self, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        match self {
+            TlsAcceptor::Native(a) => Ok(TlsStream::Native(a.accept(stream)?)),
+            #[cfg(feature = "rustls")]
+            TlsAcceptor::Rustls(a) => Ok(TlsStream::Rustls(Box::new(a.accept(stream)?))),
+        }
+    }
+}
+
+pub enum TlsStream<S> {
+    Native(native::TlsStream<S>),
+    #[cfg(feature = "rustls")]
+    Rustls(Box<rustls::TlsStream<S>>),
+}
+
+impl<S: fmt::Debug> fmt::Debug for TlsStream<S> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        dispatch!(TlsStream, self, s => fmt::Debug::fmt(s, fmt))
+    }
+}
+
+impl<S> TlsStream<S> {
+    pub fn get_ref(&self) -> &S {
+        dispatch!(TlsStream, self, s => s.get_ref())
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        dispatch!(TlsStream, self, s => s.get_mut())
+    }
+}
+
+impl<S: io::Read + io::Write> TlsStream<S> {
+    pub fn buffered_read_size(&self) -> Result<usize, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.buffered_read_size()?))
+    }
+
+    pub fn peer_certificate(&self) -> Result<Option<Certificate>, Error> {
+        dispatch!(TlsStream, self, s => match s.peer_certificate()? {
+            Some(cert) => Ok(Some(cert.into_certificate()?)),
+            None => Ok(None),
+        })
+    }
+
+    pub fn session_resumed(&self) -> Result<bool, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.session_resumed()?))
+    }
+
+    pub fn early_data_accepted(&self) -> Result<bool, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.early_data_accepted()?))
+    }
+
+    pub fn protocol_version(&self) -> Result<Option<Protocol>, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.protocol_version()?))
+    }
+
+    pub fn cipher_suite(&self) -> Result<u16, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.cipher_suite()?))
+    }
+
+    #[cfg(feature = "alpn")]
+    pub fn negotiated_alpn(&self) -> Result<Option<Vec<u8>>, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.negotiated_alpn()?))
+    }
+
+    pub fn peer_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        dispatch!(TlsStream, self, s => certificates(s.peer_certificate_chain()?))
+    }
+
+    pub fn verified_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        dispatch!(TlsStream, self, s => certificates(s.verified_certificate_chain()?))
+    }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.sni_hostname()?))
+    }
+
+    pub fn client_hello_ja3(&self) -> Result<Option<String>, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.client_hello_ja3()?))
+    }
+
+    pub fn client_hello_ja4(&self) -> Result<Option<String>, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.client_hello_ja4()?))
+    }
+
+    pub fn tls_server_end_point(&self) -> Result<Option<Vec<u8>>, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.tls_server_end_point()?))
+    }
+
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.export_keying_material(label, context, len)?))
+    }
+
+    pub fn tls_unique(&self) -> Result<Option<Vec<u8>>, Error> {
+        dispatch!(TlsStream, self, s => Ok(s.tls_unique()?))
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        dispatch!(TlsStream, self, s => s.shutdown())
+    }
+}
+
+impl<S: io::Read + io::Write> io::Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        dispatch!(TlsStream, self, s => io::Read::read(s, buf))
+    }
+}
+
+impl<S: io::Read + io::Write> io::Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        dispatch!(TlsStream, self, s => io::Write::write(s, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        dispatch!(TlsStream, self, s => io::Write::flush(s))
+    }
+}
@@ -1,23 +1,35 @@
+extern crate foreign_types;
 extern crate openssl;
 extern crate openssl_probe;
+extern crate openssl_sys;
 
+use self::foreign_types::{ForeignType, ForeignTypeRef};
 use self::openssl::error::ErrorStack;
-use self::openssl::hash::MessageDigest;
+use self::openssl::hash::{hash, MessageDigest};
 use self::openssl::nid::Nid;
 use self::openssl::pkcs12::Pkcs12;
 use self::openssl::pkey::PKey;
 use self::openssl::ssl::{
-    self, MidHandshakeSslStream, SslAcceptor, SslConnector, SslContextBuilder, SslMethod,
-    SslVerifyMode,
+    self, MidHandshakeSslStream, NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslConnector,
+    SslContextBuilder, SslMethod, SslVerifyMode, StatusType,
 };
-use self::openssl::x509::{X509, store::X509StoreBuilder, X509VerifyResult};
+use self::openssl::x509::verify::X509VerifyFlags;
+use self::openssl::x509::{X509, X509Crl, store::X509StoreBuilder, X509VerifyResult};
+use std::cmp;
 use std::error;
 use std::fmt;
 use std::io;
-use std::sync::Once;
+use std::os::raw::{c_int, c_ulong, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex, Once};
 
-use {Protocol, TlsAcceptorBuilder, TlsConnectorBuilder};
-use self::openssl::pkey::Private;
+use {
+    chain, der, hostname, jks, pem, policy, ClientAuth, Digest, Protocol, TlsAcceptorBuilder,
+    TlsConnectorBuilder, WildcardPolicy,
+};
+use self::openssl::pkey::{Private, Public};
+
+pub use self::tickets::SessionTicketKeys;
 
 #[cfg(have_min_max_version)]
 fn supported_protocols(
@@ -33,12 +45,23 @@ fn supported_protocols(
             Protocol::Tlsv10 => SslVersion::TLS1,
             Protocol::Tlsv11 => SslVersion::TLS1_1,
             Protocol::Tlsv12 => SslVersion::TLS1_2,
+            #[cfg(have_tls13)]
+            Protocol::Tlsv13 => SslVersion::TLS1_3,
+            #[cfg(not(have_tls13))]
+            Protocol::Tlsv13 => SslVersion::TLS1_2,
             Protocol::__NonExhaustive => unreachable!(),
         }
     }
 
     ctx.set_min_proto_version(min.map(cvt))?;
     ctx.set_max_proto_version(max.map(cvt))?;
+    // Without TLS 1.3 there's nothing left to negotiate
+    #[cfg(not(have_tls13))]
+    {
+        if min == Some(Protocol::Tlsv13) {
+            ctx.set_options(ssl::SslOptions::NO_TLSV1_2);
+        }
+    }
 
     Ok(())
 }
@@ -72,10 +95,12 @@ fn supported_protocols(
                 | SslOptions::NO_TLSV1
                 | SslOptions::NO_TLSV1_1
         }
+        // TLS 1.3 isn't supported, so nothing is left
+        Some(Protocol::Tlsv13) => no_ssl_mask,
         Some(Protocol::__NonExhaustive) => unreachable!(),
     };
     options |= match max {
-        None | Some(Protocol::Tlsv12) => SslOptions::empty(),
+        None | Some(Protocol::Tlsv12) | Some(Protocol::Tlsv13) => SslOptions::empty(),
         Some(Protocol::Tlsv11) => SslOptions::NO_TLSV1_2,
         Some(Protocol::Tlsv10) => SslOptions::NO_TLSV1_1 | SslOptions::NO_TLSV1_2,
         Some(Protocol::Sslv3) => {
@@ -89,11 +114,286 @@ fn supported_protocols(
     Ok(())
 }
 
+// DTLS 1.0 is derived from TLS 1.1 and DTLS 1.2 from TLS 1.2. OpenSSL doesn't implement DTLS 1.3,
+// so TLS 1.3 is treated like TLS 1.2.
+#[cfg(have_min_max_version)]
+fn supported_dtls_protocols(
+    min: Option<Protocol>,
+    max: Option<Protocol>,
+    ctx: &mut SslContextBuilder,
+) -> Result<(), ErrorStack> {
+    use self::openssl::ssl::SslVersion;
+
+    fn cvt(p: Protocol) -> SslVersion {
+        match p {
+            Protocol::Sslv3 | Protocol::Tlsv10 | Protocol::Tlsv11 => SslVersion::DTLS1,
+            Protocol::Tlsv12 | Protocol::Tlsv13 => SslVersion::DTLS1_2,
+            Protocol::__NonExhaustive => unreachable!(),
+        }
+    }
+
+    ctx.set_min_proto_version(min.map(cvt))?;
+    ctx.set_max_proto_version(max.map(cvt))?;
+
+    Ok(())
+}
+
+#[cfg(not(have_min_max_version))]
+fn supported_dtls_protocols(
+    min: Option<Protocol>,
+    max: Option<Protocol>,
+    ctx: &mut SslContextBuilder,
+) -> Result<(), ErrorStack> {
+    use self::openssl::ssl::SslOptions;
+
+    ctx.clear_options(SslOptions::NO_DTLSV1 | SslOptions::NO_DTLSV1_2);
+    match min {
+        Some(Protocol::Tlsv12) | Some(Protocol::Tlsv13) => {
+            ctx.set_options(SslOptions::NO_DTLSV1);
+        }
+        _ => {}
+    }
+    match max {
+        Some(Protocol::Sslv3) | Some(Protocol::Tlsv10) | Some(Protocol::Tlsv11) => {
+            ctx.set_options(SslOptions::NO_DTLSV1_2);
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// FIXME openssl doesn't expose SSL_CTX_set_max_send_fragment yet
+const SSL_CTRL_SET_MAX_SEND_FRAGMENT: i32 = 52;
+
+fn set_max_send_fragment(max: Option<usize>, ctx: &mut SslContextBuilder) -> Result<(), Error> {
+    let max = match max {
+        Some(max) if !(512..=16384).contains(&max) => return Err(Error::MaxSendFragment(max)),
+        Some(max) => max,
+        None => return Ok(()),
+    };
+
+    unsafe {
+        if openssl_sys::SSL_CTX_ctrl(
+            ctx.as_ptr(),
+            SSL_CTRL_SET_MAX_SEND_FRAGMENT,
+            max as _,
+            ptr::null_mut(),
+        ) <= 0
+        {
+            return Err(ErrorStack::get().into());
+        }
+    }
+
+    Ok(())
+}
+
+// Each protocol is prefixed by its length as a byte.
+#[cfg(feature = "alpn")]
+fn alpn_wire_format(protocols: &[String]) -> Vec<u8> {
+    let mut out = vec![];
+    for protocol in protocols {
+        out.push(protocol.len() as u8);
+        out.extend_from_slice(protocol.as_bytes());
+    }
+    out
+}
+
+// Returns the first of the server's protocols found in the client's wire format list.
+#[cfg(feature = "alpn")]
+fn select_alpn<'a>(protocols: &[String], mut client: &'a [u8]) -> Option<&'a [u8]> {
+    let mut offered = vec![];
+    while let Some((&len, rest)) = client.split_first() {
+        if rest.len() < len as usize {
+            break;
+        }
+        let (protocol, rest) = rest.split_at(len as usize);
+        offered.push(protocol);
+        client = rest;
+    }
+
+    for protocol in protocols {
+        if let Some(offered) = offered.iter().find(|o| **o == protocol.as_bytes()) {
+            return Some(offered);
+        }
+    }
+    None
+}
+
+fn set_client_auth(
+    builder: &TlsAcceptorBuilder,
+    ctx: &mut SslContextBuilder,
+) -> Result<(), ErrorStack> {
+    let mode = match builder.client_auth {
+        ClientAuth::Disabled => return Ok(()),
+        ClientAuth::Optional => SslVerifyMode::PEER,
+        ClientAuth::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        ClientAuth::__NonExhaustive => unreachable!(),
+    };
+    ctx.set_verify(mode);
+    // Resumed sessions are only accepted from the context which verified the client.
+    ctx.set_session_id_context(b"native-tls")?;
+
+    let mut store = X509StoreBuilder::new()?;
+    for cert in &builder.client_root_certificates {
+        store.add_cert(cert.0.native().0.clone())?;
+        ctx.add_client_ca(&cert.0.native().0)?;
+    }
+    ctx.set_cert_store(store.build());
+    Ok(())
+}
+
+// FIXME openssl-sys doesn't expose SSL_CIPHER_get_id or X509_STORE_add_crl yet
+extern "C" {
+    fn SSL_CIPHER_get_id(cipher: *const openssl_sys::SSL_CIPHER) -> c_ulong;
+    fn X509_STORE_add_crl(
+        store: *mut openssl_sys::X509_STORE,
+        crl: *mut openssl_sys::X509_CRL,
+    ) -> c_int;
+}
+
+// FIXME openssl doesn't expose DTLSv1_handle_timeout yet
+const DTLS_CTRL_HANDLE_TIMEOUT: c_int = 74;
+
+const LEGACY_CIPHERS: &str = "ALL:!aNULL:!eNULL:@SECLEVEL=0";
+
+const SSL_CTRL_CHAIN_CERT: i32 = 89;
+
+// Extra chain certificates are shared by every certificate in the context, so each identity's
+// chain is attached to its own certificate instead. OpenSSL stores the certificate and key in the
+// slot for their key type and selects among the slots during the handshake.
+fn add_identity(
+    identity: &Identity,
+    omit_root: bool,
+    ctx: &mut SslContextBuilder,
+) -> Result<(), ErrorStack> {
+    ctx.set_certificate(&identity.cert)?;
+    ctx.set_private_key(&identity.pkey)?;
+    ctx.check_private_key()?;
+    for cert in &identity.chain {
+        if omit_root && is_root(cert) {
+            continue;
+        }
+        unsafe {
+            // SSL_CTX_add1_chain_cert
+            if openssl_sys::SSL_CTX_ctrl(
+                ctx.as_ptr(),
+                SSL_CTRL_CHAIN_CERT,
+                1,
+                cert.as_ptr() as *mut c_void,
+            ) <= 0
+            {
+                return Err(ErrorStack::get());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(have_tls13)]
+fn set_middlebox_compat(compat: Option<bool>, ctx: &mut SslContextBuilder) {
+    match compat {
+        Some(true) => {
+            ctx.set_options(ssl::SslOptions::ENABLE_MIDDLEBOX_COMPAT);
+        }
+        Some(false) => {
+            ctx.clear_options(ssl::SslOptions::ENABLE_MIDDLEBOX_COMPAT);
+        }
+        None => {}
+    }
+}
+
+// TLS 1.3 isn't supported, so there's nothing to be compatible with
+#[cfg(not(have_tls13))]
+fn set_middlebox_compat(_: Option<bool>, _: &mut SslContextBuilder) {}
+
+#[cfg(have_tls13)]
+fn enable_sm_cipher_suites(ctx: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+    const DEFAULT_CIPHER_SUITES: &str =
+        "TLS_AES_256_GCM_SHA384:TLS_CHACHA20_POLY1305_SHA256:TLS_AES_128_GCM_SHA256";
+    const SM_CIPHER_SUITES: &str = "TLS_SM4_GCM_SM3:TLS_SM4_CCM_SM3";
+
+    // OpenSSL skips unknown cipher suites, so check that at least one SM suite is known first.
+    ctx.set_ciphersuites(SM_CIPHER_SUITES)?;
+    ctx.set_ciphersuites(&format!("{}:{}", DEFAULT_CIPHER_SUITES, SM_CIPHER_SUITES))
+}
+
+// The SM cipher suites are TLS 1.3 only
+#[cfg(not(have_tls13))]
+fn enable_sm_cipher_suites(_: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+    Ok(())
+}
+
+// OpenSSL configures TLS 1.3 suites separately from older ones, which it knows by its own names
+// rather than the IANA ones. It fails handshakes rather than skipping a version without any suites,
+// so each enabled version must be left with at least one.
+#[cfg(have_tls13)]
+fn set_cipher_suites(
+    suites: &[::CipherSuite],
+    min: Option<Protocol>,
+    max: Option<Protocol>,
+    dtls: bool,
+    ctx: &mut SslContextBuilder,
+) -> Result<(), Error> {
+    use std::ffi::{CStr, CString};
+
+    let mut tls13 = vec![];
+    let mut older = vec![];
+    for name in suites.iter().filter_map(|suite| suite.name()) {
+        // Only TLS 1.3 suites leave the key exchange out of their names.
+        if !name.contains("_WITH_") {
+            tls13.push(name.to_owned());
+            continue;
+        }
+        let name = CString::new(name).unwrap();
+        let name = unsafe { CStr::from_ptr(openssl_sys::OPENSSL_cipher_name(name.as_ptr())) };
+        match name.to_str() {
+            Ok("(NONE)") | Err(_) => {}
+            Ok(name) => older.push(name.to_owned()),
+        }
+    }
+    if tls13.is_empty() && older.is_empty() {
+        return Err(Error::NoCipherSuites);
+    }
+
+    // DTLS has no 1.3 version, so a minimum of TLS 1.3 is treated like TLS 1.2.
+    let tls13_enabled = !dtls && (max.is_none() || max == Some(Protocol::Tlsv13));
+    let older_enabled = dtls || min != Some(Protocol::Tlsv13);
+    if tls13_enabled && tls13.is_empty() {
+        return Err(Error::NoCipherSuitesFor("TLS 1.3"));
+    }
+    if older_enabled && older.is_empty() {
+        return Err(Error::NoCipherSuitesFor("protocol versions older than TLS 1.3"));
+    }
+
+    ctx.set_ciphersuites(&tls13.join(":"))?;
+    if older_enabled {
+        ctx.set_cipher_list(&older.join(":"))?;
+    }
+    Ok(())
+}
+
+// IANA names can only be translated from OpenSSL 1.1.1
+#[cfg(not(have_tls13))]
+fn set_cipher_suites(
+    _: &[::CipherSuite],
+    _: Option<Protocol>,
+    _: Option<Protocol>,
+    _: bool,
+    _: &mut SslContextBuilder,
+) -> Result<(), Error> {
+    Err(Error::NoCipherSuites)
+}
+
 fn init_trust() {
     static ONCE: Once = Once::new();
     ONCE.call_once(openssl_probe::init_ssl_cert_env_vars);
 }
 
+pub fn backend_version() -> String {
+    openssl::version::version().to_owned()
+}
+
 #[cfg(target_os = "android")]
 fn load_android_root_certs(connector: &mut SslContextBuilder) -> Result<(), Error> {
     use std::fs;
@@ -117,6 +417,16 @@ fn load_android_root_certs(connector: &mut SslContextBuilder) -> Result<(), Erro
 pub enum Error {
     Normal(ErrorStack),
     Ssl(ssl::Error, X509VerifyResult),
+    EmptyChain,
+    Jks(jks::Error),
+    Der(der::Error),
+    Pem(pem::Error),
+    Policy(policy::Error),
+    NoCipherSuites,
+    NoCipherSuitesFor(&'static str),
+    MaxSendFragment(usize),
+    KeyMismatch,
+    Io(io::Error),
 }
 
 impl error::Error for Error {
@@ -124,6 +434,16 @@ impl error::Error for Error {
         match *self {
             Error::Normal(ref e) => error::Error::source(e),
             Error::Ssl(ref e, _) => error::Error::source(e),
+            Error::EmptyChain => None,
+            Error::Jks(ref e) => error::Error::source(e),
+            Error::Der(ref e) => error::Error::source(e),
+            Error::Pem(ref e) => error::Error::source(e),
+            Error::Policy(ref e) => error::Error::source(e),
+            Error::NoCipherSuites => None,
+            Error::NoCipherSuitesFor(_) => None,
+            Error::MaxSendFragment(_) => None,
+            Error::KeyMismatch => None,
+            Error::Io(ref e) => error::Error::source(e),
         }
     }
 }
@@ -134,6 +454,128 @@ impl fmt::Display for Error {
             Error::Normal(ref e) => fmt::Display::fmt(e, fmt),
             Error::Ssl(ref e, X509VerifyResult::OK) => fmt::Display::fmt(e, fmt),
             Error::Ssl(ref e, v) => write!(fmt, "{} ({})", e, v),
+            Error::EmptyChain => write!(
+                fmt,
+                "at least one certificate must be provided to create an identity"
+            ),
+            Error::Jks(ref e) => fmt::Display::fmt(e, fmt),
+            Error::Der(ref e) => fmt::Display::fmt(e, fmt),
+            Error::Pem(ref e) => fmt::Display::fmt(e, fmt),
+            Error::Policy(ref e) => fmt::Display::fmt(e, fmt),
+            Error::NoCipherSuites => write!(
+                fmt,
+                "none of the configured cipher suites are supported"
+            ),
+            Error::NoCipherSuitesFor(protocols) => write!(
+                fmt,
+                "none of the configured cipher suites can be used with {}",
+                protocols
+            ),
+            Error::MaxSendFragment(max) => write!(
+                fmt,
+                "maximum send fragment of {} bytes is not between 512 and 16384 bytes",
+                max
+            ),
+            Error::KeyMismatch => write!(
+                fmt,
+                "the private key does not match the certificate's public key"
+            ),
+            Error::Io(ref e) => fmt::Display::fmt(e, fmt),
+        }
+    }
+}
+
+// Reasons OpenSSL gives for handshakes failing against servers stuck on older protocol versions
+// or cipher suites.
+const NEGOTIATION_FAILURES: &[&str] = &[
+    "unsupported protocol",
+    "no protocols available",
+    "wrong version number",
+    "no ciphers available",
+    "dh key too small",
+    "unsafe legacy renegotiation disabled",
+    "sslv3 alert handshake failure",
+    "tlsv1 alert protocol version",
+    "tlsv1 alert insufficient security",
+];
+
+// Reasons OpenSSL gives for handshakes failing because the peers have no protocol version in
+// common.
+const PROTOCOL_VERSION_FAILURES: &[&str] = &[
+    "unsupported protocol",
+    "no protocols available",
+    "wrong version number",
+    "tlsv1 alert protocol version",
+];
+
+impl Error {
+    pub fn kind(&self) -> ::ErrorKind {
+        match *self {
+            Error::Ssl(_, v) if v != X509VerifyResult::OK => match v.as_raw() {
+                openssl_sys::X509_V_ERR_HOSTNAME_MISMATCH
+                | openssl_sys::X509_V_ERR_IP_ADDRESS_MISMATCH => ::ErrorKind::HostnameMismatch,
+                openssl_sys::X509_V_ERR_CERT_HAS_EXPIRED
+                | openssl_sys::X509_V_ERR_CERT_NOT_YET_VALID => ::ErrorKind::CertExpired,
+                openssl_sys::X509_V_ERR_UNABLE_TO_GET_ISSUER_CERT
+                | openssl_sys::X509_V_ERR_UNABLE_TO_GET_ISSUER_CERT_LOCALLY
+                | openssl_sys::X509_V_ERR_UNABLE_TO_VERIFY_LEAF_SIGNATURE
+                | openssl_sys::X509_V_ERR_DEPTH_ZERO_SELF_SIGNED_CERT
+                | openssl_sys::X509_V_ERR_SELF_SIGNED_CERT_IN_CHAIN
+                | openssl_sys::X509_V_ERR_CERT_UNTRUSTED => ::ErrorKind::UntrustedRoot,
+                _ => ::ErrorKind::Other,
+            },
+            Error::Ssl(ref e, _) => match e.ssl_error() {
+                Some(stack) => {
+                    let version = stack.errors().iter().any(|e| match e.reason() {
+                        Some(reason) => PROTOCOL_VERSION_FAILURES.contains(&reason),
+                        None => false,
+                    });
+                    if version {
+                        ::ErrorKind::ProtocolVersion
+                    } else {
+                        ::ErrorKind::Other
+                    }
+                }
+                // Either the stream failed or the peer closed it unexpectedly.
+                None if e.code() == ssl::ErrorCode::SYSCALL => ::ErrorKind::Io,
+                None => ::ErrorKind::Other,
+            },
+            Error::Policy(policy::Error::Hostname) | Error::Policy(policy::Error::IpAddress) => {
+                ::ErrorKind::HostnameMismatch
+            }
+            Error::Io(_) => ::ErrorKind::Io,
+            _ => ::ErrorKind::Other,
+        }
+    }
+
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match *self {
+            Error::Ssl(ref e, _) => e.io_error(),
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    pub fn into_io_error(self) -> Result<io::Error, Error> {
+        match self {
+            Error::Ssl(e, v) => e.into_io_error().map_err(|e| Error::Ssl(e, v)),
+            Error::Io(e) => Ok(e),
+            e => Err(e),
+        }
+    }
+
+    pub fn is_negotiation_failure(&self) -> bool {
+        let e = match *self {
+            Error::Ssl(ref e, X509VerifyResult::OK) => e,
+            _ => return false,
+        };
+        match e.ssl_error() {
+            Some(stack) => stack.errors().iter().any(|e| match e.reason() {
+                Some(reason) => NEGOTIATION_FAILURES.contains(&reason),
+                None => false,
+            }),
+            // The server dropped the connection
+            None => e.code() == ssl::ErrorCode::SYSCALL || e.code() == ssl::ErrorCode::ZERO_RETURN,
         }
     }
 }
@@ -144,6 +586,36 @@ impl From<ErrorStack> for Error {
     }
 }
 
+impl From<jks::Error> for Error {
+    fn from(err: jks::Error) -> Error {
+        Error::Jks(err)
+    }
+}
+
+impl From<der::Error> for Error {
+    fn from(err: der::Error) -> Error {
+        Error::Der(err)
+    }
+}
+
+impl From<pem::Error> for Error {
+    fn from(err: pem::Error) -> Error {
+        Error::Pem(err)
+    }
+}
+
+impl From<policy::Error> for Error {
+    fn from(err: policy::Error) -> Error {
+        Error::Policy(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
 #[derive(Clone)]
 pub struct Identity {
     pkey: PKey<Private>,
@@ -155,12 +627,69 @@ impl Identity {
     pub fn from_pkcs12(buf: &[u8], pass: &str) -> Result<Identity, Error> {
         let pkcs12 = Pkcs12::from_der(buf)?;
         let parsed = pkcs12.parse(pass)?;
+        let chain = sort_chain(&parsed.cert, parsed.chain.into_iter().flatten().collect());
         Ok(Identity {
             pkey: parsed.pkey,
             cert: parsed.cert,
-            chain: parsed.chain.into_iter().flatten().collect(),
+            chain,
         })
     }
+
+    pub fn from_pkcs8_der(chain: &[&[u8]], key: &[u8]) -> Result<Identity, Error> {
+        let pkey = PKey::private_key_from_pkcs8(key)?;
+        let mut chain = chain
+            .iter()
+            .map(|cert| X509::from_der(cert))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter();
+        let cert = chain.next().ok_or(Error::EmptyChain)?;
+        let chain = sort_chain(&cert, chain.collect());
+        Ok(Identity { pkey, cert, chain })
+    }
+
+    pub fn from_encrypted_pkcs8_der(
+        chain: &[&[u8]],
+        key: &[u8],
+        pass: &str,
+    ) -> Result<Identity, Error> {
+        let pkey = PKey::private_key_from_pkcs8_passphrase(key, pass.as_bytes())?;
+        Identity::from_pkcs8_der(chain, &pkey.private_key_to_pkcs8()?)
+    }
+
+    pub fn from_parts(
+        pkey: PKey<Private>,
+        cert: X509,
+        chain: Vec<X509>,
+    ) -> Result<Identity, Error> {
+        if !cert.public_key()?.public_eq(&pkey) {
+            return Err(Error::KeyMismatch);
+        }
+        let chain = sort_chain(&cert, chain);
+        Ok(Identity { pkey, cert, chain })
+    }
+
+    pub fn certificate(&self) -> Result<Certificate, Error> {
+        Ok(Certificate(self.cert.clone()))
+    }
+
+    pub fn chain(&self) -> Result<Vec<Certificate>, Error> {
+        Ok(self.chain.iter().cloned().map(Certificate).collect())
+    }
+}
+
+fn is_root(cert: &X509) -> bool {
+    match cert.to_der() {
+        Ok(der) => der::is_self_issued(&der).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+fn sort_chain(cert: &X509, chain: Vec<X509>) -> Vec<X509> {
+    let ders = chain.iter().map(|c| c.to_der()).collect::<Result<Vec<_>, _>>();
+    match (cert.to_der(), ders) {
+        (Ok(leaf), Ok(ders)) => chain::sort(&leaf, chain, &ders),
+        _ => chain,
+    }
 }
 
 #[derive(Clone)]
@@ -181,27 +710,116 @@ impl Certificate {
         let der = self.0.to_der()?;
         Ok(der)
     }
+
+    pub fn fingerprint(&self, digest: Digest) -> Result<Vec<u8>, Error> {
+        let md = match digest {
+            Digest::Sha1 => MessageDigest::sha1(),
+            Digest::Sha256 => MessageDigest::sha256(),
+            Digest::Sha384 => MessageDigest::sha384(),
+            Digest::Sha512 => MessageDigest::sha512(),
+            Digest::__NonExhaustive => unreachable!(),
+        };
+        let fingerprint = self.0.digest(md)?;
+        Ok(fingerprint.to_vec())
+    }
+
+    pub fn public_key_sha256(&self) -> Result<Vec<u8>, Error> {
+        let der = self.0.to_der()?;
+        let spki = der::subject_public_key_info(&der)?;
+        let digest = hash(MessageDigest::sha256(), spki)?;
+        Ok(digest.to_vec())
+    }
+
+    pub fn verify_signed_by(&self, issuer: &Certificate) -> Result<bool, Error> {
+        let key = issuer.0.public_key()?;
+        Ok(self.0.verify(&key)?)
+    }
+
+    pub fn as_inner(&self) -> &X509 {
+        &self.0
+    }
+}
+
+impl From<X509> for Certificate {
+    fn from(cert: X509) -> Certificate {
+        Certificate(cert)
+    }
+}
+
+#[derive(Clone)]
+pub struct Crl(Arc<X509Crl>);
+
+impl Crl {
+    pub fn from_der(buf: &[u8]) -> Result<Crl, Error> {
+        let crl = X509Crl::from_der(buf)?;
+        Ok(Crl(Arc::new(crl)))
+    }
+
+    pub fn from_pem(buf: &[u8]) -> Result<Crl, Error> {
+        let der = pem::crl(buf)?;
+        Crl::from_der(&der)
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, Error> {
+        let der = self.0.to_der()?;
+        Ok(der)
+    }
 }
 
-pub struct MidHandshakeTlsStream<S>(MidHandshakeSslStream<S>);
+pub struct MidHandshakeTlsStream<S>(Handshake<S>);
+
+enum Handshake<S> {
+    Ssl(MidHandshakeSslStream<S>),
+    #[cfg(have_tls13)]
+    EarlyData(early_data::Handshake<S>),
+}
 
 impl<S> fmt::Debug for MidHandshakeTlsStream<S>
 where
     S: fmt::Debug,
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(&self.0, fmt)
+        match self.0 {
+            Handshake::Ssl(ref s) => fmt::Debug::fmt(s, fmt),
+            #[cfg(have_tls13)]
+            Handshake::EarlyData(ref s) => fmt::Debug::fmt(s, fmt),
+        }
     }
 }
 
 impl<S> MidHandshakeTlsStream<S> {
     pub fn get_ref(&self) -> &S {
-        self.0.get_ref()
+        match self.0 {
+            Handshake::Ssl(ref s) => s.get_ref(),
+            #[cfg(have_tls13)]
+            Handshake::EarlyData(ref s) => s.get_ref(),
+        }
     }
 
     pub fn get_mut(&mut self) -> &mut S {
-        self.0.get_mut()
+        match self.0 {
+            Handshake::Ssl(ref mut s) => s.get_mut(),
+            #[cfg(have_tls13)]
+            Handshake::EarlyData(ref mut s) => s.get_mut(),
+        }
+    }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        let ssl = match self.0 {
+            Handshake::Ssl(ref s) => s.ssl(),
+            #[cfg(have_tls13)]
+            Handshake::EarlyData(ref s) => s.ssl(),
+        };
+        Ok(sni_hostname(ssl))
+    }
+}
+
+// On the client side, OpenSSL returns the name we sent rather than one requested by the peer.
+fn sni_hostname(ssl: &ssl::SslRef) -> Option<String> {
+    if !ssl.is_server() {
+        return None;
     }
+    ssl.servername(NameType::HOST_NAME).map(str::to_owned)
 }
 
 impl<S> MidHandshakeTlsStream<S>
@@ -209,9 +827,24 @@ where
     S: io::Read + io::Write,
 {
     pub fn handshake(self) -> Result<TlsStream<S>, HandshakeError<S>> {
-        match self.0.handshake() {
-            Ok(s) => Ok(TlsStream(s)),
-            Err(e) => Err(e.into()),
+        match self.0 {
+            Handshake::Ssl(s) => {
+                // Retransmits the last DTLS flight if the peer hasn't answered in time.
+                unsafe {
+                    openssl_sys::SSL_ctrl(
+                        s.ssl().as_ptr(),
+                        DTLS_CTRL_HANDLE_TIMEOUT,
+                        0,
+                        ptr::null_mut(),
+                    );
+                }
+                match s.handshake() {
+                    Ok(s) => Ok(TlsStream::new(s)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            #[cfg(have_tls13)]
+            Handshake::EarlyData(s) => s.handshake(),
         }
     }
 }
@@ -230,7 +863,7 @@ impl<S> From<ssl::HandshakeError<S>> for HandshakeError<S> {
                 HandshakeError::Failure(Error::Ssl(e.into_error(), v))
             }
             ssl::HandshakeError::WouldBlock(s) => {
-                HandshakeError::WouldBlock(MidHandshakeTlsStream(s))
+                HandshakeError::WouldBlock(MidHandshakeTlsStream(Handshake::Ssl(s)))
             }
         }
     }
@@ -242,46 +875,169 @@ impl<S> From<ErrorStack> for HandshakeError<S> {
     }
 }
 
+type ContextFn = dyn Fn(&mut SslContextBuilder) -> Result<(), ErrorStack> + Sync + Send;
+
+#[derive(Clone)]
+pub struct ContextCallback(Arc<ContextFn>);
+
+impl ContextCallback {
+    pub fn new<F>(callback: F) -> ContextCallback
+    where
+        F: Fn(&mut SslContextBuilder) -> Result<(), ErrorStack> + Sync + Send + 'static,
+    {
+        ContextCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ContextCallback {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ContextCallback").finish()
+    }
+}
+
 #[derive(Clone)]
 pub struct TlsConnector {
     connector: SslConnector,
     use_sni: bool,
     accept_invalid_hostnames: bool,
     accept_invalid_certs: bool,
+    certificate_exceptions: Option<::CertificateExceptions>,
+    expected_raw_public_keys: Vec<PKey<Public>>,
+    sessions: Option<Arc<Mutex<session::SessionCache>>>,
+    require_ocsp_staple: bool,
+    #[cfg_attr(not(have_tls13), allow(dead_code))]
+    early_data: bool,
 }
 
 impl TlsConnector {
     pub fn new(builder: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
+        TlsConnector::new_inner(builder, false)
+    }
+
+    pub fn new_dtls(builder: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
+        TlsConnector::new_inner(builder, true)
+    }
+
+    fn new_inner(builder: &TlsConnectorBuilder, dtls: bool) -> Result<TlsConnector, Error> {
         init_trust();
 
-        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        let method = if dtls { SslMethod::dtls() } else { SslMethod::tls() };
+        let mut connector = SslConnector::builder(method)?;
+        // Writes retried after WouldBlock may come from a moved buffer.
+        connector.set_mode(ssl::SslMode::ACCEPT_MOVING_WRITE_BUFFER);
         if let Some(ref identity) = builder.identity {
-            connector.set_certificate(&identity.0.cert)?;
-            connector.set_private_key(&identity.0.pkey)?;
-            for cert in identity.0.chain.iter().rev() {
+            connector.set_certificate(&identity.0.native().cert)?;
+            connector.set_private_key(&identity.0.native().pkey)?;
+            for cert in &identity.0.native().chain {
                 connector.add_extra_chain_cert(cert.to_owned())?;
             }
         }
-        supported_protocols(builder.min_protocol, builder.max_protocol, &mut connector)?;
+        if dtls {
+            supported_dtls_protocols(builder.min_protocol, builder.max_protocol, &mut connector)?;
+        } else {
+            supported_protocols(builder.min_protocol, builder.max_protocol, &mut connector)?;
+        }
+        set_max_send_fragment(builder.max_send_fragment, &mut connector)?;
+        set_middlebox_compat(builder.middlebox_compat, &mut connector);
+        rekey::set_limits(builder.rekey_after_bytes, builder.rekey_interval, &mut connector)?;
+        if builder.sm_cipher_suites {
+            enable_sm_cipher_suites(&mut connector)?;
+        }
+        if builder.legacy_ciphers {
+            connector.set_cipher_list(LEGACY_CIPHERS)?;
+        }
+        if !builder.cipher_suites.is_empty() {
+            set_cipher_suites(
+                &builder.cipher_suites,
+                builder.min_protocol,
+                builder.max_protocol,
+                dtls,
+                &mut connector,
+            )?;
+        }
+        #[cfg(feature = "alpn")]
+        {
+            if !builder.alpn.is_empty() {
+                connector.set_alpn_protos(&alpn_wire_format(&builder.alpn))?;
+            }
+        }
+
+        let mut expected_raw_public_keys = vec![];
+        for key in &builder.expected_raw_public_keys {
+            expected_raw_public_keys.push(PKey::public_key_from_der(key)?);
+        }
+        if !expected_raw_public_keys.is_empty() {
+            rpk::enable_client(&mut connector)?;
+        }
+
+        if builder.allow_unsafe_legacy_renegotiation {
+            connector.set_options(ssl::SslOptions::ALLOW_UNSAFE_LEGACY_RENEGOTIATION);
+            // FIXME openssl doesn't expose SSL_OP_LEGACY_SERVER_CONNECT yet
+            unsafe {
+                openssl_sys::SSL_CTX_set_options(
+                    connector.as_ptr(),
+                    openssl_sys::SSL_OP_LEGACY_SERVER_CONNECT as _,
+                );
+            }
+        }
 
         if builder.disable_built_in_roots {
             connector.set_cert_store(X509StoreBuilder::new()?.build());
         }
 
         for cert in &builder.root_certificates {
-            if let Err(err) = connector.cert_store_mut().add_cert((cert.0).0.clone()) {
+            if let Err(err) = connector.cert_store_mut().add_cert(cert.0.native().0.clone()) {
                 debug!("add_cert error: {:?}", err);
             }
         }
 
+        if builder.allow_partial_chains {
+            connector
+                .cert_store_mut()
+                .set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+        }
+
+        for crl in &builder.crls {
+            // The store takes its own reference to the CRL.
+            let store = connector.cert_store_mut().as_ptr();
+            if unsafe { X509_STORE_add_crl(store, crl.0.native().0.as_ptr()) } <= 0 {
+                return Err(ErrorStack::get().into());
+            }
+        }
+
+        if builder.check_revocation {
+            connector
+                .cert_store_mut()
+                .set_flags(X509VerifyFlags::CRL_CHECK | X509VerifyFlags::CRL_CHECK_ALL)?;
+        }
+
         #[cfg(target_os = "android")]
         load_android_root_certs(&mut connector)?;
 
+        if builder.require_ocsp_staple {
+            ocsp::require(&mut connector)?;
+        }
+
+        let sessions = if builder.session_cache {
+            Some(session::enable(builder.session_cache_size, &mut connector)?)
+        } else {
+            None
+        };
+
+        if let Some(ref callback) = builder.context_callback {
+            (callback.0)(&mut connector)?;
+        }
+
         Ok(TlsConnector {
             connector: connector.build(),
             use_sni: builder.use_sni,
             accept_invalid_hostnames: builder.accept_invalid_hostnames,
             accept_invalid_certs: builder.accept_invalid_certs,
+            certificate_exceptions: builder.certificate_exceptions.clone(),
+            expected_raw_public_keys,
+            sessions,
+            require_ocsp_staple: builder.require_ocsp_staple,
+            early_data: builder.early_data,
         })
     }
 
@@ -289,19 +1045,90 @@ impl TlsConnector {
     where
         S: io::Read + io::Write,
     {
-        let mut ssl = self
-            .connector
-            .configure()?
-            .use_server_name_indication(self.use_sni)
-            .verify_hostname(!self.accept_invalid_hostnames);
-        if self.accept_invalid_certs {
-            ssl.set_verify(SslVerifyMode::NONE);
-        }
-
-        let s = ssl.connect(domain, stream)?;
-        Ok(TlsStream(s))
+        self.connect_inner(Some(domain), self.use_sni, stream, &[])
     }
-}
+
+    pub fn connect_without_sni<S>(
+        &self,
+        domain: Option<&str>,
+        stream: S,
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect_inner(domain, false, stream, &[])
+    }
+
+    pub fn connect_with_early_data<S>(
+        &self,
+        domain: &str,
+        stream: S,
+        data: &[u8],
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect_inner(Some(domain), self.use_sni, stream, data)
+    }
+
+    #[cfg_attr(not(have_tls13), allow(unused_variables))]
+    fn connect_inner<S>(
+        &self,
+        domain: Option<&str>,
+        use_sni: bool,
+        stream: S,
+        early_data: &[u8],
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        let verify_hostname = domain.is_some() && !self.accept_invalid_hostnames;
+        let mut ssl = self
+            .connector
+            .configure()?
+            .use_server_name_indication(use_sni)
+            .verify_hostname(verify_hostname);
+        let domain = domain.unwrap_or("");
+        if self.accept_invalid_certs {
+            ssl.set_verify(SslVerifyMode::NONE);
+        } else if let Some(ref exceptions) = self.certificate_exceptions {
+            let exceptions = exceptions.clone();
+            let domain = domain.to_owned();
+            ssl.set_verify_callback(SslVerifyMode::PEER, move |preverify_ok, ctx| {
+                if preverify_ok {
+                    return true;
+                }
+                match ctx.chain().and_then(|chain| chain.get(0)) {
+                    Some(cert) => {
+                        let cert = ::Certificate(Certificate(cert.to_owned()).into());
+                        exceptions.verify(&domain, &cert)
+                    }
+                    None => false,
+                }
+            });
+        }
+        rpk::add_expected(&mut ssl, domain, &self.expected_raw_public_keys)?;
+        if let Some(ref sessions) = self.sessions {
+            // Sessions are cached by domain, so there's nothing to share without one.
+            if !domain.is_empty() {
+                session::resume(sessions, domain, &mut ssl)?;
+            }
+        }
+        if self.require_ocsp_staple {
+            ssl.set_status_type(StatusType::OCSP)?;
+        }
+
+        let ssl = ssl.into_ssl(domain)?;
+        #[cfg(have_tls13)]
+        {
+            if self.early_data && !early_data.is_empty() && early_data::fits(&ssl, early_data) {
+                return early_data::connect(ssl, stream, early_data);
+            }
+        }
+        let s = ssl.connect(stream)?;
+        Ok(TlsStream::new(s))
+    }
+}
 
 impl fmt::Debug for TlsConnector {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
@@ -311,6 +1138,7 @@ impl fmt::Debug for TlsConnector {
             .field("use_sni", &self.use_sni)
             .field("accept_invalid_hostnames", &self.accept_invalid_hostnames)
             .field("accept_invalid_certs", &self.accept_invalid_certs)
+            .field("certificate_exceptions", &self.certificate_exceptions)
             .finish()
     }
 }
@@ -320,27 +1148,156 @@ pub struct TlsAcceptor(SslAcceptor);
 
 impl TlsAcceptor {
     pub fn new(builder: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
-        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
-        acceptor.set_private_key(&builder.identity.0.pkey)?;
-        acceptor.set_certificate(&builder.identity.0.cert)?;
-        for cert in builder.identity.0.chain.iter().rev() {
-            acceptor.add_extra_chain_cert(cert.to_owned())?;
+        TlsAcceptor::new_inner(builder, false)
+    }
+
+    pub fn new_dtls(builder: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
+        TlsAcceptor::new_inner(builder, true)
+    }
+
+    fn new_inner(builder: &TlsAcceptorBuilder, dtls: bool) -> Result<TlsAcceptor, Error> {
+        let identities = Some(&builder.identity)
+            .into_iter()
+            .chain(&builder.additional_identities)
+            .collect::<Vec<_>>();
+        let mut acceptor = TlsAcceptor::context(builder, &identities, dtls)?;
+
+        if !builder.sni_identities.is_empty() {
+            // Each hostname gets a context of its own, configured just like the default one.
+            let mut contexts = vec![];
+            for (name, identity) in &builder.sni_identities {
+                let context = TlsAcceptor::context(builder, &[identity], dtls)?;
+                contexts.push((name.clone(), context.build().into_context()));
+            }
+            acceptor.set_servername_callback(move |ssl, _| {
+                let name = match ssl.servername(NameType::HOST_NAME) {
+                    Some(name) => hostname::normalize(name),
+                    None => return Ok(()),
+                };
+                let context = contexts
+                    .iter()
+                    .find(|context| hostname::matches(&context.0, &name, WildcardPolicy::Allow));
+                if let Some(context) = context {
+                    ssl.set_ssl_context(&context.1)
+                        .map_err(|_| SniError::ALERT_FATAL)?;
+                }
+                Ok(())
+            });
         }
-        supported_protocols(builder.min_protocol, builder.max_protocol, &mut acceptor)?;
 
         Ok(TlsAcceptor(acceptor.build()))
     }
 
+    fn context(
+        builder: &TlsAcceptorBuilder,
+        identities: &[&::Identity],
+        dtls: bool,
+    ) -> Result<SslAcceptorBuilder, Error> {
+        let method = if dtls { SslMethod::dtls() } else { SslMethod::tls() };
+        let mut acceptor = SslAcceptor::mozilla_intermediate(method)?;
+        // The intermediate profile disables TLS 1.3 on OpenSSL 1.1.1.
+        #[cfg(have_tls13)]
+        acceptor.clear_options(ssl::SslOptions::NO_TLSV1_3);
+        // Writes retried after WouldBlock may come from a moved buffer.
+        acceptor.set_mode(ssl::SslMode::ACCEPT_MOVING_WRITE_BUFFER);
+        if let [identity] = identities {
+            acceptor.set_private_key(&identity.0.native().pkey)?;
+            acceptor.set_certificate(&identity.0.native().cert)?;
+            for cert in &identity.0.native().chain {
+                if builder.omit_root_certificate && is_root(cert) {
+                    continue;
+                }
+                acceptor.add_extra_chain_cert(cert.to_owned())?;
+            }
+        } else {
+            for identity in identities {
+                add_identity(identity.0.native(), builder.omit_root_certificate, &mut acceptor)?;
+            }
+        }
+        if dtls {
+            supported_dtls_protocols(builder.min_protocol, builder.max_protocol, &mut acceptor)?;
+        } else {
+            supported_protocols(builder.min_protocol, builder.max_protocol, &mut acceptor)?;
+        }
+        set_client_auth(builder, &mut acceptor)?;
+        set_max_send_fragment(builder.max_send_fragment, &mut acceptor)?;
+        set_middlebox_compat(builder.middlebox_compat, &mut acceptor);
+        rekey::set_limits(builder.rekey_after_bytes, builder.rekey_interval, &mut acceptor)?;
+        if !builder.session_tickets {
+            acceptor.set_options(ssl::SslOptions::NO_TICKET);
+        } else if let Some(ref keys) = builder.session_ticket_keys {
+            tickets::set_keys(keys, &mut acceptor)?;
+        }
+        if builder.raw_public_key {
+            rpk::enable_server(&mut acceptor)?;
+        }
+        if let Some(ref response) = builder.ocsp_response {
+            ocsp::staple(response.clone(), &mut acceptor)?;
+        }
+        #[cfg(have_tls13)]
+        {
+            if builder.max_early_data_size > 0 {
+                acceptor.set_max_early_data(builder.max_early_data_size)?;
+            }
+        }
+        #[cfg(feature = "alpn")]
+        {
+            if !builder.alpn.is_empty() {
+                let protocols = builder.alpn.clone();
+                acceptor.set_alpn_select_callback(move |_, client| {
+                    select_alpn(&protocols, client).ok_or(ssl::AlpnError::NOACK)
+                });
+            }
+        }
+        if builder.sm_cipher_suites {
+            enable_sm_cipher_suites(&mut acceptor)?;
+        }
+        if !builder.cipher_suites.is_empty() {
+            set_cipher_suites(
+                &builder.cipher_suites,
+                builder.min_protocol,
+                builder.max_protocol,
+                dtls,
+                &mut acceptor,
+            )?;
+        }
+
+        #[cfg(have_client_hello_callback)]
+        {
+            if builder.capture_client_hello {
+                let index = client_hello::index()?;
+                acceptor.set_client_hello_callback(move |ssl, _| {
+                    let hello = client_hello::ClientHello::new(ssl);
+                    ssl.set_ex_data(index, hello);
+                    Ok(ssl::ClientHelloResponse::SUCCESS)
+                });
+            }
+        }
+
+        if let Some(ref callback) = builder.context_callback {
+            (callback.0)(&mut acceptor)?;
+        }
+
+        Ok(acceptor)
+    }
+
     pub fn accept<S>(&self, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
     where
         S: io::Read + io::Write,
     {
+        #[cfg(have_tls13)]
+        {
+            if self.0.context().max_early_data() > 0 {
+                return early_data::accept(ssl::Ssl::new(self.0.context())?, stream);
+            }
+        }
         let s = self.0.accept(stream)?;
-        Ok(TlsStream(s))
+        Ok(TlsStream::new(s))
     }
 }
 
-pub struct TlsStream<S>(ssl::SslStream<S>);
+// The third field holds early data received during the handshake which hasn't been read yet.
+pub struct TlsStream<S>(ssl::SslStream<S>, rekey::Rekey, Vec<u8>);
 
 impl<S: fmt::Debug> fmt::Debug for TlsStream<S> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
@@ -349,6 +1306,11 @@ impl<S: fmt::Debug> fmt::Debug for TlsStream<S> {
 }
 
 impl<S> TlsStream<S> {
+    fn new(stream: ssl::SslStream<S>) -> TlsStream<S> {
+        let rekey = rekey::Rekey::new(stream.ssl());
+        TlsStream(stream, rekey, vec![])
+    }
+
     pub fn get_ref(&self) -> &S {
         self.0.get_ref()
     }
@@ -360,13 +1322,114 @@ impl<S> TlsStream<S> {
 
 impl<S: io::Read + io::Write> TlsStream<S> {
     pub fn buffered_read_size(&self) -> Result<usize, Error> {
-        Ok(self.0.ssl().pending())
+        Ok(self.2.len() + self.0.ssl().pending())
     }
 
     pub fn peer_certificate(&self) -> Result<Option<Certificate>, Error> {
         Ok(self.0.ssl().peer_certificate().map(Certificate))
     }
 
+    #[cfg(feature = "alpn")]
+    pub fn negotiated_alpn(&self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.0.ssl().selected_alpn_protocol().map(|alpn| alpn.to_vec()))
+    }
+
+    pub fn session_resumed(&self) -> Result<bool, Error> {
+        Ok(self.0.ssl().session_reused())
+    }
+
+    #[cfg(have_tls13)]
+    pub fn early_data_accepted(&self) -> Result<bool, Error> {
+        Ok(early_data::accepted(self.0.ssl()))
+    }
+
+    #[cfg(not(have_tls13))]
+    pub fn early_data_accepted(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    pub fn protocol_version(&self) -> Result<Option<Protocol>, Error> {
+        let version = unsafe { openssl_sys::SSL_version(self.0.ssl().as_ptr()) };
+        let protocol = match version {
+            openssl_sys::SSL3_VERSION => Protocol::Sslv3,
+            openssl_sys::TLS1_VERSION => Protocol::Tlsv10,
+            openssl_sys::TLS1_1_VERSION => Protocol::Tlsv11,
+            openssl_sys::TLS1_2_VERSION => Protocol::Tlsv12,
+            #[cfg(have_tls13)]
+            openssl_sys::TLS1_3_VERSION => Protocol::Tlsv13,
+            // DTLS versions are reported as the TLS versions they're derived from.
+            openssl_sys::DTLS1_VERSION => Protocol::Tlsv11,
+            openssl_sys::DTLS1_2_VERSION => Protocol::Tlsv12,
+            _ => return Ok(None),
+        };
+        Ok(Some(protocol))
+    }
+
+    pub fn cipher_suite(&self) -> Result<u16, Error> {
+        match self.0.ssl().current_cipher() {
+            // The low two bytes of the ID are the cipher suite's value in the protocol.
+            Some(cipher) => Ok(unsafe { SSL_CIPHER_get_id(cipher.as_ptr()) } as u16),
+            None => Err(Error::Normal(ErrorStack::get())),
+        }
+    }
+
+    pub fn peer_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        let ssl = self.0.ssl();
+        let mut chain = vec![];
+        // Servers don't include the client's leaf certificate in the peer chain.
+        if ssl.is_server() {
+            chain.extend(ssl.peer_certificate().map(Certificate));
+        }
+        if let Some(certs) = ssl.peer_cert_chain() {
+            chain.extend(certs.iter().map(|cert| Certificate(cert.to_owned())));
+        }
+        Ok(chain)
+    }
+
+    #[cfg(have_verified_chain)]
+    pub fn verified_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        match self.0.ssl().verified_chain() {
+            Some(certs) => Ok(certs.iter().map(|cert| Certificate(cert.to_owned())).collect()),
+            None => Ok(self.peer_certificate()?.into_iter().collect()),
+        }
+    }
+
+    // Older versions don't expose the chain they verified, so only the leaf can be relied upon.
+    #[cfg(not(have_verified_chain))]
+    pub fn verified_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        Ok(self.peer_certificate()?.into_iter().collect())
+    }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        Ok(sni_hostname(self.0.ssl()))
+    }
+
+    #[cfg(have_client_hello_callback)]
+    pub fn client_hello_ja3(&self) -> Result<Option<String>, Error> {
+        match self.0.ssl().ex_data(client_hello::index()?) {
+            Some(hello) => Ok(Some(hello.ja3()?)),
+            None => Ok(None),
+        }
+    }
+
+    #[cfg(not(have_client_hello_callback))]
+    pub fn client_hello_ja3(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    #[cfg(have_client_hello_callback)]
+    pub fn client_hello_ja4(&self) -> Result<Option<String>, Error> {
+        match self.0.ssl().ex_data(client_hello::index()?) {
+            Some(hello) => Ok(Some(hello.ja4()?)),
+            None => Ok(None),
+        }
+    }
+
+    #[cfg(not(have_client_hello_callback))]
+    pub fn client_hello_ja4(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
     pub fn tls_server_end_point(&self) -> Result<Option<Vec<u8>>, Error> {
         let cert = if self.0.ssl().is_server() {
             self.0.ssl().certificate().map(|x| x.to_owned())
@@ -398,6 +1461,40 @@ impl<S: io::Read + io::Write> TlsStream<S> {
         Ok(Some(digest.to_vec()))
     }
 
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut out = vec![0; len];
+        self.0
+            .ssl()
+            .export_keying_material(&mut out, label, context)?;
+        Ok(out)
+    }
+
+    pub fn tls_unique(&self) -> Result<Option<Vec<u8>>, Error> {
+        let ssl = self.0.ssl();
+        // tls-unique isn't defined for TLS 1.3
+        if unsafe { openssl_sys::SSL_version(ssl.as_ptr()) } > openssl_sys::TLS1_2_VERSION {
+            return Ok(None);
+        }
+
+        // The first Finished message of a handshake is the client's, unless the session was
+        // resumed.
+        let mut buf = [0; 64];
+        let len = if ssl.is_server() == ssl.session_reused() {
+            ssl.finished(&mut buf)
+        } else {
+            ssl.peer_finished(&mut buf)
+        };
+        if len == 0 {
+            return Ok(None);
+        }
+        Ok(Some(buf[..len].to_vec()))
+    }
+
     pub fn shutdown(&mut self) -> io::Result<()> {
         match self.0.shutdown() {
             Ok(_) => Ok(()),
@@ -411,16 +1508,979 @@ impl<S: io::Read + io::Write> TlsStream<S> {
 
 impl<S: io::Read + io::Write> io::Read for TlsStream<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        if !self.2.is_empty() {
+            let n = cmp::min(buf.len(), self.2.len());
+            buf[..n].copy_from_slice(&self.2[..n]);
+            self.2.drain(..n);
+            return Ok(n);
+        }
+        self.1.check(self.0.ssl())?;
+        let n = self.0.read(buf)?;
+        self.1.record(n);
+        Ok(n)
     }
 }
 
 impl<S: io::Read + io::Write> io::Write for TlsStream<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        self.1.check(self.0.ssl())?;
+        let n = self.0.write(buf)?;
+        self.1.record(n);
+        Ok(n)
     }
 
     fn flush(&mut self) -> io::Result<()> {
         self.0.flush()
     }
 }
+
+#[cfg(have_client_hello_callback)]
+mod client_hello {
+    extern crate foreign_types;
+
+    use self::foreign_types::ForeignTypeRef;
+    use super::openssl::error::ErrorStack;
+    use super::openssl::ex_data::Index;
+    use super::openssl::hash::{hash, MessageDigest};
+    use super::openssl::ssl::{Ssl, SslRef, SslVersion};
+    use super::openssl_sys;
+    use std::fmt::Write;
+    use std::os::raw::{c_int, c_void};
+    use std::ptr;
+    use std::slice;
+    use std::sync::Once;
+
+    const SERVER_NAME: u16 = 0;
+    const SUPPORTED_GROUPS: u16 = 10;
+    const EC_POINT_FORMATS: u16 = 11;
+    const SIGNATURE_ALGORITHMS: u16 = 13;
+    const ALPN: u16 = 16;
+    const SUPPORTED_VERSIONS: u16 = 43;
+
+    pub fn index() -> Result<Index<Ssl, ClientHello>, ErrorStack> {
+        static ONCE: Once = Once::new();
+        static mut INDEX: c_int = -1;
+
+        unsafe {
+            ONCE.call_once(|| {
+                if let Ok(index) = Ssl::new_ex_index::<ClientHello>() {
+                    INDEX = index.as_raw();
+                }
+            });
+            if INDEX < 0 {
+                return Err(ErrorStack::get());
+            }
+            Ok(Index::from_raw(INDEX))
+        }
+    }
+
+    /// The parts of a ClientHello which make up its JA3 and JA4 fingerprints.
+    pub struct ClientHello {
+        version: u16,
+        ciphers: Vec<u16>,
+        extensions: Vec<u16>,
+        groups: Vec<u16>,
+        point_formats: Vec<u8>,
+        signature_algorithms: Vec<u16>,
+        supported_versions: Vec<u16>,
+        alpn: Option<Vec<u8>>,
+    }
+
+    impl ClientHello {
+        pub fn new(ssl: &mut SslRef) -> ClientHello {
+            let version = match ssl.client_hello_legacy_version() {
+                Some(SslVersion::SSL3) => 0x0300,
+                Some(SslVersion::TLS1) => 0x0301,
+                Some(SslVersion::TLS1_1) => 0x0302,
+                Some(SslVersion::TLS1_2) => 0x0303,
+                Some(SslVersion::TLS1_3) => 0x0304,
+                _ => 0,
+            };
+            let ciphers = ssl.client_hello_ciphers().map_or(vec![], u16s);
+
+            let extensions = extensions_present(ssl);
+            let groups = ext(ssl, SUPPORTED_GROUPS).map_or(vec![], |e| u16s(skip(e, 2)));
+            let point_formats = ext(ssl, EC_POINT_FORMATS).map_or(vec![], |e| skip(e, 1).to_vec());
+            let signature_algorithms =
+                ext(ssl, SIGNATURE_ALGORITHMS).map_or(vec![], |e| u16s(skip(e, 2)));
+            let supported_versions =
+                ext(ssl, SUPPORTED_VERSIONS).map_or(vec![], |e| u16s(skip(e, 1)));
+            let alpn = ext(ssl, ALPN).and_then(|e| {
+                let e = skip(e, 2);
+                let len = *e.first()? as usize;
+                e.get(1..1 + len).map(|p| p.to_vec())
+            });
+
+            ClientHello {
+                version,
+                ciphers: ciphers.into_iter().filter(|&c| !is_grease(c)).collect(),
+                extensions: extensions.into_iter().filter(|&e| !is_grease(e)).collect(),
+                groups: groups.into_iter().filter(|&g| !is_grease(g)).collect(),
+                point_formats,
+                signature_algorithms,
+                supported_versions: supported_versions
+                    .into_iter()
+                    .filter(|&v| !is_grease(v))
+                    .collect(),
+                alpn,
+            }
+        }
+
+        pub fn ja3(&self) -> Result<String, ErrorStack> {
+            let s = format!(
+                "{},{},{},{},{}",
+                self.version,
+                join(&self.ciphers, "-", |v| v.to_string()),
+                join(&self.extensions, "-", |v| v.to_string()),
+                join(&self.groups, "-", |v| v.to_string()),
+                join(&self.point_formats, "-", |v| v.to_string()),
+            );
+            let digest = hash(MessageDigest::md5(), s.as_bytes())?;
+            Ok(to_hex(&digest))
+        }
+
+        pub fn ja4(&self) -> Result<String, ErrorStack> {
+            let version = self
+                .supported_versions
+                .iter()
+                .cloned()
+                .max()
+                .unwrap_or(self.version);
+            let version = match version {
+                0x0304 => "13",
+                0x0303 => "12",
+                0x0302 => "11",
+                0x0301 => "10",
+                0x0300 => "s3",
+                _ => "00",
+            };
+            let sni = if self.extensions.contains(&SERVER_NAME) {
+                'd'
+            } else {
+                'i'
+            };
+            let alpn = match self.alpn {
+                Some(ref alpn) if !alpn.is_empty() => {
+                    let (first, last) = (alpn[0], alpn[alpn.len() - 1]);
+                    if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                        format!("{}{}", first as char, last as char)
+                    } else {
+                        let hex = to_hex(alpn);
+                        format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+                    }
+                }
+                _ => "00".to_string(),
+            };
+            let a = format!(
+                "t{}{}{:02}{:02}{}",
+                version,
+                sni,
+                self.ciphers.len().min(99),
+                self.extensions.len().min(99),
+                alpn
+            );
+
+            let mut ciphers = self.ciphers.clone();
+            ciphers.sort();
+            let b = truncated_sha256(&join(&ciphers, ",", |v| format!("{:04x}", v)))?;
+
+            let mut extensions = self
+                .extensions
+                .iter()
+                .cloned()
+                .filter(|&e| e != SERVER_NAME && e != ALPN)
+                .collect::<Vec<_>>();
+            extensions.sort();
+            let mut c = join(&extensions, ",", |v| format!("{:04x}", v));
+            if !self.signature_algorithms.is_empty() {
+                c.push('_');
+                c.push_str(&join(&self.signature_algorithms, ",", |v| {
+                    format!("{:04x}", v)
+                }));
+            }
+            let c = if extensions.is_empty() {
+                "000000000000".to_string()
+            } else {
+                truncated_sha256(&c)?
+            };
+
+            Ok(format!("{}_{}_{}", a, b, c))
+        }
+    }
+
+    fn extensions_present(ssl: &mut SslRef) -> Vec<u16> {
+        unsafe {
+            let mut out = ptr::null_mut();
+            let mut len = 0;
+            if openssl_sys::SSL_client_hello_get1_extensions_present(
+                ssl.as_ptr(),
+                &mut out,
+                &mut len,
+            ) != 1
+            {
+                return vec![];
+            }
+            let extensions = slice::from_raw_parts(out, len)
+                .iter()
+                .map(|&e| e as u16)
+                .collect();
+            openssl_sys::OPENSSL_free(out as *mut c_void);
+            extensions
+        }
+    }
+
+    fn ext(ssl: &mut SslRef, type_: u16) -> Option<&[u8]> {
+        unsafe {
+            let mut out = ptr::null();
+            let mut len = 0;
+            if openssl_sys::SSL_client_hello_get0_ext(
+                ssl.as_ptr(),
+                u32::from(type_),
+                &mut out,
+                &mut len,
+            ) != 1
+            {
+                return None;
+            }
+            Some(slice::from_raw_parts(out, len))
+        }
+    }
+
+    // strips the length prefix from an extension's list
+    fn skip(buf: &[u8], len: usize) -> &[u8] {
+        buf.get(len..).unwrap_or(&[])
+    }
+
+    fn u16s(buf: &[u8]) -> Vec<u16> {
+        buf.chunks(2)
+            .filter(|c| c.len() == 2)
+            .map(|c| u16::from(c[0]) << 8 | u16::from(c[1]))
+            .collect()
+    }
+
+    // RFC 8701 reserves values of the form 0x?a?a
+    fn is_grease(v: u16) -> bool {
+        v & 0x0f0f == 0x0a0a && v >> 8 == v & 0xff
+    }
+
+    fn join<T, F>(values: &[T], sep: &str, f: F) -> String
+    where
+        F: Fn(&T) -> String,
+    {
+        values.iter().map(f).collect::<Vec<_>>().join(sep)
+    }
+
+    fn to_hex(buf: &[u8]) -> String {
+        let mut s = String::with_capacity(buf.len() * 2);
+        for b in buf {
+            write!(s, "{:02x}", b).unwrap();
+        }
+        s
+    }
+
+    fn truncated_sha256(s: &str) -> Result<String, ErrorStack> {
+        if s.is_empty() {
+            return Ok("000000000000".to_string());
+        }
+        let digest = hash(MessageDigest::sha256(), s.as_bytes())?;
+        Ok(to_hex(&digest[..6]))
+    }
+}
+
+#[cfg(have_tls13)]
+mod early_data {
+    extern crate foreign_types;
+
+    use self::foreign_types::ForeignTypeRef;
+    use super::openssl::ssl::{self, ErrorCode, Ssl, SslRef, SslStream};
+    use super::openssl_sys;
+    use super::{Error, HandshakeError, MidHandshakeTlsStream, TlsStream};
+    use std::fmt;
+    use std::io;
+    use std::os::raw::c_int;
+
+    // FIXME openssl-sys doesn't expose SSL_get_early_data_status yet
+    extern "C" {
+        fn SSL_get_early_data_status(s: *const openssl_sys::SSL) -> c_int;
+    }
+
+    const SSL_EARLY_DATA_ACCEPTED: c_int = 2;
+
+    /// A handshake exchanging early data, which OpenSSL reads and writes through calls of its own
+    /// before carrying on with the rest of the handshake.
+    pub struct Handshake<S> {
+        stream: SslStream<S>,
+        // The data a client has yet to send, or the data a server has received so far.
+        data: Vec<u8>,
+        done: bool,
+    }
+
+    impl<S: fmt::Debug> fmt::Debug for Handshake<S> {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            fmt::Debug::fmt(&self.stream, fmt)
+        }
+    }
+
+    /// Returns whether the session offered by a client allows `data` to be sent as early data.
+    pub fn fits(ssl: &SslRef, data: &[u8]) -> bool {
+        match ssl.session() {
+            Some(session) => data.len() <= session.max_early_data() as usize,
+            None => false,
+        }
+    }
+
+    pub fn connect<S>(
+        mut ssl: Ssl,
+        stream: S,
+        data: &[u8],
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        ssl.set_connect_state();
+        let handshake = Handshake {
+            stream: SslStream::new(ssl, stream)?,
+            data: data.to_vec(),
+            done: false,
+        };
+        handshake.handshake()
+    }
+
+    pub fn accept<S>(mut ssl: Ssl, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        ssl.set_accept_state();
+        let handshake = Handshake {
+            stream: SslStream::new(ssl, stream)?,
+            data: vec![],
+            done: false,
+        };
+        handshake.handshake()
+    }
+
+    pub fn accepted(ssl: &SslRef) -> bool {
+        unsafe { SSL_get_early_data_status(ssl.as_ptr()) == SSL_EARLY_DATA_ACCEPTED }
+    }
+
+    impl<S> Handshake<S> {
+        pub fn get_ref(&self) -> &S {
+            self.stream.get_ref()
+        }
+
+        pub fn get_mut(&mut self) -> &mut S {
+            self.stream.get_mut()
+        }
+
+        pub fn ssl(&self) -> &SslRef {
+            self.stream.ssl()
+        }
+    }
+
+    impl<S> Handshake<S>
+    where
+        S: io::Read + io::Write,
+    {
+        pub fn handshake(mut self) -> Result<TlsStream<S>, HandshakeError<S>> {
+            while !self.done {
+                let result = if self.stream.ssl().is_server() {
+                    self.read()
+                } else {
+                    self.write()
+                };
+                if let Err(e) = result {
+                    return Err(self.error(e));
+                }
+            }
+            match self.stream.do_handshake() {
+                Ok(()) => {
+                    let mut stream = TlsStream::new(self.stream);
+                    stream.2 = self.data;
+                    Ok(stream)
+                }
+                Err(e) => Err(self.error(e)),
+            }
+        }
+
+        fn read(&mut self) -> Result<(), ssl::Error> {
+            let mut buf = [0; 4096];
+            let len = self.stream.read_early_data(&mut buf)?;
+            self.data.extend_from_slice(&buf[..len]);
+            self.done = len == 0;
+            Ok(())
+        }
+
+        fn write(&mut self) -> Result<(), ssl::Error> {
+            let len = self.stream.write_early_data(&self.data)?;
+            self.data.drain(..len);
+            self.done = self.data.is_empty();
+            Ok(())
+        }
+
+        fn error(self, e: ssl::Error) -> HandshakeError<S> {
+            match e.code() {
+                ErrorCode::WANT_READ | ErrorCode::WANT_WRITE => {
+                    let handshake = super::Handshake::EarlyData(self);
+                    HandshakeError::WouldBlock(MidHandshakeTlsStream(handshake))
+                }
+                _ => {
+                    let v = self.stream.ssl().verify_result();
+                    HandshakeError::Failure(Error::Ssl(e, v))
+                }
+            }
+        }
+    }
+}
+
+mod session {
+    use super::openssl::error::ErrorStack;
+    use super::openssl::ex_data::Index;
+    #[cfg(have_tls13)]
+    use super::openssl::ssl::SslVersion;
+    use super::openssl::ssl::{Ssl, SslContextBuilder, SslRef, SslSession, SslSessionCacheMode};
+    use std::collections::VecDeque;
+    use std::os::raw::c_int;
+    use std::sync::{Arc, Mutex, Once};
+
+    /// The most recently established sessions of a connector and the domains they were made to.
+    pub struct SessionCache {
+        sessions: VecDeque<(String, SslSession)>,
+        capacity: usize,
+    }
+
+    // The domain a connection was made to.
+    fn index() -> Result<Index<Ssl, String>, ErrorStack> {
+        static ONCE: Once = Once::new();
+        static mut INDEX: c_int = -1;
+
+        unsafe {
+            ONCE.call_once(|| {
+                if let Ok(index) = Ssl::new_ex_index::<String>() {
+                    INDEX = index.as_raw();
+                }
+            });
+            if INDEX < 0 {
+                return Err(ErrorStack::get());
+            }
+            Ok(Index::from_raw(INDEX))
+        }
+    }
+
+    pub fn enable(
+        capacity: usize,
+        ctx: &mut SslContextBuilder,
+    ) -> Result<Arc<Mutex<SessionCache>>, ErrorStack> {
+        let index = index()?;
+        let cache = Arc::new(Mutex::new(SessionCache {
+            sessions: VecDeque::new(),
+            capacity,
+        }));
+
+        // OpenSSL never looks up client sessions itself, so there's no point in storing them twice.
+        ctx.set_session_cache_mode(SslSessionCacheMode::CLIENT | SslSessionCacheMode::NO_INTERNAL);
+        let callback_cache = cache.clone();
+        ctx.set_new_session_callback(move |ssl, session| {
+            let domain = match ssl.ex_data(index) {
+                Some(domain) => domain.clone(),
+                None => return,
+            };
+            let mut cache = callback_cache.lock().unwrap();
+            if cache.capacity == 0 {
+                return;
+            }
+            while cache.sessions.len() >= cache.capacity {
+                cache.sessions.pop_front();
+            }
+            cache.sessions.push_back((domain, session));
+        });
+        Ok(cache)
+    }
+
+    /// Offers the most recent session established with `domain` to the server.
+    pub fn resume(
+        cache: &Mutex<SessionCache>,
+        domain: &str,
+        ssl: &mut SslRef,
+    ) -> Result<(), ErrorStack> {
+        ssl.set_ex_data(index()?, domain.to_owned());
+
+        let mut cache = cache.lock().unwrap();
+        let position = cache.sessions.iter().rposition(|entry| entry.0 == domain);
+        if let Some(position) = position {
+            // TLS 1.3 tickets are single use, so they're taken out of the cache once offered.
+            let session = if is_single_use(&cache.sessions[position].1) {
+                cache.sessions.remove(position).unwrap().1
+            } else {
+                cache.sessions[position].1.clone()
+            };
+            // The session was established by the same context.
+            unsafe {
+                ssl.set_session(&session)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(have_tls13)]
+    fn is_single_use(session: &SslSession) -> bool {
+        session.protocol_version() == SslVersion::TLS1_3
+    }
+
+    #[cfg(not(have_tls13))]
+    fn is_single_use(_: &SslSession) -> bool {
+        false
+    }
+}
+
+mod ocsp {
+    use super::openssl::error::ErrorStack;
+    use super::openssl::hash::MessageDigest;
+    use super::openssl::ocsp::{
+        OcspCertId, OcspCertStatus, OcspFlag, OcspResponse, OcspResponseStatus,
+    };
+    use super::openssl::ssl::{SslContextBuilder, SslRef};
+    use super::openssl::x509::X509VerifyResult;
+
+    // The clock skew tolerated when checking the validity period of a response, in seconds.
+    const MAX_CLOCK_SKEW: u32 = 300;
+
+    /// Fails handshakes unless the server staples a response showing that its certificate is good.
+    pub fn require(ctx: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+        ctx.set_status_callback(|ssl| Ok(verify(ssl).unwrap_or(false)))
+    }
+
+    fn verify(ssl: &SslRef) -> Result<bool, ErrorStack> {
+        let response = match ssl.ocsp_status() {
+            Some(response) => OcspResponse::from_der(response)?,
+            None => return Ok(false),
+        };
+        if response.status() != OcspResponseStatus::SUCCESSFUL {
+            return Ok(false);
+        }
+        let response = response.basic()?;
+
+        let chain = match ssl.peer_cert_chain() {
+            Some(chain) => chain,
+            None => return Ok(false),
+        };
+        let store = ssl.ssl_context().cert_store();
+        if response.verify(chain, store, OcspFlag::empty()).is_err() {
+            return Ok(false);
+        }
+
+        // The issuer is needed to identify the certificate, so it must be sent by the server.
+        let cert = match chain.get(0) {
+            Some(cert) => cert,
+            None => return Ok(false),
+        };
+        let issuer = match chain.iter().find(|c| c.issued(cert) == X509VerifyResult::OK) {
+            Some(issuer) => issuer,
+            None => return Ok(false),
+        };
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer)?;
+        match response.find_status(&id) {
+            Some(status) => Ok(status.status == OcspCertStatus::GOOD
+                && status.check_validity(MAX_CLOCK_SKEW, None).is_ok()),
+            None => Ok(false),
+        }
+    }
+
+    /// Staples a DER-encoded response to the handshakes of clients requesting one.
+    pub fn staple(response: Vec<u8>, ctx: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+        ctx.set_status_callback(move |ssl| {
+            ssl.set_ocsp_status(&response)?;
+            Ok(true)
+        })
+    }
+}
+
+// Raw public keys (RFC 7250) aren't exposed by openssl yet
+#[cfg(have_rpk)]
+mod rpk {
+    extern crate foreign_types;
+
+    use self::foreign_types::{ForeignType, ForeignTypeRef};
+    use super::openssl::error::ErrorStack;
+    use super::openssl::pkey::{PKey, Public};
+    use super::openssl::ssl::{SslContextBuilder, SslRef};
+    use super::openssl_sys;
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int, c_uchar};
+
+    const TLSEXT_CERT_TYPE_X509: c_uchar = 0;
+    const TLSEXT_CERT_TYPE_RPK: c_uchar = 2;
+
+    extern "C" {
+        fn SSL_CTX_set1_server_cert_type(
+            ctx: *mut openssl_sys::SSL_CTX,
+            val: *const c_uchar,
+            len: usize,
+        ) -> c_int;
+        fn SSL_CTX_dane_enable(ctx: *mut openssl_sys::SSL_CTX) -> c_int;
+        fn SSL_dane_enable(s: *mut openssl_sys::SSL, basedomain: *const c_char) -> c_int;
+        fn SSL_add_expected_rpk(s: *mut openssl_sys::SSL, rpk: *mut openssl_sys::EVP_PKEY) -> c_int;
+    }
+
+    // Prefers raw public keys for the server's credentials, falling back to X509 certificates.
+    fn set_server_cert_types(ctx: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+        let types = [TLSEXT_CERT_TYPE_RPK, TLSEXT_CERT_TYPE_X509];
+        unsafe {
+            if SSL_CTX_set1_server_cert_type(ctx.as_ptr(), types.as_ptr(), types.len()) <= 0 {
+                return Err(ErrorStack::get());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn enable_client(ctx: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+        set_server_cert_types(ctx)?;
+        // Expected keys are matched as DANE-EE SPKI records.
+        unsafe {
+            if SSL_CTX_dane_enable(ctx.as_ptr()) <= 0 {
+                return Err(ErrorStack::get());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn enable_server(ctx: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+        set_server_cert_types(ctx)
+    }
+
+    pub fn add_expected(
+        ssl: &mut SslRef,
+        domain: &str,
+        keys: &[PKey<Public>],
+    ) -> Result<(), ErrorStack> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let domain = CString::new(domain).unwrap_or_default();
+        unsafe {
+            if SSL_dane_enable(ssl.as_ptr(), domain.as_ptr()) <= 0 {
+                return Err(ErrorStack::get());
+            }
+            for key in keys {
+                if SSL_add_expected_rpk(ssl.as_ptr(), key.as_ptr()) <= 0 {
+                    return Err(ErrorStack::get());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Raw public keys require OpenSSL 3.2, so servers will always present X509 certificates
+#[cfg(not(have_rpk))]
+mod rpk {
+    use super::openssl::error::ErrorStack;
+    use super::openssl::pkey::{PKey, Public};
+    use super::openssl::ssl::{SslContextBuilder, SslRef};
+
+    pub fn enable_client(_: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+        Ok(())
+    }
+
+    pub fn enable_server(_: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+        Ok(())
+    }
+
+    pub fn add_expected(_: &mut SslRef, _: &str, _: &[PKey<Public>]) -> Result<(), ErrorStack> {
+        Ok(())
+    }
+}
+
+mod rekey {
+    extern crate foreign_types;
+
+    use self::foreign_types::ForeignTypeRef;
+    use super::openssl::error::ErrorStack;
+    use super::openssl::ex_data::Index;
+    use super::openssl::ssl::{SslContext, SslContextBuilder, SslRef};
+    use super::openssl_sys;
+    use std::os::raw::c_int;
+    use std::ptr;
+    use std::sync::Once;
+    use std::time::{Duration, Instant};
+
+    // FIXME openssl doesn't expose SSL_get_secure_renegotiation_support yet
+    const SSL_CTRL_GET_RI_SUPPORT: c_int = 76;
+    #[cfg(have_tls13)]
+    const SSL_KEY_UPDATE_REQUESTED: c_int = 1;
+
+    extern "C" {
+        fn SSL_renegotiate(s: *mut openssl_sys::SSL) -> c_int;
+        #[cfg(have_tls13)]
+        fn SSL_key_update(s: *mut openssl_sys::SSL, updatetype: c_int) -> c_int;
+    }
+
+    #[derive(Copy, Clone)]
+    struct Limits {
+        bytes: Option<u64>,
+        interval: Option<Duration>,
+    }
+
+    fn index() -> Result<Index<SslContext, Limits>, ErrorStack> {
+        static ONCE: Once = Once::new();
+        static mut INDEX: c_int = -1;
+
+        unsafe {
+            ONCE.call_once(|| {
+                if let Ok(index) = SslContext::new_ex_index::<Limits>() {
+                    INDEX = index.as_raw();
+                }
+            });
+            if INDEX < 0 {
+                return Err(ErrorStack::get());
+            }
+            Ok(Index::from_raw(INDEX))
+        }
+    }
+
+    pub fn set_limits(
+        bytes: Option<u64>,
+        interval: Option<Duration>,
+        ctx: &mut SslContextBuilder,
+    ) -> Result<(), ErrorStack> {
+        if bytes.is_none() && interval.is_none() {
+            return Ok(());
+        }
+        ctx.set_ex_data(index()?, Limits { bytes, interval });
+        Ok(())
+    }
+
+    pub struct Rekey {
+        limits: Option<Limits>,
+        bytes: u64,
+        since: Instant,
+    }
+
+    impl Rekey {
+        pub fn new(ssl: &SslRef) -> Rekey {
+            let limits = index()
+                .ok()
+                .and_then(|index| ssl.ssl_context().ex_data(index))
+                .cloned();
+            Rekey {
+                limits,
+                bytes: 0,
+                since: Instant::now(),
+            }
+        }
+
+        pub fn record(&mut self, bytes: usize) {
+            self.bytes += bytes as u64;
+        }
+
+        // This only schedules the key update; OpenSSL carries it out as part of the next read or
+        // write. TLS 1.2 connections without secure renegotiation are never rekeyed.
+        pub fn check(&mut self, ssl: &SslRef) -> Result<(), ErrorStack> {
+            let limits = match self.limits {
+                Some(limits) => limits,
+                None => return Ok(()),
+            };
+            let bytes_due = match limits.bytes {
+                Some(bytes) => self.bytes >= bytes,
+                None => false,
+            };
+            let interval_due = match limits.interval {
+                Some(interval) => self.since.elapsed() >= interval,
+                None => false,
+            };
+            if !(bytes_due || interval_due) {
+                return Ok(());
+            }
+
+            unsafe {
+                let ssl = ssl.as_ptr();
+                let ret = match openssl_sys::SSL_version(ssl) {
+                    #[cfg(have_tls13)]
+                    openssl_sys::TLS1_3_VERSION => SSL_key_update(ssl, SSL_KEY_UPDATE_REQUESTED),
+                    // Renegotiation is only safe if both sides support RFC 5746.
+                    openssl_sys::TLS1_2_VERSION
+                        if openssl_sys::SSL_ctrl(ssl, SSL_CTRL_GET_RI_SUPPORT, 0, ptr::null_mut())
+                            > 0 =>
+                    {
+                        SSL_renegotiate(ssl)
+                    }
+                    _ => return Ok(()),
+                };
+                if ret <= 0 {
+                    return Err(ErrorStack::get());
+                }
+            }
+            self.bytes = 0;
+            self.since = Instant::now();
+            Ok(())
+        }
+    }
+}
+
+mod tickets {
+    extern crate foreign_types;
+
+    use self::foreign_types::ForeignTypeRef;
+    use super::openssl::error::ErrorStack;
+    use super::openssl::ex_data::Index;
+    use super::openssl::ssl::{SslContext, SslContextBuilder, SslRef};
+    use super::openssl_sys;
+    use std::mem;
+    use std::os::raw::{c_int, c_uchar, c_void};
+    use std::ptr;
+    use std::slice;
+    use std::sync::{Arc, Once, RwLock};
+
+    // FIXME openssl doesn't expose SSL_CTX_set_tlsext_ticket_key_cb yet
+    const SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB: c_int = 72;
+    const NAME_LEN: usize = 16;
+    const IV_LEN: usize = 16;
+
+    type TicketKeyCallback = unsafe extern "C" fn(
+        *mut openssl_sys::SSL,
+        *mut c_uchar,
+        *mut c_uchar,
+        *mut openssl_sys::EVP_CIPHER_CTX,
+        *mut openssl_sys::HMAC_CTX,
+        c_int,
+    ) -> c_int;
+
+    // A 16 byte name, followed by a 32 byte HMAC-SHA256 key and a 32 byte AES-256-CBC key.
+    type Key = [u8; 80];
+
+    struct Keys {
+        current: Key,
+        previous: Option<Key>,
+    }
+
+    #[derive(Clone)]
+    pub struct SessionTicketKeys(Arc<RwLock<Keys>>);
+
+    impl SessionTicketKeys {
+        pub fn new(key: &Key) -> SessionTicketKeys {
+            SessionTicketKeys(Arc::new(RwLock::new(Keys {
+                current: *key,
+                previous: None,
+            })))
+        }
+
+        pub fn rotate(&self, key: &Key) {
+            let mut keys = self.0.write().unwrap();
+            keys.previous = Some(keys.current);
+            keys.current = *key;
+        }
+    }
+
+    fn index() -> Result<Index<SslContext, SessionTicketKeys>, ErrorStack> {
+        static ONCE: Once = Once::new();
+        static mut INDEX: c_int = -1;
+
+        unsafe {
+            ONCE.call_once(|| {
+                if let Ok(index) = SslContext::new_ex_index::<SessionTicketKeys>() {
+                    INDEX = index.as_raw();
+                }
+            });
+            if INDEX < 0 {
+                return Err(ErrorStack::get());
+            }
+            Ok(Index::from_raw(INDEX))
+        }
+    }
+
+    pub fn set_keys(
+        keys: &SessionTicketKeys,
+        ctx: &mut SslContextBuilder,
+    ) -> Result<(), ErrorStack> {
+        ctx.set_ex_data(index()?, keys.clone());
+        let callback: TicketKeyCallback = ticket_key;
+        unsafe {
+            let callback = mem::transmute::<TicketKeyCallback, unsafe extern "C" fn()>(callback);
+            if openssl_sys::SSL_CTX_callback_ctrl__fixed_rust(
+                ctx.as_ptr(),
+                SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB,
+                Some(callback),
+            ) <= 0
+            {
+                return Err(ErrorStack::get());
+            }
+        }
+        Ok(())
+    }
+
+    // Returns 1 if the ticket was encrypted or decrypted with the current key, 2 if it was
+    // decrypted with the previous key and should be renewed, 0 if the key is unknown and -1 on
+    // error.
+    unsafe extern "C" fn ticket_key(
+        ssl: *mut openssl_sys::SSL,
+        key_name: *mut c_uchar,
+        iv: *mut c_uchar,
+        cipher_ctx: *mut openssl_sys::EVP_CIPHER_CTX,
+        hmac_ctx: *mut openssl_sys::HMAC_CTX,
+        enc: c_int,
+    ) -> c_int {
+        let index = match index() {
+            Ok(index) => index,
+            Err(_) => return -1,
+        };
+        let keys = match SslRef::from_ptr(ssl).ssl_context().ex_data(index) {
+            Some(keys) => keys,
+            None => return -1,
+        };
+        let keys = match keys.0.read() {
+            Ok(keys) => keys,
+            Err(_) => return -1,
+        };
+
+        if enc == 1 {
+            if openssl_sys::RAND_bytes(iv, IV_LEN as c_int) <= 0 {
+                return -1;
+            }
+            ptr::copy_nonoverlapping(keys.current.as_ptr(), key_name, NAME_LEN);
+            return init(&keys.current, iv, cipher_ctx, hmac_ctx, true);
+        }
+
+        let name = slice::from_raw_parts(key_name, NAME_LEN);
+        if keys.current[..NAME_LEN] == *name {
+            return init(&keys.current, iv, cipher_ctx, hmac_ctx, false);
+        }
+        match keys.previous {
+            Some(ref key) if key[..NAME_LEN] == *name => {
+                match init(key, iv, cipher_ctx, hmac_ctx, false) {
+                    1 => 2,
+                    ret => ret,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    unsafe fn init(
+        key: &Key,
+        iv: *const c_uchar,
+        cipher_ctx: *mut openssl_sys::EVP_CIPHER_CTX,
+        hmac_ctx: *mut openssl_sys::HMAC_CTX,
+        encrypt: bool,
+    ) -> c_int {
+        let hmac_key = key[NAME_LEN..NAME_LEN + 32].as_ptr();
+        let aes_key = key[NAME_LEN + 32..].as_ptr();
+        let cipher = openssl_sys::EVP_aes_256_cbc();
+        let ret = if encrypt {
+            openssl_sys::EVP_EncryptInit_ex(cipher_ctx, cipher, ptr::null_mut(), aes_key, iv)
+        } else {
+            openssl_sys::EVP_DecryptInit_ex(cipher_ctx, cipher, ptr::null_mut(), aes_key, iv)
+        };
+        if ret <= 0
+            || openssl_sys::HMAC_Init_ex(
+                hmac_ctx,
+                hmac_key as *const c_void,
+                32,
+                openssl_sys::EVP_sha256(),
+                ptr::null_mut(),
+            ) <= 0
+        {
+            return -1;
+        }
+        1
+    }
+}
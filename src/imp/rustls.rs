@@ -0,0 +1,1289 @@
+extern crate p12_keystore;
+extern crate pkcs5;
+extern crate ring;
+extern crate rustls_crate as rustls;
+extern crate webpki;
+extern crate webpki_roots;
+
+use self::p12_keystore::KeyStore;
+use self::pkcs5::der::Decode;
+use self::pkcs5::EncryptionScheme;
+use self::ring::digest;
+use self::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use self::rustls::client::{Resumption, WebPkiServerVerifier};
+use self::rustls::crypto::ring::{default_provider, Ticketer};
+use self::rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms};
+use self::rustls::pki_types::{
+    CertificateDer, CertificateRevocationListDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName,
+    UnixTime,
+};
+use self::rustls::server::{Acceptor, ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use self::rustls::sign::CertifiedKey;
+use self::rustls::version;
+use self::rustls::{
+    AlertDescription, CertificateError, ClientConfig, ClientConnection, Connection,
+    DigitallySignedStruct, HandshakeKind, PeerIncompatible, ProtocolVersion, RootCertStore,
+    ServerConfig, SignatureScheme, SupportedProtocolVersion,
+};
+use std::borrow::Cow;
+use std::cmp;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use {chain, der, hostname, jks, pem, policy, TlsAcceptorBuilder, TlsConnectorBuilder};
+use {Protocol, WildcardPolicy};
+
+static TLS12: &[&SupportedProtocolVersion] = &[&version::TLS12];
+static TLS13: &[&SupportedProtocolVersion] = &[&version::TLS13];
+static ALL_VERSIONS: &[&SupportedProtocolVersion] = &[&version::TLS13, &version::TLS12];
+
+// rustls doesn't implement anything older than TLS 1.2, so only a TLS 1.3 minimum matters.
+fn protocol_versions(
+    min: Option<Protocol>,
+    max: Option<Protocol>,
+) -> Result<&'static [&'static SupportedProtocolVersion], Error> {
+    match max {
+        None | Some(Protocol::Tlsv13) if min == Some(Protocol::Tlsv13) => Ok(TLS13),
+        None | Some(Protocol::Tlsv13) => Ok(ALL_VERSIONS),
+        Some(Protocol::Tlsv12) if min != Some(Protocol::Tlsv13) => Ok(TLS12),
+        Some(Protocol::Tlsv12) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the minimum protocol version is newer than the maximum",
+        )
+        .into()),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "protocols older than TLS 1.2 are not supported by rustls",
+        )
+        .into()),
+    }
+}
+
+// rustls counts the record header against the fragment size.
+fn max_fragment_size(max: Option<usize>) -> Result<Option<usize>, Error> {
+    match max {
+        Some(max) if !(512..=16384).contains(&max) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("maximum send fragment of {} bytes is not between 512 and 16384 bytes", max),
+        )
+        .into()),
+        Some(max) => Ok(Some(max + 5)),
+        None => Ok(None),
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(default_provider())
+}
+
+fn provider_with_suites(suites: &[::CipherSuite]) -> Result<Arc<CryptoProvider>, Error> {
+    let mut provider = default_provider();
+    if !suites.is_empty() {
+        provider
+            .cipher_suites
+            .retain(|suite| suites.contains(&::CipherSuite::from_u16(u16::from(suite.suite()))));
+        if provider.cipher_suites.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "none of the configured cipher suites are supported",
+            )
+            .into());
+        }
+    }
+    Ok(Arc::new(provider))
+}
+
+pub struct Error(io::Error);
+
+impl Error {
+    #[cfg(target_os = "windows")]
+    pub fn as_inner(&self) -> &io::Error {
+        &self.0
+    }
+
+    pub fn kind(&self) -> ::ErrorKind {
+        let inner = match self.0.get_ref() {
+            Some(inner) => inner,
+            None => return ::ErrorKind::Io,
+        };
+        if let Some(e) = inner.downcast_ref() {
+            return match *e {
+                rustls::Error::InvalidCertificate(ref e) => match *e {
+                    CertificateError::NotValidForName
+                    | CertificateError::NotValidForNameContext { .. } => {
+                        ::ErrorKind::HostnameMismatch
+                    }
+                    CertificateError::Expired
+                    | CertificateError::ExpiredContext { .. }
+                    | CertificateError::NotValidYet
+                    | CertificateError::NotValidYetContext { .. } => ::ErrorKind::CertExpired,
+                    CertificateError::UnknownIssuer => ::ErrorKind::UntrustedRoot,
+                    _ => ::ErrorKind::Other,
+                },
+                rustls::Error::PeerIncompatible(PeerIncompatible::ServerDoesNotSupportTls12Or13)
+                | rustls::Error::PeerIncompatible(PeerIncompatible::Tls12NotOffered)
+                | rustls::Error::PeerIncompatible(PeerIncompatible::Tls12NotOfferedOrEnabled)
+                | rustls::Error::PeerIncompatible(
+                    PeerIncompatible::ServerTlsVersionIsDisabledByOurConfig,
+                )
+                | rustls::Error::PeerIncompatible(
+                    PeerIncompatible::SupportedVersionsExtensionRequired,
+                ) => ::ErrorKind::ProtocolVersion,
+                rustls::Error::AlertReceived(AlertDescription::ProtocolVersion) => {
+                    ::ErrorKind::ProtocolVersion
+                }
+                _ => ::ErrorKind::Other,
+            };
+        }
+        match inner.downcast_ref() {
+            Some(&policy::Error::Hostname) | Some(&policy::Error::IpAddress) => {
+                ::ErrorKind::HostnameMismatch
+            }
+            // Errors raised by this crate rather than by the stream
+            _ if self.0.kind() == io::ErrorKind::InvalidData
+                || self.0.kind() == io::ErrorKind::Other =>
+            {
+                ::ErrorKind::Other
+            }
+            _ => ::ErrorKind::Io,
+        }
+    }
+
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self.kind() {
+            ::ErrorKind::Io => Some(&self.0),
+            _ => None,
+        }
+    }
+
+    pub fn into_io_error(self) -> Result<io::Error, Error> {
+        match self.kind() {
+            ::ErrorKind::Io => Ok(self.0),
+            _ => Err(self),
+        }
+    }
+
+    pub fn is_negotiation_failure(&self) -> bool {
+        match self.0.kind() {
+            // The server dropped the connection
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof => return true,
+            _ => {}
+        }
+        match self.0.get_ref().and_then(|e| e.downcast_ref()) {
+            Some(&rustls::Error::PeerIncompatible(_)) => true,
+            Some(&rustls::Error::AlertReceived(alert)) => {
+                alert == AlertDescription::ProtocolVersion
+                    || alert == AlertDescription::HandshakeFailure
+                    || alert == AlertDescription::InsufficientSecurity
+            }
+            _ => false,
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        error::Error::source(&self.0)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, fmt)
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, fmt)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error(error)
+    }
+}
+
+impl From<rustls::Error> for Error {
+    fn from(error: rustls::Error) -> Error {
+        Error(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+impl From<jks::Error> for Error {
+    fn from(error: jks::Error) -> Error {
+        Error(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+impl From<der::Error> for Error {
+    fn from(error: der::Error) -> Error {
+        Error(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+impl From<pem::Error> for Error {
+    fn from(error: pem::Error) -> Error {
+        Error(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+impl From<policy::Error> for Error {
+    fn from(error: policy::Error) -> Error {
+        Error(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+#[derive(Clone)]
+pub struct Identity {
+    key: Vec<u8>,
+    cert: Vec<u8>,
+    chain: Vec<Vec<u8>>,
+}
+
+impl Identity {
+    pub fn from_pkcs12(buf: &[u8], pass: &str) -> Result<Identity, Error> {
+        let store = KeyStore::from_pkcs12(buf, pass)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some((_, identity)) = store.private_key_chain() {
+            let chain = identity
+                .chain()
+                .iter()
+                .map(|c| c.as_der())
+                .collect::<Vec<_>>();
+            return Identity::from_pkcs8_der(&chain, identity.key());
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No identity found in PKCS #12 archive",
+        )
+        .into())
+    }
+
+    pub fn from_encrypted_pkcs8_der(
+        chain: &[&[u8]],
+        key: &[u8],
+        pass: &str,
+    ) -> Result<Identity, Error> {
+        let (algorithm, key) = der::encrypted_private_key_info(key)?;
+        let scheme = EncryptionScheme::from_der(algorithm).map_err(|_| pem::Error::Unsupported)?;
+        let key = scheme.decrypt(pass, key).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "failed to decrypt the private key",
+            )
+        })?;
+        Identity::from_pkcs8_der(chain, &key)
+    }
+
+    pub fn from_pkcs8_der(chain: &[&[u8]], key: &[u8]) -> Result<Identity, Error> {
+        let mut chain = chain.iter();
+        let cert = match chain.next() {
+            Some(cert) => Certificate::from_der(cert)?.0,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "at least one certificate must be provided to create an identity",
+                )
+                .into());
+            }
+        };
+        let chain = chain
+            .map(|cert| Certificate::from_der(cert).map(|cert| cert.0))
+            .collect::<Result<Vec<_>, _>>()?;
+        let chain = chain::sort(&cert, chain.clone(), &chain);
+
+        let identity = Identity {
+            key: key.to_vec(),
+            cert,
+            chain,
+        };
+        // Keys rustls can't sign with are rejected here rather than during a handshake.
+        provider()
+            .key_provider
+            .load_private_key(identity.private_key())?;
+        Ok(identity)
+    }
+
+    fn private_key(&self) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key.clone()))
+    }
+
+    fn certificates(&self, omit_root_certificate: bool) -> Vec<CertificateDer<'static>> {
+        let mut certs = vec![CertificateDer::from(self.cert.clone())];
+        for cert in &self.chain {
+            if omit_root_certificate && der::is_self_issued(cert).unwrap_or(false) {
+                continue;
+            }
+            certs.push(CertificateDer::from(cert.clone()));
+        }
+        certs
+    }
+}
+
+#[derive(Clone)]
+pub struct Certificate(Vec<u8>);
+
+impl Certificate {
+    pub fn from_der(buf: &[u8]) -> Result<Certificate, Error> {
+        der::subject_public_key_info(buf)?;
+        Ok(Certificate(buf.to_vec()))
+    }
+
+    pub fn from_pem(buf: &[u8]) -> Result<Certificate, Error> {
+        let der = pem::certificate(buf)?;
+        Certificate::from_der(&der)
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.clone())
+    }
+
+    pub fn fingerprint(&self, digest: ::Digest) -> Result<Vec<u8>, Error> {
+        let algorithm = match digest {
+            ::Digest::Sha1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            ::Digest::Sha256 => &digest::SHA256,
+            ::Digest::Sha384 => &digest::SHA384,
+            ::Digest::Sha512 => &digest::SHA512,
+            ::Digest::__NonExhaustive => unreachable!(),
+        };
+        Ok(digest::digest(algorithm, &self.0).as_ref().to_vec())
+    }
+
+    pub fn public_key_sha256(&self) -> Result<Vec<u8>, Error> {
+        let spki = der::subject_public_key_info(&self.0)?;
+        Ok(digest::digest(&digest::SHA256, spki).as_ref().to_vec())
+    }
+
+    // Signature algorithm identifiers can carry parameters which differ from the ones rustls
+    // encodes, so candidates are matched by OID and the key's algorithm, and tried in turn.
+    pub fn verify_signed_by(&self, issuer: &Certificate) -> Result<bool, Error> {
+        let signed = der::signed_data(&self.0)?;
+        let (key_algorithm, key) = der::public_key(der::subject_public_key_info(&issuer.0)?)?;
+        let candidates = provider()
+            .signature_verification_algorithms
+            .all
+            .iter()
+            .filter(|algorithm| {
+                let signature_algorithm = algorithm.signature_alg_id();
+                let oid = der::Reader::new(signature_algorithm.as_ref()).read(der::OID);
+                oid == Ok(signed.algorithm)
+                    && algorithm.public_key_alg_id().as_ref() == key_algorithm
+            })
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the certificate's signature algorithm is not supported by rustls",
+            )
+            .into());
+        }
+        Ok(candidates.iter().any(|algorithm| {
+            algorithm
+                .verify_signature(key, signed.tbs, signed.signature)
+                .is_ok()
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct Crl(Vec<u8>);
+
+impl Crl {
+    pub fn from_der(buf: &[u8]) -> Result<Crl, Error> {
+        der::Reader::new(buf).read(der::SEQUENCE)?;
+        Ok(Crl(buf.to_vec()))
+    }
+}
+
+pub struct MidHandshakeTlsStream<S>(Box<Handshake<S>>);
+
+enum Handshake<S> {
+    // The server reads the ClientHello before committing to a configuration.
+    Accepting(Acceptor, TlsAcceptor, S),
+    Connecting(TlsStream<S>),
+}
+
+impl<S> fmt::Debug for MidHandshakeTlsStream<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MidHandshakeTlsStream")
+            .field("stream", self.get_ref())
+            .finish()
+    }
+}
+
+impl<S> MidHandshakeTlsStream<S> {
+    pub fn get_ref(&self) -> &S {
+        match *self.0 {
+            Handshake::Accepting(_, _, ref stream) => stream,
+            Handshake::Connecting(ref stream) => stream.get_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        match *self.0 {
+            Handshake::Accepting(_, _, ref mut stream) => stream,
+            Handshake::Connecting(ref mut stream) => stream.get_mut(),
+        }
+    }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        match *self.0 {
+            Handshake::Accepting(..) => Ok(None),
+            Handshake::Connecting(ref stream) => Ok(stream.server_name()),
+        }
+    }
+}
+
+impl<S> MidHandshakeTlsStream<S>
+where
+    S: io::Read + io::Write,
+{
+    pub fn handshake(self) -> Result<TlsStream<S>, HandshakeError<S>> {
+        match *self.0 {
+            Handshake::Accepting(acceptor, server, stream) => server.handshake(acceptor, stream),
+            Handshake::Connecting(stream) => stream.handshake(),
+        }
+    }
+}
+
+pub enum HandshakeError<S> {
+    Failure(Error),
+    WouldBlock(MidHandshakeTlsStream<S>),
+}
+
+impl<S> From<io::Error> for HandshakeError<S> {
+    fn from(e: io::Error) -> HandshakeError<S> {
+        HandshakeError::Failure(e.into())
+    }
+}
+
+impl<S> From<rustls::Error> for HandshakeError<S> {
+    fn from(e: rustls::Error) -> HandshakeError<S> {
+        HandshakeError::Failure(e.into())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Verifier {
+    // webpki refuses to build a verifier which could never succeed, so the error it would report
+    // is kept instead.
+    webpki: Result<Arc<WebPkiServerVerifier>, CertificateError>,
+    // Trusted certificates which may be presented as the end entity themselves.
+    anchors: Vec<Vec<u8>>,
+    // The trust anchors given to webpki, along with the certificates added as roots, to find the
+    // path it verified once the handshake completes.
+    roots: Arc<RootCertStore>,
+    root_certificates: Vec<Vec<u8>>,
+    algorithms: WebPkiSupportedAlgorithms,
+    accept_invalid_certs: bool,
+    accept_invalid_hostnames: bool,
+    certificate_exceptions: Option<::CertificateExceptions>,
+    // Connections without a domain pass rustls a placeholder name, which is never matched.
+    domain: bool,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer,
+        intermediates: &[CertificateDer],
+        server_name: &ServerName,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.accept_invalid_certs {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let result = if self
+            .anchors
+            .iter()
+            .any(|anchor| anchor[..] == end_entity[..])
+        {
+            verify_anchor(end_entity, server_name, now)
+        } else {
+            match self.webpki {
+                Ok(ref webpki) => webpki.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    now,
+                ),
+                Err(ref e) => Err(rustls::Error::InvalidCertificate(e.clone())),
+            }
+        };
+        // The name is only checked once the chain has been verified.
+        let err = match result {
+            Ok(verified) => return Ok(verified),
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName))
+            | Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForNameContext {
+                ..
+            })) if self.accept_invalid_hostnames || !self.domain => {
+                return Ok(ServerCertVerified::assertion());
+            }
+            Err(err) => err,
+        };
+        if let Some(ref exceptions) = self.certificate_exceptions {
+            let domain = if self.domain {
+                server_name.to_str()
+            } else {
+                Cow::Borrowed("")
+            };
+            if let Ok(cert) = ::Certificate::from_der(end_entity) {
+                if exceptions.verify(&domain, &cert) {
+                    return Ok(ServerCertVerified::assertion());
+                }
+            }
+        }
+        Err(err)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+impl Verifier {
+    // Returns the path from the leaf of an accepted chain to the root certificate it was verified
+    // against. The built-in roots are only known as trust anchors, so they're left out, and
+    // chains which were accepted without being verified only yield the leaf.
+    fn verified_path(&self, certs: &[CertificateDer]) -> Vec<Certificate> {
+        let (leaf, intermediates) = match certs.split_first() {
+            Some(certs) => certs,
+            None => return vec![],
+        };
+        let mut chain = vec![Certificate(leaf.to_vec())];
+        if self.accept_invalid_certs || self.anchors.iter().any(|anchor| anchor[..] == leaf[..]) {
+            return chain;
+        }
+
+        let cert = match webpki::EndEntityCert::try_from(leaf) {
+            Ok(cert) => cert,
+            Err(_) => return chain,
+        };
+        let path = cert.verify_for_usage(
+            self.algorithms.all,
+            &self.roots.roots,
+            intermediates,
+            UnixTime::now(),
+            webpki::KeyUsage::server_auth(),
+            None,
+            None,
+        );
+        if let Ok(path) = path {
+            chain.extend(
+                path.intermediate_certificates()
+                    .map(|cert| Certificate(cert.der().to_vec())),
+            );
+            let anchor = path.anchor();
+            let root = self.root_certificates.iter().find(|root| {
+                let root = CertificateDer::from(&root[..]);
+                match webpki::anchor_from_trusted_cert(&root) {
+                    Ok(root) => root.subject_public_key_info == anchor.subject_public_key_info,
+                    Err(_) => false,
+                }
+            });
+            chain.extend(root.map(|root| Certificate(root.clone())));
+        }
+        chain
+    }
+}
+
+// webpki won't accept a trust anchor as the end entity, which the other backends do as long as it's
+// valid for the name.
+fn verify_anchor(
+    cert: &[u8],
+    server_name: &ServerName,
+    now: UnixTime,
+) -> Result<ServerCertVerified, rustls::Error> {
+    let invalid = rustls::Error::InvalidCertificate;
+    let (not_before, not_after) =
+        der::validity(cert).map_err(|_| invalid(CertificateError::BadEncoding))?;
+    let now = UNIX_EPOCH + Duration::from_secs(now.as_secs());
+    if now < not_before {
+        return Err(invalid(CertificateError::NotValidYet));
+    }
+    if now > not_after {
+        return Err(invalid(CertificateError::Expired));
+    }
+
+    let valid = match *server_name {
+        ServerName::DnsName(ref name) => {
+            hostname::verify(cert, name.as_ref(), WildcardPolicy::Allow)
+        }
+        ServerName::IpAddress(addr) => hostname::verify_ip(cert, IpAddr::from(addr)),
+        _ => Ok(false),
+    };
+    match valid {
+        Ok(true) => Ok(ServerCertVerified::assertion()),
+        Ok(false) => Err(invalid(CertificateError::NotValidForName)),
+        Err(_) => Err(invalid(CertificateError::BadEncoding)),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    verifier: Arc<Verifier>,
+}
+
+impl TlsConnector {
+    pub fn new_dtls(_: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "DTLS is not supported by rustls").into())
+    }
+
+    pub fn new(builder: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
+        if builder.require_ocsp_staple {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "requiring OCSP stapling is not supported by rustls",
+            )
+            .into());
+        }
+
+        let provider = provider_with_suites(&builder.cipher_suites)?;
+        let mut roots = RootCertStore::empty();
+        let root_certificates = builder
+            .root_certificates
+            .iter()
+            .map(|cert| Ok(cert.0.rustls()?.0))
+            .collect::<Result<Vec<_>, Error>>()?;
+        if !builder.disable_built_in_roots {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+        for cert in &root_certificates {
+            roots.add(CertificateDer::from(cert.clone()))?;
+        }
+
+        let roots = Arc::new(roots);
+        let webpki = if roots.is_empty() {
+            Err(CertificateError::UnknownIssuer)
+        } else if builder.check_revocation && builder.crls.is_empty() {
+            Err(CertificateError::UnknownRevocationStatus)
+        } else {
+            let mut webpki =
+                WebPkiServerVerifier::builder_with_provider(roots.clone(), provider.clone());
+            if builder.check_revocation {
+                let crls = builder
+                    .crls
+                    .iter()
+                    .map(|crl| Ok(CertificateRevocationListDer::from(crl.0.rustls()?.0)))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                webpki = webpki.with_crls(crls);
+            }
+            Ok(webpki
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?)
+        };
+        let anchors = root_certificates
+            .iter()
+            .filter(|cert| {
+                builder.allow_partial_chains || der::is_self_issued(cert).unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        let verifier = Arc::new(Verifier {
+            webpki,
+            anchors,
+            roots,
+            root_certificates,
+            algorithms: provider.signature_verification_algorithms,
+            accept_invalid_certs: builder.accept_invalid_certs,
+            accept_invalid_hostnames: builder.accept_invalid_hostnames,
+            certificate_exceptions: builder.certificate_exceptions.clone(),
+            domain: true,
+        });
+
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(protocol_versions(builder.min_protocol, builder.max_protocol)?)?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier.clone());
+        let mut config = match builder.identity {
+            Some(ref identity) => {
+                let identity = identity.0.rustls()?;
+                config
+                    .with_client_auth_cert(identity.certificates(false), identity.private_key())?
+            }
+            None => config.with_no_client_auth(),
+        };
+        config.enable_sni = builder.use_sni;
+        config.max_fragment_size = max_fragment_size(builder.max_send_fragment)?;
+        config.resumption = if builder.session_cache {
+            Resumption::in_memory_sessions(builder.session_cache_size)
+        } else {
+            Resumption::disabled()
+        };
+        config.enable_early_data = builder.early_data;
+        #[cfg(feature = "alpn")]
+        {
+            config.alpn_protocols = builder.alpn.iter().map(|s| s.as_bytes().to_vec()).collect();
+        }
+
+        Ok(TlsConnector {
+            config: Arc::new(config),
+            verifier,
+        })
+    }
+
+    pub fn connect<S>(&self, domain: &str, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect_inner(Some(domain), self.config.enable_sni, stream, &[])
+    }
+
+    pub fn connect_without_sni<S>(
+        &self,
+        domain: Option<&str>,
+        stream: S,
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect_inner(domain, false, stream, &[])
+    }
+
+    pub fn connect_with_early_data<S>(
+        &self,
+        domain: &str,
+        stream: S,
+        data: &[u8],
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect_inner(Some(domain), self.config.enable_sni, stream, data)
+    }
+
+    fn connect_inner<S>(
+        &self,
+        domain: Option<&str>,
+        use_sni: bool,
+        stream: S,
+        early_data: &[u8],
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        let mut config = self.config.clone();
+        let mut verifier = self.verifier.clone();
+        if use_sni != config.enable_sni {
+            Arc::make_mut(&mut config).enable_sni = use_sni;
+        }
+        let name = match domain {
+            Some(domain) => ServerName::try_from(domain)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+                .to_owned(),
+            None => {
+                // Sessions are cached by name, so there's nothing to share without one.
+                let config = Arc::make_mut(&mut config);
+                config.resumption = Resumption::disabled();
+                Arc::make_mut(&mut verifier).domain = false;
+                config.dangerous().set_certificate_verifier(verifier.clone());
+                ServerName::from(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+            }
+        };
+        let mut conn = ClientConnection::new(config, name)?;
+        if !early_data.is_empty() {
+            // Data which doesn't fit in what the server allows is sent after the handshake by
+            // the caller instead.
+            if let Some(mut writer) = conn.early_data() {
+                if early_data.len() <= writer.bytes_left() {
+                    io::Write::write_all(&mut writer, early_data)?;
+                }
+            }
+        }
+        let mut stream = TlsStream::new(Connection::Client(conn), stream, None);
+        stream.verifier = Some(verifier);
+        stream.handshake()
+    }
+}
+
+#[derive(Debug)]
+struct Identities {
+    default: Vec<Arc<CertifiedKey>>,
+    sni: Vec<(String, Arc<CertifiedKey>)>,
+}
+
+impl Identities {
+    fn by_name(&self, name: Option<&str>) -> Option<&Arc<CertifiedKey>> {
+        let name = hostname::normalize(name?);
+        self.sni
+            .iter()
+            .find(|identity| hostname::matches(&identity.0, &name, WildcardPolicy::Allow))
+            .map(|identity| &identity.1)
+    }
+
+    // Among several default identities, the first one whose key the client can verify wins.
+    fn select(&self, hello: &ClientHello) -> Option<&Arc<CertifiedKey>> {
+        let schemes = hello.signature_schemes();
+        self.by_name(hello.server_name())
+            .or_else(|| {
+                self.default
+                    .iter()
+                    .find(|identity| identity.key.choose_scheme(schemes).is_some())
+            })
+            .or_else(|| self.default.first())
+    }
+}
+
+impl ResolvesServerCert for Identities {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.select(&hello).cloned()
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+    identities: Arc<Identities>,
+}
+
+impl TlsAcceptor {
+    pub fn new_dtls(_: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "DTLS is not supported by rustls").into())
+    }
+
+    pub fn new(builder: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
+        let provider = provider_with_suites(&builder.cipher_suites)?;
+        let certified_key = |identity: &::Identity| -> Result<Arc<CertifiedKey>, Error> {
+            let identity = identity.0.rustls()?;
+            let key = provider
+                .key_provider
+                .load_private_key(identity.private_key())?;
+            let certs = identity.certificates(builder.omit_root_certificate);
+            let mut certified_key = CertifiedKey::new(certs, key);
+            certified_key.ocsp = builder.ocsp_response.clone();
+            Ok(Arc::new(certified_key))
+        };
+        let identities = Identities {
+            default: Some(&builder.identity)
+                .into_iter()
+                .chain(&builder.additional_identities)
+                .map(&certified_key)
+                .collect::<Result<_, _>>()?,
+            sni: builder
+                .sni_identities
+                .iter()
+                .map(|(name, identity)| Ok((name.clone(), certified_key(identity)?)))
+                .collect::<Result<_, Error>>()?,
+        };
+        let identities = Arc::new(identities);
+
+        let config = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(protocol_versions(builder.min_protocol, builder.max_protocol)?)?;
+        let config = match builder.client_auth {
+            ::ClientAuth::Disabled => config.with_no_client_auth(),
+            ::ClientAuth::Optional | ::ClientAuth::Required => {
+                let mut roots = RootCertStore::empty();
+                for cert in &builder.client_root_certificates {
+                    roots.add(CertificateDer::from(cert.0.rustls()?.0))?;
+                }
+                let mut verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+                if builder.client_auth == ::ClientAuth::Optional {
+                    verifier = verifier.allow_unauthenticated();
+                }
+                let verifier = verifier
+                    .build()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                config.with_client_cert_verifier(verifier)
+            }
+            ::ClientAuth::__NonExhaustive => unreachable!(),
+        };
+        let mut config = config.with_cert_resolver(identities.clone());
+        config.max_fragment_size = max_fragment_size(builder.max_send_fragment)?;
+        // rustls only accepts early data when resuming sessions from its own cache, as tickets
+        // can't be taken out of circulation after a single use.
+        if builder.max_early_data_size > 0 {
+            config.max_early_data_size = builder.max_early_data_size;
+        } else {
+            config.ticketer = Ticketer::new()?;
+        }
+        #[cfg(feature = "alpn")]
+        {
+            config.alpn_protocols = builder.alpn.iter().map(|s| s.as_bytes().to_vec()).collect();
+        }
+
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+            identities,
+        })
+    }
+
+    pub fn accept<S>(&self, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.handshake(Acceptor::default(), stream)
+    }
+
+    fn handshake<S>(
+        &self,
+        mut acceptor: Acceptor,
+        mut stream: S,
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        let accepted = loop {
+            match acceptor.accept() {
+                Ok(Some(accepted)) => break accepted,
+                Ok(None) => {}
+                Err((e, mut alert)) => {
+                    let _ = alert.write_all(&mut stream);
+                    return Err(e.into());
+                }
+            }
+            match acceptor.read_tls(&mut stream) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let handshake = Handshake::Accepting(acceptor, self.clone(), stream);
+                    return Err(HandshakeError::WouldBlock(MidHandshakeTlsStream(Box::new(
+                        handshake,
+                    ))));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let hello = accepted.client_hello();
+        let certificate = self
+            .identities
+            .select(&hello)
+            .map(|identity| identity.cert[0].to_vec());
+        #[cfg_attr(not(feature = "alpn"), allow(unused_mut))]
+        let mut config = self.config.clone();
+        // rustls rejects clients which offer none of the server's protocols, while the other
+        // backends carry on without one.
+        #[cfg(feature = "alpn")]
+        {
+            if !config.alpn_protocols.is_empty() {
+                let offered = hello.alpn().map(|alpn| alpn.collect::<Vec<_>>());
+                let offered = offered.unwrap_or_default();
+                let selected = config
+                    .alpn_protocols
+                    .iter()
+                    .find(|protocol| offered.contains(&&protocol[..]))
+                    .cloned();
+                Arc::make_mut(&mut config).alpn_protocols = selected.into_iter().collect();
+            }
+        }
+
+        match accepted.into_connection(config) {
+            Ok(conn) => TlsStream::new(Connection::Server(conn), stream, certificate).handshake(),
+            Err((e, mut alert)) => {
+                let _ = alert.write_all(&mut stream);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+pub struct TlsStream<S> {
+    conn: Connection,
+    stream: S,
+    // The server's own certificate, as rustls doesn't report which one it sent.
+    certificate: Option<Vec<u8>>,
+    buffered: usize,
+    // Early data accepted by the server, which reads return before anything else.
+    early_data: Option<Vec<u8>>,
+    // The verifier of the server's certificate, which clients use to find the verified path.
+    verifier: Option<Arc<Verifier>>,
+}
+
+impl<S: fmt::Debug> fmt::Debug for TlsStream<S> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TlsStream")
+            .field("connection", &self.conn)
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl<S> TlsStream<S> {
+    fn new(conn: Connection, stream: S, certificate: Option<Vec<u8>>) -> TlsStream<S> {
+        TlsStream {
+            conn,
+            stream,
+            certificate,
+            buffered: 0,
+            early_data: None,
+            verifier: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    fn server_name(&self) -> Option<String> {
+        match self.conn {
+            Connection::Server(ref conn) => conn.server_name().map(str::to_owned),
+            Connection::Client(_) => None,
+        }
+    }
+
+    fn read_early_data(&mut self) -> io::Result<()> {
+        if let Connection::Server(ref mut conn) = self.conn {
+            if let Some(mut reader) = conn.early_data() {
+                io::Read::read_to_end(&mut reader, self.early_data.get_or_insert_with(Vec::new))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_buffered(&mut self) {
+        self.buffered = match self.conn.process_new_packets() {
+            Ok(state) => state.plaintext_bytes_to_read(),
+            Err(_) => 0,
+        };
+    }
+}
+
+impl<S: io::Read + io::Write> TlsStream<S> {
+    fn handshake(mut self) -> Result<TlsStream<S>, HandshakeError<S>> {
+        while self.conn.is_handshaking() {
+            match self.conn.complete_io(&mut self.stream) {
+                Ok(_) => self.read_early_data()?,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let handshake = Handshake::Connecting(self);
+                    return Err(HandshakeError::WouldBlock(MidHandshakeTlsStream(Box::new(
+                        handshake,
+                    ))));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.update_buffered();
+        Ok(self)
+    }
+
+    fn complete_prior_io(&mut self) -> io::Result<()> {
+        if self.conn.is_handshaking() || self.conn.wants_write() {
+            self.conn.complete_io(&mut self.stream)?;
+        }
+        Ok(())
+    }
+
+    pub fn buffered_read_size(&self) -> Result<usize, Error> {
+        Ok(self.buffered + self.early_data.as_ref().map_or(0, Vec::len))
+    }
+
+    pub fn peer_certificate(&self) -> Result<Option<Certificate>, Error> {
+        let certs = self.conn.peer_certificates().unwrap_or(&[]);
+        Ok(certs.first().map(|cert| Certificate(cert.to_vec())))
+    }
+
+    pub fn session_resumed(&self) -> Result<bool, Error> {
+        Ok(self.conn.handshake_kind() == Some(HandshakeKind::Resumed))
+    }
+
+    pub fn early_data_accepted(&self) -> Result<bool, Error> {
+        match self.conn {
+            Connection::Client(ref conn) => Ok(conn.is_early_data_accepted()),
+            Connection::Server(_) => Ok(self.early_data.is_some()),
+        }
+    }
+
+    pub fn protocol_version(&self) -> Result<Option<Protocol>, Error> {
+        match self.conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => Ok(Some(Protocol::Tlsv12)),
+            Some(ProtocolVersion::TLSv1_3) => Ok(Some(Protocol::Tlsv13)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn cipher_suite(&self) -> Result<u16, Error> {
+        match self.conn.negotiated_cipher_suite() {
+            Some(suite) => Ok(u16::from(suite.suite())),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "no cipher suite has been negotiated",
+            )
+            .into()),
+        }
+    }
+
+    #[cfg(feature = "alpn")]
+    pub fn negotiated_alpn(&self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.conn.alpn_protocol().map(|alpn| alpn.to_vec()))
+    }
+
+    pub fn peer_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        let certs = self.conn.peer_certificates().unwrap_or(&[]);
+        Ok(certs
+            .iter()
+            .map(|cert| Certificate(cert.to_vec()))
+            .collect())
+    }
+
+    pub fn verified_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        let certs = self.conn.peer_certificates().unwrap_or(&[]);
+        match self.verifier {
+            Some(ref verifier) => Ok(verifier.verified_path(certs)),
+            None => Ok(certs.first().map(|cert| Certificate(cert.to_vec())).into_iter().collect()),
+        }
+    }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        Ok(self.server_name())
+    }
+
+    pub fn client_hello_ja3(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    pub fn client_hello_ja4(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    pub fn tls_server_end_point(&self) -> Result<Option<Vec<u8>>, Error> {
+        let cert = match self.conn {
+            Connection::Server(_) => self.certificate.clone(),
+            Connection::Client(_) => self.peer_certificate()?.map(|cert| cert.0),
+        };
+        let cert = match cert {
+            Some(cert) => cert,
+            None => return Ok(None),
+        };
+
+        let algorithm = match end_point_digest(der::signature_algorithm(&cert)?) {
+            Some(algorithm) => algorithm,
+            None => return Ok(None),
+        };
+        Ok(Some(digest::digest(algorithm, &cert).as_ref().to_vec()))
+    }
+
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        Ok(self
+            .conn
+            .export_keying_material(vec![0; len], label.as_bytes(), context)?)
+    }
+
+    // rustls doesn't expose the Finished messages
+    pub fn tls_unique(&self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.stream)?;
+        }
+        self.stream.flush()
+    }
+}
+
+// RFC 5929 hashes certificates signed with MD5 or SHA-1 with SHA-256, and leaves the binding
+// undefined for signature algorithms without a single digest.
+fn end_point_digest(oid: &[u8]) -> Option<&'static digest::Algorithm> {
+    let algorithm = match oid {
+        // 1.2.840.113549.1.1.4 md5WithRSAEncryption
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x04]
+        // 1.2.840.113549.1.1.5 sha1WithRSAEncryption
+        | [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05]
+        // 1.2.840.113549.1.1.11 sha256WithRSAEncryption
+        | [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b]
+        // 1.2.840.10045.4.1 ecdsa-with-SHA1
+        | [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x01]
+        // 1.2.840.10045.4.3.2 ecdsa-with-SHA256
+        | [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02] => &digest::SHA256,
+        // 1.2.840.113549.1.1.12 sha384WithRSAEncryption
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c]
+        // 1.2.840.10045.4.3.3 ecdsa-with-SHA384
+        | [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03] => &digest::SHA384,
+        // 1.2.840.113549.1.1.13 sha512WithRSAEncryption
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d]
+        // 1.2.840.10045.4.3.4 ecdsa-with-SHA512
+        | [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04] => &digest::SHA512,
+        _ => return None,
+    };
+    Some(algorithm)
+}
+
+impl<S: io::Read + io::Write> io::Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(ref mut early_data) = self.early_data {
+            if !early_data.is_empty() {
+                let len = cmp::min(buf.len(), early_data.len());
+                buf[..len].copy_from_slice(&early_data[..len]);
+                early_data.drain(..len);
+                return Ok(len);
+            }
+        }
+        self.complete_prior_io()?;
+        while self.conn.wants_read() {
+            if self.conn.complete_io(&mut self.stream)?.0 == 0 {
+                break;
+            }
+        }
+        let result = match self.conn.reader().read(buf) {
+            // The other backends treat a connection closed without close_notify as the end of
+            // the stream.
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        };
+        self.update_buffered();
+        result
+    }
+}
+
+impl<S: io::Read + io::Write> io::Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.complete_prior_io()?;
+        let len = self.conn.writer().write(buf)?;
+        // The plaintext has been consumed either way, so errors writing it out are left for the
+        // next call to report.
+        let _ = self.conn.complete_io(&mut self.stream);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.complete_prior_io()?;
+        self.stream.flush()
+    }
+}
@@ -1,15 +1,26 @@
 extern crate schannel;
 
-use self::schannel::cert_context::{CertContext, HashAlgorithm};
+use self::schannel::cert_context::{CertContext, HashAlgorithm, KeySpec};
 use self::schannel::cert_store::{CertAdd, CertStore, Memory, PfxImportOptions};
+use self::schannel::crypt_prov::{AcquireOptions, ProviderType};
 use self::schannel::schannel_cred::{Direction, Protocol, SchannelCred};
-use self::schannel::tls_stream;
+use self::schannel::tls_stream::{self, CertValidationResult};
+use self::schannel::RawPointer;
+use std::cmp;
 use std::error;
 use std::fmt;
 use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::raw::c_void;
+use std::ptr;
 use std::str;
 
-use {TlsAcceptorBuilder, TlsConnectorBuilder};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use {chain, der, jks, pem, policy, TlsAcceptorBuilder, TlsConnectorBuilder};
 
 const SEC_E_NO_CREDENTIALS: u32 = 0x8009030E;
 
@@ -18,6 +29,7 @@ static PROTOCOLS: &'static [Protocol] = &[
     Protocol::Tls10,
     Protocol::Tls11,
     Protocol::Tls12,
+    Protocol::Tls13,
 ];
 
 fn convert_protocols(min: Option<::Protocol>, max: Option<::Protocol>) -> &'static [Protocol] {
@@ -31,8 +43,92 @@ fn convert_protocols(min: Option<::Protocol>, max: Option<::Protocol>) -> &'stat
     protocols
 }
 
+fn gen_container_name() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    format!(
+        "native-tls-{}-{}",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn check_send_fragment(max: Option<usize>) -> Result<Option<usize>, Error> {
+    match max {
+        Some(max) if !(512..=16384).contains(&max) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("maximum send fragment of {} bytes is not between 512 and 16384 bytes", max),
+        )
+        .into()),
+        max => Ok(max),
+    }
+}
+
 pub struct Error(io::Error);
 
+impl Error {
+    pub fn as_inner(&self) -> &io::Error {
+        &self.0
+    }
+
+    pub fn kind(&self) -> ::ErrorKind {
+        match self.0.raw_os_error() {
+            Some(CERT_E_CN_NO_MATCH) | Some(SEC_E_WRONG_PRINCIPAL) => {
+                return ::ErrorKind::HostnameMismatch
+            }
+            Some(CERT_E_EXPIRED) | Some(SEC_E_CERT_EXPIRED) => return ::ErrorKind::CertExpired,
+            Some(CERT_E_UNTRUSTEDROOT) | Some(CERT_E_CHAINING) | Some(SEC_E_UNTRUSTED_ROOT) => {
+                return ::ErrorKind::UntrustedRoot
+            }
+            Some(SEC_E_UNSUPPORTED_FUNCTION) => return ::ErrorKind::ProtocolVersion,
+            // Other SSPI and certificate errors are HRESULTs, while socket errors are positive.
+            Some(code) if code < 0 => return ::ErrorKind::Other,
+            Some(_) => return ::ErrorKind::Io,
+            None => {}
+        }
+        match self.0.get_ref().and_then(|e| e.downcast_ref()) {
+            Some(&policy::Error::Hostname) | Some(&policy::Error::IpAddress) => {
+                ::ErrorKind::HostnameMismatch
+            }
+            // Errors raised by this crate rather than by the stream
+            _ if self.0.kind() == io::ErrorKind::InvalidData
+                || self.0.kind() == io::ErrorKind::Other =>
+            {
+                ::ErrorKind::Other
+            }
+            _ => ::ErrorKind::Io,
+        }
+    }
+
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self.kind() {
+            ::ErrorKind::Io => Some(&self.0),
+            _ => None,
+        }
+    }
+
+    pub fn into_io_error(self) -> Result<io::Error, Error> {
+        match self.kind() {
+            ::ErrorKind::Io => Ok(self.0),
+            _ => Err(self),
+        }
+    }
+
+    pub fn is_negotiation_failure(&self) -> bool {
+        match self.0.kind() {
+            // The server dropped the connection
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof => return true,
+            _ => {}
+        }
+        match self.0.raw_os_error() {
+            Some(SEC_E_ALGORITHM_MISMATCH) | Some(SEC_E_UNSUPPORTED_FUNCTION) => true,
+            _ => false,
+        }
+    }
+}
+
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         error::Error::source(&self.0)
@@ -57,6 +153,143 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<jks::Error> for Error {
+    fn from(error: jks::Error) -> Error {
+        Error(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+impl From<der::Error> for Error {
+    fn from(error: der::Error) -> Error {
+        Error(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+impl From<pem::Error> for Error {
+    fn from(error: pem::Error) -> Error {
+        Error(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+impl From<policy::Error> for Error {
+    fn from(error: policy::Error) -> Error {
+        Error(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+pub fn backend_version() -> String {
+    let mut info = OsVersionInfo {
+        size: mem::size_of::<OsVersionInfo>() as u32,
+        major_version: 0,
+        minor_version: 0,
+        build_number: 0,
+        platform_id: 0,
+        csd_version: [0; 128],
+    };
+    // RtlGetVersion, unlike GetVersionEx, isn't subject to manifest-based version lies.
+    if unsafe { RtlGetVersion(&mut info) } != 0 {
+        return "SChannel".to_owned();
+    }
+    format!(
+        "SChannel (Windows {}.{}.{})",
+        info.major_version, info.minor_version, info.build_number
+    )
+}
+
+// OSVERSIONINFOW
+#[repr(C)]
+struct OsVersionInfo {
+    size: u32,
+    major_version: u32,
+    minor_version: u32,
+    build_number: u32,
+    platform_id: u32,
+    csd_version: [u16; 128],
+}
+
+#[link(name = "ntdll")]
+extern "system" {
+    fn RtlGetVersion(info: *mut OsVersionInfo) -> i32;
+}
+
+const X509_ASN_ENCODING: u32 = 1;
+const CRYPT_VERIFY_CERT_SIGN_SUBJECT_BLOB: u32 = 1;
+const CRYPT_VERIFY_CERT_SIGN_ISSUER_CERT: u32 = 2;
+const NTE_BAD_SIGNATURE: i32 = 0x8009_0006_u32 as i32;
+const SEC_E_ALGORITHM_MISMATCH: i32 = 0x8009_0331_u32 as i32;
+const SEC_E_UNSUPPORTED_FUNCTION: i32 = 0x8009_0302_u32 as i32;
+const SEC_E_WRONG_PRINCIPAL: i32 = 0x8009_0322_u32 as i32;
+const SEC_E_UNTRUSTED_ROOT: i32 = 0x8009_0325_u32 as i32;
+const SEC_E_CERT_EXPIRED: i32 = 0x8009_0328_u32 as i32;
+const CERT_E_EXPIRED: i32 = 0x800B_0101_u32 as i32;
+const CERT_E_UNTRUSTEDROOT: i32 = 0x800B_0109_u32 as i32;
+const CERT_E_CHAINING: i32 = 0x800B_010A_u32 as i32;
+const CERT_E_CN_NO_MATCH: i32 = 0x800B_010F_u32 as i32;
+const CRYPT_ACQUIRE_CACHE_FLAG: u32 = 0x0000_0001;
+const CRYPT_ACQUIRE_SILENT_FLAG: u32 = 0x0000_0040;
+const CRYPT_ACQUIRE_ALLOW_NCRYPT_KEY_FLAG: u32 = 0x0001_0000;
+const CERT_NCRYPT_KEY_SPEC: u32 = 0xffff_ffff;
+const AT_SIGNATURE: u32 = 2;
+const PP_KEYEXCHANGE_PIN: u32 = 32;
+const PP_SIGNATURE_PIN: u32 = 33;
+
+#[repr(C)]
+struct CryptDataBlob {
+    len: u32,
+    data: *const u8,
+}
+
+#[link(name = "crypt32")]
+extern "system" {
+    fn CertCreateCertificateContext(encoding: u32, data: *const u8, len: u32) -> *const c_void;
+    fn CertFreeCertificateContext(cert: *const c_void) -> i32;
+    fn CryptVerifyCertificateSignatureEx(
+        prov: usize,
+        encoding: u32,
+        subject_type: u32,
+        subject: *mut c_void,
+        issuer_type: u32,
+        issuer: *mut c_void,
+        flags: u32,
+        extra: *mut c_void,
+    ) -> i32;
+    fn CryptHashCertificate2(
+        algorithm: *const u16,
+        flags: u32,
+        reserved: *mut c_void,
+        data: *const u8,
+        len: u32,
+        hash: *mut u8,
+        hash_len: *mut u32,
+    ) -> i32;
+    fn CryptAcquireCertificatePrivateKey(
+        cert: *const c_void,
+        flags: u32,
+        parameters: *mut c_void,
+        key: *mut usize,
+        key_spec: *mut u32,
+        caller_free: *mut i32,
+    ) -> i32;
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn CryptSetProvParam(prov: usize, param: u32, data: *const u8, flags: u32) -> i32;
+    fn CryptReleaseContext(prov: usize, flags: u32) -> i32;
+}
+
+#[link(name = "ncrypt")]
+extern "system" {
+    fn NCryptSetProperty(
+        object: usize,
+        property: *const u16,
+        input: *const u8,
+        len: u32,
+        flags: u32,
+    ) -> i32;
+    fn NCryptFreeObject(object: usize) -> i32;
+}
+
 #[derive(Clone)]
 pub struct Identity {
     cert: CertContext,
@@ -64,7 +297,13 @@ pub struct Identity {
 
 impl Identity {
     pub fn from_pkcs12(buf: &[u8], pass: &str) -> Result<Identity, Error> {
-        let store = PfxImportOptions::new().password(pass).import(buf)?;
+        let store = match PfxImportOptions::new().password(pass).import(buf) {
+            Ok(store) => store,
+            // Tools disagree on whether an empty password is encoded as an empty string or
+            // omitted entirely, and CryptoAPI only tries the one it's given.
+            Err(_) if pass.is_empty() => PfxImportOptions::new().import(buf)?,
+            Err(e) => return Err(e.into()),
+        };
         let mut identity = None;
 
         for cert in store.certs() {
@@ -92,6 +331,131 @@ impl Identity {
 
         Ok(Identity { cert: identity })
     }
+
+    // CryptoAPI has no way to decrypt a bare PKCS #8 key
+    pub fn from_encrypted_pkcs8_der(_: &[&[u8]], _: &[u8], _: &str) -> Result<Identity, Error> {
+        Err(pem::Error::Unsupported.into())
+    }
+
+    pub fn from_pkcs8_der(chain: &[&[u8]], key: &[u8]) -> Result<Identity, Error> {
+        let mut chain = chain.iter();
+        let cert = match chain.next() {
+            Some(cert) => CertContext::new(cert)?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "at least one certificate must be provided to create an identity",
+                ).into());
+            }
+        };
+
+        let name = gen_container_name();
+        let type_ = ProviderType::rsa_full();
+        let mut container = AcquireOptions::new()
+            .container(&name)
+            .new_keyset(true)
+            .acquire(type_)?;
+        container.import().import_pkcs8(key)?;
+
+        cert.set_key_prov_info()
+            .container(&name)
+            .type_(type_)
+            .keep_open(true)
+            .key_spec(KeySpec::key_exchange())
+            .set()?;
+
+        // The intermediates need to live in the same store as the leaf for SChannel to send them.
+        let mut store = Memory::new()?.into_store();
+        let cert = store.add_cert(&cert, CertAdd::Always)?;
+        for cert in chain {
+            store.add_cert(&CertContext::new(cert)?, CertAdd::Always)?;
+        }
+
+        Ok(Identity { cert })
+    }
+
+    pub fn from_parts(cert: CertContext) -> Identity {
+        Identity { cert }
+    }
+
+    // Acquiring the key with CRYPT_ACQUIRE_CACHE_FLAG stores the handle on the certificate
+    // context, where SChannel picks it up again during the handshake. Setting the PIN on that
+    // handle unlocks the card without the provider prompting the user.
+    pub fn from_smart_card(cert: CertContext, pin: &str) -> Result<Identity, Error> {
+        unsafe {
+            let mut key = 0;
+            let mut spec = 0;
+            let mut free = 0;
+            let ok = CryptAcquireCertificatePrivateKey(
+                cert.as_ptr() as *const c_void,
+                CRYPT_ACQUIRE_CACHE_FLAG
+                    | CRYPT_ACQUIRE_SILENT_FLAG
+                    | CRYPT_ACQUIRE_ALLOW_NCRYPT_KEY_FLAG,
+                ptr::null_mut(),
+                &mut key,
+                &mut spec,
+                &mut free,
+            );
+            if ok == 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let result = if spec == CERT_NCRYPT_KEY_SPEC {
+                let pin = pin.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+                let property = "SmartCardPin".encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+                match NCryptSetProperty(
+                    key,
+                    property.as_ptr(),
+                    pin.as_ptr() as *const u8,
+                    (pin.len() * mem::size_of::<u16>()) as u32,
+                    0,
+                ) {
+                    0 => Ok(()),
+                    status => Err(io::Error::from_raw_os_error(status)),
+                }
+            } else {
+                let param = if spec == AT_SIGNATURE {
+                    PP_SIGNATURE_PIN
+                } else {
+                    PP_KEYEXCHANGE_PIN
+                };
+                let pin = pin.bytes().chain(Some(0)).collect::<Vec<_>>();
+                if CryptSetProvParam(key, param, pin.as_ptr(), 0) == 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            };
+
+            // The cache flag should leave the handle owned by the certificate context.
+            if free != 0 {
+                if spec == CERT_NCRYPT_KEY_SPEC {
+                    NCryptFreeObject(key);
+                } else {
+                    CryptReleaseContext(key, 0);
+                }
+            }
+
+            result?;
+        }
+
+        Ok(Identity { cert })
+    }
+
+    pub fn certificate(&self) -> Result<Certificate, Error> {
+        Ok(Certificate(self.cert.clone()))
+    }
+
+    // The chain is whatever else is in the leaf's store, which SChannel sends along with it.
+    pub fn chain(&self) -> Result<Vec<Certificate>, Error> {
+        let certs = match self.cert.cert_store() {
+            Some(store) => store.certs().collect::<Vec<_>>(),
+            None => vec![],
+        };
+        let ders = certs.iter().map(|c| c.to_der().to_vec()).collect::<Vec<_>>();
+        let chain = chain::sort(self.cert.to_der(), certs, &ders);
+        Ok(chain.into_iter().map(Certificate).collect())
+    }
 }
 
 #[derive(Clone)]
@@ -119,9 +483,117 @@ impl Certificate {
     pub fn to_der(&self) -> Result<Vec<u8>, Error> {
         Ok(self.0.to_der().to_vec())
     }
+
+    pub fn fingerprint(&self, digest: ::Digest) -> Result<Vec<u8>, Error> {
+        let algorithm = match digest {
+            ::Digest::Sha1 => HashAlgorithm::sha1(),
+            ::Digest::Sha256 => HashAlgorithm::sha256(),
+            ::Digest::Sha384 => HashAlgorithm::sha384(),
+            ::Digest::Sha512 => HashAlgorithm::sha512(),
+            ::Digest::__NonExhaustive => unreachable!(),
+        };
+        let fingerprint = self.0.fingerprint(algorithm)?;
+        Ok(fingerprint)
+    }
+
+    pub fn public_key_sha256(&self) -> Result<Vec<u8>, Error> {
+        let der = self.0.to_der();
+        let spki = der::subject_public_key_info(der)?;
+        let algorithm = "SHA256".encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let mut digest = [0; 32];
+        let mut len = digest.len() as u32;
+        unsafe {
+            if CryptHashCertificate2(
+                algorithm.as_ptr(),
+                0,
+                ptr::null_mut(),
+                spki.as_ptr(),
+                spki.len() as u32,
+                digest.as_mut_ptr(),
+                &mut len,
+            ) == 0
+            {
+                return Err(Error(io::Error::last_os_error()));
+            }
+        }
+        Ok(digest[..len as usize].to_vec())
+    }
+
+    pub fn verify_signed_by(&self, issuer: &Certificate) -> Result<bool, Error> {
+        let subject = self.0.to_der();
+        let mut subject = CryptDataBlob {
+            len: subject.len() as u32,
+            data: subject.as_ptr(),
+        };
+        let issuer = issuer.0.to_der();
+
+        unsafe {
+            let issuer =
+                CertCreateCertificateContext(X509_ASN_ENCODING, issuer.as_ptr(), issuer.len() as u32);
+            if issuer.is_null() {
+                return Err(io::Error::last_os_error().into());
+            }
+            let ret = CryptVerifyCertificateSignatureEx(
+                0,
+                X509_ASN_ENCODING,
+                CRYPT_VERIFY_CERT_SIGN_SUBJECT_BLOB,
+                &mut subject as *mut _ as *mut c_void,
+                CRYPT_VERIFY_CERT_SIGN_ISSUER_CERT,
+                issuer as *mut c_void,
+                0,
+                ptr::null_mut(),
+            );
+            let err = io::Error::last_os_error();
+            CertFreeCertificateContext(issuer);
+
+            match ret {
+                0 if err.raw_os_error() == Some(NTE_BAD_SIGNATURE) => Ok(false),
+                0 => Err(err.into()),
+                _ => Ok(true),
+            }
+        }
+    }
+
+    pub fn as_inner(&self) -> &CertContext {
+        &self.0
+    }
 }
 
-pub struct MidHandshakeTlsStream<S>(tls_stream::MidHandshakeTlsStream<S>);
+impl From<CertContext> for Certificate {
+    fn from(cert: CertContext) -> Certificate {
+        Certificate(cert)
+    }
+}
+
+// CRLs aren't loaded by this backend, so they're only checked to be well-formed.
+#[derive(Clone)]
+pub struct Crl(Vec<u8>);
+
+impl Crl {
+    pub fn from_der(buf: &[u8]) -> Result<Crl, Error> {
+        der::Reader::new(buf).read(der::SEQUENCE)?;
+        Ok(Crl(buf.to_vec()))
+    }
+
+    pub fn from_pem(buf: &[u8]) -> Result<Crl, Error> {
+        let der = pem::crl(buf)?;
+        Crl::from_der(&der)
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.clone())
+    }
+}
+
+// The chain SChannel validated for the server, which is recorded by the verification callback as
+// it isn't exposed once the handshake completes.
+type VerifiedChain = Arc<Mutex<Vec<CertContext>>>;
+
+pub struct MidHandshakeTlsStream<S>(
+    tls_stream::MidHandshakeTlsStream<S>,
+    Option<usize>,
+    VerifiedChain,
+);
 
 impl<S> fmt::Debug for MidHandshakeTlsStream<S>
 where
@@ -140,6 +612,10 @@ impl<S> MidHandshakeTlsStream<S> {
     pub fn get_mut(&mut self) -> &mut S {
         self.0.get_mut()
     }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
 }
 
 impl<S> MidHandshakeTlsStream<S>
@@ -148,8 +624,8 @@ where
 {
     pub fn handshake(self) -> Result<TlsStream<S>, HandshakeError<S>> {
         match self.0.handshake() {
-            Ok(s) => Ok(TlsStream(s)),
-            Err(e) => Err(e.into()),
+            Ok(s) => Ok(TlsStream(s, self.1, self.2)),
+            Err(e) => Err(HandshakeError::new(e, self.1, self.2)),
         }
     }
 }
@@ -159,12 +635,16 @@ pub enum HandshakeError<S> {
     WouldBlock(MidHandshakeTlsStream<S>),
 }
 
-impl<S> From<tls_stream::HandshakeError<S>> for HandshakeError<S> {
-    fn from(e: tls_stream::HandshakeError<S>) -> HandshakeError<S> {
+impl<S> HandshakeError<S> {
+    fn new(
+        e: tls_stream::HandshakeError<S>,
+        max_send_fragment: Option<usize>,
+        verified: VerifiedChain,
+    ) -> HandshakeError<S> {
         match e {
             tls_stream::HandshakeError::Failure(e) => HandshakeError::Failure(e.into()),
             tls_stream::HandshakeError::Interrupted(s) => {
-                HandshakeError::WouldBlock(MidHandshakeTlsStream(s))
+                HandshakeError::WouldBlock(MidHandshakeTlsStream(s, max_send_fragment, verified))
             }
         }
     }
@@ -176,6 +656,30 @@ impl<S> From<io::Error> for HandshakeError<S> {
     }
 }
 
+fn verify_user_roots(res: &CertValidationResult, roots: Option<&CertStore>) -> io::Result<()> {
+    // Propagate previous error encountered during normal cert validation.
+    res.result()?;
+
+    let roots = match roots {
+        Some(roots) => roots,
+        None => return Ok(()),
+    };
+
+    if let Some(chain) = res.chain() {
+        if chain
+            .certificates()
+            .any(|cert| roots.certs().any(|root_cert| root_cert == cert))
+        {
+            return Ok(());
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "unable to find any user-specified roots in the final cert chain",
+    ))
+}
+
 #[derive(Clone, Debug)]
 pub struct TlsConnector {
     cert: Option<CertContext>,
@@ -186,17 +690,57 @@ pub struct TlsConnector {
     accept_invalid_hostnames: bool,
     accept_invalid_certs: bool,
     disable_built_in_roots: bool,
+    max_send_fragment: Option<usize>,
+    certificate_exceptions: Option<::CertificateExceptions>,
+    use_system_protocols: bool,
+    // Shared by every connection when session resumption is enabled, as SChannel only resumes
+    // sessions established with the same credentials handle.
+    cred: Option<SharedCred>,
+    #[cfg(feature = "alpn")]
+    alpn: Vec<Vec<u8>>,
+}
+
+#[derive(Clone)]
+struct SharedCred(SchannelCred);
+
+impl fmt::Debug for SharedCred {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SchannelCred").finish()
+    }
 }
 
 impl TlsConnector {
+    pub fn new_dtls(_: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "DTLS is not supported by SChannel").into())
+    }
+
     pub fn new(builder: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
-        let cert = builder.identity.as_ref().map(|i| i.0.cert.clone());
+        if !builder.cipher_suites.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "configuring cipher suites is not supported by SChannel",
+            ).into());
+        }
+        if builder.require_ocsp_staple {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "requiring OCSP stapling is not supported by SChannel",
+            ).into());
+        }
+        if builder.check_revocation || !builder.crls.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "revocation checking against CRLs is not supported by SChannel",
+            ).into());
+        }
+
+        let cert = builder.identity.as_ref().map(|i| i.0.native().cert.clone());
         let mut roots = Memory::new()?.into_store();
         for cert in &builder.root_certificates {
-            roots.add_cert(&(cert.0).0, CertAdd::ReplaceExisting)?;
+            roots.add_cert(&cert.0.native().0, CertAdd::ReplaceExisting)?;
         }
 
-        Ok(TlsConnector {
+        let mut connector = TlsConnector {
             cert,
             roots,
             min_protocol: builder.min_protocol,
@@ -205,70 +749,191 @@ impl TlsConnector {
             accept_invalid_hostnames: builder.accept_invalid_hostnames,
             accept_invalid_certs: builder.accept_invalid_certs,
             disable_built_in_roots: builder.disable_built_in_roots,
-        })
+            max_send_fragment: check_send_fragment(builder.max_send_fragment)?,
+            certificate_exceptions: builder.certificate_exceptions.clone(),
+            use_system_protocols: builder.use_system_protocols,
+            cred: None,
+            #[cfg(feature = "alpn")]
+            alpn: builder.alpn.iter().map(|s| s.as_bytes().to_vec()).collect(),
+        };
+        if builder.session_cache {
+            connector.cred = Some(SharedCred(connector.acquire_cred()?));
+        }
+        Ok(connector)
     }
 
-    pub fn connect<S>(&self, domain: &str, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
-    where
-        S: io::Read + io::Write,
-    {
+    fn acquire_cred(&self) -> io::Result<SchannelCred> {
         let mut builder = SchannelCred::builder();
-        builder.enabled_protocols(convert_protocols(self.min_protocol, self.max_protocol));
+        if !self.use_system_protocols {
+            builder.enabled_protocols(convert_protocols(self.min_protocol, self.max_protocol));
+        }
         if let Some(cert) = self.cert.as_ref() {
             builder.cert(cert.clone());
         }
-        let cred = builder.acquire(Direction::Outbound)?;
+        builder.acquire(Direction::Outbound)
+    }
+
+    pub fn connect<S>(&self, domain: &str, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect_inner(Some(domain), self.use_sni, stream)
+    }
+
+    pub fn connect_without_sni<S>(
+        &self,
+        domain: Option<&str>,
+        stream: S,
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect_inner(domain, false, stream)
+    }
+
+    // Early data isn't supported, so the caller sends it after the handshake instead.
+    pub fn connect_with_early_data<S>(
+        &self,
+        domain: &str,
+        stream: S,
+        _: &[u8],
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect(domain, stream)
+    }
+
+    fn connect_inner<S>(
+        &self,
+        domain: Option<&str>,
+        use_sni: bool,
+        stream: S,
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        // IP addresses can't be sent through SNI, and are matched against the certificate by the
+        // caller instead.
+        let ip = domain.map_or(false, |domain| domain.parse::<IpAddr>().is_ok());
+        let cred = match self.cred {
+            Some(ref cred) => cred.0.clone(),
+            None => self.acquire_cred()?,
+        };
         let mut builder = tls_stream::Builder::new();
         builder
             .cert_store(self.roots.clone())
-            .domain(domain)
-            .use_sni(self.use_sni)
-            .accept_invalid_hostnames(self.accept_invalid_hostnames);
+            .use_sni(use_sni && !ip)
+            .accept_invalid_hostnames(ip || self.accept_invalid_hostnames);
+        if let Some(domain) = domain {
+            builder.domain(domain);
+        }
+        let domain = domain.unwrap_or("");
+        #[cfg(feature = "alpn")]
+        {
+            if !self.alpn.is_empty() {
+                let alpn = self.alpn.iter().map(|s| &s[..]).collect::<Vec<_>>();
+                builder.request_application_protocols(&alpn);
+            }
+        }
+        let verified = VerifiedChain::default();
         if self.accept_invalid_certs {
             builder.verify_callback(|_| Ok(()));
-        } else if self.disable_built_in_roots {
-            let roots_copy = self.roots.clone();
+        } else {
+            let roots_copy = if self.disable_built_in_roots {
+                Some(self.roots.clone())
+            } else {
+                None
+            };
+            let exceptions = self.certificate_exceptions.clone();
+            let domain = domain.to_owned();
+            let verified = verified.clone();
             builder.verify_callback(move |res| {
-                if let Err(err) = res.result() {
-                    // Propagate previous error encountered during normal cert validation.
-                    return Err(err);
-                }
-
-                if let Some(chain) = res.chain() {
-                    if chain
-                        .certificates()
-                        .any(|cert| roots_copy.certs().any(|root_cert| root_cert == cert))
-                    {
-                        return Ok(());
+                let result = verify_user_roots(&res, roots_copy.as_ref());
+                match (result, &exceptions) {
+                    (Ok(()), _) => {
+                        if let Some(chain) = res.chain() {
+                            *verified.lock().unwrap() = chain.certificates().collect();
+                        }
+                        Ok(())
+                    }
+                    (Err(err), &Some(ref exceptions)) => {
+                        let cert = res
+                            .chain()
+                            .and_then(|chain| chain.get(0))
+                            .map(|cert| ::Certificate(Certificate(cert).into()));
+                        match cert {
+                            Some(ref cert) if exceptions.verify(&domain, cert) => Ok(()),
+                            _ => Err(err),
+                        }
                     }
+                    (result, _) => result,
                 }
-
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "unable to find any user-specified roots in the final cert chain",
-                ))
             });
         }
         match builder.connect(cred, stream) {
-            Ok(s) => Ok(TlsStream(s)),
-            Err(e) => Err(e.into()),
+            Ok(s) => Ok(TlsStream(s, self.max_send_fragment, verified)),
+            Err(e) => Err(HandshakeError::new(e, self.max_send_fragment, verified)),
         }
     }
 }
 
 #[derive(Clone)]
 pub struct TlsAcceptor {
-    cert: CertContext,
+    certs: Vec<CertContext>,
     min_protocol: Option<::Protocol>,
     max_protocol: Option<::Protocol>,
+    max_send_fragment: Option<usize>,
+    use_system_protocols: bool,
+    #[cfg(feature = "alpn")]
+    alpn: Vec<Vec<u8>>,
 }
 
 impl TlsAcceptor {
+    pub fn new_dtls(_: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "DTLS is not supported by SChannel").into())
+    }
+
     pub fn new(builder: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
+        if !builder.cipher_suites.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "configuring cipher suites is not supported by SChannel",
+            ).into());
+        }
+        if builder.client_auth != ::ClientAuth::Disabled || !builder.client_root_certificates.is_empty()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "client certificate authentication is not supported by SChannel",
+            ).into());
+        }
+        if builder.ocsp_response.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "stapling OCSP responses is not supported by SChannel",
+            ).into());
+        }
+        if !builder.sni_identities.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "SNI-based identity selection is not supported by SChannel",
+            ).into());
+        }
+
+        let identities = Some(&builder.identity).into_iter();
+        let certs = identities
+            .chain(&builder.additional_identities)
+            .map(|i| i.0.native().cert.clone())
+            .collect();
         Ok(TlsAcceptor {
-            cert: builder.identity.0.cert.clone(),
+            certs,
             min_protocol: builder.min_protocol,
             max_protocol: builder.max_protocol,
+            max_send_fragment: check_send_fragment(builder.max_send_fragment)?,
+            use_system_protocols: builder.use_system_protocols,
+            #[cfg(feature = "alpn")]
+            alpn: builder.alpn.iter().map(|s| s.as_bytes().to_vec()).collect(),
         })
     }
 
@@ -277,18 +942,33 @@ impl TlsAcceptor {
         S: io::Read + io::Write,
     {
         let mut builder = SchannelCred::builder();
-        builder.enabled_protocols(convert_protocols(self.min_protocol, self.max_protocol));
-        builder.cert(self.cert.clone());
+        if !self.use_system_protocols {
+            builder.enabled_protocols(convert_protocols(self.min_protocol, self.max_protocol));
+        }
+        // SChannel picks the certificate best suited to the client from those provided.
+        for cert in &self.certs {
+            builder.cert(cert.clone());
+        }
         // FIXME we're probably missing the certificate chain?
         let cred = builder.acquire(Direction::Inbound)?;
-        match tls_stream::Builder::new().accept(cred, stream) {
-            Ok(s) => Ok(TlsStream(s)),
-            Err(e) => Err(e.into()),
+        #[cfg_attr(not(feature = "alpn"), allow(unused_mut))]
+        let mut builder = tls_stream::Builder::new();
+        #[cfg(feature = "alpn")]
+        {
+            if !self.alpn.is_empty() {
+                let alpn = self.alpn.iter().map(|s| &s[..]).collect::<Vec<_>>();
+                builder.request_application_protocols(&alpn);
+            }
+        }
+        let verified = VerifiedChain::default();
+        match builder.accept(cred, stream) {
+            Ok(s) => Ok(TlsStream(s, self.max_send_fragment, verified)),
+            Err(e) => Err(HandshakeError::new(e, self.max_send_fragment, verified)),
         }
     }
 }
 
-pub struct TlsStream<S>(tls_stream::TlsStream<S>);
+pub struct TlsStream<S>(tls_stream::TlsStream<S>, Option<usize>, VerifiedChain);
 
 impl<S: fmt::Debug> fmt::Debug for TlsStream<S> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
@@ -319,6 +999,73 @@ impl<S: io::Read + io::Write> TlsStream<S> {
         }
     }
 
+    pub fn session_resumed(&self) -> Result<bool, Error> {
+        Ok(self.0.session_resumed()?)
+    }
+
+    pub fn early_data_accepted(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    pub fn protocol_version(&self) -> Result<Option<::Protocol>, Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the negotiated protocol version is not exposed by SChannel",
+        )
+        .into())
+    }
+
+    pub fn cipher_suite(&self) -> Result<u16, Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the negotiated cipher suite is not exposed by SChannel",
+        )
+        .into())
+    }
+
+    #[cfg(feature = "alpn")]
+    pub fn negotiated_alpn(&self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.0.negotiated_application_protocol()?)
+    }
+
+    pub fn peer_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        let cert = match self.0.peer_certificate() {
+            Ok(cert) => cert,
+            Err(ref e) if e.raw_os_error() == Some(SEC_E_NO_CREDENTIALS as i32) => return Ok(vec![]),
+            Err(e) => return Err(Error(e)),
+        };
+        // The remaining certificates sent by the peer are in the leaf's store, in no particular
+        // order.
+        let certs = match cert.cert_store() {
+            Some(store) => store.certs().collect::<Vec<_>>(),
+            None => vec![],
+        };
+        let ders = certs.iter().map(|c| c.to_der().to_vec()).collect::<Vec<_>>();
+        let mut chain = vec![Certificate(cert.clone())];
+        chain.extend(chain::sort(cert.to_der(), certs, &ders).into_iter().map(Certificate));
+        Ok(chain)
+    }
+
+    pub fn verified_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        let chain = self.2.lock().unwrap();
+        if chain.is_empty() {
+            return Ok(self.peer_certificate()?.into_iter().collect());
+        }
+        Ok(chain.iter().cloned().map(Certificate).collect())
+    }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    pub fn client_hello_ja3(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    pub fn client_hello_ja4(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
     pub fn tls_server_end_point(&self) -> Result<Option<Vec<u8>>, Error> {
         let cert = if self.0.is_server() {
             self.0.certificate()
@@ -344,6 +1091,23 @@ impl<S: io::Read + io::Write> TlsStream<S> {
         Ok(Some(digest))
     }
 
+    pub fn export_keying_material(
+        &self,
+        _: &str,
+        _: Option<&[u8]>,
+        _: usize,
+    ) -> Result<Vec<u8>, Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "exporting keying material is not supported by SChannel",
+        )
+        .into())
+    }
+
+    pub fn tls_unique(&self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
     pub fn shutdown(&mut self) -> io::Result<()> {
         self.0.shutdown()?;
         Ok(())
@@ -358,6 +1122,12 @@ impl<S: io::Read + io::Write> io::Read for TlsStream<S> {
 
 impl<S: io::Read + io::Write> io::Write for TlsStream<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // SChannel has no knob for the record size, but it encrypts each write into records of its
+        // own, so shortening writes caps the records.
+        let buf = match self.1 {
+            Some(max) => &buf[..cmp::min(buf.len(), max)],
+            None => buf,
+        };
         self.0.write(buf)
     }
 
@@ -1,3 +1,4 @@
+extern crate core_foundation;
 extern crate libc;
 extern crate security_framework;
 extern crate security_framework_sys;
@@ -5,16 +6,51 @@ extern crate tempfile;
 
 use self::security_framework::base;
 use self::security_framework::certificate::SecCertificate;
+use self::security_framework::cipher_suite::CipherSuite;
 use self::security_framework::identity::SecIdentity;
+use self::security_framework::key::Algorithm;
+use self::security_framework::policy::SecPolicy;
+#[cfg(not(target_os = "ios"))]
+use self::security_framework::random::SecRandom;
 use self::security_framework::import_export::{ImportedIdentity, Pkcs12ImportOptions};
 use self::security_framework::secure_transport::{
-    self, ClientBuilder, SslConnectionType, SslContext, SslProtocol, SslProtocolSide,
+    self, ClientBuilder, SslAuthenticate, SslConnectionType, SslContext, SslProtocol,
+    SslProtocolSide, SslStream,
+};
+use self::security_framework_sys::secure_transport::{
+    errSSLBadCert, errSSLCertExpired, errSSLCertNotYetValid, errSSLClosedAbort,
+    errSSLClosedNoNotify, errSSLHostNameMismatch, errSSLNoRootCert, errSSLPeerProtocolVersion,
+    errSSLUnknownRootCert, errSSLXCertChainInvalid,
+};
+use self::security_framework_sys::base::{
+    errSecAuthFailed, errSecHostNameMismatch, errSecIO, errSecItemNotFound, errSecNotTrusted,
+    errSecParam, errSecUnimplemented,
 };
-use self::security_framework_sys::base::errSecIO;
 use self::tempfile::TempDir;
+#[cfg(not(target_os = "ios"))]
+use self::core_foundation::array::CFArray;
+#[cfg(not(target_os = "ios"))]
+use self::core_foundation::base::TCFType;
+#[cfg(not(target_os = "ios"))]
+use self::core_foundation::boolean::CFBoolean;
+#[cfg(not(target_os = "ios"))]
+use self::core_foundation::data::CFData;
+#[cfg(not(target_os = "ios"))]
+use self::core_foundation::dictionary::CFDictionary;
+#[cfg(not(target_os = "ios"))]
+use self::core_foundation::string::{CFString, CFStringRef};
+#[cfg(not(target_os = "ios"))]
+use self::security_framework_sys::import_export::{
+    kSecImportExportPassphrase, kSecImportItemCertChain, kSecImportItemIdentity, SecPKCS12Import,
+};
+use std::cmp;
 use std::error;
 use std::fmt;
 use std::io;
+use std::net::IpAddr;
+use std::ptr;
+use std::result;
+use std::str;
 use std::sync::Mutex;
 use std::sync::Once;
 
@@ -27,30 +63,103 @@ use self::security_framework::os::macos::import_export::{ImportOptions, SecItems
 #[cfg(not(target_os = "ios"))]
 use self::security_framework::os::macos::keychain::{self, KeychainSettings, SecKeychain};
 #[cfg(not(target_os = "ios"))]
-use self::security_framework_sys::base::errSecParam;
+use self::security_framework::os::macos::identity::SecIdentityExt;
 
-use {Protocol, TlsAcceptorBuilder, TlsConnectorBuilder};
+use {
+    chain, der, jks, pem, policy, ClientAuth, Protocol, TlsAcceptorBuilder, TlsConnectorBuilder,
+};
 use self::security_framework::os::macos::import_export::Pkcs12ImportOptionsExt;
 
 static SET_AT_EXIT: Once = Once::new();
 
+// FIXME security-framework-sys doesn't expose errSecVerifyFailed yet
+const ERR_SEC_VERIFY_FAILED: i32 = -67808;
+
 #[cfg(not(target_os = "ios"))]
 lazy_static! {
     static ref TEMP_KEYCHAIN: Mutex<Option<(SecKeychain, TempDir)>> = Mutex::new(None);
 }
 
+fn check_send_fragment(max: Option<usize>) -> Result<Option<usize>, Error> {
+    match max {
+        Some(max) if !(512..=16384).contains(&max) => Err(Error(base::Error::from(errSecParam))),
+        max => Ok(max),
+    }
+}
+
+// Secure Transport rejects suites it doesn't support rather than skipping them.
+fn convert_cipher_suites(
+    side: SslProtocolSide,
+    suites: &[::CipherSuite],
+) -> Result<Vec<CipherSuite>, Error> {
+    if suites.is_empty() {
+        return Ok(vec![]);
+    }
+    let supported = SslContext::new(side, SslConnectionType::STREAM)?.supported_ciphers()?;
+    let suites = suites
+        .iter()
+        .map(|suite| CipherSuite::from_raw(suite.to_u16() as _))
+        .filter(|suite| supported.contains(suite))
+        .collect::<Vec<_>>();
+    if suites.is_empty() {
+        return Err(Error(base::Error::from(errSecParam)));
+    }
+    Ok(suites)
+}
+
 fn convert_protocol(protocol: Protocol) -> SslProtocol {
     match protocol {
         Protocol::Sslv3 => SslProtocol::SSL3,
         Protocol::Tlsv10 => SslProtocol::TLS1,
         Protocol::Tlsv11 => SslProtocol::TLS11,
         Protocol::Tlsv12 => SslProtocol::TLS12,
+        Protocol::Tlsv13 => SslProtocol::TLS13,
         Protocol::__NonExhaustive => unreachable!(),
     }
 }
 
 pub struct Error(base::Error);
 
+impl Error {
+    pub fn as_inner(&self) -> &base::Error {
+        &self.0
+    }
+
+    pub fn kind(&self) -> ::ErrorKind {
+        match self.0.code() {
+            errSSLHostNameMismatch | errSecHostNameMismatch => ::ErrorKind::HostnameMismatch,
+            // errSecCertificateExpired, errSecCertificateNotValidYet
+            errSSLCertExpired | errSSLCertNotYetValid | -67818 | -67819 => {
+                ::ErrorKind::CertExpired
+            }
+            errSSLUnknownRootCert
+            | errSSLNoRootCert
+            | errSSLXCertChainInvalid
+            | errSecNotTrusted => ::ErrorKind::UntrustedRoot,
+            errSSLPeerProtocolVersion => ::ErrorKind::ProtocolVersion,
+            errSecIO | errSSLClosedAbort | errSSLClosedNoNotify => ::ErrorKind::Io,
+            _ => ::ErrorKind::Other,
+        }
+    }
+
+    pub fn io_error(&self) -> Option<&io::Error> {
+        None
+    }
+
+    pub fn into_io_error(self) -> Result<io::Error, Error> {
+        Err(self)
+    }
+
+    pub fn is_negotiation_failure(&self) -> bool {
+        match self.0.code() {
+            // errSSLNegotiation, errSSLClosedAbort, errSSLPeerHandshakeFail, errSSLClosedNoNotify,
+            // errSSLPeerProtocolVersion, errSSLPeerInsufficientSecurity
+            -9812 | -9806 | -9824 | -9816 | -9836 | -9839 => true,
+            _ => false,
+        }
+    }
+}
+
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         error::Error::source(&self.0)
@@ -75,6 +184,88 @@ impl From<base::Error> for Error {
     }
 }
 
+// The error itself is dropped, since Secure Transport errors are only status codes.
+impl From<io::Error> for Error {
+    fn from(_: io::Error) -> Error {
+        Error(base::Error::from(errSecIO))
+    }
+}
+
+impl From<jks::Error> for Error {
+    fn from(error: jks::Error) -> Error {
+        let code = match error {
+            jks::Error::Malformed => errSecParam,
+            jks::Error::BadPassword => errSecAuthFailed,
+            jks::Error::Unsupported => errSecUnimplemented,
+            jks::Error::NoPrivateKey => errSecItemNotFound,
+        };
+        Error(base::Error::from(code))
+    }
+}
+
+impl From<der::Error> for Error {
+    fn from(_: der::Error) -> Error {
+        Error(base::Error::from(errSecParam))
+    }
+}
+
+impl From<pem::Error> for Error {
+    fn from(error: pem::Error) -> Error {
+        let code = match error {
+            pem::Error::Malformed | pem::Error::UnknownFormat => errSecParam,
+            pem::Error::NoCertificate | pem::Error::NoPrivateKey | pem::Error::NoCrl => {
+                errSecItemNotFound
+            }
+            pem::Error::Unsupported => errSecUnimplemented,
+            pem::Error::NoSecret => errSecAuthFailed,
+        };
+        Error(base::Error::from(code))
+    }
+}
+
+impl From<policy::Error> for Error {
+    fn from(error: policy::Error) -> Error {
+        let code = match error {
+            policy::Error::Malformed => errSecParam,
+            policy::Error::Hostname | policy::Error::IpAddress => errSSLHostNameMismatch,
+            policy::Error::NoCertificate
+            | policy::Error::ValidityPeriod
+            | policy::Error::Sha1Signature
+            | policy::Error::Rejected
+            | policy::Error::Pin => errSSLBadCert,
+        };
+        Error(base::Error::from(code))
+    }
+}
+
+pub fn backend_version() -> String {
+    #[cfg(target_os = "macos")]
+    const OS_NAME: &str = "macOS";
+    #[cfg(target_os = "ios")]
+    const OS_NAME: &str = "iOS";
+
+    let mut buf = [0u8; 32];
+    let mut len = buf.len();
+    let ret = unsafe {
+        libc::sysctlbyname(
+            b"kern.osproductversion\0".as_ptr() as *const _,
+            buf.as_mut_ptr() as *mut _,
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    let version = if ret == 0 {
+        buf[..len].split(|&b| b == 0).next().and_then(|v| str::from_utf8(v).ok())
+    } else {
+        None
+    };
+    match version {
+        Some(version) => format!("Secure Transport ({} {})", OS_NAME, version),
+        None => "Secure Transport".to_owned(),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Identity {
     identity: SecIdentity,
@@ -83,52 +274,98 @@ pub struct Identity {
 
 impl Identity {
     pub fn from_pkcs12(buf: &[u8], pass: &str) -> Result<Identity, Error> {
+        let (identity, chain) = Identity::import_pkcs12(buf, pass)?;
+        let cert = identity.certificate()?;
+        let chain = sort_chain(&cert, chain);
+        Ok(Identity { identity, chain })
+    }
+
+    fn import_pkcs12(buf: &[u8], pass: &str) -> Result<(SecIdentity, Vec<SecCertificate>), Error> {
+        #[cfg(not(target_os = "ios"))]
+        {
+            if let Some(memory_only) = import_to_memory_only() {
+                return import_pkcs12_to_memory(buf, pass, memory_only);
+            }
+        }
+
         let mut imports = Identity::import_options(buf, pass)?;
         let import = imports.pop().unwrap();
 
         let identity = import
             .identity
             .expect("Pkcs12 files must include an identity");
+        Ok((identity, import.cert_chain.unwrap_or(vec![])))
+    }
+
+    pub fn from_parts(identity: SecIdentity, chain: Vec<SecCertificate>) -> Identity {
+        let chain = match identity.certificate() {
+            Ok(cert) => sort_chain(&cert, chain),
+            Err(_) => chain,
+        };
+        Identity { identity, chain }
+    }
 
-        // FIXME: Compare the certificates for equality using CFEqual
-        let identity_cert = identity.certificate()?.to_der();
+    pub fn certificate(&self) -> Result<Certificate, Error> {
+        Ok(Certificate(self.identity.certificate()?))
+    }
 
-        Ok(Identity {
-            identity,
-            chain: import
-                .cert_chain
-                .unwrap_or(vec![])
-                .into_iter()
-                .filter(|c| c.to_der() != identity_cert)
-                .collect(),
-        })
+    pub fn chain(&self) -> Result<Vec<Certificate>, Error> {
+        Ok(self.chain.iter().cloned().map(Certificate).collect())
     }
 
     #[cfg(not(target_os = "ios"))]
-    fn import_options(buf: &[u8], pass: &str) -> Result<Vec<ImportedIdentity>, Error> {
-        SET_AT_EXIT.call_once(|| {
-            extern "C" fn atexit() {
-                *TEMP_KEYCHAIN.lock().unwrap() = None;
-            }
-            unsafe {
-                libc::atexit(atexit);
-            }
-        });
+    pub fn from_pkcs8_der(chain: &[&[u8]], key: &[u8]) -> Result<Identity, Error> {
+        // Secure Transport can't import bare DER keys, so wrap it back up in PEM.
+        Identity::import_key(chain, pem::block("PRIVATE KEY", key).as_bytes(), None)
+    }
 
-        let keychain = match *TEMP_KEYCHAIN.lock().unwrap() {
-            Some((ref keychain, _)) => keychain.clone(),
-            ref mut lock @ None => {
-                let dir = TempDir::new().map_err(|_| Error(base::Error::from(errSecIO)))?;
+    #[cfg(not(target_os = "ios"))]
+    pub fn from_encrypted_pkcs8_der(
+        chain: &[&[u8]],
+        key: &[u8],
+        pass: &str,
+    ) -> Result<Identity, Error> {
+        let key = pem::block("ENCRYPTED PRIVATE KEY", key);
+        Identity::import_key(chain, key.as_bytes(), Some(pass))
+    }
+
+    #[cfg(not(target_os = "ios"))]
+    fn import_key(chain: &[&[u8]], key: &[u8], pass: Option<&str>) -> Result<Identity, Error> {
+        let mut chain = chain
+            .iter()
+            .map(|cert| SecCertificate::from_der(cert))
+            .collect::<Result<Vec<_>, _>>()?;
+        if chain.is_empty() {
+            return Err(Error(base::Error::from(errSecParam)));
+        }
+        let cert = chain.remove(0);
+        let chain = sort_chain(&cert, chain);
+
+        let keychain = temp_keychain()?;
+        let mut options = ImportOptions::new();
+        options.filename("key.pem").keychain(&keychain);
+        if let Some(pass) = pass {
+            options.passphrase(pass);
+        }
+        options.import(key)?;
 
-                let mut keychain = keychain::CreateOptions::new()
-                    .password(pass)
-                    .create(dir.path().join("tmp.keychain"))?;
-                keychain.set_settings(&KeychainSettings::new())?;
+        let identity = SecIdentity::with_certificate(&[keychain], &cert)?;
+        Ok(Identity { identity, chain })
+    }
 
-                *lock = Some((keychain.clone(), dir));
-                keychain
-            }
-        };
+    #[cfg(target_os = "ios")]
+    pub fn from_pkcs8_der(_: &[&[u8]], _: &[u8]) -> Result<Identity, Error> {
+        Err(Error(base::Error::from(errSecUnimplemented)))
+    }
+
+    #[cfg(target_os = "ios")]
+    pub fn from_encrypted_pkcs8_der(_: &[&[u8]], _: &[u8], _: &str) -> Result<Identity, Error> {
+        Err(Error(base::Error::from(errSecUnimplemented)))
+    }
+
+    #[cfg(not(target_os = "ios"))]
+    fn import_options(buf: &[u8], pass: &str) -> Result<Vec<ImportedIdentity>, Error> {
+        let keychain = temp_keychain()?;
         let imports = Pkcs12ImportOptions::new()
             .passphrase(pass)
             .keychain(keychain)
@@ -143,6 +380,152 @@ impl Identity {
     }
 }
 
+fn signature_algorithm(oid: &[u8]) -> Option<Algorithm> {
+    let algorithm = match oid {
+        // 1.2.840.113549.1.1.5 sha1WithRSAEncryption
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05] => {
+            Algorithm::RSASignatureMessagePKCS1v15SHA1
+        }
+        // 1.2.840.113549.1.1.11 sha256WithRSAEncryption
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b] => {
+            Algorithm::RSASignatureMessagePKCS1v15SHA256
+        }
+        // 1.2.840.113549.1.1.12 sha384WithRSAEncryption
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c] => {
+            Algorithm::RSASignatureMessagePKCS1v15SHA384
+        }
+        // 1.2.840.113549.1.1.13 sha512WithRSAEncryption
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d] => {
+            Algorithm::RSASignatureMessagePKCS1v15SHA512
+        }
+        // 1.2.840.10045.4.1 ecdsa-with-SHA1
+        [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x01] => Algorithm::ECDSASignatureMessageX962SHA1,
+        // 1.2.840.10045.4.3.2 ecdsa-with-SHA256
+        [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02] => {
+            Algorithm::ECDSASignatureMessageX962SHA256
+        }
+        // 1.2.840.10045.4.3.3 ecdsa-with-SHA384
+        [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03] => {
+            Algorithm::ECDSASignatureMessageX962SHA384
+        }
+        // 1.2.840.10045.4.3.4 ecdsa-with-SHA512
+        [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04] => {
+            Algorithm::ECDSASignatureMessageX962SHA512
+        }
+        _ => return None,
+    };
+    Some(algorithm)
+}
+
+fn sort_chain(cert: &SecCertificate, chain: Vec<SecCertificate>) -> Vec<SecCertificate> {
+    let ders = chain.iter().map(|c| c.to_der()).collect::<Vec<_>>();
+    chain::sort(&cert.to_der(), chain, &ders)
+}
+
+/// Returns the `kSecImportToMemoryOnly` import option if the system supports it.
+///
+/// The option was added in macOS 15, so it's looked up at runtime rather than linked against.
+#[cfg(not(target_os = "ios"))]
+fn import_to_memory_only() -> Option<CFString> {
+    unsafe {
+        let name = b"kSecImportToMemoryOnly\0";
+        let key = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr() as *const _) as *const CFStringRef;
+        if key.is_null() || (*key).is_null() {
+            None
+        } else {
+            Some(CFString::wrap_under_get_rule(*key))
+        }
+    }
+}
+
+/// Imports a PKCS #12 archive without adding its contents to any keychain.
+#[cfg(not(target_os = "ios"))]
+fn import_pkcs12_to_memory(
+    buf: &[u8],
+    pass: &str,
+    memory_only: CFString,
+) -> Result<(SecIdentity, Vec<SecCertificate>), Error> {
+    unsafe {
+        let data = CFData::from_buffer(buf);
+        let options = CFDictionary::from_CFType_pairs(&[
+            (
+                CFString::wrap_under_get_rule(kSecImportExportPassphrase),
+                CFString::new(pass).as_CFType(),
+            ),
+            (memory_only, CFBoolean::true_value().as_CFType()),
+        ]);
+
+        let mut items = ptr::null();
+        let status = SecPKCS12Import(
+            data.as_concrete_TypeRef(),
+            options.as_concrete_TypeRef(),
+            &mut items,
+        );
+        if status != 0 {
+            return Err(Error(base::Error::from(status)));
+        }
+        let items =
+            CFArray::<CFDictionary<CFString, *const libc::c_void>>::wrap_under_create_rule(items);
+
+        let item = match items.iter().last() {
+            Some(item) => item,
+            None => return Err(Error(base::Error::from(errSecItemNotFound))),
+        };
+        let identity = match item.find(kSecImportItemIdentity) {
+            Some(identity) => SecIdentity::wrap_under_get_rule(*identity as *mut _),
+            None => return Err(Error(base::Error::from(errSecItemNotFound))),
+        };
+        let chain = match item.find(kSecImportItemCertChain) {
+            Some(chain) => CFArray::<SecCertificate>::wrap_under_get_rule(*chain as _)
+                .iter()
+                .map(|cert| cert.clone())
+                .collect(),
+            None => vec![],
+        };
+        Ok((identity, chain))
+    }
+}
+
+#[cfg(not(target_os = "ios"))]
+fn temp_keychain() -> Result<SecKeychain, Error> {
+    SET_AT_EXIT.call_once(|| {
+        extern "C" fn atexit() {
+            *TEMP_KEYCHAIN.lock().unwrap() = None;
+        }
+        unsafe {
+            libc::atexit(atexit);
+        }
+    });
+
+    let keychain = match *TEMP_KEYCHAIN.lock().unwrap() {
+        Some((ref keychain, _)) => keychain.clone(),
+        ref mut lock @ None => {
+            let dir = TempDir::new().map_err(|_| Error(base::Error::from(errSecIO)))?;
+
+            // The keychain is shared by every identity loaded by the process, so it mustn't reuse
+            // the password of whichever archive happened to be loaded first.
+            let mut password = [0; 32];
+            SecRandom::default()
+                .copy_bytes(&mut password)
+                .map_err(|_| Error(base::Error::from(errSecIO)))?;
+            let password = password
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+
+            // If creating or configuring the keychain fails, dropping `dir` removes it again.
+            let mut keychain = keychain::CreateOptions::new()
+                .password(&password)
+                .create(dir.path().join("tmp.keychain"))?;
+            keychain.set_settings(&KeychainSettings::new())?;
+
+            *lock = Some((keychain.clone(), dir));
+            keychain
+        }
+    };
+    Ok(keychain)
+}
+
 #[derive(Clone)]
 pub struct Certificate(SecCertificate);
 
@@ -171,24 +554,75 @@ impl Certificate {
     pub fn to_der(&self) -> Result<Vec<u8>, Error> {
         Ok(self.0.to_der())
     }
+
+    pub fn fingerprint(&self, digest: ::Digest) -> Result<Vec<u8>, Error> {
+        let digest = match digest {
+            ::Digest::Sha1 => Digest::Sha1,
+            ::Digest::Sha256 => Digest::Sha256,
+            ::Digest::Sha384 => Digest::Sha384,
+            ::Digest::Sha512 => Digest::Sha512,
+            ::Digest::__NonExhaustive => unreachable!(),
+        };
+        Ok(digest.hash(&self.0.to_der()))
+    }
+
+    pub fn public_key_sha256(&self) -> Result<Vec<u8>, Error> {
+        let der = self.0.to_der();
+        let spki = der::subject_public_key_info(&der)?;
+        Ok(Digest::Sha256.hash(spki))
+    }
+
+    pub fn verify_signed_by(&self, issuer: &Certificate) -> Result<bool, Error> {
+        let cert = self.0.to_der();
+        let signed = der::signed_data(&cert)?;
+        let algorithm = match signature_algorithm(signed.algorithm) {
+            Some(algorithm) => algorithm,
+            None => return Err(Error(base::Error::from(errSecUnimplemented))),
+        };
+        let key = issuer.0.public_key()?;
+        match key.verify_signature(algorithm, signed.tbs, signed.signature) {
+            Ok(valid) => Ok(valid),
+            Err(ref e) if e.code() == ERR_SEC_VERIFY_FAILED as isize => Ok(false),
+            Err(e) => Err(Error(base::Error::from(e.code() as i32))),
+        }
+    }
+
+    pub fn as_inner(&self) -> &SecCertificate {
+        &self.0
+    }
 }
 
-pub enum HandshakeError<S> {
-    WouldBlock(MidHandshakeTlsStream<S>),
-    Failure(Error),
+impl From<SecCertificate> for Certificate {
+    fn from(cert: SecCertificate) -> Certificate {
+        Certificate(cert)
+    }
 }
 
-impl<S> From<secure_transport::ClientHandshakeError<S>> for HandshakeError<S> {
-    fn from(e: secure_transport::ClientHandshakeError<S>) -> HandshakeError<S> {
-        match e {
-            secure_transport::ClientHandshakeError::Failure(e) => HandshakeError::Failure(e.into()),
-            secure_transport::ClientHandshakeError::Interrupted(s) => {
-                HandshakeError::WouldBlock(MidHandshakeTlsStream::Client(s))
-            }
-        }
+// CRLs aren't loaded by this backend, so they're only checked to be well-formed.
+#[derive(Clone)]
+pub struct Crl(Vec<u8>);
+
+impl Crl {
+    pub fn from_der(buf: &[u8]) -> Result<Crl, Error> {
+        der::Reader::new(buf).read(der::SEQUENCE)?;
+        Ok(Crl(buf.to_vec()))
+    }
+
+    pub fn from_pem(buf: &[u8]) -> Result<Crl, Error> {
+        let der = pem::crl(buf)?;
+        Crl::from_der(&der)
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.clone())
     }
 }
 
+pub enum HandshakeError<S> {
+    WouldBlock(MidHandshakeTlsStream<S>),
+    Failure(Error),
+}
+
 impl<S> From<base::Error> for HandshakeError<S> {
     fn from(e: base::Error) -> HandshakeError<S> {
         HandshakeError::Failure(e.into())
@@ -199,8 +633,14 @@ pub enum MidHandshakeTlsStream<S> {
     Server(
         secure_transport::MidHandshakeSslStream<S>,
         Option<SecCertificate>,
+        Option<usize>,
+        Option<ClientVerifier>,
+    ),
+    Client(
+        secure_transport::MidHandshakeClientBuilder<S>,
+        Option<usize>,
+        Option<ExceptionVerifier>,
     ),
-    Client(secure_transport::MidHandshakeClientBuilder<S>),
 }
 
 impl<S> fmt::Debug for MidHandshakeTlsStream<S>
@@ -209,8 +649,8 @@ where
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            MidHandshakeTlsStream::Server(ref s, _) => s.fmt(fmt),
-            MidHandshakeTlsStream::Client(ref s) => s.fmt(fmt),
+            MidHandshakeTlsStream::Server(ref s, _, _, _) => s.fmt(fmt),
+            MidHandshakeTlsStream::Client(ref s, _, _) => s.fmt(fmt),
         }
     }
 }
@@ -218,17 +658,21 @@ where
 impl<S> MidHandshakeTlsStream<S> {
     pub fn get_ref(&self) -> &S {
         match *self {
-            MidHandshakeTlsStream::Server(ref s, _) => s.get_ref(),
-            MidHandshakeTlsStream::Client(ref s) => s.get_ref(),
+            MidHandshakeTlsStream::Server(ref s, _, _, _) => s.get_ref(),
+            MidHandshakeTlsStream::Client(ref s, _, _) => s.get_ref(),
         }
     }
 
     pub fn get_mut(&mut self) -> &mut S {
         match *self {
-            MidHandshakeTlsStream::Server(ref mut s, _) => s.get_mut(),
-            MidHandshakeTlsStream::Client(ref mut s) => s.get_mut(),
+            MidHandshakeTlsStream::Server(ref mut s, _, _, _) => s.get_mut(),
+            MidHandshakeTlsStream::Client(ref mut s, _, _) => s.get_mut(),
         }
     }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
 }
 
 impl<S> MidHandshakeTlsStream<S>
@@ -237,19 +681,31 @@ where
 {
     pub fn handshake(self) -> Result<TlsStream<S>, HandshakeError<S>> {
         match self {
-            MidHandshakeTlsStream::Server(s, cert) => match s.handshake() {
-                Ok(stream) => Ok(TlsStream { stream, cert }),
-                Err(secure_transport::HandshakeError::Failure(e)) => {
-                    Err(HandshakeError::Failure(Error(e)))
+            MidHandshakeTlsStream::Server(s, cert, max_send_fragment, verifier) => {
+                server_handshake(s.handshake(), cert, max_send_fragment, verifier)
+            }
+            MidHandshakeTlsStream::Client(s, max_send_fragment, verifier) => match s.handshake() {
+                Ok(stream) => {
+                    if let Some(ref verifier) = verifier {
+                        verifier.verify(&stream)?;
+                    }
+                    Ok(TlsStream {
+                        stream,
+                        cert: None,
+                        max_send_fragment,
+                    })
+                }
+                Err(secure_transport::ClientHandshakeError::Failure(e)) => {
+                    Err(HandshakeError::Failure(e.into()))
                 }
-                Err(secure_transport::HandshakeError::Interrupted(s)) => Err(
-                    HandshakeError::WouldBlock(MidHandshakeTlsStream::Server(s, cert)),
+                Err(secure_transport::ClientHandshakeError::Interrupted(s)) => Err(
+                    HandshakeError::WouldBlock(MidHandshakeTlsStream::Client(
+                        s,
+                        max_send_fragment,
+                        verifier,
+                    )),
                 ),
             },
-            MidHandshakeTlsStream::Client(s) => match s.handshake() {
-                Ok(stream) => Ok(TlsStream { stream, cert: None }),
-                Err(e) => Err(e.into()),
-            },
         }
     }
 }
@@ -264,23 +720,41 @@ pub struct TlsConnector {
     danger_accept_invalid_hostnames: bool,
     danger_accept_invalid_certs: bool,
     disable_built_in_roots: bool,
+    max_send_fragment: Option<usize>,
+    cipher_suites: Vec<CipherSuite>,
+    certificate_exceptions: Option<::CertificateExceptions>,
+    #[cfg(feature = "alpn")]
+    alpn: Vec<String>,
 }
 
 impl TlsConnector {
+    pub fn new_dtls(_: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
+        Err(Error(base::Error::from(errSecUnimplemented)))
+    }
+
     pub fn new(builder: &TlsConnectorBuilder) -> Result<TlsConnector, Error> {
+        if builder.require_ocsp_staple || builder.check_revocation || !builder.crls.is_empty() {
+            return Err(Error(base::Error::from(errSecUnimplemented)));
+        }
+
         Ok(TlsConnector {
-            identity: builder.identity.as_ref().map(|i| i.0.clone()),
+            identity: builder.identity.as_ref().map(|i| i.0.native().clone()),
             min_protocol: builder.min_protocol,
             max_protocol: builder.max_protocol,
             roots: builder
                 .root_certificates
                 .iter()
-                .map(|c| (c.0).0.clone())
+                .map(|c| c.0.native().0.clone())
                 .collect(),
             use_sni: builder.use_sni,
             danger_accept_invalid_hostnames: builder.accept_invalid_hostnames,
             danger_accept_invalid_certs: builder.accept_invalid_certs,
             disable_built_in_roots: builder.disable_built_in_roots,
+            max_send_fragment: check_send_fragment(builder.max_send_fragment)?,
+            cipher_suites: convert_cipher_suites(SslProtocolSide::CLIENT, &builder.cipher_suites)?,
+            certificate_exceptions: builder.certificate_exceptions.clone(),
+            #[cfg(feature = "alpn")]
+            alpn: builder.alpn.clone(),
         })
     }
 
@@ -288,6 +762,48 @@ impl TlsConnector {
     where
         S: io::Read + io::Write,
     {
+        self.connect_inner(Some(domain), self.use_sni, stream)
+    }
+
+    pub fn connect_without_sni<S>(
+        &self,
+        domain: Option<&str>,
+        stream: S,
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect_inner(domain, false, stream)
+    }
+
+    // Early data isn't supported, so the caller sends it after the handshake instead.
+    pub fn connect_with_early_data<S>(
+        &self,
+        domain: &str,
+        stream: S,
+        _: &[u8],
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        self.connect(domain, stream)
+    }
+
+    fn connect_inner<S>(
+        &self,
+        domain: Option<&str>,
+        use_sni: bool,
+        stream: S,
+    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        // IP addresses can't be sent through SNI, and are matched against the certificate by the
+        // caller instead.
+        let ip = domain.map_or(false, |domain| domain.parse::<IpAddr>().is_ok());
+        let use_sni = use_sni && !ip;
+        let verify_hostname = domain.is_some() && !ip && !self.danger_accept_invalid_hostnames;
+        let domain = domain.unwrap_or("");
         let mut builder = ClientBuilder::new();
         if let Some(min) = self.min_protocol {
             builder.protocol_min(convert_protocol(min));
@@ -299,14 +815,163 @@ impl TlsConnector {
             builder.identity(&identity.identity, &identity.chain);
         }
         builder.anchor_certificates(&self.roots);
-        builder.use_sni(self.use_sni);
-        builder.danger_accept_invalid_hostnames(self.danger_accept_invalid_hostnames);
+        builder.use_sni(use_sni);
+        builder.danger_accept_invalid_hostnames(!verify_hostname);
         builder.danger_accept_invalid_certs(self.danger_accept_invalid_certs);
         builder.trust_anchor_certificates_only(self.disable_built_in_roots);
+        if !self.cipher_suites.is_empty() {
+            builder.whitelist_ciphers(&self.cipher_suites);
+        }
+        #[cfg(feature = "alpn")]
+        {
+            if !self.alpn.is_empty() {
+                let alpn = self.alpn.iter().map(|s| &**s).collect::<Vec<_>>();
+                builder.alpn_protocols(&alpn);
+            }
+        }
+
+        let verifier = match self.certificate_exceptions {
+            Some(ref exceptions) if !self.danger_accept_invalid_certs => {
+                builder.danger_accept_invalid_certs(true);
+                Some(ExceptionVerifier {
+                    domain: domain.to_owned(),
+                    verify_hostname,
+                    roots: self.roots.clone(),
+                    trust_roots_only: self.disable_built_in_roots,
+                    exceptions: exceptions.clone(),
+                })
+            }
+            _ => None,
+        };
 
         match builder.handshake(domain, stream) {
-            Ok(stream) => Ok(TlsStream { stream, cert: None }),
-            Err(e) => Err(e.into()),
+            Ok(stream) => {
+                if let Some(ref verifier) = verifier {
+                    verifier.verify(&stream)?;
+                }
+                Ok(TlsStream {
+                    stream,
+                    cert: None,
+                    max_send_fragment: self.max_send_fragment,
+                })
+            }
+            Err(secure_transport::ClientHandshakeError::Failure(e)) => {
+                Err(HandshakeError::Failure(e.into()))
+            }
+            Err(secure_transport::ClientHandshakeError::Interrupted(s)) => Err(
+                HandshakeError::WouldBlock(MidHandshakeTlsStream::Client(
+                    s,
+                    self.max_send_fragment,
+                    verifier,
+                )),
+            ),
+        }
+    }
+}
+
+// Secure Transport only lets us hook into certificate verification by taking it over entirely, so
+// when exceptions are configured the handshake runs unverified and the trust is evaluated here.
+#[derive(Debug)]
+pub struct ExceptionVerifier {
+    domain: String,
+    verify_hostname: bool,
+    roots: Vec<SecCertificate>,
+    trust_roots_only: bool,
+    exceptions: ::CertificateExceptions,
+}
+
+impl ExceptionVerifier {
+    fn verify<S>(&self, stream: &SslStream<S>) -> Result<(), base::Error> {
+        let mut trust = match stream.context().peer_trust2()? {
+            Some(trust) => trust,
+            None => return Ok(()),
+        };
+        trust.set_anchor_certificates(&self.roots)?;
+        trust.set_trust_anchor_certificates_only(self.trust_roots_only)?;
+        let domain = if self.verify_hostname {
+            Some(&*self.domain)
+        } else {
+            None
+        };
+        trust.set_policy(&SecPolicy::create_ssl(SslProtocolSide::SERVER, domain))?;
+
+        if trust.evaluate()?.success() {
+            return Ok(());
+        }
+
+        if let Some(cert) = trust.certificate_at_index(0) {
+            if self.exceptions.verify(&self.domain, &::Certificate(Certificate(cert).into())) {
+                return Ok(());
+            }
+        }
+
+        Err(base::Error::from(errSSLXCertChainInvalid))
+    }
+}
+
+// Client certificates are verified against our own roots, so Secure Transport is told to leave the
+// client's trust to us and it's evaluated once the handshake completes.
+#[derive(Debug, Clone)]
+pub struct ClientVerifier {
+    roots: Vec<SecCertificate>,
+    required: bool,
+}
+
+impl ClientVerifier {
+    fn verify<S>(&self, stream: &SslStream<S>) -> Result<(), base::Error> {
+        let mut trust = match stream.context().peer_trust2()? {
+            Some(trust) => trust,
+            None if self.required => return Err(base::Error::from(errSSLXCertChainInvalid)),
+            None => return Ok(()),
+        };
+        trust.set_anchor_certificates(&self.roots)?;
+        trust.set_trust_anchor_certificates_only(true)?;
+        trust.set_policy(&SecPolicy::create_ssl(SslProtocolSide::CLIENT, None))?;
+
+        if trust.evaluate()?.success() {
+            Ok(())
+        } else {
+            Err(base::Error::from(errSSLXCertChainInvalid))
+        }
+    }
+}
+
+fn server_handshake<S>(
+    mut result: result::Result<SslStream<S>, secure_transport::HandshakeError<S>>,
+    cert: Option<SecCertificate>,
+    max_send_fragment: Option<usize>,
+    verifier: Option<ClientVerifier>,
+) -> Result<TlsStream<S>, HandshakeError<S>>
+where
+    S: io::Read + io::Write,
+{
+    loop {
+        match result {
+            Ok(stream) => {
+                if let Some(ref verifier) = verifier {
+                    verifier.verify(&stream)?;
+                }
+                return Ok(TlsStream {
+                    stream,
+                    cert,
+                    max_send_fragment,
+                });
+            }
+            Err(secure_transport::HandshakeError::Failure(e)) => {
+                return Err(HandshakeError::Failure(Error(e)))
+            }
+            // The client's certificate has been received
+            Err(secure_transport::HandshakeError::Interrupted(s)) if s.server_auth_completed() => {
+                result = s.handshake();
+            }
+            Err(secure_transport::HandshakeError::Interrupted(s)) => {
+                return Err(HandshakeError::WouldBlock(MidHandshakeTlsStream::Server(
+                    s,
+                    cert,
+                    max_send_fragment,
+                    verifier,
+                )))
+            }
         }
     }
 }
@@ -316,14 +981,52 @@ pub struct TlsAcceptor {
     identity: Identity,
     min_protocol: Option<Protocol>,
     max_protocol: Option<Protocol>,
+    max_send_fragment: Option<usize>,
+    cipher_suites: Vec<CipherSuite>,
+    client_auth: ClientAuth,
+    client_roots: Vec<SecCertificate>,
 }
 
 impl TlsAcceptor {
+    pub fn new_dtls(_: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
+        Err(Error(base::Error::from(errSecUnimplemented)))
+    }
+
     pub fn new(builder: &TlsAcceptorBuilder) -> Result<TlsAcceptor, Error> {
+        // A Secure Transport context holds a single identity.
+        if !builder.additional_identities.is_empty()
+            || !builder.sni_identities.is_empty()
+            || builder.ocsp_response.is_some()
+        {
+            return Err(Error(base::Error::from(errSecUnimplemented)));
+        }
+        // Secure Transport only supports ALPN on the client side.
+        #[cfg(feature = "alpn")]
+        {
+            if !builder.alpn.is_empty() {
+                return Err(Error(base::Error::from(errSecUnimplemented)));
+            }
+        }
+
+        let mut identity = builder.identity.0.native().clone();
+        if builder.omit_root_certificate {
+            identity
+                .chain
+                .retain(|c| !der::is_self_issued(&c.to_der()).unwrap_or(false));
+        }
+
         Ok(TlsAcceptor {
-            identity: builder.identity.0.clone(),
+            identity,
             min_protocol: builder.min_protocol,
             max_protocol: builder.max_protocol,
+            max_send_fragment: check_send_fragment(builder.max_send_fragment)?,
+            cipher_suites: convert_cipher_suites(SslProtocolSide::SERVER, &builder.cipher_suites)?,
+            client_auth: builder.client_auth,
+            client_roots: builder
+                .client_root_certificates
+                .iter()
+                .map(|c| c.0.native().0.clone())
+                .collect(),
         })
     }
 
@@ -340,22 +1043,47 @@ impl TlsAcceptor {
             ctx.set_protocol_version_max(convert_protocol(max))?;
         }
         ctx.set_certificate(&self.identity.identity, &self.identity.chain)?;
+        if !self.cipher_suites.is_empty() {
+            ctx.set_enabled_ciphers(&self.cipher_suites)?;
+        }
         let cert = Some(self.identity.identity.certificate()?);
-        match ctx.handshake(stream) {
-            Ok(stream) => Ok(TlsStream { stream, cert }),
-            Err(secure_transport::HandshakeError::Failure(e)) => {
-                Err(HandshakeError::Failure(Error(e)))
+
+        let required = match self.client_auth {
+            ClientAuth::Disabled => None,
+            ClientAuth::Optional => Some(false),
+            ClientAuth::Required => Some(true),
+            ClientAuth::__NonExhaustive => unreachable!(),
+        };
+        let verifier = match required {
+            Some(required) => {
+                let auth = if required {
+                    SslAuthenticate::ALWAYS
+                } else {
+                    SslAuthenticate::TRY
+                };
+                ctx.set_client_side_authenticate(auth)?;
+                ctx.set_break_on_client_auth(true)?;
+                Some(ClientVerifier {
+                    roots: self.client_roots.clone(),
+                    required,
+                })
             }
-            Err(secure_transport::HandshakeError::Interrupted(s)) => Err(
-                HandshakeError::WouldBlock(MidHandshakeTlsStream::Server(s, cert)),
-            ),
-        }
+            None => None,
+        };
+
+        server_handshake(
+            ctx.handshake(stream),
+            cert,
+            self.max_send_fragment,
+            verifier,
+        )
     }
 }
 
 pub struct TlsStream<S> {
     stream: secure_transport::SslStream<S>,
     cert: Option<SecCertificate>,
+    max_send_fragment: Option<usize>,
 }
 
 impl<S: fmt::Debug> fmt::Debug for TlsStream<S> {
@@ -389,6 +1117,72 @@ impl<S: io::Read + io::Write> TlsStream<S> {
         Ok(trust.certificate_at_index(0).map(Certificate))
     }
 
+    pub fn session_resumed(&self) -> Result<bool, Error> {
+        Err(Error(base::Error::from(errSecUnimplemented)))
+    }
+
+    pub fn early_data_accepted(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    pub fn protocol_version(&self) -> Result<Option<Protocol>, Error> {
+        let protocol = match self.stream.context().negotiated_protocol_version()? {
+            SslProtocol::SSL3 => Protocol::Sslv3,
+            SslProtocol::TLS1 => Protocol::Tlsv10,
+            SslProtocol::TLS11 => Protocol::Tlsv11,
+            SslProtocol::TLS12 => Protocol::Tlsv12,
+            SslProtocol::TLS13 => Protocol::Tlsv13,
+            _ => return Ok(None),
+        };
+        Ok(Some(protocol))
+    }
+
+    pub fn cipher_suite(&self) -> Result<u16, Error> {
+        Ok(self.stream.context().negotiated_cipher()?.to_raw() as u16)
+    }
+
+    #[cfg(feature = "alpn")]
+    pub fn negotiated_alpn(&self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self
+            .stream
+            .context()
+            .alpn_protocols()?
+            .into_iter()
+            .next()
+            .map(String::into_bytes))
+    }
+
+    pub fn peer_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        let trust = match self.stream.context().peer_trust2()? {
+            Some(trust) => trust,
+            None => return Ok(vec![]),
+        };
+        trust.evaluate()?;
+
+        Ok((0..trust.certificate_count())
+            .filter_map(|i| trust.certificate_at_index(i))
+            .map(Certificate)
+            .collect())
+    }
+
+    // Evaluating the trust builds the path from the leaf, leaving out any other certificates the
+    // server sent.
+    pub fn verified_certificate_chain(&self) -> Result<Vec<Certificate>, Error> {
+        self.peer_certificate_chain()
+    }
+
+    pub fn sni_hostname(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    pub fn client_hello_ja3(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    pub fn client_hello_ja4(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
     #[cfg(target_os = "ios")]
     pub fn tls_server_end_point(&self) -> Result<Option<Vec<u8>>, Error> {
         Ok(None)
@@ -467,6 +1261,19 @@ impl<S: io::Read + io::Write> TlsStream<S> {
         Ok(Some(digest.hash(&der)))
     }
 
+    pub fn export_keying_material(
+        &self,
+        _: &str,
+        _: Option<&[u8]>,
+        _: usize,
+    ) -> Result<Vec<u8>, Error> {
+        Err(Error(base::Error::from(errSecUnimplemented)))
+    }
+
+    pub fn tls_unique(&self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
     pub fn shutdown(&mut self) -> io::Result<()> {
         self.stream.close()?;
         Ok(())
@@ -481,6 +1288,12 @@ impl<S: io::Read + io::Write> io::Read for TlsStream<S> {
 
 impl<S: io::Read + io::Write> io::Write for TlsStream<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Secure Transport has no knob for the record size, but it encrypts each write into records
+        // of its own, so shortening writes caps the records.
+        let buf = match self.max_send_fragment {
+            Some(max) => &buf[..cmp::min(buf.len(), max)],
+            None => buf,
+        };
         self.stream.write(buf)
     }
 
@@ -490,6 +1303,7 @@ impl<S: io::Read + io::Write> io::Write for TlsStream<S> {
 }
 
 enum Digest {
+    Sha1,
     Sha224,
     Sha256,
     Sha384,
@@ -501,6 +1315,11 @@ impl Digest {
         unsafe {
             assert!(data.len() <= CC_LONG::max_value() as usize);
             match *self {
+                Digest::Sha1 => {
+                    let mut buf = [0; CC_SHA1_DIGEST_LENGTH];
+                    CC_SHA1(data.as_ptr(), data.len() as CC_LONG, buf.as_mut_ptr());
+                    buf.to_vec()
+                }
                 Digest::Sha224 => {
                     let mut buf = [0; CC_SHA224_DIGEST_LENGTH];
                     CC_SHA224(data.as_ptr(), data.len() as CC_LONG, buf.as_mut_ptr());
@@ -527,6 +1346,7 @@ impl Digest {
 }
 
 // FIXME ideally we'd pull these in from elsewhere
+const CC_SHA1_DIGEST_LENGTH: usize = 20;
 const CC_SHA224_DIGEST_LENGTH: usize = 28;
 const CC_SHA256_DIGEST_LENGTH: usize = 32;
 const CC_SHA384_DIGEST_LENGTH: usize = 48;
@@ -535,6 +1355,7 @@ const CC_SHA512_DIGEST_LENGTH: usize = 64;
 type CC_LONG = u32;
 
 extern "C" {
+    fn CC_SHA1(data: *const u8, len: CC_LONG, md: *mut u8) -> *mut u8;
     fn CC_SHA224(data: *const u8, len: CC_LONG, md: *mut u8) -> *mut u8;
     fn CC_SHA256(data: *const u8, len: CC_LONG, md: *mut u8) -> *mut u8;
     fn CC_SHA384(data: *const u8, len: CC_LONG, md: *mut u8) -> *mut u8;
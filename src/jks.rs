@@ -0,0 +1,310 @@
+//! A minimal parser for Java KeyStore (JKS and JCEKS) files.
+//!
+//! Only the pieces needed to pull out certificates and private keys are implemented. Private keys
+//! in JKS files are protected with Sun's proprietary SHA-1 based key protector, which is
+//! reimplemented here. JCEKS files protect private keys with 3DES, which isn't supported.
+
+use std::error;
+use std::fmt;
+
+use der;
+
+const JKS_MAGIC: u32 = 0xfeed_feed;
+const JCEKS_MAGIC: u32 = 0xcece_cece;
+
+const PRIVATE_KEY_TAG: u32 = 1;
+const TRUSTED_CERT_TAG: u32 = 2;
+const SECRET_KEY_TAG: u32 = 3;
+
+// 1.3.6.1.4.1.42.2.17.1.1
+const KEY_PROTECTOR_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x2a, 0x02, 0x11, 0x01, 0x01];
+
+const DIGEST_LEN: usize = 20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    Malformed,
+    BadPassword,
+    Unsupported,
+    NoPrivateKey,
+}
+
+impl error::Error for Error {}
+
+impl From<der::Error> for Error {
+    fn from(_: der::Error) -> Error {
+        Error::Malformed
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Error::Malformed => "malformed Java keystore",
+            Error::BadPassword => "Java keystore password is incorrect",
+            Error::Unsupported => "unsupported Java keystore entry",
+            Error::NoPrivateKey => "no private key entry found in Java keystore",
+        };
+        fmt.write_str(s)
+    }
+}
+
+pub struct PrivateKeyEntry {
+    pub key: Vec<u8>,
+    pub chain: Vec<Vec<u8>>,
+}
+
+/// Determines if `buf` starts with the magic number of a JKS or JCEKS keystore.
+pub fn is_keystore(buf: &[u8]) -> bool {
+    let magic = Reader(buf).u32();
+    magic == Ok(JKS_MAGIC) || magic == Ok(JCEKS_MAGIC)
+}
+
+/// Returns the first private key entry of the keystore, with the key decrypted into PKCS #8 DER.
+pub fn private_key(buf: &[u8], store_pass: &str, key_pass: &str) -> Result<PrivateKeyEntry, Error> {
+    for entry in parse(buf, store_pass)? {
+        if let Entry::PrivateKey { key, chain } = entry {
+            let key = decrypt_key(key, key_pass)?;
+            return Ok(PrivateKeyEntry { key, chain });
+        }
+    }
+
+    Err(Error::NoPrivateKey)
+}
+
+/// Returns the DER-encoded certificates of all trusted certificate entries in the keystore.
+pub fn trusted_certs(buf: &[u8], store_pass: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let certs = parse(buf, store_pass)?
+        .into_iter()
+        .filter_map(|e| match e {
+            Entry::TrustedCert(cert) => Some(cert),
+            _ => None,
+        })
+        .collect();
+    Ok(certs)
+}
+
+enum Entry<'a> {
+    PrivateKey { key: &'a [u8], chain: Vec<Vec<u8>> },
+    TrustedCert(Vec<u8>),
+}
+
+fn parse<'a>(buf: &'a [u8], store_pass: &str) -> Result<Vec<Entry<'a>>, Error> {
+    if buf.len() < DIGEST_LEN {
+        return Err(Error::Malformed);
+    }
+    let (body, digest) = buf.split_at(buf.len() - DIGEST_LEN);
+
+    let mut sha1 = Sha1::new();
+    sha1.update(&password_bytes(store_pass));
+    sha1.update(b"Mighty Aphrodite");
+    sha1.update(body);
+    if sha1.finish()[..] != *digest {
+        return Err(Error::BadPassword);
+    }
+
+    let mut r = Reader(body);
+    match r.u32()? {
+        JKS_MAGIC | JCEKS_MAGIC => {}
+        _ => return Err(Error::Malformed),
+    }
+    let version = r.u32()?;
+    if version != 1 && version != 2 {
+        return Err(Error::Malformed);
+    }
+
+    let count = r.u32()?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let tag = r.u32()?;
+        r.utf()?; // alias
+        r.bytes(8)?; // timestamp
+
+        let entry = match tag {
+            PRIVATE_KEY_TAG => {
+                let len = r.u32()? as usize;
+                let key = r.bytes(len)?;
+                let chain_len = r.u32()?;
+                let mut chain = vec![];
+                for _ in 0..chain_len {
+                    chain.push(r.cert(version)?);
+                }
+                Entry::PrivateKey { key, chain }
+            }
+            TRUSTED_CERT_TAG => Entry::TrustedCert(r.cert(version)?),
+            // Secret keys are Java-serialized objects, so we can't skip past them.
+            SECRET_KEY_TAG => return Err(Error::Unsupported),
+            _ => return Err(Error::Malformed),
+        };
+        entries.push(entry);
+    }
+
+    if !r.0.is_empty() {
+        return Err(Error::Malformed);
+    }
+
+    Ok(entries)
+}
+
+fn decrypt_key(encrypted: &[u8], key_pass: &str) -> Result<Vec<u8>, Error> {
+    // EncryptedPrivateKeyInfo ::= SEQUENCE {
+    //     encryptionAlgorithm AlgorithmIdentifier,
+    //     encryptedData OCTET STRING }
+    let mut info = der::Reader::new(der::Reader::new(encrypted).read(der::SEQUENCE)?);
+    let mut algorithm = der::Reader::new(info.read(der::SEQUENCE)?);
+    if algorithm.read(der::OID)? != KEY_PROTECTOR_OID {
+        return Err(Error::Unsupported);
+    }
+    let data = info.read(der::OCTET_STRING)?;
+
+    if data.len() < 2 * DIGEST_LEN {
+        return Err(Error::Malformed);
+    }
+    let (salt, rest) = data.split_at(DIGEST_LEN);
+    let (ciphertext, check) = rest.split_at(rest.len() - DIGEST_LEN);
+
+    let password = password_bytes(key_pass);
+    let mut key = Vec::with_capacity(ciphertext.len());
+    let mut digest = [0; DIGEST_LEN];
+    digest.copy_from_slice(salt);
+    for chunk in ciphertext.chunks(DIGEST_LEN) {
+        let mut sha1 = Sha1::new();
+        sha1.update(&password);
+        sha1.update(&digest);
+        digest = sha1.finish();
+        key.extend(chunk.iter().zip(&digest).map(|(a, b)| a ^ b));
+    }
+
+    let mut sha1 = Sha1::new();
+    sha1.update(&password);
+    sha1.update(&key);
+    if sha1.finish()[..] != *check {
+        return Err(Error::BadPassword);
+    }
+
+    Ok(key)
+}
+
+// Java hashes passwords as big-endian UTF-16.
+fn password_bytes(pass: &str) -> Vec<u8> {
+    pass.encode_utf16()
+        .flat_map(|c| vec![(c >> 8) as u8, c as u8])
+        .collect()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::Malformed);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.bytes(2)?;
+        Ok(u16::from(b[0]) << 8 | u16::from(b[1]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.bytes(4)?;
+        Ok(b.iter().fold(0, |n, &b| n << 8 | u32::from(b)))
+    }
+
+    fn utf(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    fn cert(&mut self, version: u32) -> Result<Vec<u8>, Error> {
+        if version == 2 && self.utf()? != b"X.509" {
+            return Err(Error::Unsupported);
+        }
+        let len = self.u32()? as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+}
+
+struct Sha1 {
+    state: [u32; 5],
+    buf: Vec<u8>,
+    len: u64,
+}
+
+impl Sha1 {
+    fn new() -> Sha1 {
+        Sha1 {
+            state: [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476, 0xc3d2_e1f0],
+            buf: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.len += data.len() as u64;
+        self.buf.extend_from_slice(data);
+        let blocks = self.buf.len() / 64;
+        for i in 0..blocks {
+            let mut block = [0; 64];
+            block.copy_from_slice(&self.buf[i * 64..(i + 1) * 64]);
+            self.compress(&block);
+        }
+        self.buf.drain(..blocks * 64);
+    }
+
+    fn finish(mut self) -> [u8; DIGEST_LEN] {
+        let bits = self.len * 8;
+        let mut padding = vec![0x80];
+        padding.resize((119 - self.len % 64) as usize % 64 + 1, 0);
+        padding.extend((0..8).rev().map(|i| (bits >> (i * 8)) as u8));
+        self.update(&padding);
+
+        let mut out = [0; DIGEST_LEN];
+        for (chunk, word) in out.chunks_mut(4).zip(&self.state) {
+            for (i, b) in chunk.iter_mut().enumerate() {
+                *b = (word >> (24 - 8 * i)) as u8;
+            }
+        }
+        out
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = block[i * 4..i * 4 + 4]
+                .iter()
+                .fold(0, |n, &b| n << 8 | u32::from(b));
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, &w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (s, v) in self.state.iter_mut().zip(&[a, b, c, d, e]) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+}
@@ -30,12 +30,31 @@
 //! * Secure-by-default for client and server
 //!     * Includes hostname verification for clients
 //! * Supports asynchronous I/O for both the server and the client
+//! * DTLS over datagram transports with OpenSSL
 //!
 //! # Cargo Features
 //!
 //! * `vendored` - If enabled, the crate will compile and statically link to a
 //!     vendored copy of OpenSSL. This feature has no effect on Windows and
 //!     macOS, where OpenSSL is not used.
+//! * `rustls-pki-types` - If enabled, `Certificate`s and `Identity`s can be
+//!     converted from and to the DER types of the `rustls-pki-types` crate.
+//! * `serde` - If enabled, `TlsConfig` can be deserialized from configuration
+//!     files.
+//! * `tcp` - If enabled, `TlsConnector::connect_tcp` connects to a `host:port`
+//!     address in a single call.
+//! * `alpn` - If enabled, ALPN (Application-Layer Protocol Negotiation) is
+//!     supported.
+//! * `tokio` - If enabled, handshakes can be driven from Tokio tasks, producing
+//!     streams which implement Tokio's `AsyncRead` and `AsyncWrite`.
+//! * `futures-io` - If enabled, the `futures_io` module drives handshakes over
+//!     streams implementing the `futures-io` crate's `AsyncRead` and
+//!     `AsyncWrite`, as used by async-std and smol.
+//! * `rustls` - If enabled, a backend built on rustls and ring is available
+//!     through `TlsConnector::rustls_builder` and `TlsAcceptor::rustls_builder`.
+//!     Protocols older than TLS 1.2 aren't supported, and the built-in roots
+//!     are those of the `webpki-roots` crate. Other builders keep using the
+//!     platform's TLS library.
 //!
 //! # Examples
 //!
@@ -64,7 +83,6 @@
 //! use std::fs::File;
 //! use std::io::{Read};
 //! use std::net::{TcpListener, TcpStream};
-//! use std::sync::Arc;
 //! use std::thread;
 //!
 //! let mut file = File::open("identity.pfx").unwrap();
@@ -74,7 +92,6 @@
 //!
 //! let listener = TcpListener::bind("0.0.0.0:8443").unwrap();
 //! let acceptor = TlsAcceptor::new(identity).unwrap();
-//! let acceptor = Arc::new(acceptor);
 //!
 //! fn handle_client(stream: TlsStream<TcpStream>) {
 //!     // ...
@@ -102,26 +119,60 @@ extern crate lazy_static;
 
 #[cfg(test)]
 extern crate hex;
+#[cfg(all(unix, feature = "tcp"))]
+extern crate libc;
+#[cfg(feature = "rustls-pki-types")]
+extern crate rustls_pki_types;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(test)]
+extern crate serde_json;
 
 use std::any::Any;
 use std::error;
+use std::cmp;
 use std::fmt;
+use std::fs;
 use std::io;
+use std::mem;
+use std::net::IpAddr;
+#[cfg(feature = "tcp")]
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
 use std::result;
+use std::str;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "tcp")]
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "rustls-pki-types")]
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer};
 
 #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
 #[macro_use]
 extern crate log;
-#[cfg(any(target_os = "macos", target_os = "ios"))]
-#[path = "imp/security_framework.rs"]
-mod imp;
-#[cfg(target_os = "windows")]
-#[path = "imp/schannel.rs"]
-mod imp;
-#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
-#[path = "imp/openssl.rs"]
+
 mod imp;
 
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+mod async_io;
+pub mod backend;
+mod chain;
+mod cipher;
+mod der;
+pub mod dtls;
+#[cfg(feature = "futures-io")]
+pub mod futures_io;
+mod hostname;
+mod policy;
+mod jks;
+mod pem;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
 #[cfg(test)]
 mod test;
 
@@ -129,7 +180,7 @@ mod test;
 pub type Result<T> = result::Result<T, Error>;
 
 /// An error returned from the TLS implementation.
-pub struct Error(imp::Error);
+pub struct Error(imp::Error, Option<&'static str>);
 
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
@@ -139,29 +190,94 @@ impl error::Error for Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(format) = self.1 {
+            write!(fmt, "error loading {} identity: ", format)?;
+        }
         fmt::Display::fmt(&self.0, fmt)
     }
 }
 
 impl fmt::Debug for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(format) = self.1 {
+            write!(fmt, "{} identity: ", format)?;
+        }
         fmt::Debug::fmt(&self.0, fmt)
     }
 }
 
+impl Error {
+    /// Returns the general category of this error.
+    ///
+    /// The underlying error of the backend is still available through `source` and the `Debug`
+    /// output.
+    pub fn kind(&self) -> ErrorKind {
+        self.0.kind()
+    }
+
+    /// Returns the error of the underlying stream which caused this error, if any.
+    ///
+    /// Secure Transport doesn't retain the stream's error, so `None` is always returned on Apple
+    /// platforms.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        self.0.io_error()
+    }
+
+    /// Converts this error into the error of the underlying stream which caused it.
+    ///
+    /// The error is returned unchanged if it wasn't caused by the stream.
+    pub fn into_io_error(self) -> result::Result<io::Error, Error> {
+        let Error(e, format) = self;
+        e.into_io_error().map_err(|e| Error(e, format))
+    }
+}
+
 impl From<imp::Error> for Error {
     fn from(err: imp::Error) -> Error {
-        Error(err)
+        Error(err, None)
     }
 }
 
+/// The general category of an `Error`, common to every backend.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The peer's certificate is not valid for the requested hostname or IP address.
+    HostnameMismatch,
+    /// The peer's certificate chain does not lead to a trusted root certificate.
+    UntrustedRoot,
+    /// A certificate in the peer's chain has expired or is not yet valid.
+    CertExpired,
+    /// The peers do not support a common protocol version.
+    ProtocolVersion,
+    /// The underlying stream failed.
+    Io,
+    /// Any other error, including errors loading identities and certificates.
+    Other,
+    #[doc(hidden)]
+    __NonExhaustive,
+}
+
 /// A cryptographic identity.
 ///
 /// An identity is an X509 certificate along with its corresponding private key and chain of certificates to a trusted
 /// root.
+///
+/// Identities are `Send` and `Sync` with every backend, so an identity loaded once can be cloned
+/// into acceptors built on other threads.
 #[derive(Clone)]
 pub struct Identity(imp::Identity);
 
+impl fmt::Debug for Identity {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // The private key is never shown.
+        let mut debug = fmt.debug_struct("Identity");
+        if let Ok(cert) = self.certificate() {
+            debug.field("certificate", &cert);
+        }
+        debug.finish()
+    }
+}
+
 impl Identity {
     /// Parses a DER-formatted PKCS #12 archive, using the specified password to decrypt the key.
     ///
@@ -175,16 +291,222 @@ impl Identity {
     /// ```bash
     /// openssl pkcs12 -export -out identity.pfx -inkey key.pem -in cert.pem -certfile chain_certs.pem
     /// ```
+    ///
+    /// Archives created with an empty password, or without any password at all, are loaded by
+    /// passing an empty `password`. The rustls backend only loads archives whose private key is
+    /// encrypted, as written by `openssl pkcs12` unless `-keypbe NONE` is passed. It loads the
+    /// archive when a rustls builder using the identity is built, and reports errors from there.
     pub fn from_pkcs12(der: &[u8], password: &str) -> Result<Identity> {
         let identity = imp::Identity::from_pkcs12(der, password)?;
         Ok(Identity(identity))
     }
+
+    /// Like `from_pkcs12`, but requests the password from `provider`.
+    pub fn from_pkcs12_with_provider(
+        der: &[u8],
+        provider: &dyn SecretProvider,
+    ) -> Result<Identity> {
+        let password = secret(provider, SecretKind::Pkcs12Password)?;
+        Identity::from_pkcs12(der, &password)
+    }
+
+    /// Parses a chain of PEM-formatted X509 certificates and a separate PEM-formatted private key.
+    ///
+    /// `pem` contains the leaf certificate first, followed by any intermediate certificates in
+    /// order from the leaf towards the root, as in the `fullchain.pem` files written by ACME
+    /// clients. `key` contains the leaf certificate's unencrypted private key, either as a PKCS #8
+    /// (`PRIVATE KEY`) or PKCS #1 (`RSA PRIVATE KEY`) block. Use `from_bytes` to load encrypted
+    /// keys.
+    pub fn from_pkcs8(pem: &[u8], key: &[u8]) -> Result<Identity> {
+        let pem = pem::identity_parts(pem, key).map_err(imp::Error::from)?;
+        pem_identity(pem, &|_| None)
+    }
+
+    /// Creates an identity from a chain of DER-formatted X509 certificates and a PKCS #8
+    /// private key.
+    ///
+    /// The first certificate in the chain is the leaf certificate corresponding to the private
+    /// key, and the remaining certificates should be in order from the leaf towards the root.
+    ///
+    /// Only PKCS #8 keys are supported on all platforms; other key formats are rejected.
+    ///
+    /// Requires the `rustls-pki-types` Cargo feature.
+    #[cfg(feature = "rustls-pki-types")]
+    pub fn from_pki_types(chain: &[CertificateDer], key: &PrivateKeyDer) -> Result<Identity> {
+        let chain = chain.iter().map(|c| &**c).collect::<Vec<&[u8]>>();
+        let identity = imp::Identity::from_pkcs8_der(&chain, key.secret_der())?;
+        Ok(Identity(identity))
+    }
+
+    /// Returns the leaf certificate of this identity.
+    pub fn certificate(&self) -> Result<Certificate> {
+        let cert = self.0.certificate()?;
+        Ok(Certificate(cert))
+    }
+
+    /// Returns the certificates presented alongside the leaf certificate of this identity.
+    ///
+    /// The chain does not include the leaf certificate. It is sorted from the leaf's issuer towards
+    /// the root regardless of the order the identity was created with, followed by any
+    /// certificates which aren't part of that path.
+    pub fn chain(&self) -> Result<Vec<Certificate>> {
+        let chain = self.0.chain()?;
+        Ok(chain.into_iter().map(Certificate).collect())
+    }
+
+    /// Parses a Java KeyStore (JKS) file containing a private key and its certificate chain.
+    ///
+    /// The first private key entry of the keystore is used. `store_password` is used to verify
+    /// the integrity of the keystore and `key_password` to decrypt the private key; keystores
+    /// created by `keytool` typically use the same password for both.
+    ///
+    /// JCEKS keystores can be read, but their private key entries cannot be decrypted.
+    pub fn from_jks(der: &[u8], store_password: &str, key_password: &str) -> Result<Identity> {
+        let entry =
+            jks::private_key(der, store_password, key_password).map_err(imp::Error::from)?;
+        let chain = entry.chain.iter().map(|c| &**c).collect::<Vec<&[u8]>>();
+        let identity = imp::Identity::from_pkcs8_der(&chain, &entry.key)?;
+        Ok(Identity(identity))
+    }
+
+    /// Like `from_jks`, but requests the store and key passwords from `provider`.
+    pub fn from_jks_with_provider(der: &[u8], provider: &dyn SecretProvider) -> Result<Identity> {
+        let store_password = secret(provider, SecretKind::KeyStorePassword)?;
+        let key_password = secret(provider, SecretKind::PrivateKeyPassword)?;
+        Identity::from_jks(der, &store_password, &key_password)
+    }
+
+    /// Parses an identity in any supported format, detecting the format from its contents.
+    ///
+    /// The following formats are recognized:
+    ///
+    /// * DER-formatted PKCS #12 archives, as accepted by `from_pkcs12`.
+    /// * Java KeyStore files, as accepted by `from_jks`, with `password` used as both the store
+    ///     and key password.
+    /// * PEM files containing X509 certificates and a private key. The first certificate is the
+    ///     leaf certificate. The key may be an unencrypted PKCS #8 (`PRIVATE KEY`) or PKCS #1
+    ///     (`RSA PRIVATE KEY`) key, or a PKCS #8 key encrypted with `password` (`ENCRYPTED
+    ///     PRIVATE KEY`). Encrypted keys aren't supported by the SChannel backend.
+    ///
+    /// `password` is ignored by formats which aren't encrypted. If the contents can't be parsed,
+    /// the error names the detected format.
+    pub fn from_bytes(buf: &[u8], password: &str) -> Result<Identity> {
+        Identity::from_bytes_with_provider(buf, &|_| Some(password.to_owned()))
+    }
+
+    /// Like `from_bytes`, but requests passwords from `provider`.
+    ///
+    /// Passwords are only requested if the detected format needs them, so no password is
+    /// requested for a PEM file with an unencrypted key.
+    pub fn from_bytes_with_provider(
+        buf: &[u8],
+        provider: &dyn SecretProvider,
+    ) -> Result<Identity> {
+        let (format, identity) = if pem::is_pem(buf) {
+            let identity = pem::identity(buf)
+                .map_err(|e| imp::Error::from(e).into())
+                .and_then(|pem| pem_identity(pem, provider));
+            ("PEM", identity)
+        } else if jks::is_keystore(buf) {
+            ("Java KeyStore", Identity::from_jks_with_provider(buf, provider))
+        } else if buf.first() == Some(&der::SEQUENCE) {
+            ("PKCS #12", Identity::from_pkcs12_with_provider(buf, provider))
+        } else {
+            return Err(imp::Error::from(pem::Error::UnknownFormat).into());
+        };
+        identity.map_err(|e| Error(e.0, Some(format)))
+    }
+}
+
+fn pem_identity(pem: pem::Identity, provider: &dyn SecretProvider) -> Result<Identity> {
+    let chain = pem.chain.iter().map(|c| &**c).collect::<Vec<&[u8]>>();
+    let identity = match pem.key {
+        pem::Key::Pkcs8(ref key) => imp::Identity::from_pkcs8_der(&chain, key)?,
+        pem::Key::EncryptedPkcs8(ref key) => {
+            let password = secret(provider, SecretKind::PrivateKeyPassword)?;
+            imp::Identity::from_encrypted_pkcs8_der(&chain, key, &password)?
+        }
+    };
+    Ok(Identity(identity))
+}
+
+/// The kind of secret requested from a `SecretProvider`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SecretKind {
+    /// The password of a PKCS #12 archive.
+    Pkcs12Password,
+    /// The password used to verify the integrity of a Java KeyStore.
+    KeyStorePassword,
+    /// The password of an encrypted private key, such as a Java KeyStore key entry or an
+    /// encrypted PEM key.
+    PrivateKeyPassword,
+    /// The PIN of a smart card or hardware token holding a private key.
+    Pin,
+    #[doc(hidden)]
+    __NonExhaustive,
+}
+
+/// A source of the passwords needed to load identities.
+///
+/// Secrets are only requested when they're needed, so an interactive tool can prompt the user
+/// lazily and a service can fetch them from a secrets manager rather than holding them up front.
+/// The trait is implemented for closures taking a `SecretKind`.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use native_tls::{Identity, SecretKind};
+/// use std::env;
+/// use std::fs;
+///
+/// let buf = fs::read("identity.pem").unwrap();
+/// let identity = Identity::from_bytes_with_provider(&buf, &|kind| match kind {
+///     SecretKind::PrivateKeyPassword => env::var("KEY_PASSWORD").ok(),
+///     _ => None,
+/// })
+/// .unwrap();
+/// ```
+pub trait SecretProvider {
+    /// Returns the requested secret, or `None` if it is unavailable.
+    fn secret(&self, kind: SecretKind) -> Option<String>;
+}
+
+impl<F> SecretProvider for F
+where
+    F: Fn(SecretKind) -> Option<String>,
+{
+    fn secret(&self, kind: SecretKind) -> Option<String> {
+        self(kind)
+    }
+}
+
+fn secret(provider: &dyn SecretProvider, kind: SecretKind) -> Result<String> {
+    match provider.secret(kind) {
+        Some(secret) => Ok(secret),
+        None => Err(imp::Error::from(pem::Error::NoSecret).into()),
+    }
 }
 
 /// An X509 certificate.
+///
+/// Certificates are `Send` and `Sync` with every backend.
 #[derive(Clone)]
 pub struct Certificate(imp::Certificate);
 
+impl fmt::Debug for Certificate {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = fmt.debug_struct("Certificate");
+        if let Ok(der) = self.to_der() {
+            let subject = der::subject(&der).and_then(der::display_name);
+            let issuer = der::issuer(&der).and_then(der::display_name);
+            if let (Ok(subject), Ok(issuer)) = (subject, issuer) {
+                debug.field("subject", &subject).field("issuer", &issuer);
+            }
+        }
+        debug.finish()
+    }
+}
+
 impl Certificate {
     /// Parses a DER-formatted X509 certificate.
     pub fn from_der(der: &[u8]) -> Result<Certificate> {
@@ -203,10 +525,378 @@ impl Certificate {
         let der = self.0.to_der()?;
         Ok(der)
     }
+
+    /// Returns the PEM-encoded representation of this certificate.
+    pub fn to_pem(&self) -> Result<Vec<u8>> {
+        let der = self.0.to_der()?;
+        Ok(pem::block("CERTIFICATE", &der).into_bytes())
+    }
+
+    /// Returns the time from which this certificate is valid.
+    pub fn not_before(&self) -> Result<SystemTime> {
+        let (not_before, _) = der::validity(&self.to_der()?).map_err(imp::Error::from)?;
+        Ok(not_before)
+    }
+
+    /// Returns the time at which this certificate expires.
+    pub fn not_after(&self) -> Result<SystemTime> {
+        let (_, not_after) = der::validity(&self.to_der()?).map_err(imp::Error::from)?;
+        Ok(not_after)
+    }
+
+    /// Returns the URLs of the OCSP responders listed in this certificate's authority information
+    /// access extension.
+    pub fn ocsp_responders(&self) -> Result<Vec<String>> {
+        let der = self.to_der()?;
+        let locations = der::access_locations(&der, der::OCSP_OID).map_err(imp::Error::from)?;
+        urls(&locations)
+    }
+
+    /// Returns the URLs of the issuer's certificate listed in this certificate's authority
+    /// information access extension.
+    pub fn ca_issuers(&self) -> Result<Vec<String>> {
+        let der = self.to_der()?;
+        let locations =
+            der::access_locations(&der, der::CA_ISSUERS_OID).map_err(imp::Error::from)?;
+        urls(&locations)
+    }
+
+    /// Returns the URLs listed in this certificate's CRL distribution points extension.
+    ///
+    /// Distribution points named relative to the CRL issuer are skipped.
+    pub fn crl_distribution_points(&self) -> Result<Vec<String>> {
+        let der = self.to_der()?;
+        let points = der::crl_distribution_points(&der).map_err(imp::Error::from)?;
+        urls(&points)
+    }
+
+    /// Returns the fingerprint of this certificate: the hash of its DER encoding.
+    pub fn fingerprint(&self, digest: Digest) -> Result<Vec<u8>> {
+        let fingerprint = self.0.fingerprint(digest)?;
+        Ok(fingerprint)
+    }
+
+    /// Returns the SHA-256 fingerprint of this certificate's DER encoding.
+    ///
+    /// This is equivalent to `fingerprint(Digest::Sha256)`.
+    pub fn fingerprint_sha256(&self) -> Result<Vec<u8>> {
+        self.fingerprint(Digest::Sha256)
+    }
+
+    /// Determines if this certificate's signature was made by the key of `issuer`.
+    ///
+    /// Only the signature is checked; neither certificate is otherwise validated, and the
+    /// certificates' names aren't compared. RSA-PSS signatures are not supported by the Security
+    /// Framework backend.
+    pub fn verify_signed_by(&self, issuer: &Certificate) -> Result<bool> {
+        let valid = self.0.verify_signed_by(&issuer.0)?;
+        Ok(valid)
+    }
+
+    /// Parses the trusted certificate entries of a Java KeyStore (JKS or JCEKS) trust store.
+    ///
+    /// `password` is used to verify the integrity of the keystore. Private key entries are
+    /// ignored.
+    pub fn stack_from_jks(der: &[u8], password: &str) -> Result<Vec<Certificate>> {
+        let certs = jks::trusted_certs(der, password).map_err(imp::Error::from)?;
+        certs.iter().map(|c| Certificate::from_der(c)).collect()
+    }
+
+    /// Parses a `rustls-pki-types` DER-formatted X509 certificate.
+    ///
+    /// Requires the `rustls-pki-types` Cargo feature.
+    #[cfg(feature = "rustls-pki-types")]
+    pub fn from_certificate_der(der: &CertificateDer) -> Result<Certificate> {
+        Certificate::from_der(der)
+    }
+
+    /// Returns the `rustls-pki-types` DER-encoded representation of this certificate.
+    ///
+    /// Requires the `rustls-pki-types` Cargo feature.
+    #[cfg(feature = "rustls-pki-types")]
+    pub fn to_certificate_der(&self) -> Result<CertificateDer<'static>> {
+        let der = self.to_der()?;
+        Ok(CertificateDer::from(der))
+    }
+}
+
+/// An X509 certificate revocation list.
+#[derive(Clone)]
+pub struct Crl(imp::Crl);
+
+impl fmt::Debug for Crl {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = fmt.debug_struct("Crl");
+        if let Ok(der) = self.to_der() {
+            if let Ok(issuer) = der::crl_issuer(&der).and_then(der::display_name) {
+                debug.field("issuer", &issuer);
+            }
+        }
+        debug.finish()
+    }
+}
+
+impl Crl {
+    /// Parses a DER-formatted certificate revocation list.
+    pub fn from_der(der: &[u8]) -> Result<Crl> {
+        let crl = imp::Crl::from_der(der)?;
+        Ok(Crl(crl))
+    }
+
+    /// Parses a PEM-formatted certificate revocation list.
+    pub fn from_pem(pem: &[u8]) -> Result<Crl> {
+        let crl = imp::Crl::from_pem(pem)?;
+        Ok(Crl(crl))
+    }
+
+    /// Returns the DER-encoded representation of this certificate revocation list.
+    pub fn to_der(&self) -> Result<Vec<u8>> {
+        let der = self.0.to_der()?;
+        Ok(der)
+    }
+}
+
+/// Hash algorithms used to compute certificate fingerprints.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Digest {
+    /// SHA-1.
+    ///
+    /// # Warning
+    ///
+    /// SHA-1 is not collision resistant. Its fingerprints are suitable for display and for
+    /// interoperating with tools which only report SHA-1 fingerprints, but should not be relied on
+    /// to identify certificates.
+    Sha1,
+    /// SHA-256.
+    Sha256,
+    /// SHA-384.
+    Sha384,
+    /// SHA-512.
+    Sha512,
+    #[doc(hidden)]
+    __NonExhaustive,
+}
+
+/// A pinned public key: the SHA-256 hash of a certificate's DER-encoded subject public key info.
+///
+/// This is the value of the `pin-sha256` directive of HTTP Public Key Pinning, which can be
+/// produced with:
+///
+/// ```sh
+/// openssl x509 -in cert.pem -pubkey -noout \
+///     | openssl pkey -pubin -outform der \
+///     | openssl dgst -sha256 -binary \
+///     | base64
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Sha256Pin([u8; 32]);
+
+impl Sha256Pin {
+    /// Creates a pin from a raw SHA-256 hash.
+    pub fn new(hash: [u8; 32]) -> Sha256Pin {
+        Sha256Pin(hash)
+    }
+
+    /// Parses a base64-encoded pin, as used in `pin-sha256` directives.
+    ///
+    /// Returns `None` if the string isn't valid base64 or doesn't encode a SHA-256 hash.
+    pub fn from_base64(pin: &str) -> Option<Sha256Pin> {
+        let hash = pem::base64_decode(pin).ok()?;
+        if hash.len() != 32 {
+            return None;
+        }
+        let mut pin = [0; 32];
+        pin.copy_from_slice(&hash);
+        Some(Sha256Pin(pin))
+    }
+
+    /// Returns the pin of a certificate's public key.
+    pub fn from_certificate(cert: &Certificate) -> Result<Sha256Pin> {
+        let hash = (cert.0).public_key_sha256()?;
+        let mut pin = [0; 32];
+        pin.copy_from_slice(&hash);
+        Ok(Sha256Pin(pin))
+    }
+
+    /// Returns the SHA-256 hash.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Sha256Pin {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&pem::base64_encode(&self.0))
+    }
+}
+
+/// A user-approved exception to certificate verification for a single host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateException {
+    host: String,
+    fingerprint: Vec<u8>,
+    expires: Option<SystemTime>,
+}
+
+impl CertificateException {
+    /// Creates an exception trusting the certificate with the specified SHA-256 fingerprint when
+    /// connecting to `host`.
+    ///
+    /// If `expires` is set, the exception is ignored from that time onwards.
+    pub fn new(host: &str, fingerprint: &[u8], expires: Option<SystemTime>) -> CertificateException {
+        CertificateException {
+            host: host.to_owned(),
+            fingerprint: fingerprint.to_vec(),
+            expires,
+        }
+    }
+
+    /// Returns the host the exception applies to.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the SHA-256 fingerprint of the trusted certificate.
+    pub fn fingerprint(&self) -> &[u8] {
+        &self.fingerprint
+    }
+
+    /// Returns the time at which the exception expires, if any.
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+    }
+
+    fn matches(&self, host: &str, fingerprint: &[u8], now: SystemTime) -> bool {
+        self.host.eq_ignore_ascii_case(host)
+            && self.fingerprint == fingerprint
+            && match self.expires {
+                Some(expires) => expires > now,
+                None => true,
+            }
+    }
+}
+
+type UnknownCertificateCallback = dyn Fn(&str, &Certificate) + Sync + Send;
+
+fn urls(urls: &[&[u8]]) -> Result<Vec<String>> {
+    urls.iter()
+        .map(|url| match str::from_utf8(url) {
+            Ok(url) => Ok(url.to_owned()),
+            Err(_) => Err(imp::Error::from(der::Error).into()),
+        })
+        .collect()
+}
+
+/// A store of user-approved exceptions to certificate verification.
+///
+/// When a server's certificate fails verification, a `TlsConnector` configured with this store
+/// will accept it anyway if an unexpired exception for the host matches the certificate's
+/// SHA-256 fingerprint. Otherwise, the unknown certificate callback is invoked before the
+/// handshake fails, allowing the application to ask the user whether the certificate should be
+/// trusted in the future.
+///
+/// Clones share the same set of exceptions, so exceptions added after a connector has been
+/// built apply to its future connections. The store is not persisted automatically; use
+/// `exceptions` to save it and `add` to restore it.
+#[derive(Clone, Default)]
+pub struct CertificateExceptions(Arc<ExceptionsInner>);
+
+#[derive(Default)]
+struct ExceptionsInner {
+    exceptions: Mutex<Vec<CertificateException>>,
+    callback: Mutex<Option<Arc<UnknownCertificateCallback>>>,
+}
+
+impl fmt::Debug for CertificateExceptions {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("CertificateExceptions")
+            .field("exceptions", &*self.0.exceptions.lock().unwrap())
+            .finish()
+    }
+}
+
+impl CertificateExceptions {
+    /// Creates an empty store.
+    pub fn new() -> CertificateExceptions {
+        CertificateExceptions::default()
+    }
+
+    /// Adds an exception to the store, replacing any existing exception for the same host and
+    /// certificate.
+    pub fn add(&self, exception: CertificateException) {
+        let mut exceptions = self.0.exceptions.lock().unwrap();
+        exceptions.retain(|e| {
+            !e.host.eq_ignore_ascii_case(&exception.host) || e.fingerprint != exception.fingerprint
+        });
+        exceptions.push(exception);
+    }
+
+    /// Removes all exceptions for the specified host.
+    pub fn remove(&self, host: &str) {
+        self.0
+            .exceptions
+            .lock()
+            .unwrap()
+            .retain(|e| !e.host.eq_ignore_ascii_case(host));
+    }
+
+    /// Returns the exceptions currently in the store.
+    pub fn exceptions(&self) -> Vec<CertificateException> {
+        self.0.exceptions.lock().unwrap().clone()
+    }
+
+    /// Sets a callback invoked with the host and certificate whenever a certificate fails
+    /// verification and no exception matches it.
+    pub fn set_unknown_certificate_callback<F>(&self, callback: F)
+    where
+        F: Fn(&str, &Certificate) + Sync + Send + 'static,
+    {
+        *self.0.callback.lock().unwrap() = Some(Arc::new(callback));
+    }
+
+    fn verify(&self, host: &str, cert: &Certificate) -> bool {
+        let fingerprint = match cert.fingerprint_sha256() {
+            Ok(fingerprint) => fingerprint,
+            Err(_) => return false,
+        };
+
+        let now = SystemTime::now();
+        let trusted = self
+            .0
+            .exceptions
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|e| e.matches(host, &fingerprint, now));
+
+        if !trusted {
+            // clone the callback out so it can add exceptions without deadlocking
+            let callback = self.0.callback.lock().unwrap().clone();
+            if let Some(callback) = callback {
+                callback(host, cert);
+            }
+        }
+
+        trusted
+    }
+}
+
+/// Returns a description of the TLS implementation in use and its version.
+///
+/// With OpenSSL this is the version string of the linked OpenSSL or LibreSSL library. With
+/// SChannel and Secure Transport, which are updated along with the operating system, it includes
+/// the operating system version. The format is unspecified; it is intended for bug reports and
+/// diagnostics.
+pub fn backend_version() -> String {
+    imp::backend_version()
 }
 
 /// A TLS stream which has been interrupted midway through the handshake process.
-pub struct MidHandshakeTlsStream<S>(imp::MidHandshakeTlsStream<S>);
+pub struct MidHandshakeTlsStream<S>(
+    imp::MidHandshakeTlsStream<S>,
+    Option<Box<PeerCheck>>,
+    Option<Instant>,
+);
 
 impl<S> fmt::Debug for MidHandshakeTlsStream<S>
 where
@@ -227,6 +917,13 @@ impl<S> MidHandshakeTlsStream<S> {
     pub fn get_mut(&mut self) -> &mut S {
         self.0.get_mut()
     }
+
+    /// Returns the hostname requested by the client through SNI, if it has been received.
+    ///
+    /// See `TlsStream::sni_hostname`.
+    pub fn sni_hostname(&self) -> Result<Option<String>> {
+        Ok(self.0.sni_hostname()?)
+    }
 }
 
 impl<S> MidHandshakeTlsStream<S>
@@ -240,66 +937,167 @@ where
     /// Note that the error may not be fatal. For example if the underlying
     /// stream is an asynchronous one then `HandshakeError::WouldBlock` may
     /// just mean to wait for more I/O to happen later.
+    ///
+    /// If a handshake timeout is configured and has elapsed by the time the handshake is
+    /// interrupted again, a `HandshakeError::Failure` is returned instead.
     pub fn handshake(self) -> result::Result<TlsStream<S>, HandshakeError<S>> {
-        match self.0.handshake() {
-            Ok(s) => Ok(TlsStream(s)),
-            Err(e) => Err(e.into()),
-        }
+        finish_handshake(self.0.handshake(), self.1, self.2)
     }
 }
 
-/// An error returned from `ClientBuilder::handshake`.
-#[derive(Debug)]
-pub enum HandshakeError<S> {
-    /// A fatal error.
-    Failure(Error),
+fn io_failure<S>(e: io::Error) -> HandshakeError<S> {
+    HandshakeError::Failure(Error::from(imp::Error::from(e)))
+}
 
-    /// A stream interrupted midway through the handshake process due to a
-    /// `WouldBlock` error.
-    ///
-    /// Note that this is not a fatal error and it should be safe to call
-    /// `handshake` at a later time once the stream is ready to perform I/O
-    /// again.
-    WouldBlock(MidHandshakeTlsStream<S>),
+fn handshake_deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.and_then(|timeout| Instant::now().checked_add(timeout))
 }
 
-impl<S> error::Error for HandshakeError<S>
+fn finish_handshake<S>(
+    result: result::Result<imp::TlsStream<S>, imp::HandshakeError<S>>,
+    check: Option<Box<PeerCheck>>,
+    deadline: Option<Instant>,
+) -> result::Result<TlsStream<S>, HandshakeError<S>>
 where
-    S: Any + fmt::Debug,
+    S: io::Read + io::Write,
 {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match *self {
-            HandshakeError::Failure(ref e) => Some(e),
-            HandshakeError::WouldBlock(_) => None,
+    match result {
+        Ok(s) => {
+            if let Some(ref check) = check {
+                check.verify(&s).map_err(HandshakeError::Failure)?;
+            }
+            Ok(TlsStream(s, Traffic::default()))
         }
+        Err(imp::HandshakeError::WouldBlock(s)) => match deadline {
+            Some(deadline) if deadline <= Instant::now() => {
+                let e = io::Error::new(io::ErrorKind::TimedOut, "the handshake timed out");
+                Err(io_failure(e))
+            }
+            _ => Err(HandshakeError::WouldBlock(MidHandshakeTlsStream(s, check, deadline))),
+        },
+        Err(e) => Err(e.into()),
     }
 }
 
-impl<S> fmt::Display for HandshakeError<S>
-where
-    S: Any + fmt::Debug,
-{
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            HandshakeError::Failure(ref e) => fmt::Display::fmt(e, fmt),
-            HandshakeError::WouldBlock(_) => fmt.write_str("the handshake process was interrupted"),
-        }
+// Verifies the server's certificate against the connector's policies, pins and verification
+// callback once the handshake completes, on top of the backend's own verification.
+struct PeerCheck {
+    domain: Option<String>,
+    policy: policy::Policy,
+    callback: Option<VerifyCallback>,
+    pins: Arc<[Sha256Pin]>,
+}
+
+impl PeerCheck {
+    fn verify<S>(&self, stream: &imp::TlsStream<S>) -> Result<()>
+    where
+        S: io::Read + io::Write,
+    {
+        // Only the path the backend verified is inspected, since a server can send any other
+        // certificates along with it. Without verification, only the leaf can be relied upon.
+        let certs = if self.policy.verified {
+            stream.verified_certificate_chain()?
+        } else {
+            stream.peer_certificate()?.into_iter().collect()
+        };
+        if !self.policy.is_empty() {
+            let chain = certs
+                .iter()
+                .map(|cert| cert.to_der())
+                .collect::<result::Result<Vec<_>, _>>()?;
+            self.policy
+                .verify(&chain, self.domain.as_deref())
+                .map_err(|e| Error::from(imp::Error::from(e)))?;
+        }
+
+        if !self.pins.is_empty() {
+            let mut pinned = false;
+            for cert in &certs {
+                let hash = cert.public_key_sha256()?;
+                if self.pins.iter().any(|pin| pin.0[..] == hash[..]) {
+                    pinned = true;
+                    break;
+                }
+            }
+            if !pinned {
+                return Err(Error::from(imp::Error::from(policy::Error::Pin)));
+            }
+        }
+
+        if let Some(ref callback) = self.callback {
+            let chain = stream.peer_certificate_chain()?;
+            let chain = chain.into_iter().map(Certificate).collect::<Vec<_>>();
+            if !(callback.0)(self.domain.as_deref().unwrap_or(""), &chain) {
+                return Err(Error::from(imp::Error::from(policy::Error::Rejected)));
+            }
+        }
+        Ok(())
+    }
+}
+
+type VerifyFn = dyn Fn(&str, &[Certificate]) -> bool + Sync + Send;
+
+#[derive(Clone)]
+struct VerifyCallback(Arc<VerifyFn>);
+
+impl fmt::Debug for VerifyCallback {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("VerifyCallback").finish()
+    }
+}
+
+/// An error returned from `ClientBuilder::handshake`.
+#[derive(Debug)]
+pub enum HandshakeError<S> {
+    /// A fatal error.
+    Failure(Error),
+
+    /// A stream interrupted midway through the handshake process due to a
+    /// `WouldBlock` error.
+    ///
+    /// Note that this is not a fatal error and it should be safe to call
+    /// `handshake` at a later time once the stream is ready to perform I/O
+    /// again.
+    WouldBlock(MidHandshakeTlsStream<S>),
+}
+
+impl<S> error::Error for HandshakeError<S>
+where
+    S: Any + fmt::Debug,
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            HandshakeError::Failure(ref e) => Some(e),
+            HandshakeError::WouldBlock(_) => None,
+        }
+    }
+}
+
+impl<S> fmt::Display for HandshakeError<S>
+where
+    S: Any + fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandshakeError::Failure(ref e) => fmt::Display::fmt(e, fmt),
+            HandshakeError::WouldBlock(_) => fmt.write_str("the handshake process was interrupted"),
+        }
     }
 }
 
 impl<S> From<imp::HandshakeError<S>> for HandshakeError<S> {
     fn from(e: imp::HandshakeError<S>) -> HandshakeError<S> {
         match e {
-            imp::HandshakeError::Failure(e) => HandshakeError::Failure(Error(e)),
+            imp::HandshakeError::Failure(e) => HandshakeError::Failure(Error::from(e)),
             imp::HandshakeError::WouldBlock(s) => {
-                HandshakeError::WouldBlock(MidHandshakeTlsStream(s))
+                HandshakeError::WouldBlock(MidHandshakeTlsStream(s, None, None))
             }
         }
     }
 }
 
 /// SSL/TLS protocol versions.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Protocol {
     /// The SSL 3.0 protocol.
     ///
@@ -314,11 +1112,134 @@ pub enum Protocol {
     Tlsv11,
     /// The TLS 1.2 protocol.
     Tlsv12,
+    /// The TLS 1.3 protocol.
+    ///
+    /// This requires OpenSSL 1.1.1 or newer, or a version of Windows whose SChannel supports it.
+    /// Secure Transport does not implement TLS 1.3, so it is never negotiated on Apple platforms.
+    Tlsv13,
+    #[doc(hidden)]
+    __NonExhaustive,
+}
+
+/// A TLS cipher suite, identified by its IANA-assigned value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CipherSuite(u16);
+
+impl CipherSuite {
+    /// Creates a cipher suite from its IANA-assigned value.
+    pub fn from_u16(id: u16) -> CipherSuite {
+        CipherSuite(id)
+    }
+
+    /// Looks up a cipher suite by its IANA name, such as `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`.
+    ///
+    /// `None` is returned for names unknown to this crate.
+    pub fn from_name(name: &str) -> Option<CipherSuite> {
+        cipher::id(name).map(CipherSuite)
+    }
+
+    /// Returns the IANA-assigned value of the cipher suite.
+    pub fn to_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// Returns the IANA name of the cipher suite, such as `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`.
+    ///
+    /// `None` is returned for cipher suites unknown to this crate.
+    pub fn name(&self) -> Option<&'static str> {
+        cipher::name(self.0)
+    }
+}
+
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => fmt.write_str(name),
+            None => write!(fmt, "0x{:04X}", self.0),
+        }
+    }
+}
+
+/// Levels of server certificate verification performed by a `TlsConnector`.
+///
+/// These correspond to the `require`, `verify-ca` and `verify-full` SSL modes used by database
+/// clients such as PostgreSQL's.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VerificationMode {
+    /// Encrypt the connection, but do not verify the server's certificate at all.
+    ///
+    /// # Warning
+    ///
+    /// This mode provides no protection against man-in-the-middle attacks.
+    Require,
+    /// Verify that the server's certificate chains to a trusted root, but not that it is valid
+    /// for the server's hostname.
+    ///
+    /// # Warning
+    ///
+    /// Any server with a certificate from a trusted root can impersonate any other.
+    VerifyCa,
+    /// Verify that the server's certificate chains to a trusted root and is valid for the
+    /// server's hostname.
+    VerifyFull,
+    #[doc(hidden)]
+    __NonExhaustive,
+}
+
+/// A TLS implementation used by a `TlsConnector` or `TlsAcceptor`.
+///
+/// The backend is chosen at runtime through `TlsConnectorBuilder::backend` and
+/// `TlsAcceptorBuilder::backend`. The native backend is always compiled in, and other backends are
+/// added by Cargo features.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// The platform's implementation: SChannel on Windows, Secure Transport on macOS and iOS, and
+    /// OpenSSL elsewhere.
+    Native,
+    /// The rustls implementation. See `TlsConnector::rustls_builder` and
+    /// `TlsAcceptor::rustls_builder` for its limitations.
+    ///
+    /// Requires the `rustls` Cargo feature.
+    #[cfg(feature = "rustls")]
+    Rustls,
+    #[doc(hidden)]
+    __NonExhaustive,
+}
+
+impl Backend {
+    fn imp(self) -> imp::Backend {
+        match self {
+            Backend::Native => imp::Backend::Native,
+            #[cfg(feature = "rustls")]
+            Backend::Rustls => imp::Backend::Rustls,
+            Backend::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+/// Restrictions on wildcard certificate names, applied in addition to the backend's hostname
+/// verification.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WildcardPolicy {
+    /// Wildcards are matched as usual by the backend.
+    Allow,
+    /// Wildcards are only matched when they make up the entire left-most label of a name, such as
+    /// `*.example.com`, and never as part of a label, such as `w*.example.com`.
+    SingleLabel,
+    /// Wildcards are not matched directly below common registry domains of country code TLDs,
+    /// such as `*.co.uk` or `*.com.au`.
+    ///
+    /// This checks a fixed list of registry labels rather than the public suffix list, so
+    /// wildcards below other public suffixes, such as `*.github.io`, are still matched.
+    NoRegistryDomain,
+    /// Wildcards are never matched.
+    Disallow,
     #[doc(hidden)]
     __NonExhaustive,
 }
 
 /// A builder for `TlsConnector`s.
+#[derive(Clone, Debug)]
 pub struct TlsConnectorBuilder {
     identity: Option<Identity>,
     min_protocol: Option<Protocol>,
@@ -328,6 +1249,43 @@ pub struct TlsConnectorBuilder {
     accept_invalid_hostnames: bool,
     use_sni: bool,
     disable_built_in_roots: bool,
+    max_send_fragment: Option<usize>,
+    cipher_suites: Vec<CipherSuite>,
+    certificate_exceptions: Option<CertificateExceptions>,
+    allow_unsafe_legacy_renegotiation: bool,
+    allow_partial_chains: bool,
+    wildcard_policy: WildcardPolicy,
+    max_certificate_validity: Option<Duration>,
+    reject_sha1_signatures: bool,
+    verify_callback: Option<VerifyCallback>,
+    pinned_public_keys: Vec<Sha256Pin>,
+    require_ocsp_staple: bool,
+    check_revocation: bool,
+    crls: Vec<Crl>,
+    legacy_fallback: Option<LegacyProfile>,
+    session_cache: bool,
+    session_cache_size: usize,
+    early_data: bool,
+    handshake_timeout: Option<Duration>,
+    backend: imp::Backend,
+    #[cfg(feature = "alpn")]
+    alpn: Vec<String>,
+    #[cfg(target_os = "windows")]
+    use_system_protocols: bool,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    legacy_ciphers: bool,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    middlebox_compat: Option<bool>,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    expected_raw_public_keys: Vec<Vec<u8>>,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    rekey_after_bytes: Option<u64>,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    rekey_interval: Option<Duration>,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    sm_cipher_suites: bool,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    context_callback: Option<imp::ContextCallback>,
 }
 
 impl TlsConnectorBuilder {
@@ -341,7 +1299,8 @@ impl TlsConnectorBuilder {
     ///
     /// A value of `None` enables support for the oldest protocols supported by the implementation.
     ///
-    /// Defaults to `Some(Protocol::Tlsv10)`.
+    /// Defaults to `Some(Protocol::Tlsv12)`. Peers which only support older protocols can be
+    /// accommodated by lowering this to `Some(Protocol::Tlsv10)`.
     pub fn min_protocol_version(&mut self, protocol: Option<Protocol>) -> &mut TlsConnectorBuilder {
         self.min_protocol = protocol;
         self
@@ -376,6 +1335,20 @@ impl TlsConnectorBuilder {
         self
     }
 
+    /// Controls whether certificates added with `add_root_certificate` are trusted as anchors
+    /// even if they are not self-signed roots.
+    ///
+    /// This allows an issuing intermediate CA to be trusted without also trusting the root it
+    /// chains to. SChannel and Security Framework always treat added certificates as anchors, so
+    /// this only changes the behavior of OpenSSL, which otherwise requires a chain to terminate in
+    /// a self-signed root.
+    ///
+    /// Defaults to `false`.
+    pub fn allow_partial_chains(&mut self, allow: bool) -> &mut TlsConnectorBuilder {
+        self.allow_partial_chains = allow;
+        self
+    }
+
     /// Controls the use of certificate validation.
     ///
     /// Defaults to `false`.
@@ -418,15 +1391,439 @@ impl TlsConnectorBuilder {
         self
     }
 
+    /// Sets the policy for matching wildcard names in the server's certificate.
+    ///
+    /// This is ignored if hostname verification is disabled. The policy is enforced once the
+    /// handshake completes, after the client's certificate has been sent; see `verify_callback`.
+    ///
+    /// Defaults to `WildcardPolicy::Allow`.
+    pub fn wildcard_policy(&mut self, policy: WildcardPolicy) -> &mut TlsConnectorBuilder {
+        self.wildcard_policy = policy;
+        self
+    }
+
+    /// Sets the maximum validity period of the server's certificate.
+    ///
+    /// Certificates valid for longer are rejected, even if the backend would otherwise accept them.
+    /// For example, the CA/Browser Forum limits publicly trusted certificates to 398 days. This is
+    /// ignored if certificate validation is disabled. As with `verify_callback`, the check only
+    /// runs once the handshake completes.
+    ///
+    /// Defaults to `None`.
+    pub fn max_certificate_validity(
+        &mut self,
+        max: Option<Duration>,
+    ) -> &mut TlsConnectorBuilder {
+        self.max_certificate_validity = max;
+        self
+    }
+
+    /// Controls the rejection of server certificate chains containing SHA-1 signatures.
+    ///
+    /// Some platform verifiers still accept SHA-1 signed certificates. This rejects them
+    /// regardless, with the exception of the self-signatures of root certificates. Only the
+    /// chain the backend verified is checked, so other certificates the server sends along with it
+    /// are ignored. This is ignored if certificate validation is disabled. The check runs once the
+    /// handshake completes, like `verify_callback`.
+    ///
+    /// Defaults to `false`.
+    pub fn reject_sha1_signatures(&mut self, reject: bool) -> &mut TlsConnectorBuilder {
+        self.reject_sha1_signatures = reject;
+        self
+    }
+
+    /// Sets a store of user-approved exceptions consulted when the server's certificate fails
+    /// verification.
+    ///
+    /// Defaults to no store.
+    pub fn certificate_exceptions(
+        &mut self,
+        exceptions: CertificateExceptions,
+    ) -> &mut TlsConnectorBuilder {
+        self.certificate_exceptions = Some(exceptions);
+        self
+    }
+
+    /// Sets a callback which inspects the server's certificate chain once the handshake completes.
+    ///
+    /// The callback is passed the domain given to `connect`, or an empty string for
+    /// `danger_connect_without_domain`, along with the chain returned by
+    /// `TlsStream::peer_certificate_chain`. The handshake fails if it returns `false`. It runs
+    /// after the backend's own verification, so by default it can only reject chains which the
+    /// backend accepted. To replace the backend's verification instead, for example to trust
+    /// certificates of a custom PKI or on first use, disable it with `danger_accept_invalid_certs`
+    /// and `danger_accept_invalid_hostnames`. The callback then decides on its own whether the
+    /// chain is trusted, and must check that it belongs to the domain.
+    ///
+    /// The callback, like `wildcard_policy`, `max_certificate_validity`,
+    /// `reject_sha1_signatures` and `pin_public_keys`, runs after the backend has completed the
+    /// handshake rather than from within its certificate verification. By then the client has
+    /// already sent its certificate, if an identity is set, along with any early data. A rejected
+    /// server is not sent an alert either; `connect` returns an error and the connection is
+    /// dropped.
+    ///
+    /// Defaults to no callback.
+    pub fn verify_callback<F>(&mut self, callback: F) -> &mut TlsConnectorBuilder
+    where
+        F: Fn(&str, &[Certificate]) -> bool + Sync + Send + 'static,
+    {
+        self.verify_callback = Some(VerifyCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Pins the public keys the server's certificate chain must contain.
+    ///
+    /// Once the handshake completes, it fails unless the public key of at least one certificate in
+    /// the chain the backend verified, from the leaf up to the root it was verified against,
+    /// matches one of the pins. Other certificates the server sends are never matched. The rustls
+    /// backend doesn't include its built-in roots, which it only knows as trust anchors, and
+    /// OpenSSL versions older than 1.1.0 only report the leaf, so prefer pinning the key of the
+    /// leaf or an intermediate, along with a backup key.
+    ///
+    /// Pins are checked in addition to the backend's own verification. If it is disabled by
+    /// `danger_accept_invalid_certs`, only the key of the leaf is matched, and the pins alone
+    /// decide which certificates are trusted. Either way they are only checked once the
+    /// handshake completes, as described for `verify_callback`, so they don't keep a client
+    /// certificate or early data from being sent to a server which fails them.
+    ///
+    /// Defaults to no pins.
+    pub fn pin_public_keys(&mut self, pins: Vec<Sha256Pin>) -> &mut TlsConnectorBuilder {
+        self.pinned_public_keys = pins;
+        self
+    }
+
+    /// Requires the server to staple an OCSP response to the handshake.
+    ///
+    /// The handshake fails unless the server sends a response signed by a trusted certificate
+    /// which shows that its certificate is good and is within its validity period.
+    ///
+    /// This is only supported by the OpenSSL backend, and `build` returns an error on other
+    /// platforms if it is enabled.
+    ///
+    /// Defaults to `false`.
+    pub fn require_ocsp_staple(&mut self, require: bool) -> &mut TlsConnectorBuilder {
+        self.require_ocsp_staple = require;
+        self
+    }
+
+    /// Requires the revocation status of the server's certificate chain to be checked.
+    ///
+    /// When enabled, the handshake fails unless every certificate in the chain is covered by one
+    /// of the CRLs added with `add_crl`, and none of them are revoked. When disabled, the
+    /// backend's own behavior applies: OpenSSL doesn't check revocation, SChannel only consults
+    /// revocation information already cached by the system, and Security Framework follows the
+    /// system's policy.
+    ///
+    /// This is only supported by the OpenSSL backend, and `build` returns an error on other
+    /// platforms if it is enabled.
+    ///
+    /// Defaults to `false`.
+    pub fn check_revocation(&mut self, check: bool) -> &mut TlsConnectorBuilder {
+        self.check_revocation = check;
+        self
+    }
+
+    /// Adds a certificate revocation list to the verification store.
+    ///
+    /// CRLs are only consulted if revocation checking is enabled with `check_revocation`.
+    ///
+    /// This is only supported by the OpenSSL backend, and `build` returns an error on other
+    /// platforms if any CRLs have been added.
+    pub fn add_crl(&mut self, crl: Crl) -> &mut TlsConnectorBuilder {
+        self.crls.push(crl);
+        self
+    }
+
+    /// Sets the TLS implementation used by the connector.
+    ///
+    /// `TlsConnector::rustls_builder` describes the limitations of the rustls backend.
+    ///
+    /// Defaults to `Backend::Native`.
+    pub fn backend(&mut self, backend: Backend) -> &mut TlsConnectorBuilder {
+        self.backend = backend.imp();
+        self
+    }
+
+    /// Sets both `danger_accept_invalid_certs` and `danger_accept_invalid_hostnames` according to
+    /// the specified verification mode.
+    ///
+    /// Defaults to `VerificationMode::VerifyFull`.
+    pub fn verification_mode(&mut self, mode: VerificationMode) -> &mut TlsConnectorBuilder {
+        let (accept_invalid_certs, accept_invalid_hostnames) = match mode {
+            VerificationMode::Require => (true, true),
+            VerificationMode::VerifyCa => (false, true),
+            VerificationMode::VerifyFull => (false, false),
+            VerificationMode::__NonExhaustive => unreachable!(),
+        };
+        self.accept_invalid_certs = accept_invalid_certs;
+        self.accept_invalid_hostnames = accept_invalid_hostnames;
+        self
+    }
+
+    /// Sets the maximum amount of plaintext placed in a single TLS record when writing.
+    ///
+    /// Smaller records reduce latency for interactive or streaming traffic at the cost of
+    /// throughput. The value must be between 512 and 16384 bytes, or `build` returns an error. A
+    /// value of `None` uses the implementation's default, which is typically the protocol maximum
+    /// of 16384 bytes.
+    ///
+    /// Defaults to `None`.
+    pub fn max_send_fragment(&mut self, max: Option<usize>) -> &mut TlsConnectorBuilder {
+        self.max_send_fragment = max;
+        self
+    }
+
+    /// Restricts the cipher suites which may be negotiated to `suites`.
+    ///
+    /// Suites unknown to the implementation are ignored, and an error is returned if none remain.
+    /// With OpenSSL, `build` also returns an error if any protocol version enabled by
+    /// `min_protocol_version` and `max_protocol_version` is left without a suite, so restricting a
+    /// connector to TLS 1.2 suites requires a maximum version of TLS 1.2. This takes precedence
+    /// over the cipher suites enabled by `LegacyProfile::legacy_ciphers`. SChannel selects cipher
+    /// suites according to system-wide policy, and returns an error if any are configured.
+    ///
+    /// An empty list enables the implementation's default suites.
+    ///
+    /// Defaults to an empty list.
+    pub fn cipher_suites(&mut self, suites: &[CipherSuite]) -> &mut TlsConnectorBuilder {
+        self.cipher_suites = suites.to_vec();
+        self
+    }
+
+    /// Controls whether sessions are cached so that later connections to the same domain can
+    /// resume them with an abbreviated handshake.
+    ///
+    /// Sessions are only shared between connections made by the same `TlsConnector`, and are
+    /// keyed by the domain passed to `connect`. With OpenSSL the connector keeps its own cache;
+    /// with SChannel the system's session cache is used. The Security Framework backend does not
+    /// support session resumption and ignores this setting.
+    ///
+    /// OpenSSL will not resume the session of a connection which was dropped without calling
+    /// `TlsStream::shutdown`. TLS 1.3 sessions are only offered once, so later connections
+    /// resume other sessions issued by the server or perform a full handshake.
+    ///
+    /// Defaults to `false`.
+    pub fn session_cache(&mut self, session_cache: bool) -> &mut TlsConnectorBuilder {
+        self.session_cache = session_cache;
+        self
+    }
+
+    /// Sets the maximum number of sessions kept by the session cache.
+    ///
+    /// The least recently established sessions are evicted first. This is only used by the
+    /// OpenSSL backend, as SChannel's cache is managed by the system.
+    ///
+    /// Defaults to 256.
+    pub fn session_cache_size(&mut self, size: usize) -> &mut TlsConnectorBuilder {
+        self.session_cache_size = size;
+        self
+    }
+
+    /// Controls whether `TlsConnector::connect_with_early_data` sends its data as TLS 1.3 early
+    /// data (0-RTT).
+    ///
+    /// Early data can only be sent when resuming a session with a server which allows it, so this
+    /// requires `session_cache` to be enabled. It is supported by OpenSSL 1.1.1 or newer and
+    /// rustls, and ignored by the other backends.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// # Warning
+    ///
+    /// Early data is not protected against replay: an attacker can deliver it to the server
+    /// several times. Only requests which are safe to repeat should be sent this way.
+    pub fn enable_early_data(&mut self, enable: bool) -> &mut TlsConnectorBuilder {
+        self.early_data = enable;
+        self
+    }
+
+    /// Controls interoperability with servers which do not support secure renegotiation
+    /// (RFC 5746).
+    ///
+    /// This only has an effect with OpenSSL, which refuses to connect to such servers by default
+    /// as of version 3.0. SChannel and Secure Transport follow system-wide policy.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// # Warning
+    ///
+    /// Unsafe legacy renegotiation allows an attacker to prefix data of their choosing to the
+    /// connection (CVE-2009-3555). It should only be enabled to talk to legacy servers which
+    /// cannot be upgraded.
+    pub fn danger_allow_unsafe_legacy_renegotiation(
+        &mut self,
+        allow: bool,
+    ) -> &mut TlsConnectorBuilder {
+        self.allow_unsafe_legacy_renegotiation = allow;
+        self
+    }
+
+    /// Requests specific protocols through ALPN (Application-Layer Protocol Negotiation), in order
+    /// of preference.
+    ///
+    /// The protocol selected by the server is available from `TlsStream::negotiated_alpn`.
+    ///
+    /// Defaults to no protocols.
+    ///
+    /// Requires the `alpn` Cargo feature.
+    #[cfg(feature = "alpn")]
+    pub fn request_alpns(&mut self, protocols: &[&str]) -> &mut TlsConnectorBuilder {
+        self.alpn = protocols.iter().map(|s| (*s).to_string()).collect();
+        self
+    }
+
+    /// Sets the profile `TlsConnector::connect_with_fallback` retries a handshake with when the
+    /// server appears to only support older protocol versions or cipher suites.
+    ///
+    /// A value of `None` disables the retry.
+    ///
+    /// Defaults to `None`.
+    pub fn legacy_fallback(&mut self, profile: Option<LegacyProfile>) -> &mut TlsConnectorBuilder {
+        self.legacy_fallback = profile;
+        self
+    }
+
+    /// Sets the maximum total time a handshake may take.
+    ///
+    /// The time is counted from the start of the handshake, across every call to
+    /// `MidHandshakeTlsStream::handshake`. Once it has elapsed, a handshake which is interrupted
+    /// again fails with an I/O error of kind `io::ErrorKind::TimedOut`.
+    ///
+    /// The handshake can only be interrupted if the stream stops blocking. `connect_tcp` bounds
+    /// each read and write on its socket by the time remaining, but a blocking stream passed to
+    /// `connect` needs timeouts of its own, or a silent server can stall the handshake forever.
+    ///
+    /// Defaults to `None`.
+    pub fn handshake_timeout(&mut self, timeout: Option<Duration>) -> &mut TlsConnectorBuilder {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn set_legacy_ciphers(&mut self, legacy_ciphers: bool) {
+        self.legacy_ciphers = legacy_ciphers;
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "windows", target_os = "ios"))]
+    fn set_legacy_ciphers(&mut self, _: bool) {}
+
     /// Creates a new `TlsConnector`.
     pub fn build(&self) -> Result<TlsConnector> {
-        let connector = imp::TlsConnector::new(self)?;
-        Ok(TlsConnector(connector))
+        self.build_with(imp::TlsConnector::new)
+    }
+
+    /// Creates a new `DtlsConnector`.
+    ///
+    /// Protocol versions are mapped to their DTLS counterparts: TLS 1.1 and older to DTLS 1.0,
+    /// and TLS 1.2 and newer to DTLS 1.2.
+    ///
+    /// DTLS is currently only supported by the OpenSSL backend.
+    pub fn build_dtls(&self) -> Result<dtls::DtlsConnector> {
+        self.build_with(imp::TlsConnector::new_dtls)
+            .map(dtls::DtlsConnector)
+    }
+
+    fn build_with(
+        &self,
+        new: fn(&TlsConnectorBuilder) -> result::Result<imp::TlsConnector, imp::Error>,
+    ) -> Result<TlsConnector> {
+        let fallback = match self.legacy_fallback {
+            Some(profile) => {
+                let mut builder = self.clone();
+                builder.min_protocol = profile.min_protocol;
+                builder.set_legacy_ciphers(profile.legacy_ciphers);
+                builder.legacy_fallback = None;
+                Some(Arc::new(builder.build_with(new)?))
+            }
+            None => None,
+        };
+
+        let connector = new(self)?;
+        let mut policy = policy::Policy::default();
+        if !self.accept_invalid_hostnames && self.wildcard_policy != WildcardPolicy::Allow {
+            policy.wildcards = Some(self.wildcard_policy);
+        }
+        // OpenSSL and rustls match IP addresses themselves, but the other backends leave it to us.
+        if !self.accept_invalid_hostnames && !self.backend.checks_ip_addresses() {
+            policy.ip_addresses = true;
+        }
+        if !self.accept_invalid_certs {
+            policy.verified = true;
+            policy.max_validity = self.max_certificate_validity;
+            policy.reject_sha1 = self.reject_sha1_signatures;
+        }
+        Ok(TlsConnector(
+            Arc::new(connector),
+            policy,
+            fallback,
+            self.verify_callback.clone(),
+            Arc::from(&self.pinned_public_keys[..]),
+            self.handshake_timeout,
+        ))
     }
 }
 
+/// Relaxed settings for servers which only support older protocol versions or cipher suites.
+///
+/// See `TlsConnectorBuilder::legacy_fallback`.
+#[derive(Debug, Copy, Clone)]
+pub struct LegacyProfile {
+    min_protocol: Option<Protocol>,
+    legacy_ciphers: bool,
+}
+
+impl Default for LegacyProfile {
+    fn default() -> LegacyProfile {
+        LegacyProfile::new()
+    }
+}
+
+impl LegacyProfile {
+    /// Returns a profile accepting TLS 1.0 and later along with legacy cipher suites.
+    pub fn new() -> LegacyProfile {
+        LegacyProfile {
+            min_protocol: Some(Protocol::Tlsv10),
+            legacy_ciphers: true,
+        }
+    }
+
+    /// Sets the minimum supported protocol version, replacing the connector's.
+    ///
+    /// Defaults to `Some(Protocol::Tlsv10)`.
+    pub fn min_protocol_version(&mut self, protocol: Option<Protocol>) -> &mut LegacyProfile {
+        self.min_protocol = protocol;
+        self
+    }
+
+    /// Controls whether cipher suites and key sizes which are disabled by default are offered.
+    ///
+    /// This only has an effect with OpenSSL, where it also lowers the security level to 0 as
+    /// OpenSSL 3 requires for TLS 1.0 and 1.1. SChannel and Secure Transport select cipher suites
+    /// according to system-wide policy. Anonymous and unencrypted cipher suites are never offered.
+    ///
+    /// Defaults to `true`.
+    pub fn legacy_ciphers(&mut self, legacy_ciphers: bool) -> &mut LegacyProfile {
+        self.legacy_ciphers = legacy_ciphers;
+        self
+    }
+}
+
+/// The settings a handshake was completed with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HandshakeProfile {
+    /// The connector's own settings.
+    Default,
+    /// The connector's `LegacyProfile`.
+    Legacy,
+    #[doc(hidden)]
+    __NonExhaustive,
+}
+
 /// A builder for client-side TLS connections.
 ///
+/// The configuration can't change once built. Handshakes only borrow the connector, which is
+/// `Send` and `Sync` and cheap to clone, so a single connector can be shared between threads.
+///
 /// # Examples
 ///
 /// ```rust
@@ -445,7 +1842,14 @@ impl TlsConnectorBuilder {
 /// println!("{}", String::from_utf8_lossy(&res));
 /// ```
 #[derive(Clone, Debug)]
-pub struct TlsConnector(imp::TlsConnector);
+pub struct TlsConnector(
+    Arc<imp::TlsConnector>,
+    policy::Policy,
+    Option<Arc<TlsConnector>>,
+    Option<VerifyCallback>,
+    Arc<[Sha256Pin]>,
+    Option<Duration>,
+);
 
 impl TlsConnector {
     /// Returns a new connector with default settings.
@@ -457,20 +1861,74 @@ impl TlsConnector {
     pub fn builder() -> TlsConnectorBuilder {
         TlsConnectorBuilder {
             identity: None,
-            min_protocol: Some(Protocol::Tlsv10),
+            min_protocol: Some(Protocol::Tlsv12),
             max_protocol: None,
             root_certificates: vec![],
             use_sni: true,
             accept_invalid_certs: false,
             accept_invalid_hostnames: false,
             disable_built_in_roots: false,
+            max_send_fragment: None,
+            cipher_suites: vec![],
+            certificate_exceptions: None,
+            allow_unsafe_legacy_renegotiation: false,
+            allow_partial_chains: false,
+            wildcard_policy: WildcardPolicy::Allow,
+            max_certificate_validity: None,
+            reject_sha1_signatures: false,
+            verify_callback: None,
+            pinned_public_keys: vec![],
+            require_ocsp_staple: false,
+            check_revocation: false,
+            crls: vec![],
+            legacy_fallback: None,
+            session_cache: false,
+            session_cache_size: 256,
+            early_data: false,
+            handshake_timeout: None,
+            backend: imp::Backend::Native,
+            #[cfg(feature = "alpn")]
+            alpn: vec![],
+            #[cfg(target_os = "windows")]
+            use_system_protocols: false,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            legacy_ciphers: false,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            middlebox_compat: None,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            expected_raw_public_keys: vec![],
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            rekey_after_bytes: None,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            rekey_interval: None,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            sm_cipher_suites: false,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            context_callback: None,
         }
     }
 
+    /// Returns a new builder for a `TlsConnector` which uses the rustls backend.
+    ///
+    /// Unless root certificates are added, servers are verified against the roots of the
+    /// `webpki-roots` crate rather than the system's. Protocols older than TLS 1.2 aren't
+    /// supported, and settings made through the extension traits of the `backend` module are
+    /// ignored. Identities created by those extension traits can't be used, nor can PKCS #12
+    /// archives whose private key isn't encrypted.
+    ///
+    /// Requires the `rustls` Cargo feature.
+    #[cfg(feature = "rustls")]
+    pub fn rustls_builder() -> TlsConnectorBuilder {
+        let mut builder = TlsConnector::builder();
+        builder.backend(Backend::Rustls);
+        builder
+    }
+
     /// Initiates a TLS handshake.
     ///
     /// The provided domain will be used for both SNI and certificate hostname
-    /// validation.
+    /// validation. If it is an IP address, no SNI extension is sent and the
+    /// certificate is verified against it as in `connect_ip`.
     ///
     /// If the socket is nonblocking and a `WouldBlock` error is returned during
     /// the handshake, a `HandshakeError::WouldBlock` error will be returned
@@ -487,16 +1945,331 @@ impl TlsConnector {
     where
         S: io::Read + io::Write,
     {
-        let s = self.0.connect(domain, stream)?;
-        Ok(TlsStream(s))
+        let check = self.peer_check(Some(domain));
+        let deadline = handshake_deadline(self.5);
+        finish_handshake(self.0.connect(domain, stream), check, deadline)
+    }
+
+    /// Initiates a TLS handshake with a server identified by its IP address.
+    ///
+    /// No SNI extension is sent, since SNI can't carry IP addresses, and the server's certificate
+    /// is verified against `addr` rather than a hostname.
+    pub fn connect_ip<S>(
+        &self,
+        addr: IpAddr,
+        stream: S,
+    ) -> result::Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        let domain = addr.to_string();
+        let check = self.peer_check(Some(&domain));
+        let deadline = handshake_deadline(self.5);
+        finish_handshake(self.0.connect_without_sni(Some(&domain), stream), check, deadline)
+    }
+
+    /// Initiates a TLS handshake without a domain.
+    ///
+    /// No SNI extension is sent and the server's certificate is not checked against any
+    /// hostname. The rest of the certificate chain is still verified as usual, but any server
+    /// with a certificate from a trusted authority will be accepted, so this should only be used
+    /// when the server is authenticated by other means, such as `pin_public_keys`.
+    pub fn danger_connect_without_domain<S>(
+        &self,
+        stream: S,
+    ) -> result::Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        let check = self.peer_check(None);
+        let deadline = handshake_deadline(self.5);
+        finish_handshake(self.0.connect_without_sni(None, stream), check, deadline)
+    }
+
+    /// Initiates a TLS handshake, sending `data` to the server as early data if possible.
+    ///
+    /// If early data is enabled and a resumable session with the server allows enough of it,
+    /// `data` is sent along with the first flight of the handshake. Otherwise, or if the server
+    /// rejects it, the data is not delivered at all, and it is up to the caller to write it to
+    /// the returned stream once `TlsStream::early_data_accepted` returns `false`.
+    ///
+    /// Early data is sent before the connector's own checks of the server's certificate, such as
+    /// `TlsConnectorBuilder::pin_public_keys`, have run, so it must not depend on them.
+    ///
+    /// The domain is used as in `connect`.
+    pub fn connect_with_early_data<S>(
+        &self,
+        domain: &str,
+        stream: S,
+        data: &[u8],
+    ) -> result::Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: io::Read + io::Write,
+    {
+        let check = self.peer_check(Some(domain));
+        let deadline = handshake_deadline(self.5);
+        let result = self.0.connect_with_early_data(domain, stream, data);
+        finish_handshake(result, check, deadline)
+    }
+
+    fn peer_check(&self, domain: Option<&str>) -> Option<Box<PeerCheck>> {
+        let mut policy = self.1;
+        // Only IP addresses need to be matched, which saves fetching the chain for other domains.
+        if domain.and_then(|domain| domain.parse::<IpAddr>().ok()).is_none() {
+            policy.ip_addresses = false;
+        }
+        if policy.is_empty() && self.3.is_none() && self.4.is_empty() {
+            return None;
+        }
+        Some(Box::new(PeerCheck {
+            domain: domain.map(str::to_owned),
+            policy,
+            callback: self.3.clone(),
+            pins: self.4.clone(),
+        }))
+    }
+
+    /// Initiates a TLS handshake over an async stream.
+    ///
+    /// The returned future resolves to a stream implementing Tokio's `AsyncRead` and `AsyncWrite`
+    /// once the handshake completes. The domain is used as in `connect`.
+    ///
+    /// Requires the `tokio` Cargo feature.
+    #[cfg(feature = "tokio")]
+    pub fn connect_async<S>(&self, domain: &str, stream: S) -> tokio::Handshake<S> {
+        let stream = tokio::AllowStd::new(stream);
+        let handshake = async_io::Handshake::Connect(self.clone(), domain.to_owned(), stream);
+        tokio::Handshake(handshake)
+    }
+
+    /// Creates a new connector from a `TlsConfig`.
+    pub fn from_config(config: &TlsConfig) -> result::Result<TlsConnector, ConfigError> {
+        let mut builder = TlsConnector::builder();
+        builder
+            .min_protocol_version(config.min_protocol_version)
+            .max_protocol_version(config.max_protocol_version)
+            .cipher_suites(&config.cipher_suites);
+        for path in &config.root_certificates {
+            for cert in config_certificates(path)? {
+                builder.add_root_certificate(cert);
+            }
+        }
+        if let Some(ref identity) = config.identity {
+            builder.identity(config_identity(identity)?);
+        }
+        builder
+            .disable_built_in_roots(config.disable_built_in_roots)
+            .use_sni(config.use_sni)
+            .danger_accept_invalid_certs(config.danger_accept_invalid_certs)
+            .danger_accept_invalid_hostnames(config.danger_accept_invalid_hostnames);
+        builder.build().map_err(ConfigError::Tls)
+    }
+
+    /// Connects to a `host:port` address over TCP and initiates a TLS handshake.
+    ///
+    /// The host is used for both SNI and certificate hostname validation. Each address the host
+    /// resolves to is tried in turn. TLS errors are returned as `io::Error`s wrapping an `Error`.
+    /// If a `LegacyProfile` is configured, the handshake is retried over a new connection as
+    /// described in `connect_with_fallback`. If a handshake timeout is configured, it also bounds
+    /// the reads and writes on the socket during the handshake.
+    ///
+    /// Requires the `tcp` Cargo feature.
+    #[cfg(feature = "tcp")]
+    pub fn connect_tcp(&self, addr: &str) -> io::Result<TlsStream<TcpStream>> {
+        let host = match addr.rfind(':') {
+            Some(idx) => addr[..idx].trim_start_matches('[').trim_end_matches(']'),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "address must be of the form host:port",
+                ));
+            }
+        };
+
+        let handshake = |connector: &TlsConnector, stream| {
+            tcp_handshake(stream, connector.5, |stream| connector.connect(host, stream))
+        };
+        self.fallback_with(|| TcpStream::connect(addr), handshake)
+            .map(|(stream, _)| stream)
+    }
+
+    /// Initiates a TLS handshake over a stream returned by `connect`, retrying once over a new
+    /// stream with the connector's `LegacyProfile` if the server appears to only support older
+    /// protocol versions or cipher suites.
+    ///
+    /// Servers which predate a protocol version may reject it or simply drop the connection, so
+    /// both are treated as a reason to retry, but certificate verification failures are not. The
+    /// profile the handshake completed with is returned along with the stream so that callers can
+    /// keep track of the servers needing it. If no `LegacyProfile` is configured, this is
+    /// equivalent to `connect`.
+    ///
+    /// Errors from `connect` are returned as-is, and TLS errors are returned as `io::Error`s
+    /// wrapping an `Error`. If the retry fails, its error is returned.
+    pub fn connect_with_fallback<S, F>(
+        &self,
+        domain: &str,
+        connect: F,
+    ) -> io::Result<(TlsStream<S>, HandshakeProfile)>
+    where
+        S: io::Read + io::Write,
+        F: FnMut() -> io::Result<S>,
+    {
+        self.fallback_with(connect, |connector, stream| connector.connect(domain, stream))
+    }
+
+    fn fallback_with<S, F, H>(
+        &self,
+        mut connect: F,
+        handshake: H,
+    ) -> io::Result<(TlsStream<S>, HandshakeProfile)>
+    where
+        S: io::Read + io::Write,
+        F: FnMut() -> io::Result<S>,
+        H: Fn(&TlsConnector, S) -> result::Result<TlsStream<S>, HandshakeError<S>>,
+    {
+        let stream = connect()?;
+        let fallback = match self.2 {
+            Some(ref fallback) => fallback,
+            None => {
+                return match handshake(self, stream) {
+                    Ok(stream) => Ok((stream, HandshakeProfile::Default)),
+                    Err(e) => Err(handshake_io_error(e)),
+                };
+            }
+        };
+
+        match handshake(self, stream) {
+            Ok(stream) => return Ok((stream, HandshakeProfile::Default)),
+            Err(HandshakeError::Failure(ref e)) if e.0.is_negotiation_failure() => {}
+            Err(e) => return Err(handshake_io_error(e)),
+        }
+
+        let stream = connect()?;
+        match handshake(fallback, stream) {
+            Ok(stream) => Ok((stream, HandshakeProfile::Legacy)),
+            Err(e) => Err(handshake_io_error(e)),
+        }
+    }
+}
+
+// Drives a handshake over a blocking `TcpStream`, bounding each read and write by the time left
+// before the handshake timeout and resuming the handshake whenever one of them times out. The
+// socket's own timeouts are restored once the handshake completes.
+#[cfg(feature = "tcp")]
+fn tcp_handshake<F>(
+    stream: TcpStream,
+    timeout: Option<Duration>,
+    start: F,
+) -> result::Result<TlsStream<TcpStream>, HandshakeError<TcpStream>>
+where
+    F: FnOnce(TcpStream) -> result::Result<TlsStream<TcpStream>, HandshakeError<TcpStream>>,
+{
+    fn set_timeouts(stream: &TcpStream, deadline: Instant) -> io::Result<()> {
+        // A zero timeout is rejected, and the handshake fails on its own past the deadline.
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout = Some(cmp::max(remaining, Duration::from_millis(1)));
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)
+    }
+
+    let deadline = match handshake_deadline(timeout) {
+        Some(deadline) => deadline,
+        None => return start(stream),
+    };
+    let read_timeout = stream.read_timeout().map_err(io_failure)?;
+    let write_timeout = stream.write_timeout().map_err(io_failure)?;
+    set_timeouts(&stream, deadline).map_err(io_failure)?;
+
+    let mut result = start(stream);
+    loop {
+        match result {
+            Ok(stream) => {
+                stream.get_ref().set_read_timeout(read_timeout).map_err(io_failure)?;
+                stream.get_ref().set_write_timeout(write_timeout).map_err(io_failure)?;
+                return Ok(stream);
+            }
+            Err(HandshakeError::WouldBlock(stream)) => {
+                set_timeouts(stream.get_ref(), deadline).map_err(io_failure)?;
+                result = stream.handshake();
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn handshake_io_error<S>(e: HandshakeError<S>) -> io::Error {
+    match e {
+        HandshakeError::Failure(e) => io::Error::new(io::ErrorKind::Other, e),
+        HandshakeError::WouldBlock(_) => {
+            io::Error::new(io::ErrorKind::WouldBlock, "handshake would block")
+        }
     }
 }
 
 /// A builder for `TlsAcceptor`s.
 pub struct TlsAcceptorBuilder {
     identity: Identity,
+    additional_identities: Vec<Identity>,
     min_protocol: Option<Protocol>,
     max_protocol: Option<Protocol>,
+    max_send_fragment: Option<usize>,
+    cipher_suites: Vec<CipherSuite>,
+    expiry_warning: Option<(Duration, Arc<ExpiryCallback>)>,
+    omit_root_certificate: bool,
+    capture_client_hello: bool,
+    client_auth: ClientAuth,
+    client_root_certificates: Vec<Certificate>,
+    sni_identities: Vec<(String, Identity)>,
+    ocsp_response: Option<Vec<u8>>,
+    max_early_data_size: u32,
+    handshake_timeout: Option<Duration>,
+    backend: imp::Backend,
+    #[cfg(feature = "alpn")]
+    alpn: Vec<String>,
+    #[cfg(target_os = "windows")]
+    use_system_protocols: bool,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    middlebox_compat: Option<bool>,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    raw_public_key: bool,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    rekey_after_bytes: Option<u64>,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    rekey_interval: Option<Duration>,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    sm_cipher_suites: bool,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    session_tickets: bool,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    session_ticket_keys: Option<imp::SessionTicketKeys>,
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    context_callback: Option<imp::ContextCallback>,
+}
+
+impl fmt::Debug for TlsAcceptorBuilder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = fmt.debug_struct("TlsAcceptorBuilder");
+        debug
+            .field("identity", &self.identity)
+            .field("additional_identities", &self.additional_identities)
+            .field("min_protocol", &self.min_protocol)
+            .field("max_protocol", &self.max_protocol)
+            .field("max_send_fragment", &self.max_send_fragment)
+            .field("cipher_suites", &self.cipher_suites)
+            .field("expiry_warning", &self.expiry_warning.as_ref().map(|w| w.0))
+            .field("omit_root_certificate", &self.omit_root_certificate)
+            .field("capture_client_hello", &self.capture_client_hello)
+            .field("client_auth", &self.client_auth)
+            .field("client_root_certificates", &self.client_root_certificates)
+            .field("sni_identities", &self.sni_identities)
+            .field("ocsp_response", &self.ocsp_response.is_some())
+            .field("max_early_data_size", &self.max_early_data_size)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("backend", &self.backend);
+        #[cfg(feature = "alpn")]
+        debug.field("alpn", &self.alpn);
+        debug.finish()
+    }
 }
 
 impl TlsAcceptorBuilder {
@@ -504,31 +2277,318 @@ impl TlsAcceptorBuilder {
     ///
     /// A value of `None` enables support for the oldest protocols supported by the implementation.
     ///
-    /// Defaults to `Some(Protocol::Tlsv10)`.
+    /// Defaults to `Some(Protocol::Tlsv12)`. Peers which only support older protocols can be
+    /// accommodated by lowering this to `Some(Protocol::Tlsv10)`.
     pub fn min_protocol_version(&mut self, protocol: Option<Protocol>) -> &mut TlsAcceptorBuilder {
         self.min_protocol = protocol;
         self
     }
 
-    /// Sets the maximum supported protocol version.
+    /// Sets the maximum supported protocol version.
+    ///
+    /// A value of `None` enables support for the newest protocols supported by the implementation.
+    ///
+    /// Defaults to `None`.
+    pub fn max_protocol_version(&mut self, protocol: Option<Protocol>) -> &mut TlsAcceptorBuilder {
+        self.max_protocol = protocol;
+        self
+    }
+
+    /// Sets the maximum amount of plaintext placed in a single TLS record when writing.
+    ///
+    /// Smaller records reduce latency for interactive or streaming traffic at the cost of
+    /// throughput. The value must be between 512 and 16384 bytes, or `build` returns an error. A
+    /// value of `None` uses the implementation's default, which is typically the protocol maximum
+    /// of 16384 bytes.
+    ///
+    /// Defaults to `None`.
+    pub fn max_send_fragment(&mut self, max: Option<usize>) -> &mut TlsAcceptorBuilder {
+        self.max_send_fragment = max;
+        self
+    }
+
+    /// Sets the TLS implementation used by the acceptor.
+    ///
+    /// `TlsAcceptor::rustls_builder` describes the limitations of the rustls backend.
+    ///
+    /// Defaults to `Backend::Native`.
+    pub fn backend(&mut self, backend: Backend) -> &mut TlsAcceptorBuilder {
+        self.backend = backend.imp();
+        self
+    }
+
+    /// Restricts the cipher suites which may be negotiated to `suites`.
+    ///
+    /// Suites unknown to the implementation are ignored, and an error is returned if none remain.
+    /// With OpenSSL, `build` also returns an error if any protocol version enabled by
+    /// `min_protocol_version` and `max_protocol_version` is left without a suite, so restricting an
+    /// acceptor to TLS 1.2 suites requires a maximum version of TLS 1.2. SChannel selects cipher
+    /// suites according to system-wide policy, and returns an error if any are configured.
+    ///
+    /// An empty list enables the implementation's default suites.
+    ///
+    /// Defaults to an empty list.
+    pub fn cipher_suites(&mut self, suites: &[CipherSuite]) -> &mut TlsAcceptorBuilder {
+        self.cipher_suites = suites.to_vec();
+        self
+    }
+
+    /// Controls whether a self-signed root certificate in the identity's chain is sent to clients.
+    ///
+    /// Clients must already trust the root for it to be of any use, so sending it only adds to the
+    /// size of the handshake. The chain is always sent in order from the leaf towards the root,
+    /// whatever order it was provided in. SChannel never sends the root, so this has no effect
+    /// there.
+    ///
+    /// Defaults to `false`.
+    pub fn omit_root_certificate(&mut self, omit: bool) -> &mut TlsAcceptorBuilder {
+        self.omit_root_certificate = omit;
+        self
+    }
+
+    /// Controls whether the hello message of each client is captured during the handshake, making
+    /// `TlsStream::client_hello_ja3` and `TlsStream::client_hello_ja4` available.
+    ///
+    /// Capturing the hello adds work to every handshake. It is only supported by the OpenSSL
+    /// backend with OpenSSL 1.1.1 or newer, where it installs a client hello callback. A client
+    /// hello callback installed through `TlsAcceptorBuilderExt::configure_context` replaces it.
+    ///
+    /// Defaults to `false`.
+    pub fn capture_client_hello(&mut self, capture: bool) -> &mut TlsAcceptorBuilder {
+        self.capture_client_hello = capture;
+        self
+    }
+
+    /// Adds an identity with a different key type from the others, such as an ECDSA identity
+    /// alongside an RSA one.
+    ///
+    /// The identity presented in each handshake is chosen according to the signature algorithms
+    /// and cipher suites the client supports, so modern clients can be served an ECDSA
+    /// certificate while legacy clients still receive an RSA one. Each identity should hold a
+    /// different type of key; with OpenSSL, an identity replaces any earlier one with the same
+    /// key type.
+    ///
+    /// Security Framework can only present a single identity, so `build` returns an error there
+    /// if any identities have been added.
+    ///
+    /// Defaults to no additional identities.
+    pub fn add_identity(&mut self, identity: Identity) -> &mut TlsAcceptorBuilder {
+        self.additional_identities.push(identity);
+        self
+    }
+
+    /// Adds an identity to present to clients requesting `hostname` through SNI.
+    ///
+    /// `hostname` may be a wildcard pattern such as `*.example.com`, matching a single leftmost
+    /// label. Identities are checked in the order they were added and the first match is used;
+    /// clients which don't send SNI, or which request a hostname with no match, are served the
+    /// default identity.
+    ///
+    /// This is only supported by the OpenSSL backend, and `build` returns an error on other
+    /// platforms if any SNI identities have been added.
+    ///
+    /// Defaults to no SNI identities.
+    pub fn add_sni_identity(
+        &mut self,
+        hostname: &str,
+        identity: Identity,
+    ) -> &mut TlsAcceptorBuilder {
+        self.sni_identities
+            .push((hostname::normalize(hostname), identity));
+        self
+    }
+
+    /// Controls whether clients are asked to authenticate with a certificate.
+    ///
+    /// Certificates presented by clients are verified against the roots added with
+    /// `add_client_root_certificate` rather than the system's trusted roots, and the verified
+    /// certificate is available from `TlsStream::peer_certificate` once the handshake completes.
+    ///
+    /// SChannel does not support requesting client certificates, so `build` returns an error there
+    /// unless this is `ClientAuth::Disabled`.
+    ///
+    /// Defaults to `ClientAuth::Disabled`.
+    pub fn client_auth(&mut self, client_auth: ClientAuth) -> &mut TlsAcceptorBuilder {
+        self.client_auth = client_auth;
+        self
+    }
+
+    /// Adds a certificate to the set of roots client certificates are verified against.
+    ///
+    /// The subjects of these certificates are also sent to clients as the acceptable issuers of
+    /// their certificate when the backend supports it.
+    pub fn add_client_root_certificate(&mut self, cert: Certificate) -> &mut TlsAcceptorBuilder {
+        self.client_root_certificates.push(cert);
+        self
+    }
+
+    /// Sets a DER-encoded OCSP response for the identity's certificate, which is stapled to the
+    /// handshakes of clients requesting one.
+    ///
+    /// The response is sent as is, so the acceptor should be rebuilt with a fresh response before
+    /// it expires.
+    ///
+    /// This is only supported by the OpenSSL backend, and `build` returns an error on other
+    /// platforms if a response is set.
+    ///
+    /// Defaults to `None`.
+    pub fn ocsp_response(&mut self, response: Option<&[u8]>) -> &mut TlsAcceptorBuilder {
+        self.ocsp_response = response.map(|r| r.to_vec());
+        self
+    }
+
+    /// Sets the maximum amount of TLS 1.3 early data (0-RTT) accepted from clients resuming a
+    /// session, in bytes.
+    ///
+    /// Early data received during the handshake is returned by the first reads from the
+    /// `TlsStream`, and `TlsStream::early_data_accepted` reports whether any was accepted. A
+    /// value of 0 disables early data. It is supported by OpenSSL 1.1.1 or newer and rustls, and
+    /// ignored by the other backends. The rustls backend uses its own session cache rather than
+    /// session tickets when early data is enabled, as tickets can't be tracked to refuse replays.
+    ///
+    /// Defaults to 0.
+    ///
+    /// # Warning
+    ///
+    /// Early data is not protected against replay by an attacker who resends the client's first
+    /// flight to another server. Applications must only act on requests which are safe to repeat
+    /// before the handshake completes.
+    pub fn max_early_data_size(&mut self, size: u32) -> &mut TlsAcceptorBuilder {
+        self.max_early_data_size = size;
+        self
+    }
+
+    /// Sets the protocols supported through ALPN (Application-Layer Protocol Negotiation), in order
+    /// of preference.
+    ///
+    /// The first of these protocols which the client also offers is selected, and is available
+    /// from `TlsStream::negotiated_alpn`. If the client offers none of them, the handshake
+    /// continues without a protocol being negotiated.
+    ///
+    /// Security Framework cannot select a protocol on the server side, so `build` returns an error
+    /// there if any protocols have been set.
+    ///
+    /// Defaults to no protocols.
+    ///
+    /// Requires the `alpn` Cargo feature.
+    #[cfg(feature = "alpn")]
+    pub fn accept_alpns(&mut self, protocols: &[&str]) -> &mut TlsAcceptorBuilder {
+        self.alpn = protocols.iter().map(|s| (*s).to_string()).collect();
+        self
+    }
+
+    /// Sets a callback invoked whenever a handshake is accepted while the identity's certificate
+    /// expires within the specified duration of the current time.
+    ///
+    /// The callback is passed the identity's leaf certificate and its expiration time, and is
+    /// intended to feed logging or monitoring so certificates are renewed before they lapse. It
+    /// is invoked for every such handshake, including after the certificate has expired. Only the
+    /// identity passed to `TlsAcceptor::builder` is checked.
+    ///
+    /// Defaults to no callback.
+    pub fn expiry_warning<F>(&mut self, within: Duration, callback: F) -> &mut TlsAcceptorBuilder
+    where
+        F: Fn(&Certificate, SystemTime) + Sync + Send + 'static,
+    {
+        self.expiry_warning = Some((within, Arc::new(callback)));
+        self
+    }
+
+    /// Sets the maximum total time a handshake may take.
     ///
-    /// A value of `None` enables support for the newest protocols supported by the implementation.
+    /// `TlsAcceptor::accept_tcp` bounds each read and write on its socket by the time remaining,
+    /// so a client which stops responding can't hold on to the accepting thread. See
+    /// `TlsConnectorBuilder::handshake_timeout`.
     ///
     /// Defaults to `None`.
-    pub fn max_protocol_version(&mut self, protocol: Option<Protocol>) -> &mut TlsAcceptorBuilder {
-        self.max_protocol = protocol;
+    pub fn handshake_timeout(&mut self, timeout: Option<Duration>) -> &mut TlsAcceptorBuilder {
+        self.handshake_timeout = timeout;
         self
     }
 
     /// Creates a new `TlsAcceptor`.
     pub fn build(&self) -> Result<TlsAcceptor> {
-        let acceptor = imp::TlsAcceptor::new(self)?;
-        Ok(TlsAcceptor(acceptor))
+        self.build_with(imp::TlsAcceptor::new)
+    }
+
+    /// Creates a new `DtlsAcceptor`.
+    ///
+    /// Protocol versions are mapped as described in `TlsConnectorBuilder::build_dtls`.
+    ///
+    /// DTLS is currently only supported by the OpenSSL backend.
+    pub fn build_dtls(&self) -> Result<dtls::DtlsAcceptor> {
+        self.build_with(imp::TlsAcceptor::new_dtls)
+            .map(dtls::DtlsAcceptor)
+    }
+
+    fn build_with(
+        &self,
+        new: fn(&TlsAcceptorBuilder) -> result::Result<imp::TlsAcceptor, imp::Error>,
+    ) -> Result<TlsAcceptor> {
+        let acceptor = new(self)?;
+
+        let expiry_warning = match self.expiry_warning {
+            Some((within, ref callback)) => {
+                let cert = self.identity.certificate()?;
+                let not_after = cert.not_after()?;
+                Some(Arc::new(ExpiryWarning {
+                    warn_at: not_after.checked_sub(within),
+                    not_after,
+                    cert,
+                    callback: callback.clone(),
+                }))
+            }
+            None => None,
+        };
+
+        Ok(TlsAcceptor(
+            Arc::new(acceptor),
+            expiry_warning,
+            self.handshake_timeout,
+        ))
+    }
+}
+
+/// Whether a server asks clients to authenticate with a certificate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// Client certificates are not requested.
+    Disabled,
+    /// A certificate is requested, but clients may connect without one. A certificate which is
+    /// presented must still be valid.
+    Optional,
+    /// Clients must present a valid certificate.
+    Required,
+    #[doc(hidden)]
+    __NonExhaustive,
+}
+
+type ExpiryCallback = dyn Fn(&Certificate, SystemTime) + Sync + Send;
+
+struct ExpiryWarning {
+    warn_at: Option<SystemTime>,
+    not_after: SystemTime,
+    cert: Certificate,
+    callback: Arc<ExpiryCallback>,
+}
+
+impl ExpiryWarning {
+    fn check(&self) {
+        let expiring = match self.warn_at {
+            Some(warn_at) => SystemTime::now() >= warn_at,
+            None => true,
+        };
+        if expiring {
+            (self.callback)(&self.cert, self.not_after);
+        }
     }
 }
 
 /// A builder for server-side TLS connections.
 ///
+/// The configuration can't change once built. Handshakes only borrow the acceptor, which is
+/// `Send` and `Sync` and cheap to clone, so a single acceptor can serve many threads.
+///
 /// # Examples
 ///
 /// ```rust,no_run
@@ -536,7 +2596,6 @@ impl TlsAcceptorBuilder {
 /// use std::fs::File;
 /// use std::io::{Read};
 /// use std::net::{TcpListener, TcpStream};
-/// use std::sync::Arc;
 /// use std::thread;
 ///
 /// let mut file = File::open("identity.pfx").unwrap();
@@ -546,7 +2605,6 @@ impl TlsAcceptorBuilder {
 ///
 /// let listener = TcpListener::bind("0.0.0.0:8443").unwrap();
 /// let acceptor = TlsAcceptor::new(identity).unwrap();
-/// let acceptor = Arc::new(acceptor);
 ///
 /// fn handle_client(stream: TlsStream<TcpStream>) {
 ///     // ...
@@ -566,7 +2624,18 @@ impl TlsAcceptorBuilder {
 /// }
 /// ```
 #[derive(Clone)]
-pub struct TlsAcceptor(imp::TlsAcceptor);
+pub struct TlsAcceptor(
+    Arc<imp::TlsAcceptor>,
+    Option<Arc<ExpiryWarning>>,
+    Option<Duration>,
+);
+
+impl fmt::Debug for TlsAcceptor {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // The backend acceptors don't expose their configuration.
+        fmt.debug_struct("TlsAcceptor").finish()
+    }
+}
 
 impl TlsAcceptor {
     /// Creates a acceptor with default settings.
@@ -582,11 +2651,74 @@ impl TlsAcceptor {
     pub fn builder(identity: Identity) -> TlsAcceptorBuilder {
         TlsAcceptorBuilder {
             identity,
-            min_protocol: Some(Protocol::Tlsv10),
+            additional_identities: vec![],
+            min_protocol: Some(Protocol::Tlsv12),
             max_protocol: None,
+            max_send_fragment: None,
+            cipher_suites: vec![],
+            expiry_warning: None,
+            omit_root_certificate: false,
+            capture_client_hello: false,
+            client_auth: ClientAuth::Disabled,
+            client_root_certificates: vec![],
+            sni_identities: vec![],
+            ocsp_response: None,
+            max_early_data_size: 0,
+            handshake_timeout: None,
+            backend: imp::Backend::Native,
+            #[cfg(feature = "alpn")]
+            alpn: vec![],
+            #[cfg(target_os = "windows")]
+            use_system_protocols: false,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            middlebox_compat: None,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            raw_public_key: false,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            rekey_after_bytes: None,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            rekey_interval: None,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            sm_cipher_suites: false,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            session_tickets: true,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            session_ticket_keys: None,
+            #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+            context_callback: None,
         }
     }
 
+    /// Returns a new builder for a `TlsAcceptor` which uses the rustls backend.
+    ///
+    /// Protocols older than TLS 1.2 aren't supported, and settings made through the extension
+    /// traits of the `backend` module are ignored. Identities created by those extension traits
+    /// can't be used, nor can PKCS #12 archives whose private key isn't encrypted.
+    ///
+    /// Requires the `rustls` Cargo feature.
+    #[cfg(feature = "rustls")]
+    pub fn rustls_builder(identity: Identity) -> TlsAcceptorBuilder {
+        let mut builder = TlsAcceptor::builder(identity);
+        builder.backend(Backend::Rustls);
+        builder
+    }
+
+    /// Creates a new acceptor from a `TlsConfig`.
+    ///
+    /// The configuration must specify an identity. Its client-side settings are ignored.
+    pub fn from_config(config: &TlsConfig) -> result::Result<TlsAcceptor, ConfigError> {
+        let identity = match config.identity {
+            Some(ref identity) => config_identity(identity)?,
+            None => return Err(ConfigError::MissingIdentity),
+        };
+        TlsAcceptor::builder(identity)
+            .min_protocol_version(config.min_protocol_version)
+            .max_protocol_version(config.max_protocol_version)
+            .cipher_suites(&config.cipher_suites)
+            .build()
+            .map_err(ConfigError::Tls)
+    }
+
     /// Initiates a TLS handshake.
     ///
     /// If the socket is nonblocking and a `WouldBlock` error is returned during
@@ -597,15 +2729,311 @@ impl TlsAcceptor {
     where
         S: io::Read + io::Write,
     {
-        match self.0.accept(stream) {
-            Ok(s) => Ok(TlsStream(s)),
-            Err(e) => Err(e.into()),
+        if let Some(ref expiry_warning) = self.1 {
+            expiry_warning.check();
+        }
+
+        let deadline = handshake_deadline(self.2);
+        finish_handshake(self.0.accept(stream), None, deadline)
+    }
+
+    /// Accepts a TLS handshake over a blocking TCP stream.
+    ///
+    /// Unlike `accept`, the handshake is resumed until it completes. If a handshake timeout is
+    /// configured, each read and write on the socket is bounded by the time remaining, and the
+    /// socket's own timeouts are restored afterwards. TLS errors are returned as `io::Error`s
+    /// wrapping an `Error`.
+    ///
+    /// Requires the `tcp` Cargo feature.
+    #[cfg(feature = "tcp")]
+    pub fn accept_tcp(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        tcp_handshake(stream, self.2, |stream| self.accept(stream)).map_err(handshake_io_error)
+    }
+
+    /// Initiates a TLS handshake over an async stream.
+    ///
+    /// The returned future resolves to a stream implementing Tokio's `AsyncRead` and `AsyncWrite`
+    /// once the handshake completes.
+    ///
+    /// Requires the `tokio` Cargo feature.
+    #[cfg(feature = "tokio")]
+    pub fn accept_async<S>(&self, stream: S) -> tokio::Handshake<S> {
+        let stream = tokio::AllowStd::new(stream);
+        tokio::Handshake(async_io::Handshake::Accept(self.clone(), stream))
+    }
+}
+
+/// Settings for a `TlsConnector` or `TlsAcceptor`, typically loaded from a configuration file.
+///
+/// With the `serde` Cargo feature, this can be deserialized. Missing fields take their default
+/// values, protocol versions are written as `"ssl3"`, `"tls1.0"`, `"tls1.1"`, `"tls1.2"` or
+/// `"tls1.3"`, and cipher suites are written as their IANA names. A `min_protocol_version` of
+/// `"oldest"` deserializes to `None`.
+///
+/// # Examples
+///
+/// ```toml
+/// min_protocol_version = "tls1.2"
+/// cipher_suites = ["TLS_AES_128_GCM_SHA256", "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"]
+/// root_certificates = ["/etc/myservice/ca-bundle.pem"]
+///
+/// [identity]
+/// path = "/etc/myservice/identity.p12"
+/// password = "hunter2"
+/// ```
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct TlsConfig {
+    /// The minimum supported protocol version.
+    ///
+    /// A value of `None` enables the oldest protocol supported by the implementation.
+    ///
+    /// Defaults to `Some(Protocol::Tlsv12)`.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_min_protocol"))]
+    pub min_protocol_version: Option<Protocol>,
+    /// The maximum supported protocol version.
+    ///
+    /// Defaults to `None`, which enables the newest protocol supported by the implementation.
+    pub max_protocol_version: Option<Protocol>,
+    /// The cipher suites which may be negotiated. See `TlsConnectorBuilder::cipher_suites`.
+    ///
+    /// Defaults to an empty list, which enables the implementation's default suites.
+    pub cipher_suites: Vec<CipherSuite>,
+    /// Paths to additional root certificates to trust, each a DER certificate or a PEM file
+    /// containing one or more certificates.
+    pub root_certificates: Vec<PathBuf>,
+    /// Whether to trust only the `root_certificates` rather than the system's trust store too.
+    pub disable_built_in_roots: bool,
+    /// The identity to present to peers. Required by `TlsAcceptor::from_config`.
+    pub identity: Option<IdentityConfig>,
+    /// Whether to use Server Name Indication.
+    ///
+    /// Defaults to `true`.
+    pub use_sni: bool,
+    /// Whether to accept invalid certificates. See
+    /// `TlsConnectorBuilder::danger_accept_invalid_certs`.
+    pub danger_accept_invalid_certs: bool,
+    /// Whether to accept certificates for other hostnames. See
+    /// `TlsConnectorBuilder::danger_accept_invalid_hostnames`.
+    pub danger_accept_invalid_hostnames: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig {
+            min_protocol_version: Some(Protocol::Tlsv12),
+            max_protocol_version: None,
+            cipher_suites: vec![],
+            root_certificates: vec![],
+            disable_built_in_roots: false,
+            identity: None,
+            use_sni: true,
+            danger_accept_invalid_certs: false,
+            danger_accept_invalid_hostnames: false,
+        }
+    }
+}
+
+/// The location of an identity in a `TlsConfig`.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct IdentityConfig {
+    /// The path to a file containing the identity, in any format accepted by
+    /// `Identity::from_bytes`.
+    pub path: PathBuf,
+    /// The password of the identity, if its format is encrypted.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub password: String,
+}
+
+impl fmt::Debug for IdentityConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("IdentityConfig")
+            .field("path", &self.path)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Protocol {
+    fn deserialize<D>(deserializer: D) -> result::Result<Protocol, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        const PROTOCOLS: &[&str] = &["ssl3", "tls1.0", "tls1.1", "tls1.2", "tls1.3"];
+
+        let s = String::deserialize(deserializer)?;
+        match &*s {
+            "ssl3" => Ok(Protocol::Sslv3),
+            "tls1.0" => Ok(Protocol::Tlsv10),
+            "tls1.1" => Ok(Protocol::Tlsv11),
+            "tls1.2" => Ok(Protocol::Tlsv12),
+            "tls1.3" => Ok(Protocol::Tlsv13),
+            _ => Err(de::Error::unknown_variant(&s, PROTOCOLS)),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for CipherSuite {
+    fn deserialize<D>(deserializer: D) -> result::Result<CipherSuite, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        CipherSuite::from_name(&s).ok_or_else(|| {
+            de::Error::invalid_value(de::Unexpected::Str(&s), &"an IANA cipher suite name")
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_min_protocol<'de, D>(deserializer: D) -> result::Result<Option<Protocol>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match &*s {
+        "oldest" => Ok(None),
+        _ => Protocol::deserialize(de::IntoDeserializer::into_deserializer(s)).map(Some),
+    }
+}
+
+/// An error creating a `TlsConnector` or `TlsAcceptor` from a `TlsConfig`.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A file named by the configuration could not be read.
+    Io(PathBuf, io::Error),
+    /// The TLS implementation rejected a certificate, identity, or setting.
+    Tls(Error),
+    /// `TlsAcceptor::from_config` was used with a configuration lacking an identity.
+    MissingIdentity,
+    #[doc(hidden)]
+    __NonExhaustive,
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ConfigError::Io(_, ref e) => Some(e),
+            ConfigError::Tls(ref e) => Some(e),
+            ConfigError::MissingIdentity | ConfigError::__NonExhaustive => None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref e) => write!(fmt, "error reading {}: {}", path.display(), e),
+            ConfigError::Tls(ref e) => fmt::Display::fmt(e, fmt),
+            ConfigError::MissingIdentity => fmt.write_str("no identity configured"),
+            ConfigError::__NonExhaustive => unreachable!(),
         }
     }
 }
 
+fn config_file(path: &Path) -> result::Result<Vec<u8>, ConfigError> {
+    fs::read(path).map_err(|e| ConfigError::Io(path.to_owned(), e))
+}
+
+fn config_certificates(path: &Path) -> result::Result<Vec<Certificate>, ConfigError> {
+    let buf = config_file(path)?;
+    if !pem::is_pem(&buf) {
+        return Certificate::from_der(&buf).map(|cert| vec![cert]).map_err(ConfigError::Tls);
+    }
+    pem::certificates(&buf)
+        .map_err(|e| ConfigError::Tls(imp::Error::from(e).into()))?
+        .iter()
+        .map(|der| Certificate::from_der(der))
+        .collect::<Result<_>>()
+        .map_err(ConfigError::Tls)
+}
+
+fn config_identity(config: &IdentityConfig) -> result::Result<Identity, ConfigError> {
+    let buf = config_file(&config.path)?;
+    Identity::from_bytes(&buf, &config.password).map_err(ConfigError::Tls)
+}
+
+/// A bidirectional byte stream.
+///
+/// This is automatically implemented for all types implementing `Read` and `Write`, and allows a
+/// `TlsStream` to wrap a type-erased transport. Applications juggling many kinds of transports
+/// (TCP and Unix sockets, in-memory pipes, etc) can use `TlsStream<Box<dyn ReadWrite + Send>>` to
+/// store all of their streams homogeneously without instantiating the TLS machinery for each one.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use native_tls::{ReadWrite, TlsConnector, TlsStream};
+/// use std::net::TcpStream;
+///
+/// let connector = TlsConnector::new().unwrap();
+///
+/// let stream = TcpStream::connect("google.com:443").unwrap();
+/// let stream = Box::new(stream) as Box<dyn ReadWrite + Send>;
+/// let stream: TlsStream<Box<dyn ReadWrite + Send>> = connector.connect("google.com", stream).unwrap();
+/// ```
+pub trait ReadWrite: io::Read + io::Write {}
+
+impl<S> ReadWrite for S where S: io::Read + io::Write {}
+
+impl<'a> fmt::Debug for dyn ReadWrite + 'a {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ReadWrite").finish()
+    }
+}
+
+impl<'a> fmt::Debug for dyn ReadWrite + Send + 'a {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ReadWrite").finish()
+    }
+}
+
+impl<'a> fmt::Debug for dyn ReadWrite + Send + Sync + 'a {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ReadWrite").finish()
+    }
+}
+
 /// A stream managing a TLS session.
-pub struct TlsStream<S>(imp::TlsStream<S>);
+///
+/// A `TlsStream` can itself be used as the transport of another `TlsStream`, for example to
+/// tunnel a TLS connection through an HTTPS proxy. Flushing the outer stream flushes every layer
+/// beneath it, and `WouldBlock` errors from the underlying transport propagate through both
+/// layers so nonblocking handshakes can be resumed as usual.
+///
+/// # Nonblocking writes
+///
+/// If a write to a nonblocking stream fails with `WouldBlock`, the TLS implementation may already
+/// have encrypted some of the data and will send it once the transport becomes writable. The
+/// write must therefore be retried with the same data, though the buffer holding it may have been
+/// moved or reallocated in the meantime, and may have had more data appended to it. Retrying with
+/// a shorter buffer fails with an `InvalidInput` error.
+///
+/// # Vectored I/O
+///
+/// `write_vectored` copies as many slices as fit into a single TLS record and writes them at
+/// once, rather than sending a record for each slice. `read_vectored` reads from the transport
+/// once, and then fills the remaining slices only from plaintext which has already been
+/// decrypted.
+pub struct TlsStream<S>(imp::TlsStream<S>, Traffic);
+
+// The maximum amount of plaintext in a TLS record.
+const MAX_RECORD_SIZE: usize = 16384;
+
+#[derive(Default)]
+struct Traffic {
+    read: u64,
+    written: u64,
+    // The length of a write which failed with `WouldBlock` and must be retried.
+    blocked_write: usize,
+    // Reused to coalesce the slices of vectored writes.
+    vectored_buf: Vec<u8>,
+}
 
 impl<S: fmt::Debug> fmt::Debug for TlsStream<S> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
@@ -623,6 +3051,19 @@ impl<S> TlsStream<S> {
     pub fn get_mut(&mut self) -> &mut S {
         self.0.get_mut()
     }
+
+    /// Returns the number of bytes of plaintext read from the stream.
+    pub fn bytes_read(&self) -> u64 {
+        self.1.read
+    }
+
+    /// Returns the number of bytes of plaintext written to the stream.
+    ///
+    /// This counts bytes accepted by `write`, which may not have been flushed to the underlying
+    /// stream yet.
+    pub fn bytes_written(&self) -> u64 {
+        self.1.written
+    }
 }
 
 impl<S: io::Read + io::Write> TlsStream<S> {
@@ -637,6 +3078,89 @@ impl<S: io::Read + io::Write> TlsStream<S> {
         Ok(self.0.peer_certificate()?.map(Certificate))
     }
 
+    /// Returns the certificate chain presented by the peer, starting with its leaf certificate.
+    ///
+    /// The remaining certificates follow in the order the peer sent them, except with SChannel,
+    /// which doesn't preserve it, where they are sorted from the leaf's issuer towards the root.
+    /// Security Framework returns the chain it verified instead, which may end in a root
+    /// certificate from the system's trust store. The chain is empty if the peer did not present
+    /// a certificate.
+    pub fn peer_certificate_chain(&self) -> Result<Vec<Certificate>> {
+        let chain = self.0.peer_certificate_chain()?;
+        Ok(chain.into_iter().map(Certificate).collect())
+    }
+
+    /// Returns whether the handshake resumed a previous session.
+    ///
+    /// This is not supported by the Security Framework backend.
+    pub fn session_resumed(&self) -> Result<bool> {
+        Ok(self.0.session_resumed()?)
+    }
+
+    /// Returns whether TLS 1.3 early data was accepted during the handshake.
+    ///
+    /// On the client side, data passed to `TlsConnector::connect_with_early_data` must be written
+    /// again if this returns `false`. This always returns `false` on backends without early data
+    /// support.
+    pub fn early_data_accepted(&self) -> Result<bool> {
+        Ok(self.0.early_data_accepted()?)
+    }
+
+    /// Returns the negotiated protocol version.
+    ///
+    /// `None` is returned if the version has no corresponding `Protocol` variant. This is not
+    /// supported by the SChannel backend.
+    pub fn protocol_version(&self) -> Result<Option<Protocol>> {
+        Ok(self.0.protocol_version()?)
+    }
+
+    /// Returns the negotiated cipher suite.
+    ///
+    /// This is not supported by the SChannel backend.
+    pub fn cipher_suite(&self) -> Result<CipherSuite> {
+        Ok(CipherSuite(self.0.cipher_suite()?))
+    }
+
+    /// Returns the protocol negotiated through ALPN, if any.
+    ///
+    /// See `TlsConnectorBuilder::request_alpns` and `TlsAcceptorBuilder::accept_alpns`.
+    ///
+    /// Requires the `alpn` Cargo feature.
+    #[cfg(feature = "alpn")]
+    pub fn negotiated_alpn(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.negotiated_alpn()?)
+    }
+
+    /// Returns the hostname requested by the client through SNI, if any.
+    ///
+    /// This is only available on the server side of a connection, and is currently only supported
+    /// by the OpenSSL and rustls backends. `None` is returned otherwise.
+    pub fn sni_hostname(&self) -> Result<Option<String>> {
+        Ok(self.0.sni_hostname()?)
+    }
+
+    /// Returns the [JA3] fingerprint of the client's hello message, as a hex-encoded MD5 digest.
+    ///
+    /// This is only available on the server side of a connection, once the hello is captured with
+    /// `TlsAcceptorBuilder::capture_client_hello`. It is currently only supported by the OpenSSL
+    /// backend with OpenSSL 1.1.1 or newer. `None` is returned otherwise.
+    ///
+    /// [JA3]: https://github.com/salesforce/ja3
+    pub fn client_hello_ja3(&self) -> Result<Option<String>> {
+        Ok(self.0.client_hello_ja3()?)
+    }
+
+    /// Returns the [JA4] fingerprint of the client's hello message.
+    ///
+    /// This is only available on the server side of a connection, once the hello is captured with
+    /// `TlsAcceptorBuilder::capture_client_hello`. It is currently only supported by the OpenSSL
+    /// backend with OpenSSL 1.1.1 or newer. `None` is returned otherwise.
+    ///
+    /// [JA4]: https://github.com/FoxIO-LLC/ja4
+    pub fn client_hello_ja4(&self) -> Result<Option<String>> {
+        Ok(self.0.client_hello_ja4()?)
+    }
+
     /// Returns the tls-server-end-point channel binding data as defined in [RFC 5929].
     ///
     /// [RFC 5929]: https://tools.ietf.org/html/rfc5929
@@ -644,6 +3168,34 @@ impl<S: io::Read + io::Write> TlsStream<S> {
         Ok(self.0.tls_server_end_point()?)
     }
 
+    /// Returns the tls-unique channel binding data as defined in [RFC 5929].
+    ///
+    /// This is the first Finished message of the most recent handshake. It is not defined for
+    /// TLS 1.3 connections, and the SChannel and Security Framework backends don't expose it, so
+    /// `None` is returned in those cases.
+    ///
+    /// [RFC 5929]: https://tools.ietf.org/html/rfc5929
+    pub fn tls_unique(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.tls_unique()?)
+    }
+
+    /// Derives `len` bytes of keying material from the session, as defined in [RFC 5705].
+    ///
+    /// Both sides of a connection derive the same material from the same `label` and `context`.
+    /// Note that RFC 5705 distinguishes between an empty context and no context at all.
+    ///
+    /// This is currently only supported by the OpenSSL backend.
+    ///
+    /// [RFC 5705]: https://tools.ietf.org/html/rfc5705
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        Ok(self.0.export_keying_material(label, context, len)?)
+    }
+
     /// Shuts down the TLS session.
     pub fn shutdown(&mut self) -> io::Result<()> {
         self.0.shutdown()?;
@@ -653,13 +3205,79 @@ impl<S: io::Read + io::Write> TlsStream<S> {
 
 impl<S: io::Read + io::Write> io::Read for TlsStream<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        let n = self.0.read(buf)?;
+        self.1.read += n as u64;
+        Ok(n)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+        let mut read = 0;
+        for buf in bufs.iter_mut().filter(|buf| !buf.is_empty()) {
+            // Only the first read may touch the transport, which could block.
+            if read > 0 && self.0.buffered_read_size().map_or(true, |size| size == 0) {
+                break;
+            }
+            let n = match io::Read::read(self, buf) {
+                Ok(n) => n,
+                Err(_) if read > 0 => break,
+                Err(e) => return Err(e),
+            };
+            read += n;
+            if n < buf.len() {
+                break;
+            }
+        }
+        Ok(read)
     }
 }
 
 impl<S: io::Read + io::Write> io::Write for TlsStream<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        if buf.len() < self.1.blocked_write {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write retried with less data than was passed before it blocked",
+            ));
+        }
+        let n = match self.0.write(buf) {
+            Ok(n) => n,
+            Err(e) => {
+                self.1.blocked_write = if e.kind() == io::ErrorKind::WouldBlock {
+                    buf.len()
+                } else {
+                    0
+                };
+                return Err(e);
+            }
+        };
+        self.1.blocked_write = 0;
+        self.1.written += n as u64;
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let mut bufs = bufs.iter().filter(|buf| !buf.is_empty());
+        let first = match bufs.next() {
+            Some(first) => first,
+            None => return io::Write::write(self, &[]),
+        };
+        if first.len() >= MAX_RECORD_SIZE {
+            return io::Write::write(self, first);
+        }
+
+        let mut coalesced = mem::take(&mut self.1.vectored_buf);
+        coalesced.clear();
+        coalesced.extend_from_slice(first);
+        for buf in bufs {
+            let len = cmp::min(buf.len(), MAX_RECORD_SIZE - coalesced.len());
+            coalesced.extend_from_slice(&buf[..len]);
+            if coalesced.len() == MAX_RECORD_SIZE {
+                break;
+            }
+        }
+        let r = io::Write::write(self, &coalesced);
+        self.1.vectored_buf = coalesced;
+        r
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -667,6 +3285,130 @@ impl<S: io::Read + io::Write> io::Write for TlsStream<S> {
     }
 }
 
+#[cfg(feature = "tcp")]
+impl TlsStream<TcpStream> {
+    /// Splits the stream into a reading and a writing half which can be used from different
+    /// threads.
+    ///
+    /// The halves share the TLS session, but a read waiting for data doesn't hold on to it. It
+    /// waits on a clone of the socket and only takes the session once data has arrived, so the
+    /// writing half can keep writing in the meantime. A write which blocks because the peer isn't
+    /// reading does hold the session until it completes.
+    ///
+    /// While the reading half uses the session, the socket is switched to nonblocking mode and
+    /// then back to the mode it was in when the stream was split. The mode is shared by every
+    /// handle to the socket, so the caller must not change it, nor use other handles to the
+    /// socket, while the stream is split. If the socket is in nonblocking mode, reads fail with
+    /// `WouldBlock` rather than waiting for data. A read timeout set on the socket bounds each
+    /// wait of the reading half.
+    ///
+    /// Windows can't report the mode of a socket, so it must be in blocking mode there.
+    ///
+    /// Requires the `tcp` Cargo feature.
+    pub fn split(self) -> io::Result<(ReadHalf, WriteHalf)> {
+        let socket = self.get_ref().try_clone()?;
+        let nonblocking = is_nonblocking(&socket)?;
+        let stream = Arc::new(Mutex::new(self));
+        let read = ReadHalf {
+            stream: stream.clone(),
+            socket,
+            nonblocking,
+        };
+        Ok((read, WriteHalf(stream)))
+    }
+}
+
+/// The reading half of a `TlsStream`, created by `TlsStream::split`.
+///
+/// Requires the `tcp` Cargo feature.
+#[cfg(feature = "tcp")]
+#[derive(Debug)]
+pub struct ReadHalf {
+    stream: Arc<Mutex<TlsStream<TcpStream>>>,
+    socket: TcpStream,
+    nonblocking: bool,
+}
+
+#[cfg(feature = "tcp")]
+impl io::Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut stream = lock_half(&self.stream)?;
+                if self.nonblocking {
+                    return io::Read::read(&mut *stream, buf);
+                }
+                stream.get_ref().set_nonblocking(true)?;
+                let r = io::Read::read(&mut *stream, buf);
+                stream.get_ref().set_nonblocking(false)?;
+                match r {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    r => return r,
+                }
+            }
+            // Waits for more data without holding on to the session.
+            self.socket.peek(&mut [0])?;
+        }
+    }
+}
+
+/// The writing half of a `TlsStream`, created by `TlsStream::split`.
+///
+/// Requires the `tcp` Cargo feature.
+#[cfg(feature = "tcp")]
+#[derive(Debug)]
+pub struct WriteHalf(Arc<Mutex<TlsStream<TcpStream>>>);
+
+#[cfg(feature = "tcp")]
+impl WriteHalf {
+    /// Shuts down the TLS session.
+    ///
+    /// See `TlsStream::shutdown`.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        lock_half(&self.0)?.shutdown()
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl io::Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::Write::write(&mut *lock_half(&self.0)?, buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        io::Write::write_vectored(&mut *lock_half(&self.0)?, bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut *lock_half(&self.0)?)
+    }
+}
+
+#[cfg(all(unix, feature = "tcp"))]
+fn is_nonblocking(socket: &TcpStream) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let flags = unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_GETFL) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & libc::O_NONBLOCK != 0)
+}
+
+#[cfg(all(not(unix), feature = "tcp"))]
+fn is_nonblocking(_: &TcpStream) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(feature = "tcp")]
+fn lock_half(
+    stream: &Mutex<TlsStream<TcpStream>>,
+) -> io::Result<MutexGuard<'_, TlsStream<TcpStream>>> {
+    stream.lock().map_err(|_| {
+        io::Error::new(io::ErrorKind::Other, "the other half of the stream panicked")
+    })
+}
+
 fn _check_kinds() {
     use std::net::TcpStream;
 
@@ -686,4 +3428,7 @@ fn _check_kinds() {
     is_send::<TlsStream<TcpStream>>();
     is_sync::<MidHandshakeTlsStream<TcpStream>>();
     is_send::<MidHandshakeTlsStream<TcpStream>>();
+    is_send::<TlsStream<Box<dyn ReadWrite + Send>>>();
+    is_sync::<TlsStream<TlsStream<TcpStream>>>();
+    is_send::<TlsStream<TlsStream<TcpStream>>>();
 }
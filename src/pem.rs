@@ -0,0 +1,277 @@
+//! A minimal PEM parser for identities made up of certificates and a private key.
+
+use std::error;
+use std::fmt;
+use std::str;
+
+use der;
+
+// 1.2.840.113549.1.1.1 rsaEncryption
+const RSA_ENCRYPTION_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    Malformed,
+    NoCertificate,
+    NoPrivateKey,
+    Unsupported,
+    UnknownFormat,
+    NoSecret,
+    NoCrl,
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Error::Malformed => "malformed PEM data",
+            Error::NoCertificate => "no certificate found in PEM data",
+            Error::NoPrivateKey => "no private key found in PEM data",
+            Error::Unsupported => "unsupported private key format",
+            Error::UnknownFormat => "unrecognized identity format",
+            Error::NoSecret => "no password was provided",
+            Error::NoCrl => "no CRL found in PEM data",
+        };
+        fmt.write_str(s)
+    }
+}
+
+pub enum Key {
+    /// An unencrypted PKCS #8 key.
+    Pkcs8(Vec<u8>),
+    /// A PKCS #8 `EncryptedPrivateKeyInfo`.
+    EncryptedPkcs8(Vec<u8>),
+}
+
+pub struct Identity {
+    pub chain: Vec<Vec<u8>>,
+    pub key: Key,
+}
+
+/// Determines if `buf` looks like PEM data rather than a binary format.
+pub fn is_pem(buf: &[u8]) -> bool {
+    buf.windows(11).any(|w| w == b"-----BEGIN ")
+}
+
+/// Returns the DER-encoded certificates and the private key of PEM data, ignoring other blocks.
+///
+/// Certificates are returned in the order they appear. Unencrypted PKCS #1 RSA keys are converted
+/// to PKCS #8.
+pub fn identity(buf: &[u8]) -> Result<Identity, Error> {
+    identity_parts(buf, buf)
+}
+
+/// Like `identity`, but reads the certificates from `chain` and the private key from `key`.
+pub fn identity_parts(chain: &[u8], key: &[u8]) -> Result<Identity, Error> {
+    let chain = str::from_utf8(chain).map_err(|_| Error::Malformed)?;
+    let key = str::from_utf8(key).map_err(|_| Error::Malformed)?;
+
+    let chain = blocks(chain)?
+        .into_iter()
+        .filter(|block| block.label == "CERTIFICATE")
+        .filter_map(|block| block.contents)
+        .collect::<Vec<_>>();
+    if chain.is_empty() {
+        return Err(Error::NoCertificate);
+    }
+    match private_key(key)? {
+        Some(key) => Ok(Identity { chain, key }),
+        None => Err(Error::NoPrivateKey),
+    }
+}
+
+/// Returns the first DER-encoded certificate of PEM data.
+#[cfg(feature = "rustls")]
+pub fn certificate(buf: &[u8]) -> Result<Vec<u8>, Error> {
+    certificates(buf).map(|mut certs| certs.swap_remove(0))
+}
+
+/// Returns the DER-encoded certificates of PEM data in the order they appear.
+pub fn certificates(buf: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let buf = str::from_utf8(buf).map_err(|_| Error::Malformed)?;
+    let certs = blocks(buf)?
+        .into_iter()
+        .filter(|block| block.label == "CERTIFICATE")
+        .filter_map(|block| block.contents)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(Error::NoCertificate);
+    }
+    Ok(certs)
+}
+
+/// Returns the first DER-encoded certificate revocation list of PEM data.
+pub fn crl(buf: &[u8]) -> Result<Vec<u8>, Error> {
+    let buf = str::from_utf8(buf).map_err(|_| Error::Malformed)?;
+    blocks(buf)?
+        .into_iter()
+        .filter(|block| block.label == "X509 CRL")
+        .filter_map(|block| block.contents)
+        .next()
+        .ok_or(Error::NoCrl)
+}
+
+/// Returns the first private key of PEM data.
+fn private_key(buf: &str) -> Result<Option<Key>, Error> {
+    let mut key = None;
+    for block in blocks(buf)? {
+        let label = block.label;
+        let der = match block.contents {
+            Some(der) => der,
+            // Legacy OpenSSL encryption, signalled by a Proc-Type header
+            None if label.ends_with("PRIVATE KEY") => return Err(Error::Unsupported),
+            None => continue,
+        };
+        match label {
+            "PRIVATE KEY" if key.is_none() => key = Some(Key::Pkcs8(der)),
+            "ENCRYPTED PRIVATE KEY" if key.is_none() => key = Some(Key::EncryptedPkcs8(der)),
+            "RSA PRIVATE KEY" if key.is_none() => key = Some(Key::Pkcs8(rsa_to_pkcs8(&der))),
+            "EC PRIVATE KEY" | "DSA PRIVATE KEY" if key.is_none() => {
+                return Err(Error::Unsupported)
+            }
+            _ => {}
+        }
+    }
+
+    Ok(key)
+}
+
+/// Encodes DER data as a PEM block with the given label.
+pub fn block(label: &str, der: &[u8]) -> String {
+    let encoded = base64_encode(der);
+    let mut out = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        // base64 is ASCII
+        out.push_str(str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", label));
+    out
+}
+
+struct Block<'a> {
+    label: &'a str,
+    /// The decoded contents, or `None` if the block has headers.
+    contents: Option<Vec<u8>>,
+}
+
+/// Splits PEM data into its labelled blocks.
+fn blocks<'a>(buf: &'a str) -> Result<Vec<Block<'a>>, Error> {
+    let mut blocks = vec![];
+    let mut lines = buf.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let label = match begin_label(line) {
+            Some(label) => label,
+            None => continue,
+        };
+
+        let mut encoded = String::new();
+        let mut headers = false;
+        loop {
+            let line = lines.next().ok_or(Error::Malformed)?;
+            if line.starts_with("-----END ") {
+                if line != format!("-----END {}-----", label) {
+                    return Err(Error::Malformed);
+                }
+                break;
+            }
+            if line.contains(':') {
+                headers = true;
+            } else {
+                encoded.push_str(line);
+            }
+        }
+
+        let contents = if headers {
+            None
+        } else {
+            Some(base64_decode(&encoded)?)
+        };
+        blocks.push(Block { label, contents });
+    }
+    Ok(blocks)
+}
+
+fn begin_label(line: &str) -> Option<&str> {
+    if line.starts_with("-----BEGIN ") && line.ends_with("-----") && line.len() >= 16 {
+        Some(&line[11..line.len() - 5])
+    } else {
+        None
+    }
+}
+
+pub fn base64_decode(s: &str) -> Result<Vec<u8>, Error> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for b in s.bytes() {
+        let v = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err(Error::Malformed),
+        };
+        acc = acc << 6 | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 6 {
+        return Err(Error::Malformed);
+    }
+    Ok(out)
+}
+
+pub fn base64_encode(buf: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(buf.len() / 3 * 4 + 4);
+    for chunk in buf.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).cloned().unwrap_or(0),
+            chunk.get(2).cloned().unwrap_or(0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Wraps a PKCS #1 `RSAPrivateKey` in a PKCS #8 `PrivateKeyInfo`.
+fn rsa_to_pkcs8(key: &[u8]) -> Vec<u8> {
+    let mut algorithm = encode(der::OID, RSA_ENCRYPTION_OID);
+    algorithm.extend_from_slice(&[0x05, 0x00]); // NULL parameters
+
+    let mut info = encode(der::INTEGER, &[0]);
+    info.extend_from_slice(&encode(der::SEQUENCE, &algorithm));
+    info.extend_from_slice(&encode(der::OCTET_STRING, key));
+    encode(der::SEQUENCE, &info)
+}
+
+fn encode(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(value);
+    out
+}
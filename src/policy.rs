@@ -0,0 +1,122 @@
+//! Checks applied to the server's certificate once the handshake completes, in addition to the
+//! backend's own verification.
+
+use std::error;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use {der, hostname, WildcardPolicy};
+
+const SHA1_SIGNATURE_OIDS: &[&[u8]] = &[
+    // 1.2.840.113549.1.1.5 sha1WithRSAEncryption
+    &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05],
+    // 1.2.840.10045.4.1 ecdsa-with-SHA1
+    &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x01],
+    // 1.2.840.10040.4.3 dsa-with-sha1
+    &[0x2a, 0x86, 0x48, 0xce, 0x38, 0x04, 0x03],
+    // 1.3.14.3.2.29 sha1WithRSASignature
+    &[0x2b, 0x0e, 0x03, 0x02, 0x1d],
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    Malformed,
+    NoCertificate,
+    Hostname,
+    IpAddress,
+    ValidityPeriod,
+    Sha1Signature,
+    Rejected,
+    Pin,
+}
+
+impl error::Error for Error {}
+
+impl From<der::Error> for Error {
+    fn from(_: der::Error) -> Error {
+        Error::Malformed
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Error::Malformed => "malformed server certificate",
+            Error::NoCertificate => "the server did not present a certificate",
+            Error::Hostname => "certificate hostname is not permitted by the wildcard policy",
+            Error::IpAddress => "certificate is not valid for the IP address",
+            Error::ValidityPeriod => "certificate validity period exceeds the permitted maximum",
+            Error::Sha1Signature => "certificate chain contains a SHA-1 signature",
+            Error::Rejected => "certificate chain was rejected by the verification callback",
+            Error::Pin => "certificate chain does not contain a pinned public key",
+        };
+        fmt.write_str(s)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Policy {
+    pub wildcards: Option<WildcardPolicy>,
+    // Set when the backend can't match IP addresses against certificates itself.
+    pub ip_addresses: bool,
+    // Set when the backend verifies the chain, so that more than the leaf can be relied upon.
+    pub verified: bool,
+    pub max_validity: Option<Duration>,
+    pub reject_sha1: bool,
+}
+
+impl Policy {
+    pub fn is_empty(&self) -> bool {
+        self.wildcards.is_none()
+            && !self.ip_addresses
+            && self.max_validity.is_none()
+            && !self.reject_sha1
+    }
+
+    /// Checks the server's DER-encoded certificate chain, starting with the leaf.
+    ///
+    /// Hostname checks are skipped if no domain is provided.
+    pub fn verify(&self, chain: &[Vec<u8>], domain: Option<&str>) -> Result<(), Error> {
+        let cert = match chain.first() {
+            Some(cert) => cert,
+            None => return Err(Error::NoCertificate),
+        };
+
+        if let (Some(wildcards), Some(domain)) = (self.wildcards, domain) {
+            if !hostname::verify(cert, domain, wildcards)? {
+                return Err(Error::Hostname);
+            }
+        }
+
+        if self.ip_addresses {
+            if let Some(addr) = domain.and_then(|domain| domain.parse::<IpAddr>().ok()) {
+                if !hostname::verify_ip(cert, addr)? {
+                    return Err(Error::IpAddress);
+                }
+            }
+        }
+
+        if let Some(max_validity) = self.max_validity {
+            let (not_before, not_after) = der::validity(cert)?;
+            match not_after.duration_since(not_before) {
+                Ok(validity) if validity > max_validity => return Err(Error::ValidityPeriod),
+                _ => {}
+            }
+        }
+
+        if self.reject_sha1 {
+            for cert in chain {
+                // The signatures of self-signed roots aren't relied upon.
+                if der::is_self_issued(cert)? {
+                    continue;
+                }
+                if SHA1_SIGNATURE_OIDS.contains(&der::signature_algorithm(cert)?) {
+                    return Err(Error::Sha1Signature);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
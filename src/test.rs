@@ -1,11 +1,26 @@
 use hex;
+#[cfg(feature = "futures-io")]
+use self::futures_util::io::{AsyncReadExt as _, AsyncWriteExt as _};
+#[cfg(feature = "futures-io")]
+use self::tokio_util::compat::TokioAsyncReadCompatExt;
+#[cfg(feature = "tokio")]
+use self::tokio_rt::io::{AsyncReadExt, AsyncWriteExt};
 #[allow(unused_imports)]
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::thread;
 
 use super::*;
 
+#[cfg(feature = "futures-io")]
+extern crate futures_util;
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+extern crate tokio as tokio_rt;
+#[cfg(feature = "futures-io")]
+extern crate tokio_util;
+
 macro_rules! p {
     ($e:expr) => {
         match $e {
@@ -15,6 +30,20 @@ macro_rules! p {
     };
 }
 
+// A certificate signed with SHA-1 by a CA the tests don't trust, which servers append to their
+// chains to check that only the verified path is relied upon.
+const UNRELATED_CERT: &[u8] = b"-----BEGIN CERTIFICATE-----
+MIIBfDCCASOgAwIBAgIUEa0y/feIT4qUxt8aoLRA8xTcejMwCQYHKoZIzj0EATAX
+MRUwEwYDVQQDDAxVbnJlbGF0ZWQgQ0EwIBcNMjYxMDE1MTEyMDQwWhgPMjEyNjA5
+MjExMTIwNDBaMCExHzAdBgNVBAMMFlVucmVsYXRlZCBJbnRlcm1lZGlhdGUwWTAT
+BgcqhkjOPQIBBggqhkjOPQMBBwNCAAT4IqNcXbUolaskLsM4DOVX2NDoJwal9bQM
+XLtQMPWn/bN/IuBbZjIHEwkJyGnq9woDkh6jZ0RbmTIZjs9dfB2Do0IwQDAdBgNV
+HQ4EFgQU517aheL2Yu9Wfl1yHTeu4QA30dQwHwYDVR0jBBgwFoAU2LiD3NEbtiMe
+g12rtyyswFoqEfQwCQYHKoZIzj0EAQNIADBFAiEAqmvs6lYsiUWjOECBGygTis2G
+wzgo5EtMY4rgpiozge4CIHBs33MjI1WTcQhptymXYvoiReTLxFRmtbP0V3uh/jnE
+-----END CERTIFICATE-----
+";
+
 // This nested mod is needed for ios testing with rust-test-ios
 mod tests {
     use super::*;
@@ -57,6 +86,101 @@ mod tests {
         assert!(builder.connect("google.com", s).is_err());
     }
 
+    #[test]
+    #[cfg(feature = "alpn")]
+    fn alpn_google_h2() {
+        let builder = p!(TlsConnector::builder().request_alpns(&["h2"]).build());
+        let s = p!(TcpStream::connect("google.com:443"));
+        let socket = p!(builder.connect("google.com", s));
+        let alpn = p!(socket.negotiated_alpn());
+        assert_eq!(alpn, Some(b"h2".to_vec()));
+    }
+
+    #[test]
+    #[cfg(all(feature = "alpn", not(any(target_os = "macos", target_os = "ios"))))]
+    fn alpn_server_select() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .accept_alpns(&["h2", "http/1.1"])
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            assert_eq!(p!(socket.negotiated_alpn()), Some(b"h2".to_vec()));
+            p!(socket.write_all(b"hello"));
+            drop(socket);
+
+            let socket = p!(listener.accept()).0;
+            let socket = p!(builder.accept(socket));
+            assert_eq!(p!(socket.negotiated_alpn()), None);
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        // The server's preference wins
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca.clone())
+            .request_alpns(&["http/1.1", "h2"])
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        assert_eq!(p!(socket.negotiated_alpn()), Some(b"h2".to_vec()));
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"hello");
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .request_alpns(&["spdy/1"])
+            .build());
+        let socket = p!(builder.connect("foobar.com", socket));
+        assert_eq!(p!(socket.negotiated_alpn()), None);
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(feature = "alpn")]
+    fn alpn_server_unsupported() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            p!(socket.write_all(b"hello"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .request_alpns(&["h2", "http/1.1"])
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        assert_eq!(p!(socket.negotiated_alpn()), None);
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"hello");
+
+        p!(j.join());
+    }
+
     #[test]
     fn server_no_root_certs() {
         let buf = include_bytes!("../test/identity.p12");
@@ -113,6 +237,8 @@ mod tests {
             assert_eq!(&buf, b"hello");
 
             p!(socket.write_all(b"world"));
+            assert_eq!(socket.bytes_read(), 5);
+            assert_eq!(socket.bytes_written(), 5);
         });
 
         let root_ca = include_bytes!("../test/root-ca.der");
@@ -123,21 +249,25 @@ mod tests {
             .add_root_certificate(root_ca)
             .build());
         let mut socket = p!(builder.connect("foobar.com", socket));
+        assert_eq!(socket.bytes_read(), 0);
+        assert_eq!(socket.bytes_written(), 0);
 
         p!(socket.write_all(b"hello"));
         let mut buf = vec![];
         p!(socket.read_to_end(&mut buf));
         assert_eq!(buf, b"world");
+        assert_eq!(socket.bytes_read(), 5);
+        assert_eq!(socket.bytes_written(), 5);
 
         p!(j.join());
     }
 
     #[test]
-    #[cfg(not(target_os = "ios"))]
-    fn server_pem() {
+    #[cfg(feature = "rustls")]
+    fn server_rustls_builder() {
         let buf = include_bytes!("../test/identity.p12");
         let identity = p!(Identity::from_pkcs12(buf, "mypass"));
-        let builder = p!(TlsAcceptor::new(identity));
+        let builder = p!(TlsAcceptor::rustls_builder(identity).build());
 
         let listener = p!(TcpListener::bind("0.0.0.0:0"));
         let port = p!(listener.local_addr()).port();
@@ -145,16 +275,18 @@ mod tests {
         let j = thread::spawn(move || {
             let socket = p!(listener.accept()).0;
             let mut socket = p!(builder.accept(socket));
+            assert_eq!(p!(socket.sni_hostname()).as_deref(), Some("foobar.com"));
 
             let mut buf = [0; 5];
             p!(socket.read_exact(&mut buf));
             assert_eq!(&buf, b"hello");
 
             p!(socket.write_all(b"world"));
+            p!(socket.shutdown());
         });
 
-        let root_ca = include_bytes!("../test/root-ca.pem");
-        let root_ca = Certificate::from_pem(root_ca).unwrap();
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
 
         let socket = p!(TcpStream::connect(("localhost", port)));
         let builder = p!(TlsConnector::builder()
@@ -171,44 +303,53 @@ mod tests {
     }
 
     #[test]
-    fn peer_certificate() {
+    #[cfg(feature = "rustls")]
+    fn select_backend() {
         let buf = include_bytes!("../test/identity.p12");
         let identity = p!(Identity::from_pkcs12(buf, "mypass"));
-        let builder = p!(TlsAcceptor::new(identity));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .backend(Backend::Rustls)
+            .build());
 
         let listener = p!(TcpListener::bind("0.0.0.0:0"));
         let port = p!(listener.local_addr()).port();
 
         let j = thread::spawn(move || {
             let socket = p!(listener.accept()).0;
-            let socket = p!(builder.accept(socket));
-            assert!(socket.peer_certificate().unwrap().is_none());
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+            p!(socket.shutdown());
         });
 
         let root_ca = include_bytes!("../test/root-ca.der");
         let root_ca = Certificate::from_der(root_ca).unwrap();
 
         let socket = p!(TcpStream::connect(("localhost", port)));
-        let builder = p!(TlsConnector::builder()
+        let builder = p!(TlsConnector::rustls_builder()
+            .backend(Backend::Native)
             .add_root_certificate(root_ca)
             .build());
-        let socket = p!(builder.connect("foobar.com", socket));
+        let mut socket = p!(builder.connect("foobar.com", socket));
 
-        let cert_der = include_bytes!("../test/cert.der");
-        let cert = socket.peer_certificate().unwrap().unwrap();
-        assert_eq!(cert.to_der().unwrap(), &cert_der[..]);
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
 
         p!(j.join());
     }
 
     #[test]
-    fn server_tls11_only() {
+    #[cfg(feature = "rustls")]
+    fn connect_rustls_builder() {
         let buf = include_bytes!("../test/identity.p12");
         let identity = p!(Identity::from_pkcs12(buf, "mypass"));
-        let builder = p!(TlsAcceptor::builder(identity)
-            .min_protocol_version(Some(Protocol::Tlsv11))
-            .max_protocol_version(Some(Protocol::Tlsv11))
-            .build());
+        let builder = p!(TlsAcceptor::new(identity));
 
         let listener = p!(TcpListener::bind("0.0.0.0:0"));
         let port = p!(listener.local_addr()).port();
@@ -222,16 +363,15 @@ mod tests {
             assert_eq!(&buf, b"hello");
 
             p!(socket.write_all(b"world"));
+            p!(socket.shutdown());
         });
 
         let root_ca = include_bytes!("../test/root-ca.der");
         let root_ca = Certificate::from_der(root_ca).unwrap();
 
         let socket = p!(TcpStream::connect(("localhost", port)));
-        let builder = p!(TlsConnector::builder()
+        let builder = p!(TlsConnector::rustls_builder()
             .add_root_certificate(root_ca)
-            .min_protocol_version(Some(Protocol::Tlsv11))
-            .max_protocol_version(Some(Protocol::Tlsv11))
             .build());
         let mut socket = p!(builder.connect("foobar.com", socket));
 
@@ -244,38 +384,53 @@ mod tests {
     }
 
     #[test]
-    fn server_no_shared_protocol() {
+    #[cfg(not(target_os = "ios"))]
+    fn server_pem() {
         let buf = include_bytes!("../test/identity.p12");
         let identity = p!(Identity::from_pkcs12(buf, "mypass"));
-        let builder = p!(TlsAcceptor::builder(identity)
-            .min_protocol_version(Some(Protocol::Tlsv12))
-            .build());
+        let builder = p!(TlsAcceptor::new(identity));
 
         let listener = p!(TcpListener::bind("0.0.0.0:0"));
         let port = p!(listener.local_addr()).port();
 
         let j = thread::spawn(move || {
             let socket = p!(listener.accept()).0;
-            assert!(builder.accept(socket).is_err());
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
         });
 
-        let root_ca = include_bytes!("../test/root-ca.der");
-        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let root_ca = include_bytes!("../test/root-ca.pem");
+        let root_ca = Certificate::from_pem(root_ca).unwrap();
 
         let socket = p!(TcpStream::connect(("localhost", port)));
         let builder = p!(TlsConnector::builder()
             .add_root_certificate(root_ca)
-            .max_protocol_version(Some(Protocol::Tlsv11))
             .build());
-        assert!(builder.connect("foobar.com", socket).is_err());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
 
         p!(j.join());
     }
 
     #[test]
-    fn server_untrusted() {
-        let buf = include_bytes!("../test/identity.p12");
-        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+    #[cfg(feature = "rustls-pki-types")]
+    fn server_pki_types() {
+        use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+        let cert = CertificateDer::from(&include_bytes!("../test/cert.der")[..]);
+        let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(
+            &include_bytes!("../test/key.der")[..],
+        ));
+        let identity = p!(Identity::from_pki_types(&[cert], &key));
         let builder = p!(TlsAcceptor::new(identity));
 
         let listener = p!(TcpListener::bind("0.0.0.0:0"));
@@ -283,22 +438,40 @@ mod tests {
 
         let j = thread::spawn(move || {
             let socket = p!(listener.accept()).0;
-            // FIXME should assert error
-            // https://github.com/steffengy/schannel-rs/issues/20
-            let _ = builder.accept(socket);
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
         });
 
+        let root_ca = CertificateDer::from(&include_bytes!("../test/root-ca.der")[..]);
+        let root_ca = p!(Certificate::from_certificate_der(&root_ca));
+        assert_eq!(
+            &*p!(root_ca.to_certificate_der()),
+            &include_bytes!("../test/root-ca.der")[..]
+        );
+
         let socket = p!(TcpStream::connect(("localhost", port)));
-        let builder = p!(TlsConnector::new());
-        builder.connect("foobar.com", socket).unwrap_err();
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
 
         p!(j.join());
     }
 
     #[test]
-    fn server_untrusted_unverified() {
-        let buf = include_bytes!("../test/identity.p12");
-        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+    fn server_jks() {
+        let buf = include_bytes!("../test/identity.jks");
+        let identity = p!(Identity::from_jks(buf, "mypass", "mypass"));
         let builder = p!(TlsAcceptor::new(identity));
 
         let listener = p!(TcpListener::bind("0.0.0.0:0"));
@@ -315,9 +488,13 @@ mod tests {
             p!(socket.write_all(b"world"));
         });
 
+        let buf = include_bytes!("../test/root-ca.jks");
+        let mut roots = p!(Certificate::stack_from_jks(buf, "mypass"));
+        assert_eq!(roots.len(), 1);
+
         let socket = p!(TcpStream::connect(("localhost", port)));
         let builder = p!(TlsConnector::builder()
-            .danger_accept_invalid_certs(true)
+            .add_root_certificate(roots.pop().unwrap())
             .build());
         let mut socket = p!(builder.connect("foobar.com", socket));
 
@@ -330,14 +507,169 @@ mod tests {
     }
 
     #[test]
-    fn import_same_identity_multiple_times() {
+    fn jks_bad_password() {
+        let buf = include_bytes!("../test/identity.jks");
+        assert!(Identity::from_jks(buf, "badpass", "mypass").is_err());
+        assert!(Identity::from_jks(buf, "mypass", "badpass").is_err());
+    }
+
+    #[test]
+    fn certificate_urls() {
+        let cert = include_bytes!("../test/extensions.der");
+        let cert = p!(Certificate::from_der(cert));
+        assert_eq!(p!(cert.ocsp_responders()), ["http://ocsp.example.com"]);
+        assert_eq!(p!(cert.ca_issuers()), ["http://ca.example.com/ca.der"]);
+        assert_eq!(
+            p!(cert.crl_distribution_points()),
+            ["http://crl.example.com/ca.crl", "ldap://crl.example.com/cn=ca"]
+        );
+
+        let cert = include_bytes!("../test/cert.der");
+        let cert = p!(Certificate::from_der(cert));
+        assert!(p!(cert.ocsp_responders()).is_empty());
+        assert!(p!(cert.ca_issuers()).is_empty());
+        assert!(p!(cert.crl_distribution_points()).is_empty());
+    }
+
+    #[test]
+    fn identity_from_bytes() {
+        let cert = &include_bytes!("../test/cert.der")[..];
+        let root_ca = &include_bytes!("../test/root-ca.der")[..];
+
         let buf = include_bytes!("../test/identity.p12");
-        let _ = p!(Identity::from_pkcs12(buf, "mypass"));
-        let _ = p!(Identity::from_pkcs12(buf, "mypass"));
+        let identity = p!(Identity::from_bytes(buf, "mypass"));
+        assert_eq!(p!(p!(identity.certificate()).to_der()), cert);
+        let err = Identity::from_bytes(buf, "badpass").err().unwrap();
+        assert!(err.to_string().contains("PKCS #12"), "{}", err);
+
+        let buf = include_bytes!("../test/identity.jks");
+        let identity = p!(Identity::from_bytes(buf, "mypass"));
+        assert_eq!(p!(p!(identity.certificate()).to_der()), cert);
+        let err = Identity::from_bytes(buf, "badpass").err().unwrap();
+        assert!(err.to_string().contains("Java KeyStore"), "{}", err);
+
+        let buf = include_bytes!("../test/identity.pem");
+        let identity = p!(Identity::from_bytes(buf, ""));
+        assert_eq!(p!(p!(identity.certificate()).to_der()), cert);
+        let chain = p!(identity.chain());
+        assert_eq!(chain.len(), 1);
+        assert_eq!(p!(chain[0].to_der()), root_ca);
+        p!(TlsAcceptor::new(identity));
+
+        let buf = include_bytes!("../test/identity-encrypted.pem");
+        if cfg!(target_os = "windows") {
+            assert!(Identity::from_bytes(buf, "mypass").is_err());
+        } else {
+            let identity = p!(Identity::from_bytes(buf, "mypass"));
+            assert_eq!(p!(p!(identity.certificate()).to_der()), cert);
+            p!(TlsAcceptor::new(identity));
+        }
+        let err = Identity::from_bytes(buf, "badpass").err().unwrap();
+        assert!(err.to_string().contains("PEM"), "{}", err);
+
+        let err = Identity::from_bytes(b"hello", "").err().unwrap();
+        assert!(err.to_string().contains("unrecognized"), "{}", err);
+        let err = Identity::from_bytes(include_bytes!("../test/cert.pem"), "").err().unwrap();
+        assert!(err.to_string().contains("private key"), "{}", err);
     }
 
     #[test]
-    fn shutdown() {
+    fn identity_empty_password() {
+        let cert = include_bytes!("../test/cert.der");
+
+        let buf = include_bytes!("../test/identity-nopass.p12");
+        let identity = p!(Identity::from_pkcs12(buf, ""));
+        assert_eq!(p!(p!(identity.certificate()).to_der()), &cert[..]);
+        assert_eq!(p!(identity.chain()).len(), 1);
+        assert!(Identity::from_pkcs12(buf, "mypass").is_err());
+
+        let buf = include_bytes!("../test/identity-nomac.p12");
+        let identity = p!(Identity::from_pkcs12(buf, ""));
+        assert_eq!(p!(p!(identity.certificate()).to_der()), &cert[..]);
+        p!(TlsAcceptor::new(identity.clone()));
+
+        // The key is stored in a plain key bag, which the rustls backend doesn't load.
+        #[cfg(feature = "rustls")]
+        {
+            let err = TlsAcceptor::rustls_builder(identity).build().unwrap_err();
+            assert!(err.to_string().contains("PKCS #12"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn identity_pkcs12_bad_mac() {
+        // The archive ends with the MAC's iteration count.
+        let mut buf = include_bytes!("../test/identity.p12").to_vec();
+        *buf.last_mut().unwrap() ^= 1;
+        assert!(Identity::from_pkcs12(&buf, "mypass").is_err());
+    }
+
+    #[test]
+    fn identity_from_pkcs8() {
+        let mut pem = include_bytes!("../test/cert.pem").to_vec();
+        pem.extend_from_slice(include_bytes!("../test/root-ca.pem"));
+        let key = include_bytes!("../test/key.pem");
+        let identity = p!(Identity::from_pkcs8(&pem, key));
+        let cert = include_bytes!("../test/cert.der");
+        assert_eq!(p!(p!(identity.certificate()).to_der()), &cert[..]);
+        let chain = p!(identity.chain());
+        assert_eq!(chain.len(), 1);
+        assert_eq!(
+            p!(chain[0].to_der()),
+            &include_bytes!("../test/root-ca.der")[..]
+        );
+        p!(TlsAcceptor::new(identity));
+
+        assert!(Identity::from_pkcs8(key, key).is_err());
+        assert!(Identity::from_pkcs8(&pem, &pem).is_err());
+    }
+
+    #[test]
+    fn identity_secret_provider() {
+        use std::cell::RefCell;
+
+        let requests = RefCell::new(vec![]);
+        let provider = |kind| {
+            requests.borrow_mut().push(kind);
+            Some("mypass".to_string())
+        };
+
+        let buf = include_bytes!("../test/identity.pem");
+        p!(Identity::from_bytes_with_provider(buf, &provider));
+        assert_eq!(*requests.borrow(), []);
+
+        let buf = include_bytes!("../test/identity.p12");
+        p!(Identity::from_pkcs12_with_provider(buf, &provider));
+        assert_eq!(*requests.borrow(), [SecretKind::Pkcs12Password]);
+
+        requests.borrow_mut().clear();
+        let buf = include_bytes!("../test/identity.jks");
+        p!(Identity::from_bytes_with_provider(buf, &provider));
+        assert_eq!(
+            *requests.borrow(),
+            [SecretKind::KeyStorePassword, SecretKind::PrivateKeyPassword]
+        );
+
+        if !cfg!(target_os = "windows") {
+            requests.borrow_mut().clear();
+            let buf = include_bytes!("../test/identity-encrypted.pem");
+            p!(Identity::from_bytes_with_provider(buf, &provider));
+            assert_eq!(*requests.borrow(), [SecretKind::PrivateKeyPassword]);
+        }
+
+        let buf = include_bytes!("../test/identity.p12");
+        let err = Identity::from_pkcs12_with_provider(buf, &|_| None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("no password"), "{}", err);
+    }
+
+    #[test]
+    fn nonblocking_write_moved_buffer() {
+        use std::sync::mpsc;
+
+        const LEN: usize = 4 * 1024 * 1024;
+
         let buf = include_bytes!("../test/identity.p12");
         let identity = p!(Identity::from_pkcs12(buf, "mypass"));
         let builder = p!(TlsAcceptor::new(identity));
@@ -345,16 +677,16 @@ mod tests {
         let listener = p!(TcpListener::bind("0.0.0.0:0"));
         let port = p!(listener.local_addr()).port();
 
+        let (tx, rx) = mpsc::channel();
         let j = thread::spawn(move || {
             let socket = p!(listener.accept()).0;
             let mut socket = p!(builder.accept(socket));
 
-            let mut buf = [0; 5];
+            // Stop reading so the client's writes block
+            p!(rx.recv());
+            let mut buf = vec![0; LEN];
             p!(socket.read_exact(&mut buf));
-            assert_eq!(&buf, b"hello");
-
-            assert_eq!(p!(socket.read(&mut buf)), 0);
-            p!(socket.shutdown());
+            assert!(buf.iter().enumerate().all(|(i, &b)| b == (i % 251) as u8));
         });
 
         let root_ca = include_bytes!("../test/root-ca.der");
@@ -365,18 +697,35 @@ mod tests {
             .add_root_certificate(root_ca)
             .build());
         let mut socket = p!(builder.connect("foobar.com", socket));
-
-        p!(socket.write_all(b"hello"));
-        p!(socket.shutdown());
+        p!(socket.get_ref().set_nonblocking(true));
+
+        let data = (0..LEN).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut pos = 0;
+        let mut blocked = false;
+        while pos < LEN {
+            // Copy the remaining data into a fresh buffer for each attempt
+            let buf = data[pos..].to_vec();
+            match socket.write(&buf) {
+                Ok(n) => pos += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if !blocked {
+                        blocked = true;
+                        let err = socket.write(&buf[..1]).unwrap_err();
+                        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+                        p!(tx.send(()));
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        assert!(blocked);
 
         p!(j.join());
     }
 
     #[test]
-    #[cfg_attr(target_os = "ios", ignore)]
-    fn tls_server_end_point() {
-        let expected = "4712b939fbcb42a6b5101b42139a25b14f81b418facabd378746f12f85cc6544";
-
+    fn server_certificate_exceptions() {
         let buf = include_bytes!("../test/identity.p12");
         let identity = p!(Identity::from_pkcs12(buf, "mypass"));
         let builder = p!(TlsAcceptor::new(identity));
@@ -386,10 +735,10 @@ mod tests {
 
         let j = thread::spawn(move || {
             let socket = p!(listener.accept()).0;
-            let mut socket = p!(builder.accept(socket));
+            let _ = builder.accept(socket);
 
-            let binding = socket.tls_server_end_point().unwrap().unwrap();
-            assert_eq!(hex::encode(binding), expected);
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
 
             let mut buf = [0; 5];
             p!(socket.read_exact(&mut buf));
@@ -398,17 +747,29 @@ mod tests {
             p!(socket.write_all(b"world"));
         });
 
-        let root_ca = include_bytes!("../test/root-ca.der");
-        let root_ca = Certificate::from_der(root_ca).unwrap();
-
-        let socket = p!(TcpStream::connect(("localhost", port)));
+        let exceptions = CertificateExceptions::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen2 = seen.clone();
+        exceptions.set_unknown_certificate_callback(move |host, cert| {
+            let fingerprint = p!(cert.fingerprint_sha256());
+            seen2.lock().unwrap().push((host.to_owned(), fingerprint));
+        });
         let builder = p!(TlsConnector::builder()
-            .add_root_certificate(root_ca)
+            .certificate_exceptions(exceptions.clone())
             .build());
-        let mut socket = p!(builder.connect("foobar.com", socket));
 
-        let binding = socket.tls_server_end_point().unwrap().unwrap();
-        assert_eq!(hex::encode(binding), expected);
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        assert!(builder.connect("foobar.com", socket).is_err());
+
+        let cert = p!(Certificate::from_der(include_bytes!("../test/cert.der")));
+        let fingerprint = p!(cert.fingerprint_sha256());
+        let seen = seen.lock().unwrap().pop().unwrap();
+        assert_eq!(seen, ("foobar.com".to_owned(), fingerprint.clone()));
+
+        exceptions.add(CertificateException::new("foobar.com", &fingerprint, None));
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let mut socket = p!(builder.connect("foobar.com", socket));
 
         p!(socket.write_all(b"hello"));
         let mut buf = vec![];
@@ -417,4 +778,3223 @@ mod tests {
 
         p!(j.join());
     }
+
+    #[test]
+    fn server_verify_callback() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            // the client only rejects the certificate once the handshake completes
+            let socket = p!(listener.accept()).0;
+            let _ = builder.accept(socket);
+
+            for _ in 0..2 {
+                let socket = p!(listener.accept()).0;
+                let mut socket = p!(builder.accept(socket));
+                p!(socket.write_all(b"world"));
+            }
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let cert = p!(Certificate::from_der(include_bytes!("../test/cert.der")));
+        let fingerprint = p!(cert.fingerprint_sha256());
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca.clone())
+            .verify_callback(|_, _| false)
+            .build());
+        builder.connect("foobar.com", socket).unwrap_err();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen2 = seen.clone();
+        let verified = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .verify_callback(move |domain, chain| {
+                seen2.lock().unwrap().push((domain.to_owned(), chain.len()));
+                true
+            })
+            .build());
+        // Trust the certificate by its fingerprint alone.
+        let pinned = p!(TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .verify_callback(move |_, chain| {
+                chain[0].fingerprint_sha256().ok() == Some(fingerprint.clone())
+            })
+            .build());
+        for builder in &[verified, pinned] {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let mut socket = p!(builder.connect("foobar.com", socket));
+            let mut buf = vec![];
+            p!(socket.read_to_end(&mut buf));
+            assert_eq!(buf, b"world");
+        }
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].0, "foobar.com");
+        assert!(seen[0].1 >= 1);
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_pinned_public_keys() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            // the client only checks the pins once the handshake completes
+            let socket = p!(listener.accept()).0;
+            let _ = builder.accept(socket);
+
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let cert = p!(Certificate::from_der(include_bytes!("../test/cert.der")));
+        let pin = p!(Sha256Pin::from_certificate(&cert));
+        assert_eq!(Sha256Pin::from_base64(&pin.to_string()), Some(pin));
+        assert_eq!(Sha256Pin::from_base64("d29ybGQ="), None);
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca.clone())
+            .pin_public_keys(vec![Sha256Pin::new([0; 32])])
+            .build());
+        builder.connect("foobar.com", socket).unwrap_err();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .pin_public_keys(vec![Sha256Pin::new([0; 32]), pin])
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_pinned_public_keys_unverified() {
+        let builder = unrelated_chain_acceptor();
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            for _ in 0..5 {
+                let socket = p!(listener.accept()).0;
+                if let Ok(mut socket) = builder.accept(socket) {
+                    let _ = socket.write_all(b"world");
+                }
+            }
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let root_pin = p!(Sha256Pin::from_certificate(&root_ca));
+        let cert = p!(Certificate::from_der(include_bytes!("../test/cert.der")));
+        let leaf_pin = p!(Sha256Pin::from_certificate(&cert));
+        let unrelated = p!(Certificate::from_pem(UNRELATED_CERT));
+        let unrelated_pin = p!(Sha256Pin::from_certificate(&unrelated));
+
+        let connect = |pin: Sha256Pin, accept_invalid_certs: bool| {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let builder = p!(TlsConnector::builder()
+                .add_root_certificate(root_ca.clone())
+                .danger_accept_invalid_certs(accept_invalid_certs)
+                .pin_public_keys(vec![pin])
+                .build());
+            builder.connect("foobar.com", socket).is_ok()
+        };
+
+        // The unrelated certificate is sent, but isn't part of the verified chain.
+        assert!(!connect(unrelated_pin, false));
+        assert!(connect(root_pin, false));
+        // Only the leaf is matched if the chain isn't verified.
+        assert!(!connect(unrelated_pin, true));
+        assert!(!connect(root_pin, true));
+        assert!(connect(leaf_pin, true));
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn server_ocsp_staple() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let plain = p!(TlsAcceptor::new(identity.clone()));
+        let stapled = p!(TlsAcceptor::builder(identity)
+            .ocsp_response(Some(b"not a response"))
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            for builder in &[&plain, &stapled, &plain] {
+                let socket = p!(listener.accept()).0;
+                if let Ok(mut socket) = builder.accept(socket) {
+                    p!(socket.write_all(b"world"));
+                }
+            }
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let required = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca.clone())
+            .require_ocsp_staple(true)
+            .build());
+
+        // no response, and one which doesn't parse
+        for _ in 0..2 {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            required.connect("foobar.com", socket).unwrap_err();
+        }
+
+        let builder = p!(TlsConnector::builder().add_root_certificate(root_ca).build());
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn server_check_revocation() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            builder.accept(socket).unwrap_err();
+        });
+
+        assert!(Crl::from_der(b"not a crl").is_err());
+        assert!(Crl::from_pem(include_bytes!("../test/cert.pem")).is_err());
+
+        // there's no CRL for the chain
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .check_revocation(true)
+            .build());
+        builder.connect("foobar.com", socket).unwrap_err();
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn client_hello_fingerprints() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .capture_client_hello(true)
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let ja3 = p!(socket.client_hello_ja3());
+            let ja4 = p!(socket.client_hello_ja4());
+            if cfg!(not(any(target_os = "macos", target_os = "windows", target_os = "ios"))) {
+                assert_eq!(ja3.unwrap().len(), 32);
+                assert!(ja4.unwrap().starts_with("t1"));
+            }
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        assert_eq!(p!(socket.client_hello_ja3()), None);
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn client_hello_not_captured() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            assert_eq!(p!(socket.client_hello_ja3()), None);
+            assert_eq!(p!(socket.client_hello_ja4()), None);
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn connect_without_domain() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            {
+                let socket = p!(listener.accept()).0;
+                let mut socket = p!(builder.accept(socket));
+                assert_eq!(p!(socket.sni_hostname()), None);
+                p!(socket.write_all(b"world"));
+            }
+
+            let socket = p!(listener.accept()).0;
+            builder.accept(socket).unwrap_err();
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let mut socket = p!(builder.danger_connect_without_domain(socket));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        // the certificate doesn't list any IP addresses
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let addr = "127.0.0.1".parse().unwrap();
+        builder.connect_ip(addr, socket).unwrap_err();
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn connect_ip_address() {
+        let buf = include_bytes!("../test/identity-ip.pem");
+        let identity = p!(Identity::from_bytes(buf, ""));
+        let cert = p!(identity.certificate());
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            for _ in 0..2 {
+                let socket = p!(listener.accept()).0;
+                let mut socket = p!(builder.accept(socket));
+                assert_eq!(p!(socket.sni_hostname()), None);
+                p!(socket.write_all(b"world"));
+            }
+
+            let socket = p!(listener.accept()).0;
+            builder.accept(socket).unwrap_err();
+        });
+
+        let builder = p!(TlsConnector::builder()
+            .disable_built_in_roots(true)
+            .add_root_certificate(cert)
+            .build());
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let mut socket = p!(builder.connect("127.0.0.1", socket));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let mut socket = p!(builder.connect_ip("127.0.0.1".parse().unwrap(), socket));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        builder.connect("127.0.0.2", socket).unwrap_err();
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_sni_hostname() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let expected = [Some("foobar.com"), None];
+            for expected in &expected {
+                let socket = p!(listener.accept()).0;
+                let mut socket = p!(builder.accept(socket));
+
+                let hostname = p!(socket.sni_hostname());
+                if cfg!(not(any(
+                    target_os = "macos",
+                    target_os = "windows",
+                    target_os = "ios"
+                ))) {
+                    assert_eq!(hostname.as_deref(), *expected);
+                }
+
+                p!(socket.write_all(b"world"));
+            }
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        for &use_sni in &[true, false] {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let builder = p!(TlsConnector::builder()
+                .add_root_certificate(root_ca.clone())
+                .use_sni(use_sni)
+                .build());
+            let mut socket = p!(builder.connect("foobar.com", socket));
+
+            assert_eq!(p!(socket.sni_hostname()), None);
+
+            let mut buf = vec![];
+            p!(socket.read_to_end(&mut buf));
+            assert_eq!(buf, b"world");
+        }
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_expiry_warning() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let cert = p!(identity.certificate());
+        assert_eq!(p!(cert.to_der()), &include_bytes!("../test/cert.der")[..]);
+        let chain = p!(identity.chain());
+        assert_eq!(chain.len(), 1);
+        assert_eq!(p!(chain[0].to_der()), &include_bytes!("../test/root-ca.der")[..]);
+        let not_after = p!(cert.not_after());
+        assert!(p!(cert.not_before()) < not_after);
+
+        let warnings = Arc::new(Mutex::new(vec![]));
+        let warnings2 = warnings.clone();
+        let builder = p!(TlsAcceptor::builder(identity)
+            .expiry_warning(Duration::from_secs(100 * 365 * 24 * 60 * 60), move |_, t| {
+                warnings2.lock().unwrap().push(t)
+            })
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+
+        assert_eq!(*warnings.lock().unwrap(), vec![not_after]);
+    }
+
+    #[test]
+    #[cfg(target_os = "windows")]
+    fn system_protocols() {
+        use backend::schannel::{system_protocol_enabled, TlsConnectorBuilderExt};
+
+        p!(system_protocol_enabled(Protocol::Tlsv12, false));
+        p!(system_protocol_enabled(Protocol::Tlsv12, true));
+
+        let builder = p!(TlsConnector::builder().use_system_protocols(true).build());
+        let s = p!(TcpStream::connect("google.com:443"));
+        p!(builder.connect("google.com", s));
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn server_no_middlebox_compat() {
+        use backend::openssl::{TlsAcceptorBuilderExt, TlsConnectorBuilderExt};
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .middlebox_compat(false)
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .middlebox_compat(false)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn server_sm_cipher_suites() {
+        use backend::openssl::{TlsAcceptorBuilderExt, TlsConnectorBuilderExt};
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = match TlsAcceptor::builder(identity)
+            .sm_cipher_suites(true)
+            .build()
+        {
+            Ok(builder) => builder,
+            // The linked OpenSSL doesn't provide the SM cipher suites
+            Err(_) => {
+                assert!(TlsConnector::builder()
+                    .sm_cipher_suites(true)
+                    .build()
+                    .is_err());
+                return;
+            }
+        };
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .sm_cipher_suites(true)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn server_client_auth() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let builder = p!(TlsAcceptor::builder(identity.clone())
+            .client_auth(ClientAuth::Required)
+            .add_client_root_certificate(root_ca.clone())
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            let cert = p!(socket.peer_certificate()).unwrap();
+            assert_eq!(p!(cert.to_der()), &include_bytes!("../test/cert.der")[..]);
+            p!(socket.write_all(b"hello"));
+            drop(socket);
+
+            // Clients without a certificate are turned away
+            let socket = p!(listener.accept()).0;
+            assert!(builder.accept(socket).is_err());
+        });
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca.clone())
+            .identity(identity)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"hello");
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder().add_root_certificate(root_ca).build());
+        if let Ok(mut socket) = builder.connect("foobar.com", socket) {
+            // The server's rejection may only arrive after the client considers the handshake done
+            let mut buf = vec![];
+            assert!(socket.read_to_end(&mut buf).is_err() || buf.is_empty());
+        }
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn connect_legacy_fallback() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            // Drop the first connection after the ClientHello, as intolerant servers do.
+            let mut socket = p!(listener.accept()).0;
+            let mut buf = [0; 1];
+            p!(socket.read_exact(&mut buf));
+            drop(socket);
+
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            p!(socket.write_all(b"hello"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .legacy_fallback(Some(LegacyProfile::new()))
+            .build());
+        let (mut socket, profile) = p!(builder.connect_with_fallback("foobar.com", || {
+            TcpStream::connect(("localhost", port))
+        }));
+        assert_eq!(profile, HandshakeProfile::Legacy);
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"hello");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    fn connect_session_cache() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            for _ in 0..2 {
+                let socket = p!(listener.accept()).0;
+                let mut socket = p!(builder.accept(socket));
+
+                let mut buf = [0; 5];
+                p!(socket.read_exact(&mut buf));
+                assert_eq!(&buf, b"hello");
+
+                p!(socket.write_all(b"world"));
+                // Wait for the client to close the session.
+                let mut buf = vec![];
+                p!(socket.read_to_end(&mut buf));
+                p!(socket.shutdown());
+            }
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .session_cache(true)
+            .build());
+
+        for &resumed in &[false, true] {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let mut socket = p!(builder.connect("foobar.com", socket));
+            assert_eq!(p!(socket.session_resumed()), resumed);
+
+            p!(socket.write_all(b"hello"));
+            // TLS 1.3 session tickets are sent after the handshake.
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"world");
+            p!(socket.shutdown());
+        }
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn server_session_ticket_keys() {
+        use backend::openssl::{SessionTicketKeys, TlsAcceptorBuilderExt};
+
+        let keys = SessionTicketKeys::new(&[1; 80]);
+
+        let serve = |connections| {
+            let buf = include_bytes!("../test/identity.p12");
+            let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+            let builder = p!(TlsAcceptor::builder(identity)
+                .session_ticket_keys(keys.clone())
+                .build());
+
+            let listener = p!(TcpListener::bind("0.0.0.0:0"));
+            let port = p!(listener.local_addr()).port();
+
+            let j = thread::spawn(move || {
+                for _ in 0..connections {
+                    let socket = p!(listener.accept()).0;
+                    let mut socket = p!(builder.accept(socket));
+
+                    let mut buf = [0; 5];
+                    p!(socket.read_exact(&mut buf));
+                    assert_eq!(&buf, b"hello");
+
+                    p!(socket.write_all(b"world"));
+                    let mut buf = vec![];
+                    p!(socket.read_to_end(&mut buf));
+                    p!(socket.shutdown());
+                }
+            });
+            (port, j)
+        };
+        let (port1, j1) = serve(2);
+        let (port2, j2) = serve(1);
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .session_cache(true)
+            .build());
+
+        let connect = |port| {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let mut socket = p!(builder.connect("foobar.com", socket));
+            let resumed = p!(socket.session_resumed());
+
+            p!(socket.write_all(b"hello"));
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"world");
+            p!(socket.shutdown());
+            resumed
+        };
+
+        assert!(!connect(port1));
+        // The other acceptor can decrypt the ticket issued by the first.
+        assert!(connect(port2));
+        // Tickets encrypted with the previous key are still accepted.
+        keys.rotate(&[2; 80]);
+        assert!(connect(port1));
+
+        p!(j1.join());
+        p!(j2.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn server_multiple_identities() {
+        let buf = include_bytes!("../test/identity.p12");
+        let rsa = p!(Identity::from_pkcs12(buf, "mypass"));
+        let buf = include_bytes!("../test/identity-ec.pem");
+        let ec = p!(Identity::from_bytes(buf, ""));
+        let ec_cert = p!(ec.certificate());
+        let builder = p!(TlsAcceptor::builder(rsa).add_identity(ec).build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        // A client supporting ECDSA is served the ECDSA certificate, so it's the only one trusted.
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .disable_built_in_roots(true)
+            .add_root_certificate(ec_cert.clone())
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        let peer = p!(socket.peer_certificate()).unwrap();
+        assert_eq!(p!(peer.to_der()), p!(ec_cert.to_der()));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn server_sni_identities() {
+        let buf = include_bytes!("../test/identity.p12");
+        let rsa = p!(Identity::from_pkcs12(buf, "mypass"));
+        let buf = include_bytes!("../test/identity-ec.pem");
+        let ec = p!(Identity::from_bytes(buf, ""));
+        let ec_cert = p!(ec.certificate());
+        let rsa_cert = p!(rsa.certificate());
+        let builder = p!(TlsAcceptor::builder(rsa)
+            .add_sni_identity("*.Example.com", ec)
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            for _ in 0..2 {
+                let socket = p!(listener.accept()).0;
+                let mut socket = p!(builder.accept(socket));
+                p!(socket.write_all(b"hello"));
+            }
+        });
+
+        let connect = |domain: &str| {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let builder = p!(TlsConnector::builder()
+                .danger_accept_invalid_certs(true)
+                .build());
+            let mut socket = p!(builder.connect(domain, socket));
+            let mut buf = vec![];
+            p!(socket.read_to_end(&mut buf));
+            assert_eq!(buf, b"hello");
+            p!(socket.peer_certificate()).unwrap()
+        };
+
+        let peer = connect("www.example.com.");
+        assert_eq!(p!(peer.to_der()), p!(ec_cert.to_der()));
+        let peer = connect("foobar.com");
+        assert_eq!(p!(peer.to_der()), p!(rsa_cert.to_der()));
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn server_rekey_after_bytes() {
+        use backend::openssl::{TlsAcceptorBuilderExt, TlsConnectorBuilderExt};
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .rekey_after_bytes(Some(1))
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            for _ in 0..3 {
+                let mut buf = [0; 5];
+                p!(socket.read_exact(&mut buf));
+                assert_eq!(&buf, b"hello");
+                p!(socket.write_all(b"world"));
+            }
+            // The client's reads update its keys as well, so wait for it to finish.
+            let _ = socket.read(&mut [0]);
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .rekey_after_bytes(Some(1))
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        for _ in 0..3 {
+            p!(socket.write_all(b"hello"));
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"world");
+        }
+        drop(socket);
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn server_rekey_while_reading() {
+        use backend::openssl::TlsAcceptorBuilderExt;
+        use std::sync::mpsc;
+
+        // Counts the records sent by a server which only reads.
+        fn records_sent(rekey_after_bytes: Option<u64>) -> usize {
+            let buf = include_bytes!("../test/identity.p12");
+            let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+            let builder = p!(TlsAcceptor::builder(identity)
+                .rekey_after_bytes(rekey_after_bytes)
+                .build());
+
+            let listener = p!(TcpListener::bind("0.0.0.0:0"));
+            let port = p!(listener.local_addr()).port();
+            let (tx, rx) = mpsc::channel();
+
+            let j = thread::spawn(move || {
+                let socket = p!(listener.accept()).0;
+                let mut socket = p!(builder.accept(socket));
+                for _ in 0..3 {
+                    let mut buf = [0; 5];
+                    p!(socket.read_exact(&mut buf));
+                    assert_eq!(&buf, b"hello");
+                }
+                p!(tx.send(()));
+                let _ = socket.read(&mut [0]);
+            });
+
+            let root_ca = include_bytes!("../test/root-ca.der");
+            let root_ca = Certificate::from_der(root_ca).unwrap();
+
+            let transcript = Arc::new(Mutex::new(vec![]));
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let socket = Transcript(socket, transcript.clone());
+            let builder = p!(TlsConnector::builder()
+                .add_root_certificate(root_ca)
+                .build());
+            let mut socket = p!(builder.connect("foobar.com", socket));
+            for _ in 0..3 {
+                p!(socket.write_all(b"hello"));
+            }
+            p!(rx.recv());
+
+            // The server doesn't send any application data, so this only reads its other records.
+            p!(socket.get_ref().0.set_read_timeout(Some(Duration::from_millis(100))));
+            assert!(socket.read(&mut [0]).is_err());
+            drop(socket);
+            p!(j.join());
+
+            let transcript = transcript.lock().unwrap();
+            application_data_records(&transcript, 0).len()
+        }
+
+        assert!(records_sent(Some(1)) > records_sent(None));
+    }
+
+    #[test]
+    fn backend_version_nonempty() {
+        assert!(!backend_version().is_empty());
+    }
+
+    #[test]
+    #[cfg(have_rpk)]
+    fn server_raw_public_key() {
+        use backend::openssl::{CertificateExt, TlsAcceptorBuilderExt, TlsConnectorBuilderExt};
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .raw_public_key(true)
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let cert = include_bytes!("../test/cert.der");
+        let cert = p!(Certificate::from_der(cert));
+        let spki = p!(p!(cert.openssl().public_key()).public_key_to_der());
+
+        // no roots are trusted, so the server can only be authenticated by its key
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .disable_built_in_roots(true)
+            .add_expected_raw_public_key(&spki)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        // the server authenticated with its raw key rather than a certificate
+        assert!(p!(socket.peer_certificate()).is_none());
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_nested() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let socket = p!(builder.accept(socket));
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = vec![0; 64 * 1024];
+            p!(socket.read_exact(&mut buf));
+            assert!(buf.iter().all(|&b| b == b'a'));
+
+            p!(socket.write_all(&buf));
+            p!(socket.flush());
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let socket = p!(builder.connect("foobar.com", socket));
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(&vec![b'a'; 64 * 1024]));
+        p!(socket.flush());
+        let mut buf = vec![0; 64 * 1024];
+        p!(socket.read_exact(&mut buf));
+        assert!(buf.iter().all(|&b| b == b'a'));
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_verify_ca() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .verification_mode(VerificationMode::VerifyCa)
+            .build());
+        let mut socket = p!(builder.connect("example.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_allow_unsafe_legacy_renegotiation() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .danger_allow_unsafe_legacy_renegotiation(true)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_partial_chain() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        // trust the leaf directly, without its root
+        let cert = include_bytes!("../test/cert.der");
+        let cert = Certificate::from_der(cert).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(cert)
+            .disable_built_in_roots(true)
+            .allow_partial_chains(true)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_wildcard_policy() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .wildcard_policy(WildcardPolicy::Disallow)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_max_certificate_validity() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            // the client only rejects the certificate once the handshake completes
+            let _ = builder.accept(socket);
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .max_certificate_validity(Some(Duration::from_secs(398 * 24 * 60 * 60)))
+            .build());
+        builder.connect("foobar.com", socket).unwrap_err();
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_reject_sha1_signatures() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .reject_sha1_signatures(true)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    // Returns an acceptor which sends `UNRELATED_CERT` after its own certificate.
+    fn unrelated_chain_acceptor() -> TlsAcceptor {
+        let mut pem = include_bytes!("../test/cert.pem").to_vec();
+        pem.extend_from_slice(UNRELATED_CERT);
+        let key = include_bytes!("../test/key.pem");
+        let identity = p!(Identity::from_pkcs8(&pem, key));
+        #[cfg_attr(
+            any(target_os = "macos", target_os = "windows", target_os = "ios"),
+            allow(unused_mut)
+        )]
+        let mut builder = TlsAcceptor::builder(identity);
+        // OpenSSL refuses to send certificates signed with SHA-1 at its default security level.
+        #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+        {
+            use backend::openssl::TlsAcceptorBuilderExt;
+            builder.configure_context(|ctx| {
+                ctx.set_security_level(0);
+                Ok(())
+            });
+        }
+        p!(builder.build())
+    }
+
+    #[test]
+    fn server_reject_sha1_signatures_unverified() {
+        let builder = unrelated_chain_acceptor();
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            p!(socket.write_all(b"hello"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        // The SHA-1 certificate isn't part of the verified path, so it's ignored.
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .reject_sha1_signatures(true)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        assert_eq!(p!(socket.peer_certificate_chain()).len(), 2);
+
+        let mut buf = [0; 5];
+        p!(socket.read_exact(&mut buf));
+        assert_eq!(&buf, b"hello");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn tokio_connect() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("127.0.0.1:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+            p!(socket.shutdown());
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let builder = p!(TlsConnector::builder().add_root_certificate(root_ca).build());
+
+        let rt = p!(tokio_rt::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build());
+        let socket = p!(rt.block_on(tokio_rt::net::TcpStream::connect(("127.0.0.1", port))));
+        let mut socket = p!(rt.block_on(builder.connect_async("foobar.com", socket)));
+        assert!(p!(socket.get_ref().peer_certificate()).is_some());
+
+        p!(rt.block_on(socket.write_all(b"hello")));
+        let mut buf = vec![];
+        p!(rt.block_on(socket.read_to_end(&mut buf)));
+        assert_eq!(buf, b"world");
+        p!(rt.block_on(socket.shutdown()));
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn tokio_accept() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let rt = p!(tokio_rt::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build());
+        let listener = p!(rt.block_on(tokio_rt::net::TcpListener::bind("127.0.0.1:0")));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let root_ca = include_bytes!("../test/root-ca.der");
+            let root_ca = Certificate::from_der(root_ca).unwrap();
+            let builder = p!(TlsConnector::builder().add_root_certificate(root_ca).build());
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let mut socket = p!(builder.connect("foobar.com", socket));
+
+            p!(socket.write_all(b"hello"));
+            let mut buf = vec![];
+            p!(socket.read_to_end(&mut buf));
+            assert_eq!(buf, b"world");
+        });
+
+        let socket = p!(rt.block_on(listener.accept())).0;
+        let mut socket = p!(rt.block_on(builder.accept_async(socket)));
+
+        let mut buf = [0; 5];
+        p!(rt.block_on(socket.read_exact(&mut buf)));
+        assert_eq!(&buf, b"hello");
+        p!(rt.block_on(socket.write_all(b"world")));
+        p!(rt.block_on(socket.shutdown()));
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(feature = "futures-io")]
+    fn futures_io_connect() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("127.0.0.1:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+            p!(socket.shutdown());
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let builder = p!(TlsConnector::builder().add_root_certificate(root_ca).build());
+
+        let rt = p!(tokio_rt::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build());
+        let socket = p!(rt.block_on(tokio_rt::net::TcpStream::connect(("127.0.0.1", port))));
+        let handshake = futures_io::connect(&builder, "foobar.com", socket.compat());
+        let mut socket = p!(rt.block_on(handshake));
+        assert!(p!(socket.get_ref().peer_certificate()).is_some());
+
+        p!(rt.block_on(socket.write_all(b"hello")));
+        let mut buf = vec![];
+        p!(rt.block_on(socket.read_to_end(&mut buf)));
+        assert_eq!(buf, b"world");
+        p!(rt.block_on(socket.close()));
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(feature = "tcp")]
+    fn server_connect_tcp() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("127.0.0.1:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            assert!(builder.accept(socket).is_err());
+
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let addr = format!("localhost:{}", port);
+
+        // The certificate is for foobar.com, so verification of localhost fails
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca.clone())
+            .build());
+        let err = builder.connect_tcp(&addr).unwrap_err();
+        assert!(err.into_inner().unwrap().is::<Error>());
+
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .danger_accept_invalid_hostnames(true)
+            .build());
+        let mut socket = p!(builder.connect_tcp(&addr));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn certificate_verify_signed_by() {
+        let cert = p!(Certificate::from_der(include_bytes!("../test/cert.der")));
+        let root = p!(Certificate::from_der(include_bytes!("../test/root-ca.der")));
+        assert!(p!(cert.verify_signed_by(&root)));
+        assert!(p!(root.verify_signed_by(&root)));
+        assert!(!p!(root.verify_signed_by(&cert)));
+    }
+
+    #[test]
+    fn certificate_fingerprint() {
+        let cert = p!(Certificate::from_der(include_bytes!("../test/cert.der")));
+        let sha256 = p!(cert.fingerprint(Digest::Sha256));
+        assert_eq!(sha256, p!(cert.fingerprint_sha256()));
+        assert_eq!(p!(cert.fingerprint(Digest::Sha1)).len(), 20);
+        assert_eq!(sha256.len(), 32);
+        assert_eq!(p!(cert.fingerprint(Digest::Sha384)).len(), 48);
+        assert_eq!(p!(cert.fingerprint(Digest::Sha512)).len(), 64);
+
+        let root = p!(Certificate::from_der(include_bytes!("../test/root-ca.der")));
+        assert!(p!(root.fingerprint(Digest::Sha256)) != sha256);
+    }
+
+    #[test]
+    fn certificate_pem_round_trip() {
+        let der = include_bytes!("../test/cert.der");
+        let cert = p!(Certificate::from_der(der));
+        let pem = p!(cert.to_pem());
+        assert!(pem.starts_with(b"-----BEGIN CERTIFICATE-----\n"));
+        assert!(pem.split(|&b| b == b'\n').all(|line| line.len() <= 64));
+        let cert = p!(Certificate::from_pem(&pem));
+        assert_eq!(p!(cert.to_der()), &der[..]);
+    }
+
+    #[test]
+    fn chain_order() {
+        let leaf = include_bytes!("../test/cert.der").to_vec();
+        let root = include_bytes!("../test/root-ca.der").to_vec();
+        let ders = [b"junk".to_vec(), leaf.clone(), root];
+        let sorted = chain::sort(&leaf, vec!["junk", "leaf", "root"], &ders);
+        assert_eq!(sorted, ["root", "junk"]);
+    }
+
+    #[test]
+    fn peer_certificate_chain() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            assert!(p!(socket.peer_certificate_chain()).is_empty());
+            p!(socket.write_all(b"hello"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        let chain = p!(socket.peer_certificate_chain());
+        let chain = chain.iter().map(|c| p!(c.to_der())).collect::<Vec<_>>();
+        assert_eq!(chain[0], &include_bytes!("../test/cert.der")[..]);
+        assert_eq!(chain[chain.len() - 1], &include_bytes!("../test/root-ca.der")[..]);
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"hello");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_omit_root_certificate() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .omit_root_certificate(true)
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        let chain = p!(socket.peer_certificate_chain());
+        assert_eq!(p!(chain[0].to_der()), &include_bytes!("../test/cert.der")[..]);
+        // Security Framework returns the verified chain, which includes the trusted root
+        if !cfg!(any(target_os = "macos", target_os = "ios")) {
+            assert_eq!(chain.len(), 1);
+        }
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn wildcard_policy_matching() {
+        let cases = [
+            ("*.example.com", "www.example.com", [true, true, true, false]),
+            ("*.example.com", "a.b.example.com", [false, false, false, false]),
+            ("*.example.com", "example.com", [false, false, false, false]),
+            ("w*.example.com", "www.example.com", [true, false, true, false]),
+            ("*.co.uk", "example.co.uk", [true, true, false, false]),
+            ("*.github.io", "example.github.io", [true, true, true, false]),
+            ("*.com", "example.com", [false, false, false, false]),
+            ("www.example.com", "WWW.example.com.", [true, true, true, true]),
+        ];
+        let policies = [
+            WildcardPolicy::Allow,
+            WildcardPolicy::SingleLabel,
+            WildcardPolicy::NoRegistryDomain,
+            WildcardPolicy::Disallow,
+        ];
+
+        for &(pattern, host, expected) in &cases {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            for (&policy, &expected) in policies.iter().zip(&expected) {
+                assert_eq!(
+                    hostname::matches(pattern, &host, policy),
+                    expected,
+                    "{} {} {:?}",
+                    pattern,
+                    host,
+                    policy
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn peer_check_ip_addresses() {
+        let mut connector = p!(TlsConnector::new());
+        connector.1.ip_addresses = true;
+        assert!(connector.peer_check(Some("127.0.0.1")).is_some());
+        assert!(connector.peer_check(Some("::1")).is_some());
+        assert!(connector.peer_check(Some("foobar.com")).is_none());
+        assert!(connector.peer_check(None).is_none());
+    }
+
+    #[test]
+    fn peer_certificate() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let socket = p!(builder.accept(socket));
+            assert!(socket.peer_certificate().unwrap().is_none());
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let socket = p!(builder.connect("foobar.com", socket));
+
+        let cert_der = include_bytes!("../test/cert.der");
+        let cert = socket.peer_certificate().unwrap().unwrap();
+        assert_eq!(cert.to_der().unwrap(), &cert_der[..]);
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_tls11_only() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .min_protocol_version(Some(Protocol::Tlsv11))
+            .max_protocol_version(Some(Protocol::Tlsv11))
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .min_protocol_version(Some(Protocol::Tlsv11))
+            .max_protocol_version(Some(Protocol::Tlsv11))
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_max_send_fragment() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .max_send_fragment(Some(512))
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            p!(socket.write_all(&[1; 4096]));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .max_send_fragment(Some(1024))
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, &[1; 4096][..]);
+
+        p!(j.join());
+    }
+
+    // Keeps a copy of everything read from the stream.
+    #[derive(Debug)]
+    struct Transcript<S>(S, Arc<Mutex<Vec<u8>>>);
+
+    impl<S: Read> Read for Transcript<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.read(buf)?;
+            self.1.lock().unwrap().extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+    }
+
+    impl<S: Write> Write for Transcript<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    // Returns the lengths of the application data records in a transcript which start at or
+    // after `offset`.
+    fn application_data_records(transcript: &[u8], offset: usize) -> Vec<usize> {
+        let mut records = vec![];
+        let mut pos = 0;
+        while pos + 5 <= transcript.len() {
+            let len = (transcript[pos + 3] as usize) << 8 | transcript[pos + 4] as usize;
+            if transcript[pos] == 23 && pos >= offset {
+                records.push(len);
+            }
+            pos += 5 + len;
+        }
+        records
+    }
+
+    #[test]
+    fn max_send_fragment_records() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        assert!(TlsAcceptor::builder(identity.clone())
+            .max_send_fragment(Some(16385))
+            .build()
+            .is_err());
+        assert!(TlsConnector::builder()
+            .max_send_fragment(Some(511))
+            .build()
+            .is_err());
+
+        let builder = p!(TlsAcceptor::builder(identity)
+            .max_send_fragment(Some(512))
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let transcript = Arc::new(Mutex::new(vec![]));
+            let socket = Transcript(p!(listener.accept()).0, transcript.clone());
+            let mut socket = p!(builder.accept(socket));
+            let offset = transcript.lock().unwrap().len();
+
+            p!(socket.write_all(&[1; 4096]));
+            let mut buf = [0; 4096];
+            p!(socket.read_exact(&mut buf));
+
+            // the record sizes include the encryption overhead
+            let records = application_data_records(&transcript.lock().unwrap(), offset);
+            assert!(records.len() >= 4, "{:?}", records);
+            assert!(records.iter().all(|&len| len <= 1024 + 256), "{:?}", records);
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let transcript = Arc::new(Mutex::new(vec![]));
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let socket = Transcript(socket, transcript.clone());
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .max_send_fragment(Some(1024))
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        let offset = transcript.lock().unwrap().len();
+
+        // the server only writes once the handshake has completed, so everything it sends from
+        // here on is covered by the limit
+        let mut buf = [0; 4096];
+        p!(socket.read_exact(&mut buf));
+        assert_eq!(&buf[..], &[1; 4096][..]);
+        p!(socket.write_all(&[2; 4096]));
+
+        let records = application_data_records(&transcript.lock().unwrap(), offset);
+        assert!(records.len() >= 8, "{:?}", records);
+        assert!(records.iter().all(|&len| len <= 512 + 256), "{:?}", records);
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_boxed() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let socket = Box::new(socket) as Box<dyn ReadWrite + Send>;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let socket = Box::new(socket) as Box<dyn ReadWrite + Send>;
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_no_shared_protocol() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .min_protocol_version(Some(Protocol::Tlsv12))
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            assert!(builder.accept(socket).is_err());
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .min_protocol_version(Some(Protocol::Tlsv10))
+            .max_protocol_version(Some(Protocol::Tlsv11))
+            .build());
+        assert!(builder.connect("foobar.com", socket).is_err());
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_untrusted() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            // FIXME should assert error
+            // https://github.com/steffengy/schannel-rs/issues/20
+            let _ = builder.accept(socket);
+        });
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::new());
+        builder.connect("foobar.com", socket).unwrap_err();
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn server_untrusted_unverified() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn import_same_identity_multiple_times() {
+        let buf = include_bytes!("../test/identity.p12");
+        let _ = p!(Identity::from_pkcs12(buf, "mypass"));
+        let _ = p!(Identity::from_pkcs12(buf, "mypass"));
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn certificate_openssl_round_trip() {
+        use backend::openssl::CertificateExt;
+
+        let der = include_bytes!("../test/cert.der");
+        let cert = p!(Certificate::from_der(der));
+        let cert = Certificate::from_openssl(cert.openssl().clone());
+        assert_eq!(p!(cert.to_der()), &der[..]);
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn identity_openssl() {
+        extern crate openssl;
+        use backend::openssl::{CertificateExt, IdentityExt};
+
+        let key = include_bytes!("../test/key.pem");
+        let key = p!(openssl::pkey::PKey::private_key_from_pem(key));
+        let cert = p!(openssl::x509::X509::from_pem(include_bytes!("../test/cert.pem")));
+        let root_ca = p!(openssl::x509::X509::from_pem(include_bytes!("../test/root-ca.pem")));
+
+        let identity = p!(Identity::from_openssl(
+            key,
+            cert.clone(),
+            vec![root_ca.clone()]
+        ));
+        assert_eq!(p!(p!(identity.certificate()).to_der()), p!(cert.to_der()));
+        let acceptor = p!(TlsAcceptor::new(identity));
+
+        let curve = openssl::nid::Nid::X9_62_PRIME256V1;
+        let group = p!(openssl::ec::EcGroup::from_curve_name(curve));
+        let other = p!(openssl::ec::EcKey::generate(&group));
+        let other = p!(openssl::pkey::PKey::from_ec_key(other));
+        assert!(Identity::from_openssl(other, cert, vec![]).is_err());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(acceptor.accept(socket));
+            p!(socket.write_all(b"hello"));
+        });
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let connector = p!(TlsConnector::builder()
+            .add_root_certificate(Certificate::from_openssl(root_ca))
+            .build());
+        let mut socket = p!(connector.connect("foobar.com", socket));
+        let mut buf = [0; 5];
+        p!(socket.read_exact(&mut buf));
+        assert_eq!(&buf, b"hello");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn error_openssl() {
+        use backend::openssl::ErrorExt;
+
+        let e = Certificate::from_der(b"not a certificate").err().unwrap();
+        assert!(e.openssl_error_stack().is_some());
+        assert!(e.openssl_ssl_error().is_none());
+        assert_eq!(e.openssl_verify_result(), None);
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let acceptor = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            acceptor.accept(socket).unwrap_err();
+        });
+
+        // the test root isn't trusted
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let e = match p!(TlsConnector::new()).connect("foobar.com", socket) {
+            Err(HandshakeError::Failure(e)) => e,
+            _ => panic!("expected a handshake failure"),
+        };
+        assert!(e.openssl_ssl_error().is_some());
+        // anything but X509_V_OK
+        assert!(e.openssl_verify_result().unwrap().as_raw() != 0);
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn configure_context_openssl() {
+        extern crate openssl;
+        use backend::openssl::{TlsAcceptorBuilderExt, TlsConnectorBuilderExt};
+
+        let e = TlsConnector::builder()
+            .configure_context(|ctx| ctx.set_cipher_list("not a cipher"))
+            .build();
+        assert!(e.is_err());
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let acceptor = p!(TlsAcceptor::builder(identity)
+            .configure_context(|ctx| {
+                ctx.set_max_proto_version(Some(openssl::ssl::SslVersion::TLS1_2))
+            })
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let socket = p!(acceptor.accept(socket));
+            assert_eq!(p!(socket.protocol_version()), Some(Protocol::Tlsv12));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let socket = p!(builder.connect("foobar.com", socket));
+        assert_eq!(p!(socket.protocol_version()), Some(Protocol::Tlsv12));
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn shutdown() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            assert_eq!(p!(socket.read(&mut buf)), 0);
+            p!(socket.shutdown());
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        p!(socket.shutdown());
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn vectored() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            // the rest of the record is already decrypted when the first slice is full
+            let mut head = [0; 10];
+            let mut tail = [0; 100];
+            let n = p!(socket.read_vectored(&mut [
+                io::IoSliceMut::new(&mut head),
+                io::IoSliceMut::new(&mut []),
+                io::IoSliceMut::new(&mut tail),
+            ]));
+            assert_eq!(n, 100);
+            assert_eq!(&head, b"0123456789");
+            assert_eq!(&tail[..90], &b"0123456789".repeat(9)[..]);
+            assert_eq!(socket.bytes_read(), 100);
+
+            p!(socket.write_all(b"done"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        // written as a single record rather than one per slice
+        let bufs = vec![io::IoSlice::new(b"0123456789"); 10];
+        assert_eq!(p!(socket.write_vectored(&bufs)), 100);
+        assert_eq!(socket.bytes_written(), 100);
+
+        let mut buf = [0; 4];
+        p!(socket.read_exact(&mut buf));
+        assert_eq!(&buf, b"done");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(feature = "tcp")]
+    fn split() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+            p!(socket.write_all(b"world"));
+
+            assert_eq!(p!(socket.read(&mut buf)), 0);
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let socket = p!(builder.connect("foobar.com", socket));
+        let (mut read, mut write) = p!(socket.split());
+
+        // the server only responds once the write goes through, so the waiting read mustn't
+        // block it
+        let reader = thread::spawn(move || {
+            let mut buf = [0; 5];
+            p!(read.read_exact(&mut buf));
+            assert_eq!(&buf, b"world");
+        });
+        thread::sleep(Duration::from_millis(100));
+        p!(write.write_all(b"hello"));
+
+        p!(reader.join());
+        p!(write.shutdown());
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(feature = "tcp")]
+    fn split_read_timeout() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+            p!(socket.write_all(b"world"));
+
+            assert_eq!(p!(socket.read(&mut buf)), 0);
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let socket = p!(builder.connect("foobar.com", socket));
+        p!(socket.get_ref().set_read_timeout(Some(Duration::from_millis(100))));
+        let (mut read, mut write) = p!(socket.split());
+
+        let mut buf = [0; 5];
+        let err = read.read(&mut buf).unwrap_err();
+        assert!(
+            err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut,
+            "{:?}",
+            err
+        );
+
+        p!(write.write_all(b"hello"));
+        p!(read.read_exact(&mut buf));
+        assert_eq!(&buf, b"world");
+
+        p!(write.shutdown());
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "tcp"))]
+    fn split_nonblocking() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+            p!(socket.write_all(b"world"));
+
+            assert_eq!(p!(socket.read(&mut buf)), 0);
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let socket = p!(builder.connect("foobar.com", socket));
+        p!(socket.get_ref().set_nonblocking(true));
+        let (mut read, mut write) = p!(socket.split());
+
+        let mut buf = [0; 5];
+        let err = read.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        p!(write.write_all(b"hello"));
+        let mut out = vec![];
+        while out.len() < 5 {
+            match read.read(&mut buf) {
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(out, b"world");
+        // the socket is left in nonblocking mode
+        assert_eq!(read.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        p!(write.shutdown());
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn negotiated_parameters() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            let cipher_suite = p!(socket.cipher_suite());
+            p!(socket.write_all(&cipher_suite.to_u16().to_be_bytes()));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .max_protocol_version(Some(Protocol::Tlsv12))
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        assert_eq!(p!(socket.protocol_version()), Some(Protocol::Tlsv12));
+        let cipher_suite = p!(socket.cipher_suite());
+        assert!(cipher_suite.name().is_some());
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = [0; 2];
+        p!(socket.read_exact(&mut buf));
+        assert_eq!(CipherSuite::from_u16(u16::from_be_bytes(buf)), cipher_suite);
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(have_tls13)]
+    fn negotiate_tls13() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .min_protocol_version(Some(Protocol::Tlsv13))
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            assert_eq!(p!(socket.protocol_version()), Some(Protocol::Tlsv13));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        assert_eq!(p!(socket.protocol_version()), Some(Protocol::Tlsv13));
+        let cipher_suite = p!(socket.cipher_suite());
+        // TLS 1.3 suites don't name a key exchange or signature algorithm
+        assert!(!cipher_suite.name().unwrap().contains("_WITH_"));
+        assert_eq!(p!(socket.tls_unique()), None);
+
+        p!(socket.write_all(b"hello"));
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(have_tls13)]
+    fn early_data() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .max_early_data_size(1024)
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            for _ in 0..2 {
+                let socket = p!(listener.accept()).0;
+                let mut socket = p!(builder.accept(socket));
+
+                let mut buf = [0; 5];
+                p!(socket.read_exact(&mut buf));
+                assert_eq!(&buf, b"hello");
+
+                let accepted = p!(socket.early_data_accepted());
+                p!(socket.write_all(&[accepted as u8]));
+                let mut buf = vec![];
+                p!(socket.read_to_end(&mut buf));
+                p!(socket.shutdown());
+            }
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .session_cache(true)
+            .enable_early_data(true)
+            .build());
+
+        let connect = || {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let mut socket = p!(builder.connect_with_early_data("foobar.com", socket, b"hello"));
+            let accepted = p!(socket.early_data_accepted());
+            if !accepted {
+                p!(socket.write_all(b"hello"));
+            }
+
+            let mut buf = [0; 1];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(buf[0] != 0, accepted);
+            p!(socket.shutdown());
+            accepted
+        };
+
+        // There's no session to send early data with on the first connection.
+        assert!(!connect());
+        assert!(connect());
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn cipher_suite_display() {
+        let suite = CipherSuite::from_u16(0xc02f);
+        assert_eq!(suite.to_string(), "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256");
+        assert_eq!(CipherSuite::from_u16(0xfefe).to_string(), "0xFEFE");
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn restricted_cipher_suites() {
+        let suite = CipherSuite::from_name("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384").unwrap();
+        assert_eq!(suite.to_u16(), 0xc030);
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::builder(identity)
+            .max_protocol_version(Some(Protocol::Tlsv12))
+            .cipher_suites(&[suite, CipherSuite::from_u16(0xc02f)])
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+            assert_eq!(p!(socket.cipher_suite()), suite);
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .max_protocol_version(Some(Protocol::Tlsv12))
+            .cipher_suites(&[suite])
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+        assert_eq!(p!(socket.cipher_suite()), suite);
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+
+        assert!(TlsConnector::builder()
+            .cipher_suites(&[CipherSuite::from_u16(0xfefe)])
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn cipher_suites_cover_protocols() {
+        let tls12 = CipherSuite::from_u16(0xc02f);
+        let tls13 = CipherSuite::from_u16(0x1301);
+
+        assert!(TlsConnector::builder()
+            .cipher_suites(&[tls12])
+            .build()
+            .is_err());
+        assert!(TlsConnector::builder()
+            .max_protocol_version(Some(Protocol::Tlsv12))
+            .cipher_suites(&[tls13])
+            .build()
+            .is_err());
+        assert!(TlsConnector::builder()
+            .cipher_suites(&[tls13])
+            .build()
+            .is_err());
+        p!(TlsConnector::builder()
+            .min_protocol_version(Some(Protocol::Tlsv13))
+            .cipher_suites(&[tls13])
+            .build());
+        p!(TlsConnector::builder()
+            .cipher_suites(&[tls12, tls13])
+            .build());
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "ios", ignore)]
+    fn tls_server_end_point() {
+        let expected = "4712b939fbcb42a6b5101b42139a25b14f81b418facabd378746f12f85cc6544";
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let binding = socket.tls_server_end_point().unwrap().unwrap();
+            assert_eq!(hex::encode(binding), expected);
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        let binding = socket.tls_server_end_point().unwrap().unwrap();
+        assert_eq!(hex::encode(binding), expected);
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn tls_unique() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            for _ in 0..2 {
+                let socket = p!(listener.accept()).0;
+                let mut socket = p!(builder.accept(socket));
+
+                let binding = p!(socket.tls_unique()).unwrap();
+                p!(socket.write_all(&binding));
+                let mut buf = vec![];
+                p!(socket.read_to_end(&mut buf));
+                p!(socket.shutdown());
+            }
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .max_protocol_version(Some(Protocol::Tlsv12))
+            .session_cache(true)
+            .build());
+
+        // The first handshake is a full one, and the second resumes its session.
+        for &resumed in &[false, true] {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            let mut socket = p!(builder.connect("foobar.com", socket));
+            assert_eq!(p!(socket.session_resumed()), resumed);
+
+            let binding = p!(socket.tls_unique()).unwrap();
+            let mut buf = vec![0; binding.len()];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(buf, binding);
+            p!(socket.shutdown());
+        }
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn export_keying_material() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let builder = p!(TlsAcceptor::new(identity));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(builder.accept(socket));
+
+            let material = p!(socket.export_keying_material("EXPORTER-test", Some(b"ctx"), 32));
+            p!(socket.write_all(&material));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let builder = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let mut socket = p!(builder.connect("foobar.com", socket));
+
+        let material = p!(socket.export_keying_material("EXPORTER-test", Some(b"ctx"), 32));
+        assert_eq!(material.len(), 32);
+        let other = p!(socket.export_keying_material("EXPORTER-test", None, 32));
+        assert_ne!(material, other);
+
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, material);
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn from_config() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+
+        let mut config = TlsConfig::default();
+        match TlsAcceptor::from_config(&config) {
+            Err(ConfigError::MissingIdentity) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+
+        config.identity = Some(IdentityConfig {
+            path: dir.join("identity.jks"),
+            password: "mypass".to_string(),
+        });
+        assert!(!format!("{:?}", config).contains("mypass"));
+        let acceptor = p!(TlsAcceptor::from_config(&config));
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(acceptor.accept(socket));
+
+            let mut buf = [0; 5];
+            p!(socket.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+
+            p!(socket.write_all(b"world"));
+        });
+
+        let config = TlsConfig {
+            root_certificates: vec![dir.join("missing.pem")],
+            ..TlsConfig::default()
+        };
+        match TlsConnector::from_config(&config) {
+            Err(ConfigError::Io(ref path, _)) if *path == dir.join("missing.pem") => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+
+        // the root certificate follows the leaf certificate in the bundle
+        let config = TlsConfig {
+            root_certificates: vec![dir.join("identity.pem")],
+            ..TlsConfig::default()
+        };
+        let connector = p!(TlsConnector::from_config(&config));
+        let socket = p!(TcpStream::connect(("localhost", port)));
+        let mut socket = p!(connector.connect("foobar.com", socket));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = vec![];
+        p!(socket.read_to_end(&mut buf));
+        assert_eq!(buf, b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn deserialize_config() {
+        let config: TlsConfig = p!(serde_json::from_str(
+            r#"{
+                "min_protocol_version": "tls1.1",
+                "cipher_suites": ["TLS_AES_128_GCM_SHA256"],
+                "root_certificates": ["test/root-ca.der"],
+                "identity": {"path": "test/identity.p12", "password": "mypass"},
+                "danger_accept_invalid_hostnames": true
+            }"#
+        ));
+        match config.min_protocol_version {
+            Some(Protocol::Tlsv11) => {}
+            v => panic!("unexpected version {:?}", v),
+        }
+        assert!(config.max_protocol_version.is_none());
+        assert_eq!(config.cipher_suites, vec![CipherSuite::from_u16(0x1301)]);
+        assert_eq!(
+            config.root_certificates,
+            vec![std::path::PathBuf::from("test/root-ca.der")]
+        );
+        assert!(!config.disable_built_in_roots);
+        assert_eq!(config.identity.unwrap().password, "mypass");
+        assert!(config.use_sni);
+        assert!(!config.danger_accept_invalid_certs);
+        assert!(config.danger_accept_invalid_hostnames);
+
+        let config: TlsConfig = p!(serde_json::from_str("{}"));
+        match config.min_protocol_version {
+            Some(Protocol::Tlsv12) => {}
+            v => panic!("unexpected version {:?}", v),
+        }
+        let config: TlsConfig = p!(serde_json::from_str(r#"{"min_protocol_version": "oldest"}"#));
+        assert!(config.min_protocol_version.is_none());
+
+        assert!(serde_json::from_str::<TlsConfig>(r#"{"min_protocol_version": "tls9"}"#).is_err());
+        assert!(serde_json::from_str::<TlsConfig>(r#"{"cipher_suites": ["TLS_FOO"]}"#).is_err());
+        assert!(serde_json::from_str::<TlsConfig>(r#"{"use_npn": true}"#).is_err());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+    fn dtls() {
+        use dtls::{Datagram, DatagramStream, DtlsStream};
+        use std::net::UdpSocket;
+
+        // Drops the first datagram sent, forcing a retransmission.
+        #[derive(Debug)]
+        struct Lossy(UdpSocket, bool);
+
+        impl Datagram for Lossy {
+            fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if !self.1 {
+                    self.1 = true;
+                    return Ok(buf.len());
+                }
+                self.0.send(buf)
+            }
+
+            fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.recv(buf)
+            }
+        }
+
+        fn finish<D: Datagram + fmt::Debug>(
+            mut result: result::Result<DtlsStream<D>, HandshakeError<DatagramStream<D>>>,
+        ) -> DtlsStream<D> {
+            loop {
+                match result {
+                    Ok(s) => return s,
+                    Err(HandshakeError::WouldBlock(s)) => result = s.handshake(),
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        }
+
+        let server = p!(UdpSocket::bind("127.0.0.1:0"));
+        let client = p!(UdpSocket::bind("127.0.0.1:0"));
+        p!(server.connect(p!(client.local_addr())));
+        p!(client.connect(p!(server.local_addr())));
+        p!(server.set_read_timeout(Some(Duration::from_millis(100))));
+        p!(client.set_read_timeout(Some(Duration::from_millis(100))));
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let acceptor = p!(TlsAcceptor::builder(identity).build_dtls());
+
+        let j = thread::spawn(move || {
+            let mut socket = finish(acceptor.accept(server));
+            assert_eq!(p!(socket.protocol_version()), Some(Protocol::Tlsv12));
+
+            let mut buf = [0; 100];
+            let len = p!(socket.read(&mut buf));
+            assert_eq!(&buf[..len], b"hello");
+            p!(socket.write_all(b"world"));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let connector = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build_dtls());
+        let mut socket = finish(connector.connect("foobar.com", Lossy(client, false)));
+        assert_eq!(p!(socket.protocol_version()), Some(Protocol::Tlsv12));
+
+        p!(socket.write_all(b"hello"));
+        let mut buf = [0; 100];
+        let len = p!(socket.read(&mut buf));
+        assert_eq!(&buf[..len], b"world");
+
+        p!(j.join());
+    }
+
+    #[test]
+    fn shareable() {
+        fn is_shareable<T: Clone + Send + Sync + 'static>() {}
+
+        is_shareable::<TlsConnector>();
+        is_shareable::<TlsAcceptor>();
+        is_shareable::<dtls::DtlsConnector>();
+        is_shareable::<dtls::DtlsAcceptor>();
+        is_shareable::<Certificate>();
+        is_shareable::<Identity>();
+
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let acceptor = p!(TlsAcceptor::new(identity));
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let connector = p!(TlsConnector::builder().add_root_certificate(root_ca).build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let server = thread::spawn(move || {
+            let threads = (0..4)
+                .map(|_| {
+                    let socket = p!(listener.accept()).0;
+                    let acceptor = acceptor.clone();
+                    thread::spawn(move || {
+                        let mut socket = p!(acceptor.accept(socket));
+                        p!(socket.write_all(b"hello"));
+                    })
+                })
+                .collect::<Vec<_>>();
+            for j in threads {
+                p!(j.join());
+            }
+        });
+
+        let clients = (0..4)
+            .map(|_| {
+                let connector = connector.clone();
+                thread::spawn(move || {
+                    let socket = p!(TcpStream::connect(("localhost", port)));
+                    let mut socket = p!(connector.connect("foobar.com", socket));
+                    let mut buf = [0; 5];
+                    p!(socket.read_exact(&mut buf));
+                    assert_eq!(&buf, b"hello");
+                })
+            })
+            .collect::<Vec<_>>();
+        for j in clients {
+            p!(j.join());
+        }
+        p!(server.join());
+    }
+
+    #[test]
+    fn debug() {
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct Config {
+            identity: Identity,
+            connector: TlsConnectorBuilder,
+            acceptor: TlsAcceptorBuilder,
+        }
+
+        let cert = include_bytes!("../test/cert.der");
+        let cert = p!(Certificate::from_der(cert));
+        assert_eq!(
+            format!("{:?}", cert),
+            "Certificate { \
+             subject: \"C=AU, ST=Some-State, O=Internet Widgits Pty Ltd, CN=foobar.com\", \
+             issuer: \"C=AU, ST=Some-State, O=Internet Widgits Pty Ltd\" }"
+        );
+
+        let pem = include_bytes!("../test/cert.pem");
+        let key = include_bytes!("../test/key.pem");
+        let identity = p!(Identity::from_pkcs8(pem, key));
+        let config = Config {
+            identity: identity.clone(),
+            connector: TlsConnector::builder(),
+            acceptor: TlsAcceptor::builder(identity),
+        };
+        let debug = format!("{:?}", config);
+        assert!(debug.contains(&format!("identity: Identity {{ certificate: {:?} }}", cert)));
+        assert!(debug.contains("TlsConnectorBuilder {"));
+        assert!(debug.contains("TlsAcceptorBuilder {"));
+    }
+
+    #[test]
+    fn error_kind() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let acceptor = p!(TlsAcceptor::builder(identity)
+            .max_protocol_version(Some(Protocol::Tlsv12))
+            .build());
+
+        let listener = p!(TcpListener::bind("0.0.0.0:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            for _ in 0..3 {
+                let socket = p!(listener.accept()).0;
+                let _ = acceptor.accept(socket);
+            }
+            // the stream is closed before the handshake
+            drop(p!(listener.accept()));
+        });
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+
+        let connect = |builder: &TlsConnectorBuilder, domain| {
+            let socket = p!(TcpStream::connect(("localhost", port)));
+            match p!(builder.build()).connect(domain, socket) {
+                Err(HandshakeError::Failure(e)) => e.kind(),
+                Err(HandshakeError::WouldBlock(_)) => panic!("unexpected WouldBlock"),
+                Ok(_) => panic!("unexpected success"),
+            }
+        };
+
+        assert_eq!(
+            connect(TlsConnector::builder().add_root_certificate(root_ca.clone()), "example.com"),
+            ErrorKind::HostnameMismatch
+        );
+        assert_eq!(
+            connect(&TlsConnector::builder(), "foobar.com"),
+            ErrorKind::UntrustedRoot
+        );
+        let mut builder = TlsConnector::builder();
+        builder.add_root_certificate(root_ca);
+        if cfg!(have_tls13) {
+            builder.min_protocol_version(Some(Protocol::Tlsv13));
+            assert_eq!(connect(&builder, "foobar.com"), ErrorKind::ProtocolVersion);
+        } else {
+            // keep the number of connections the server expects
+            drop(p!(TcpStream::connect(("localhost", port))));
+        }
+        builder.min_protocol_version(Some(Protocol::Tlsv12));
+        assert_eq!(connect(&builder, "foobar.com"), ErrorKind::Io);
+
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    fn handshake_timeout() {
+        // Never receives anything, like a nonblocking socket to a silent server.
+        #[derive(Debug)]
+        struct Silent;
+
+        impl Read for Silent {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"))
+            }
+        }
+
+        impl Write for Silent {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let connector = p!(TlsConnector::builder()
+            .handshake_timeout(Some(Duration::from_millis(100)))
+            .build());
+        let stream = match connector.connect("foobar.com", Silent) {
+            Err(HandshakeError::WouldBlock(stream)) => stream,
+            r => panic!("unexpected result {:?}", r),
+        };
+        let stream = match stream.handshake() {
+            Err(HandshakeError::WouldBlock(stream)) => stream,
+            r => panic!("unexpected result {:?}", r),
+        };
+
+        thread::sleep(Duration::from_millis(200));
+        let e = match stream.handshake() {
+            Err(HandshakeError::Failure(e)) => e,
+            r => panic!("unexpected result {:?}", r),
+        };
+        assert_eq!(e.kind(), ErrorKind::Io);
+        assert_eq!(e.io_error().unwrap().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    #[cfg(feature = "tcp")]
+    fn accept_tcp_timeout() {
+        let buf = include_bytes!("../test/identity.p12");
+        let identity = p!(Identity::from_pkcs12(buf, "mypass"));
+        let acceptor = p!(TlsAcceptor::builder(identity)
+            .handshake_timeout(Some(Duration::from_millis(200)))
+            .build());
+
+        let listener = p!(TcpListener::bind("127.0.0.1:0"));
+        let port = p!(listener.local_addr()).port();
+
+        let j = thread::spawn(move || {
+            // the first client never sends anything
+            let socket = p!(listener.accept()).0;
+            assert!(acceptor.accept_tcp(socket).is_err());
+
+            let socket = p!(listener.accept()).0;
+            let mut socket = p!(acceptor.accept_tcp(socket));
+            assert_eq!(p!(socket.get_ref().read_timeout()), None);
+            p!(socket.write_all(b"hello"));
+        });
+
+        let silent = p!(TcpStream::connect(("127.0.0.1", port)));
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let root_ca = Certificate::from_der(root_ca).unwrap();
+        let connector = p!(TlsConnector::builder()
+            .add_root_certificate(root_ca)
+            .build());
+        let socket = p!(TcpStream::connect(("127.0.0.1", port)));
+        let mut socket = p!(connector.connect("foobar.com", socket));
+        let mut buf = [0; 5];
+        p!(socket.read_exact(&mut buf));
+        assert_eq!(&buf, b"hello");
+
+        drop(silent);
+        p!(j.join());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    fn handshake_io_error() {
+        // Accepts the client hello, then fails when reading the response.
+        #[derive(Debug)]
+        struct Reset;
+
+        impl Read for Reset {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+            }
+        }
+
+        impl Write for Reset {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let connector = p!(TlsConnector::new());
+        let e = match connector.connect("foobar.com", Reset) {
+            Err(HandshakeError::Failure(e)) => e,
+            r => panic!("unexpected result {:?}", r),
+        };
+        assert_eq!(e.kind(), ErrorKind::Io);
+        assert_eq!(e.io_error().unwrap().kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(e.into_io_error().unwrap().kind(), io::ErrorKind::ConnectionReset);
+
+        let root_ca = include_bytes!("../test/root-ca.der");
+        let e = Certificate::from_der(&root_ca[..10]).err().unwrap();
+        assert!(e.io_error().is_none());
+        assert!(e.into_io_error().is_err());
+    }
 }
@@ -0,0 +1,197 @@
+//! Support for Tokio's `AsyncRead` and `AsyncWrite` traits.
+//!
+//! Handshakes are started with `TlsConnector::connect_async` and `TlsAcceptor::accept_async`.
+//!
+//! Requires the `tokio` Cargo feature.
+
+extern crate tokio;
+
+use self::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll};
+
+use async_io;
+use Result;
+
+/// A synchronous view of an async stream, used as the transport of the wrapped `TlsStream`.
+///
+/// Reads and writes fail with `WouldBlock` outside of a call into the async `TlsStream`.
+pub struct AllowStd<S> {
+    inner: S,
+    context: *mut (),
+}
+
+// The context pointer is only set while the stream is being polled through a mutable reference.
+unsafe impl<S: Send> Send for AllowStd<S> {}
+unsafe impl<S: Sync> Sync for AllowStd<S> {}
+
+impl<S: fmt::Debug> fmt::Debug for AllowStd<S> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, fmt)
+    }
+}
+
+impl<S> AllowStd<S> {
+    pub(crate) fn new(inner: S) -> AllowStd<S> {
+        AllowStd {
+            inner,
+            context: ptr::null_mut(),
+        }
+    }
+
+    /// Returns a shared reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S> AllowStd<S>
+where
+    S: Unpin,
+{
+    fn with_context<F, R>(&mut self, f: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut Context, Pin<&mut S>) -> Poll<io::Result<R>>,
+    {
+        if self.context.is_null() {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        let cx = unsafe { &mut *(self.context as *mut Context) };
+        match f(cx, Pin::new(&mut self.inner)) {
+            Poll::Ready(r) => r,
+            Poll::Pending => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+        }
+    }
+}
+
+impl<S> io::Read for AllowStd<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        self.with_context(|cx, stream| stream.poll_read(cx, &mut buf))?;
+        Ok(buf.filled().len())
+    }
+}
+
+impl<S> io::Write for AllowStd<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_context(|cx, stream| stream.poll_write(cx, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_context(|cx, stream| stream.poll_flush(cx))
+    }
+}
+
+impl<S> async_io::AllowStd for AllowStd<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn set_context(&mut self, cx: *mut ()) {
+        self.context = cx;
+    }
+}
+
+/// A future which completes a TLS handshake.
+pub struct Handshake<S>(pub(crate) async_io::Handshake<AllowStd<S>>);
+
+impl<S> Future for Handshake<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Output = Result<TlsStream<S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<TlsStream<S>>> {
+        self.0.poll(cx).map(|r| r.map(TlsStream))
+    }
+}
+
+/// A TLS stream over an async stream.
+#[derive(Debug)]
+pub struct TlsStream<S>(::TlsStream<AllowStd<S>>);
+
+impl<S> TlsStream<S> {
+    /// Returns a shared reference to the synchronous TLS stream.
+    ///
+    /// This can be used to inspect the session, for example with `peer_certificate`.
+    pub fn get_ref(&self) -> &::TlsStream<AllowStd<S>> {
+        &self.0
+    }
+
+    /// Returns a mutable reference to the synchronous TLS stream.
+    pub fn get_mut(&mut self) -> &mut ::TlsStream<AllowStd<S>> {
+        &mut self.0
+    }
+}
+
+impl<S> AsyncRead for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let n = match async_io::poll_with(&mut self.0, cx, |s| {
+            io::Read::read(s, buf.initialize_unfilled())
+        }) {
+            Poll::Ready(Ok(n)) => n,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        async_io::poll_with(&mut self.0, cx, |s| io::Write::write(s, buf))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[io::IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        async_io::poll_with(&mut self.0, cx, |s| io::Write::write_vectored(s, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        async_io::poll_with(&mut self.0, cx, io::Write::flush)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match async_io::poll_with(&mut self.0, cx, |s| s.shutdown()) {
+            Poll::Ready(Ok(())) => {}
+            r => return r,
+        }
+        Pin::new(self.0.get_mut().get_mut()).poll_shutdown(cx)
+    }
+}